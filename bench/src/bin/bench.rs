@@ -12,7 +12,7 @@ use std::time::Instant;
 use clap::{Parser, ValueEnum};
 use llfree::frame::Frame;
 use llfree::mmap::{self, MMap};
-use llfree::util::{self, aligned_buf, WyRand};
+use llfree::util::{self, aligned_buf, Bytes, Frames, WyRand};
 use llfree::wrapper::NvmAlloc;
 #[cfg(feature = "llc")]
 use llfree::LLC;
@@ -48,12 +48,16 @@ struct Args {
     /// Specifies how many pages should be allocated: #pages = 2^order
     #[arg(short = 's', long, default_value = "0")]
     order: Vec<usize>,
-    /// Max amount of memory in GiB.
-    #[arg(short, long, default_value_t = 16)]
-    memory: usize,
+    /// Max amount of memory, like 512M or 2G (GiB without suffix).
+    #[arg(short, long, default_value = "16G", value_parser = |s: &str| Bytes::parse_in(s, Bytes::G))]
+    memory: Bytes,
     /// Use every n-th cpu.
     #[arg(long, default_value_t = 1)]
     stride: usize,
+    /// Simulated latency of persistent stores in ns (loads take a third).
+    #[cfg(feature = "pmem_sim")]
+    #[arg(long)]
+    pmem_sim: Option<usize>,
 }
 
 fn main() {
@@ -68,6 +72,8 @@ fn main() {
         order,
         memory,
         stride,
+        #[cfg(feature = "pmem_sim")]
+        pmem_sim,
     } = Args::parse();
 
     util::logging();
@@ -75,15 +81,19 @@ fn main() {
     if stride > 1 {
         thread::STRIDE.store(stride, Ordering::Relaxed);
     }
+    #[cfg(feature = "pmem_sim")]
+    if let Some(store) = pmem_sim {
+        llfree::atomic::pmem_sim::set(store, store / 3);
+    }
 
-    assert!(memory >= 1);
+    assert!(memory >= Bytes::G);
 
     let mut out = File::create(outfile).unwrap();
     writeln!(out, "alloc,x,order,iteration,memory,{}", Perf::header()).unwrap();
 
     warn!("Allocating orders {order:?}");
 
-    let mut mapping = mapping(0x1000_0000_0000, (memory << 30) / Frame::SIZE, dax);
+    let mut mapping = mapping(0x1000_0000_0000, memory.frames_floor().0, dax);
 
     for x in x {
         for o in order.iter().copied() {
@@ -91,7 +101,7 @@ fn main() {
             for name in &allocs {
                 for i in 0..iterations {
                     let perf = bench.run(name, &mut mapping, o, threads, x);
-                    writeln!(out, "{name},{x},{o},{i},{memory},{perf}").unwrap();
+                    writeln!(out, "{name},{x},{o},{i},{},{perf}", memory.0 >> 30).unwrap();
                 }
             }
         }
@@ -104,7 +114,7 @@ fn main() {
 pub fn mapping(begin: usize, length: usize, dax: Option<String>) -> Box<[Frame], MMap> {
     #[cfg(target_os = "linux")]
     if let Some(file) = dax {
-        warn!("MMap file {file} l={}G", (length * Frame::SIZE) >> 30);
+        warn!("MMap file {file} l={}", Frames(length));
         return mmap::file(begin, length, &file, true);
     }
     mmap::anon(begin, length, false, false)
@@ -140,13 +150,13 @@ fn alloc<'a>(name: &str, cores: usize, zone: &'a mut [Frame]) -> Box<dyn DynAllo
         let m = NvmAlloc::<LLC>::metadata_size(cores, zone.len());
         let local = aligned_buf(m.local).leak();
         let trees = aligned_buf(m.trees).leak();
-        return Box::new(NvmAlloc::<LLC>::create(cores, zone, false, local, trees).unwrap());
+        return Box::new(NvmAlloc::<LLC>::create(cores, zone, 0, false, local, trees).unwrap());
     }
     if LLFree::name() == name {
         let m = NvmAlloc::<LLFree>::metadata_size(cores, zone.len());
         let local = aligned_buf(m.local).leak();
         let trees = aligned_buf(m.trees).leak();
-        return Box::new(NvmAlloc::<LLFree>::create(cores, zone, false, local, trees).unwrap());
+        return Box::new(NvmAlloc::<LLFree>::create(cores, zone, 0, false, local, trees).unwrap());
     }
     panic!("Unknown allocator");
 }
@@ -8,7 +8,7 @@ use std::time::Duration;
 use clap::Parser;
 use llfree::frame::Frame;
 use llfree::mmap::{self, MMap};
-use llfree::util::{self, align_up, aligned_buf, WyRand};
+use llfree::util::{self, align_up, aligned_buf, Bytes, Frames, WyRand};
 use llfree::wrapper::NvmAlloc;
 use llfree::{thread, Alloc, Flags, LLFree};
 use log::{error, warn};
@@ -24,9 +24,9 @@ struct Args {
     dax: Option<String>,
     #[arg(short, long, default_value_t = 0)]
     order: usize,
-    /// Max amount of memory in GiB. Is by the max thread count.
-    #[arg(short, long, default_value_t = 16)]
-    memory: usize,
+    /// Max amount of memory, like 512M or 2G (GiB without suffix). Is by the max thread count.
+    #[arg(short, long, default_value = "16G", value_parser = |s: &str| Bytes::parse_in(s, Bytes::G))]
+    memory: Bytes,
 }
 
 type Allocator<'a> = NvmAlloc<'a, LLFree<'a>>;
@@ -41,7 +41,7 @@ fn main() {
 
     util::logging();
 
-    let pages = (memory << 30) / Frame::SIZE;
+    let pages = memory.frames_floor().0;
 
     let allocs = pages / threads / 2 / (1 << order);
     let out_size = align_up(allocs + 2, Frame::SIZE) * threads;
@@ -83,7 +83,7 @@ fn execute(
     let m = Allocator::metadata_size(threads, mapping.len());
     let local = aligned_buf(m.local).leak();
     let trees = aligned_buf(m.trees).leak();
-    let alloc = Allocator::create(threads, mapping, false, local, trees).unwrap();
+    let alloc = Allocator::create(threads, mapping, 0, false, local, trees).unwrap();
     warn!("initialized {}", alloc.frames());
 
     let barrier = Barrier::new(threads);
@@ -172,7 +172,7 @@ fn monitor(
     let m = Allocator::metadata_size(threads, mapping.len());
     let local = aligned_buf(m.local).leak();
     let trees = aligned_buf(m.trees).leak();
-    let alloc = Allocator::create(threads, mapping, true, local, trees).unwrap();
+    let alloc = Allocator::create(threads, mapping, 0, true, local, trees).unwrap();
     warn!("recovered {}", alloc.frames());
 
     let expected = allocs * threads - threads;
@@ -212,7 +212,7 @@ fn monitor(
 pub fn mapping(begin: usize, length: usize, dax: Option<String>) -> Box<[Frame], MMap> {
     #[cfg(target_os = "linux")]
     if let Some(file) = dax {
-        warn!("MMap file {file} l={}G", (length * Frame::SIZE) >> 30);
+        warn!("MMap file {file} l={}", Frames(length));
         return mmap::file(begin, length, &file, true);
     }
     mmap::anon(begin, length, true, false)
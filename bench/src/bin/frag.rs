@@ -9,8 +9,7 @@ use std::sync::atomic::Ordering;
 use std::sync::{Barrier, Mutex};
 
 use clap::Parser;
-use llfree::frame::Frame;
-use llfree::util::WyRand;
+use llfree::util::{Bytes, WyRand};
 use llfree::*;
 use log::warn;
 
@@ -30,9 +29,9 @@ struct Args {
     /// Number of iterations
     #[arg(short, long, default_value_t = 8)]
     iterations: usize,
-    /// Max amount of memory in GiB. Is by the max thread count.
-    #[arg(short, long, default_value_t = 16)]
-    memory: usize,
+    /// Max amount of memory, like 512M or 2G (GiB without suffix). Is by the max thread count.
+    #[arg(short, long, default_value = "16G", value_parser = |s: &str| Bytes::parse_in(s, Bytes::G))]
+    memory: Bytes,
     /// Percentage of free memory
     #[arg(short, long, default_value_t = 50)]
     free: usize,
@@ -68,7 +67,7 @@ fn main() {
     }
 
     // Map memory for the allocator and initialize it
-    let pages = (memory << 30) / Frame::SIZE;
+    let pages = memory.frames_floor().0;
     let ms = Allocator::metadata_size(threads, pages);
     let meta = MetaData::alloc(ms);
     let alloc = Allocator::new(threads, pages, Init::FreeAll, meta).unwrap();
@@ -0,0 +1,105 @@
+#![feature(allocator_api)]
+
+use std::fs::File;
+use std::io::Write;
+use std::ops::Range;
+use std::time::Instant;
+
+use clap::Parser;
+use llfree::util::{self, aligned_buf, Bytes, Frames};
+use llfree::{Alloc, Init, LLFree, MetaData, HUGE_FRAMES};
+use log::warn;
+
+/// Benchmarking the initialization, which fills the bitfields of the whole region.
+#[derive(Parser, Debug)]
+#[command(about, version, author)]
+struct Args {
+    /// Amount of managed memory, like 512M or 2G (GiB without suffix).
+    #[arg(short, long, default_value = "1024G", value_parser = |s: &str| Bytes::parse_in(s, Bytes::G))]
+    memory: Bytes,
+    /// Number of cores.
+    #[arg(short, long, default_value_t = 8)]
+    cores: usize,
+    /// DAX file for the metadata of the lower allocator, volatile memory otherwise.
+    #[arg(long)]
+    dax: Option<String>,
+    /// Number of repetitions.
+    #[arg(short, long, default_value_t = 4)]
+    iterations: usize,
+    /// Where to store the benchmark results in csv format.
+    #[arg(long, default_value = "bench/out/init.csv")]
+    outfile: String,
+}
+
+fn main() {
+    let Args {
+        memory,
+        cores,
+        dax,
+        iterations,
+        outfile,
+    } = Args::parse();
+
+    util::logging();
+
+    let frames = memory.frames_floor().0;
+    assert!(frames > 0 && cores > 0);
+
+    let m = LLFree::metadata_size(cores, frames);
+    warn!(
+        "init {} with {} lower metadata",
+        Frames(frames),
+        Bytes(m.lower)
+    );
+    let local = aligned_buf(m.local).leak();
+    let trees = aligned_buf(m.trees).leak();
+    let lower = lower_metadata(m.lower, dax.as_deref());
+
+    // Carve-outs that are not aligned to the bitfields
+    let reserved: Vec<Range<usize>> = (0..frames)
+        .step_by(64 * HUGE_FRAMES)
+        .map(|start| start + 3..(start + 17 * HUGE_FRAMES + 5).min(frames))
+        .filter(|r| !r.is_empty())
+        .collect();
+
+    let mut out = File::create(outfile).unwrap();
+    writeln!(out, "init,memory,iteration,ns").unwrap();
+
+    for init in ["free", "alloc", "reserved"] {
+        for i in 0..iterations {
+            let meta = MetaData {
+                local: &mut *local,
+                trees: &mut *trees,
+                lower: &mut *lower,
+            };
+            let timer = Instant::now();
+            let alloc = match init {
+                "free" => LLFree::new(cores, frames, Init::FreeAll, meta),
+                "alloc" => LLFree::new(cores, frames, Init::AllocAll, meta),
+                _ => LLFree::with_reserved(cores, frames, &reserved, meta),
+            }
+            .unwrap();
+            let ns = timer.elapsed().as_nanos();
+
+            warn!(
+                "{init} i={i}: {}ms, {} free",
+                ns / 1_000_000,
+                alloc.free_frames()
+            );
+            writeln!(out, "{init},{},{i},{ns}", memory.0).unwrap();
+        }
+    }
+}
+
+fn lower_metadata(size: usize, dax: Option<&str>) -> &'static mut [u8] {
+    #[cfg(target_os = "linux")]
+    if let Some(dax) = dax {
+        let len = size.div_ceil(llfree::FRAME_SIZE);
+        warn!("MMap file {dax} l={}", Frames(len));
+        let mapping = Box::leak(llfree::mmap::file(0x1000_0000_0000, len, dax, true));
+        return unsafe { std::slice::from_raw_parts_mut(mapping.as_mut_ptr().cast(), size) };
+    }
+    #[cfg(not(target_os = "linux"))]
+    assert!(dax.is_none(), "No NVRAM!");
+    aligned_buf(size).leak()
+}
@@ -11,7 +11,7 @@ use std::time::{Duration, Instant};
 use clap::Parser;
 use llfree::frame::Frame;
 use llfree::mmap::MMap;
-use llfree::util::{self, aligned_buf, WyRand};
+use llfree::util::{self, aligned_buf, Bytes, Frames, WyRand};
 use llfree::wrapper::NvmAlloc;
 use llfree::{thread, Alloc, Flags, LLFree};
 use log::warn;
@@ -23,9 +23,9 @@ struct Args {
     /// DAX file to be used for the allocator.
     #[arg(long)]
     dax: String,
-    /// Max amount of memory in GiB.
-    #[arg(short, long, default_value_t = 16)]
-    memory: usize,
+    /// Max amount of memory, like 512M or 2G (GiB without suffix).
+    #[arg(short, long, default_value = "16G", value_parser = |s: &str| Bytes::parse_in(s, Bytes::G))]
+    memory: Bytes,
     /// Number of threads.
     #[arg(short, long)]
     threads: usize,
@@ -58,7 +58,7 @@ fn main() {
         iter,
     } = Args::parse();
 
-    assert!(memory > 0 && threads > 0);
+    assert!(memory.frames_floor().0 > 0 && threads > 0);
 
     let mut time_max = 0;
     let mut time_min = u128::MAX;
@@ -101,12 +101,12 @@ fn main() {
     writeln!(out, "min,avg,max\n{time_min},{time_avg},{time_max}").unwrap();
 }
 
-fn initialize(memory: usize, dax: &str, threads: usize, crash: bool) {
-    let mut mapping = mapping(0x1000_0000_0000, (memory << 30) / Frame::SIZE, dax);
+fn initialize(memory: Bytes, dax: &str, threads: usize, crash: bool) {
+    let mut mapping = mapping(0x1000_0000_0000, memory.frames_floor().0, dax);
     let ms = Allocator::metadata_size(threads, mapping.len());
     let local = aligned_buf(ms.local).leak();
     let trees = aligned_buf(ms.trees).leak();
-    let alloc = Allocator::create(threads, &mut mapping, false, local, trees).unwrap();
+    let alloc = Allocator::create(threads, &mut mapping, 0, false, local, trees).unwrap();
     warn!("Prepare alloc");
 
     thread::parallel(0..threads, |t| {
@@ -134,15 +134,15 @@ fn initialize(memory: usize, dax: &str, threads: usize, crash: bool) {
     assert!(0 < num_allocated && num_allocated < alloc.frames());
 }
 
-fn recover(threads: usize, memory: usize, dax: &str) -> u128 {
-    let mut mapping = mapping(0x1000_0000_0000, (memory << 30) / Frame::SIZE, dax);
+fn recover(threads: usize, memory: Bytes, dax: &str) -> u128 {
+    let mut mapping = mapping(0x1000_0000_0000, memory.frames_floor().0, dax);
     let ms = Allocator::metadata_size(threads, mapping.len());
     let local = aligned_buf(ms.local).leak();
     let trees = aligned_buf(ms.trees).leak();
 
     warn!("Recover alloc");
     let timer = Instant::now();
-    let alloc = Allocator::create(threads, &mut mapping, true, local, trees).unwrap();
+    let alloc = Allocator::create(threads, &mut mapping, 0, true, local, trees).unwrap();
     let time = timer.elapsed().as_nanos();
 
     let num_alloc = alloc.allocated_frames();
@@ -157,7 +157,7 @@ fn recover(threads: usize, memory: usize, dax: &str) -> u128 {
 pub fn mapping(begin: usize, length: usize, dax: &str) -> Box<[Frame], MMap> {
     #[cfg(target_os = "linux")]
     {
-        warn!("MMap file {dax} l={}G", (length * Frame::SIZE) >> 30);
+        warn!("MMap file {dax} l={}", Frames(length));
         llfree::mmap::file(begin, length, dax, true)
     }
     #[cfg(not(target_os = "linux"))]
@@ -12,7 +12,7 @@ use std::sync::Barrier;
 use std::time::Instant;
 
 use clap::Parser;
-use llfree::frame::Frame;
+use llfree::util::Bytes;
 use llfree::*;
 use log::warn;
 
@@ -24,9 +24,9 @@ struct Args {
     /// Max number of threads
     #[arg(short, long, default_value = "8")]
     threads: usize,
-    /// Max amount of memory in GiB. Is by the max thread count.
-    #[arg(short, long, default_value_t = 8)]
-    memory: usize,
+    /// Max amount of memory, like 512M or 2G (GiB without suffix). Is by the max thread count.
+    #[arg(short, long, default_value = "8G", value_parser = |s: &str| Bytes::parse_in(s, Bytes::G))]
+    memory: Bytes,
     /// Using only every n-th CPU
     #[arg(long, default_value_t = 2)]
     stride: usize,
@@ -75,7 +75,7 @@ fn main() {
     }
 
     // TODO: replay allocations
-    let frames = (memory << 30) / Frame::SIZE;
+    let frames = memory.frames_floor().0;
     let ms = Allocator::metadata_size(threads, frames);
     let meta = MetaData::alloc(ms);
     let alloc = Allocator::new(threads, frames, Init::FreeAll, meta).unwrap();
@@ -96,7 +96,8 @@ fn main() {
 
     let score = std::thread::scope(|s| {
         let monitor = s.spawn(|| {
-            thread::pin(threads);
+            // Only if there is a core left for the monitor
+            thread::try_pin(threads);
             let mut frag = frag.map(|path| BufWriter::new(File::create(path).unwrap()));
 
             barrier.wait();
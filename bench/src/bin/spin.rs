@@ -0,0 +1,89 @@
+use std::fs::File;
+use std::io::Write;
+use std::sync::atomic::{AtomicBool, AtomicUsize, Ordering};
+use std::sync::Barrier;
+use std::time::Instant;
+
+use clap::Parser;
+use llfree::thread;
+use llfree::util::{self, Spin};
+use log::warn;
+
+/// Comparing the waiting strategies of `spin_wait` on a contended spinlock.
+#[derive(Parser, Debug)]
+#[command(about, version, author)]
+struct Args {
+    /// Tested numbers of threads.
+    #[arg(short, long, default_values_t = [4, 32])]
+    x: Vec<usize>,
+    /// Lock acquisitions per thread.
+    #[arg(short, long, default_value_t = 100_000)]
+    ops: usize,
+    /// Max retries of a single `spin_wait`.
+    #[arg(short, long, default_value_t = 64)]
+    retries: usize,
+    /// Number of repetitions.
+    #[arg(short, long, default_value_t = 1)]
+    iterations: usize,
+    /// Where to store the benchmark results in csv format.
+    #[arg(long, default_value = "bench/out/spin.csv")]
+    outfile: String,
+}
+
+fn main() {
+    let Args {
+        x,
+        ops,
+        retries,
+        iterations,
+        outfile,
+    } = Args::parse();
+
+    util::logging();
+
+    let mut out = File::create(outfile).unwrap();
+    writeln!(out, "strategy,x,iteration,ops,failed,ns").unwrap();
+
+    for threads in x {
+        for spin in [Spin::Pure, Spin::Yield, Spin::Adaptive] {
+            for i in 0..iterations {
+                let (failed, ns) = bench(spin, threads, ops, retries);
+                warn!("{spin:?} x={threads} i={i}: {failed} failed, {ns}ns/op");
+                writeln!(out, "{spin:?},{threads},{i},{ops},{failed},{ns}").unwrap();
+            }
+        }
+    }
+}
+
+/// Returns the number of failed waits and the average time of an acquisition
+fn bench(spin: Spin, threads: usize, ops: usize, retries: usize) -> (usize, u128) {
+    let lock = AtomicBool::new(false);
+    let counter = AtomicUsize::new(0);
+    let barrier = Barrier::new(threads);
+
+    let results = thread::parallel(0..threads, |t| {
+        // Oversubscribed cores are not pinned
+        thread::try_pin(t);
+        barrier.wait();
+        let timer = Instant::now();
+
+        let mut failed = 0;
+        for _ in 0..ops {
+            while !spin.wait(retries, || {
+                lock.compare_exchange_weak(false, true, Ordering::Acquire, Ordering::Relaxed)
+                    .is_ok()
+            }) {
+                failed += 1;
+            }
+            // Short critical section
+            counter.store(counter.load(Ordering::Relaxed) + 1, Ordering::Relaxed);
+            lock.store(false, Ordering::Release);
+        }
+        (failed, timer.elapsed().as_nanos() / ops as u128)
+    });
+
+    assert_eq!(counter.load(Ordering::Relaxed), threads * ops);
+    let failed = results.iter().map(|r| r.0).sum();
+    let ns = results.iter().map(|r| r.1).sum::<u128>() / threads as u128;
+    (failed, ns)
+}
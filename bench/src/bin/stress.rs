@@ -11,8 +11,7 @@ use std::sync::Barrier;
 use std::time::Instant;
 
 use clap::Parser;
-use llfree::frame::Frame;
-use llfree::util::WyRand;
+use llfree::util::{Bytes, WyRand};
 use llfree::*;
 use log::warn;
 
@@ -29,9 +28,9 @@ struct Args {
     /// Runtime in seconds
     #[arg(long, default_value_t = 20)]
     time: usize,
-    /// Max amount of memory in GiB. Is by the max thread count.
-    #[arg(short, long, default_value_t = 8)]
-    memory: usize,
+    /// Max amount of memory, like 512M or 2G (GiB without suffix). Is by the max thread count.
+    #[arg(short, long, default_value = "8G", value_parser = |s: &str| Bytes::parse_in(s, Bytes::G))]
+    memory: Bytes,
     /// Using only every n-th CPU
     #[arg(long, default_value_t = 2)]
     stride: usize,
@@ -65,7 +64,7 @@ fn main() {
     }
 
     // Map memory for the allocator and initialize it
-    let pages = (memory << 30) / Frame::SIZE;
+    let pages = memory.frames_floor().0;
     let ms = Allocator::metadata_size(threads, pages);
     let meta = MetaData::alloc(ms);
     let alloc = Allocator::new(threads, pages, Init::FreeAll, meta).unwrap();
@@ -84,7 +83,8 @@ fn main() {
 
     let (allocated, score) = std::thread::scope(|s| {
         let monitor = s.spawn(|| {
-            thread::pin(threads);
+            // Only if there is a core left for the monitor
+            thread::try_pin(threads);
             let mut frag = frag.map(|path| BufWriter::new(File::create(path).unwrap()));
 
             barrier.wait();
@@ -142,7 +142,7 @@ fn main() {
                     } else {
                         match alloc.get(t, Flags::o(order)) {
                             Ok(page) => pages.push(page),
-                            Err(Error::Memory) => break,
+                            Err(Error::Memory | Error::Fragmented) => break,
                             Err(e) => panic!("{e:?}"),
                         }
                     }
@@ -8,7 +8,7 @@ use clap::Parser;
 use llfree::frame::Frame;
 use llfree::mmap::{self, madvise, MAdvise, MMap};
 use llfree::thread;
-use llfree::util::{avg_bounds, logging, WyRand};
+use llfree::util::{avg_bounds, logging, Bytes, Frames, WyRand};
 
 /// Benchmarking the page-fault performance of a mapped memory region.
 #[derive(Parser, Debug)]
@@ -17,9 +17,9 @@ struct Args {
     /// Number of threads
     #[arg(short, long, default_value_t = 6)]
     threads: usize,
-    /// Max amount of memory in GiB. Is by the max thread count
-    #[arg(short, long, default_value_t = 16)]
-    memory: usize,
+    /// Max amount of memory, like 512M or 2G (GiB without suffix). Is by the max thread count
+    #[arg(short, long, default_value = "16G", value_parser = |s: &str| Bytes::parse_in(s, Bytes::G))]
+    memory: Bytes,
     /// DAX file to be used for the allocator
     #[arg(long)]
     dax: Option<String>,
@@ -55,12 +55,12 @@ fn main() {
 
     logging();
 
-    assert!(threads > 0 && memory > 0);
+    assert!(threads > 0 && memory.frames_floor().0 > 0);
 
     let t_map = Instant::now();
     let mut mapping = mapping(
         0x1000_0000_0000,
-        (memory << 30) / Frame::SIZE,
+        memory.frames_floor().0,
         dax,
         private,
         populate,
@@ -120,7 +120,7 @@ pub fn mapping(
 ) -> Box<[Frame], MMap> {
     #[cfg(target_os = "linux")]
     if let Some(file) = dax {
-        log::warn!("MMap file {file} l={}G", (length * Frame::SIZE) >> 30);
+        log::warn!("MMap file {file} l={}", Frames(length));
         return mmap::file(begin, length, &file, true);
     }
     mmap::anon(begin, length, !private, populate)
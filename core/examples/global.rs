@@ -0,0 +1,62 @@
+//! LLFree as the global allocator of a Rust program.
+//!
+//! All heap allocations, including those of the standard library, are served
+//! from frames of the allocator, which is set up on the first allocation.
+//!
+//! ```text
+//! cargo run --example global
+//! ```
+
+use std::collections::HashMap;
+
+use llfree::global::GlobalLLFree;
+use llfree::Alloc;
+
+/// 256 MiB for up to 4 cores
+#[global_allocator]
+static ALLOC: GlobalLLFree = GlobalLLFree::new(4, 1 << 16);
+
+fn main() {
+    let allocated = run();
+    println!("Ok, {allocated} frames allocated");
+}
+
+/// Allocates some collections and checks that they are managed by [ALLOC]
+fn run() -> usize {
+    let memory = ALLOC.memory();
+    let managed = |ptr: *const u8| memory.contains(&(ptr as usize));
+
+    let bytes: Vec<u8> = (0..=255).collect();
+    assert!(managed(bytes.as_ptr()));
+
+    let boxed = Box::new(42u32);
+    assert!(managed(&*boxed as *const u32 as *const u8));
+
+    let mut map = HashMap::new();
+    for i in 0..1000 {
+        map.insert(i, i.to_string());
+    }
+    assert!(map.values().all(|v| managed(v.as_ptr())));
+    assert_eq!(map[&999], "999");
+
+    // Allocations on other threads and cores
+    let handles: Vec<_> = (0..4)
+        .map(|t| std::thread::spawn(move || vec![t as u8; 3 << 12]))
+        .collect();
+    for (t, handle) in handles.into_iter().enumerate() {
+        let v = handle.join().unwrap();
+        assert!(managed(v.as_ptr()) && v.iter().all(|b| *b == t as u8));
+    }
+
+    let allocated = ALLOC.inner().allocated_frames();
+    assert!(allocated >= 1000);
+    allocated
+}
+
+#[cfg(test)]
+mod test {
+    #[test]
+    fn global() {
+        super::run();
+    }
+}
@@ -0,0 +1,211 @@
+//! Persistent allocator in userspace, that survives restarts.
+//!
+//! The allocator manages a memory-mapped file, which stands in for persistent memory.
+//! Worker threads, pinned to their cores, allocate and free frames of mixed orders,
+//! while the main thread prints statistics.
+//! On SIGINT (Ctrl-C), the workers stop, all cores are drained,
+//! and dropping the allocator marks the shutdown as clean.
+//! The frames that the workers still held are not freed, they are leaked.
+//!
+//! On the next start, the allocator recovers from the file and reports
+//! how many frames were still allocated by the previous run.
+//! After a crash instead of a clean shutdown, this performs a deep recovery.
+//!
+//! ```text
+//! cargo run --release --example persistent -- <file> [workers] [size, like 256M]
+//! ```
+
+use std::fs::OpenOptions;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::time::{Duration, Instant};
+
+use llfree::frame::Frame;
+use llfree::util::{aligned_buf, Bytes, WyRand};
+use llfree::wrapper::NvmAlloc;
+use llfree::{mmap, thread, Alloc, Error, Flags, LLFree, Result, HUGE_ORDER};
+
+type Allocator<'a> = NvmAlloc<'a, LLFree<'a>>;
+
+/// Virtual address of the mapping, aligned to the largest order
+const BEGIN: usize = 0x1000_0000_0000;
+
+static STOP: AtomicBool = AtomicBool::new(false);
+
+extern "C" fn on_sigint(_: libc::c_int) {
+    STOP.store(true, Ordering::Relaxed);
+}
+
+fn main() {
+    let mut args = std::env::args().skip(1);
+    let Some(path) = args.next() else {
+        eprintln!("usage: persistent <file> [workers] [size]");
+        std::process::exit(1);
+    };
+    let workers = args.next().map_or(4, |a| a.parse().expect("workers"));
+    let size = args
+        .next()
+        .map_or(Ok(Bytes(256 << 20)), |a| Bytes::parse_in(&a, Bytes::M))
+        .expect("size");
+
+    unsafe {
+        libc::signal(
+            libc::SIGINT,
+            on_sigint as extern "C" fn(libc::c_int) as libc::sighandler_t,
+        )
+    };
+
+    let frames = size.frames_floor().0;
+    match run(&path, workers, frames, &STOP, Duration::from_secs(1)) {
+        Ok(report) => {
+            if let Some(allocated) = report.recovered {
+                println!("recovered: {allocated} frames allocated by the previous run");
+            }
+            println!("shutdown: {} frames leaked", report.leaked);
+        }
+        Err(e) => {
+            eprintln!("failed: {e:?}");
+            std::process::exit(1);
+        }
+    }
+}
+
+/// Outcome of a [run]
+#[derive(Debug)]
+struct Report {
+    /// Frames that were still allocated after recovering, if the file existed
+    recovered: Option<usize>,
+    /// Frames that the workers held on shutdown
+    leaked: usize,
+}
+
+/// Runs the workload on the allocator in `path` until `stop` is set.
+fn run(
+    path: &str,
+    workers: usize,
+    frames: usize,
+    stop: &AtomicBool,
+    interval: Duration,
+) -> Result<Report> {
+    let file = OpenOptions::new()
+        .read(true)
+        .write(true)
+        .create(true)
+        .truncate(false)
+        .open(path)
+        .map_err(|_| Error::Initialization)?;
+    // An empty file has not been initialized yet
+    let recover = file.metadata().map_err(|_| Error::Initialization)?.len() > 0;
+    file.set_len((frames * Frame::SIZE) as u64)
+        .map_err(|_| Error::Initialization)?;
+    drop(file);
+
+    let mut zone = mmap::file::<Frame>(BEGIN, frames, path, false);
+    let m = Allocator::metadata_size(workers, frames);
+    // The volatile metadata is rebuilt on every start
+    let mut local = aligned_buf(m.local);
+    let mut trees = aligned_buf(m.trees);
+    let alloc = Allocator::create(workers, &mut zone, 0, recover, &mut local, &mut trees)?;
+    let recovered = recover.then(|| alloc.allocated_frames());
+
+    let leaked = std::thread::scope(|s| {
+        let handles: Vec<_> = (0..workers)
+            .map(|core| {
+                let alloc = &alloc;
+                s.spawn(move || {
+                    thread::pin(core % thread::cores());
+                    work(alloc, core, workers, stop)
+                })
+            })
+            .collect();
+
+        let mut last = Instant::now();
+        while !stop.load(Ordering::Relaxed) {
+            std::thread::sleep(Duration::from_millis(10));
+            if last.elapsed() >= interval {
+                last = Instant::now();
+                println!(
+                    "free: {} / {} frames, {} huge",
+                    alloc.free_frames(),
+                    alloc.frames(),
+                    alloc.free_huge()
+                );
+            }
+        }
+        handles.into_iter().map(|h| h.join().unwrap()).sum()
+    });
+
+    // Return the reserved trees of all cores before the clean shutdown
+    alloc.drain_all()?;
+    alloc.validate();
+    // Marks the allocator as not crashed
+    drop(alloc);
+    Ok(Report { recovered, leaked })
+}
+
+/// Allocates and frees frames of mixed orders on `core` until `stop` is set.
+///
+/// Returns the number of frames that are still held.
+fn work(alloc: &Allocator, core: usize, workers: usize, stop: &AtomicBool) -> usize {
+    let limit = alloc.frames() / workers / 2;
+    let mut rng = WyRand::new(core as u64);
+    let mut held = Vec::new();
+    let mut frames = 0;
+    while !stop.load(Ordering::Relaxed) {
+        let order = [0, 0, 0, 0, 1, 2, 3, HUGE_ORDER][rng.range(0..8) as usize];
+        let result = if frames + (1 << order) <= limit {
+            alloc.get(core, Flags::o(order))
+        } else {
+            Err(Error::Memory)
+        };
+        match result {
+            Ok(frame) => {
+                held.push((frame, order));
+                frames += 1 << order;
+            }
+            Err(Error::Memory | Error::Fragmented) if !held.is_empty() => {
+                let i = rng.range(0..held.len() as u64) as usize;
+                let (frame, order) = held.swap_remove(i);
+                alloc.put(core, frame, Flags::o(order)).unwrap();
+                frames -= 1 << order;
+            }
+            Err(Error::Memory | Error::Fragmented) => std::thread::yield_now(),
+            Err(e) => panic!("{e:?}"),
+        }
+    }
+    frames
+}
+
+#[cfg(test)]
+mod test {
+    use std::sync::atomic::{AtomicBool, Ordering};
+    use std::time::Duration;
+
+    use super::run;
+
+    /// A clean shutdown, followed by a restart that recovers the leaked frames
+    #[test]
+    fn restart() {
+        const FRAMES: usize = 16 << 10;
+        let path = std::env::temp_dir().join(format!("llfree-persistent-{}", std::process::id()));
+        let path = path.to_str().unwrap();
+        let _ = std::fs::remove_file(path);
+
+        let stop = AtomicBool::new(false);
+        let report = std::thread::scope(|s| {
+            s.spawn(|| {
+                std::thread::sleep(Duration::from_millis(200));
+                // Simulated SIGINT
+                stop.store(true, Ordering::Relaxed);
+            });
+            run(path, 2, FRAMES, &stop, Duration::from_millis(50)).unwrap()
+        });
+        assert_eq!(report.recovered, None);
+        assert!(report.leaked > 0);
+
+        // Stops immediately
+        let restart = run(path, 2, FRAMES, &stop, Duration::from_millis(50)).unwrap();
+        assert_eq!(restart.recovered, Some(report.leaked));
+
+        std::fs::remove_file(path).unwrap();
+    }
+}
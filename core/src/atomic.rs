@@ -2,6 +2,7 @@
 
 use core::cell::UnsafeCell;
 use core::fmt;
+use core::mem::{align_of, size_of};
 use core::ops::{Deref, DerefMut};
 use core::sync::atomic::Ordering::*;
 use core::sync::atomic::*;
@@ -23,21 +24,33 @@ impl<T: Atomic> Atom<T> {
     #[cfg_attr(feature = "log_debug", track_caller)]
     pub fn load(&self) -> T {
         debug!("{} load", core::panic::Location::caller());
+        #[cfg(feature = "pmem_sim")]
+        pmem_sim::load(self);
         self.0.load().into()
     }
     #[cfg_attr(feature = "log_debug", track_caller)]
     pub fn store(&self, v: T) {
         debug!("{} store", core::panic::Location::caller());
+        #[cfg(feature = "pmem_sim")]
+        pmem_sim::store(self);
         self.0.store(v.into())
     }
     #[cfg_attr(feature = "log_debug", track_caller)]
     pub fn swap(&self, v: T) -> T {
         debug!("{} swap", core::panic::Location::caller());
+        #[cfg(feature = "pmem_sim")]
+        pmem_sim::store(self);
         self.0.swap(v.into()).into()
     }
     #[cfg_attr(feature = "log_debug", track_caller)]
     pub fn compare_exchange(&self, current: T, new: T) -> Result<T, T> {
         debug!("{} cmpxchg", core::panic::Location::caller());
+        #[cfg(all(test, feature = "std"))]
+        if inject::check(self) {
+            return Err(self.0.load().into());
+        }
+        #[cfg(feature = "pmem_sim")]
+        pmem_sim::store(self);
         match self.0.compare_exchange(current.into(), new.into()) {
             Ok(v) => Ok(v.into()),
             Err(v) => Err(v.into()),
@@ -46,6 +59,12 @@ impl<T: Atomic> Atom<T> {
     #[cfg_attr(feature = "log_debug", track_caller)]
     pub fn compare_exchange_weak(&self, current: T, new: T) -> Result<T, T> {
         debug!("{} cmpxchgw", core::panic::Location::caller());
+        #[cfg(all(test, feature = "std"))]
+        if inject::check(self) {
+            return Err(self.0.load().into());
+        }
+        #[cfg(feature = "pmem_sim")]
+        pmem_sim::store(self);
         match self.0.compare_exchange_weak(current.into(), new.into()) {
             Ok(v) => Ok(v.into()),
             Err(v) => Err(v.into()),
@@ -54,6 +73,12 @@ impl<T: Atomic> Atom<T> {
     #[cfg_attr(feature = "log_debug", track_caller)]
     pub fn fetch_update<F: FnMut(T) -> Option<T>>(&self, mut f: F) -> Result<T, T> {
         debug!("{} update", core::panic::Location::caller());
+        #[cfg(all(test, feature = "std"))]
+        if inject::check(self) {
+            return Err(self.0.load().into());
+        }
+        #[cfg(feature = "pmem_sim")]
+        pmem_sim::store(self);
         match self.0.fetch_update(|v| f(v.into()).map(|v| v.into())) {
             Ok(v) => Ok(v.into()),
             Err(v) => Err(v.into()),
@@ -119,6 +144,8 @@ macro_rules! fn_trivial {
     ($ty:ident ; $($name:ident),+) => {
         $(
             pub fn $name(&self, v: $ty) -> $ty {
+                #[cfg(feature = "pmem_sim")]
+                pmem_sim::store(self);
                 AtomicImpl::$name(&self.0, v)
             }
         )+
@@ -176,6 +203,166 @@ atomic_impl!(u32, AtomicU32);
 atomic_impl!(u64, AtomicU64);
 atomic_impl!(usize, AtomicUsize);
 
+/// Failure injection for the CAS operations of [Atom] in tests.
+///
+/// Rules are thread-local, so concurrently running tests do not interfere.
+/// They match the `compare_exchange`, `compare_exchange_weak`, and `fetch_update`
+/// operations, which fail as if the value had changed concurrently.
+#[cfg(all(test, feature = "std"))]
+pub mod inject {
+    use core::cell::RefCell;
+    use std::boxed::Box;
+    use std::vec::Vec;
+
+    struct Rule {
+        matches: Box<dyn Fn(usize) -> bool>,
+        /// Number of matching operations that still succeed
+        skip: usize,
+    }
+
+    std::thread_local! {
+        static RULES: RefCell<Vec<Rule>> = const { RefCell::new(Vec::new()) };
+    }
+
+    /// Fail the `n`-th (starting at 0) upcoming CAS on `atom`.
+    pub fn fail_nth<T>(atom: *const T, n: usize) {
+        let addr = atom as usize;
+        fail_where(n, move |a| a == addr);
+    }
+    /// Fail the `n`-th (starting at 0) upcoming CAS on an atomic whose address matches `pred`.
+    pub fn fail_where(n: usize, pred: impl Fn(usize) -> bool + 'static) {
+        RULES.with(|r| {
+            r.borrow_mut().push(Rule {
+                matches: Box::new(pred),
+                skip: n,
+            })
+        });
+    }
+    /// Remove all remaining rules, returning their number.
+    pub fn clear() -> usize {
+        RULES.with(|r| r.borrow_mut().drain(..).count())
+    }
+
+    /// Returns if the CAS on `atom` should fail, consuming the firing rule.
+    pub(super) fn check<T>(atom: *const T) -> bool {
+        let addr = atom as usize;
+        RULES.with(|r| {
+            let mut rules = r.borrow_mut();
+            let mut fire = None;
+            for (i, rule) in rules.iter_mut().enumerate() {
+                if (rule.matches)(addr) {
+                    if rule.skip == 0 {
+                        fire = fire.or(Some(i));
+                    } else {
+                        rule.skip -= 1;
+                    }
+                }
+            }
+            if let Some(i) = fire {
+                rules.remove(i);
+            }
+            fire.is_some()
+        })
+    }
+}
+
+/// Simulated latency of persistent memory.
+///
+/// Persistent memory is considerably slower than DRAM.
+/// Accesses to atomics within the registered (persistent) regions are delayed accordingly.
+/// The delays in ns are configured with [pmem_sim::set] or the `LLFREE_PMEM_SIM`
+/// environment variable (stores, loads take a third), defaulting to 300ns for stores and
+/// 100ns for loads.
+#[cfg(feature = "pmem_sim")]
+pub mod pmem_sim {
+    use core::hint::spin_loop;
+    use core::ops::Range;
+    use core::sync::atomic::AtomicUsize;
+    use core::sync::atomic::Ordering::Relaxed;
+
+    /// Number of regions that can be registered at the same time
+    const REGIONS: usize = 16;
+    /// Start and end addresses of the persistent regions
+    static REGION: [(AtomicUsize, AtomicUsize); REGIONS] =
+        [const { (AtomicUsize::new(0), AtomicUsize::new(0)) }; REGIONS];
+    /// Next region to be overwritten
+    static NEXT: AtomicUsize = AtomicUsize::new(0);
+
+    /// Latency of stores and loads in ns, [usize::MAX] if not configured yet
+    static LATENCY: (AtomicUsize, AtomicUsize) =
+        (AtomicUsize::new(usize::MAX), AtomicUsize::new(usize::MAX));
+    /// Calibrated number of spin loops per µs
+    static SPINS: AtomicUsize = AtomicUsize::new(0);
+
+    /// Set the latency of stores and loads in ns.
+    pub fn set(store: usize, load: usize) {
+        LATENCY.0.store(store, Relaxed);
+        LATENCY.1.store(load, Relaxed);
+    }
+    /// Returns the latency of stores and loads in ns.
+    pub fn latency() -> (usize, usize) {
+        if LATENCY.0.load(Relaxed) == usize::MAX {
+            match std::env::var("LLFREE_PMEM_SIM").map(|v| v.parse::<usize>()) {
+                Ok(Ok(store)) => set(store, store / 3),
+                _ => set(300, 100),
+            }
+        }
+        (LATENCY.0.load(Relaxed), LATENCY.1.load(Relaxed))
+    }
+
+    /// Register a persistent memory region.
+    ///
+    /// If too many regions are registered, the oldest ones are overwritten.
+    pub fn register(region: Range<usize>) {
+        let (start, end) = &REGION[NEXT.fetch_add(1, Relaxed) % REGIONS];
+        start.store(region.start, Relaxed);
+        end.store(region.end, Relaxed);
+    }
+    /// Returns if `addr` is within a persistent memory region.
+    pub fn contains<T>(addr: *const T) -> bool {
+        let addr = addr as usize;
+        REGION
+            .iter()
+            .any(|(start, end)| (start.load(Relaxed)..end.load(Relaxed)).contains(&addr))
+    }
+
+    /// Delay a load from `addr` if it is persistent.
+    pub fn load<T>(addr: *const T) {
+        if contains(addr) {
+            delay(latency().1);
+        }
+    }
+    /// Delay a store to `addr` if it is persistent.
+    pub fn store<T>(addr: *const T) {
+        if contains(addr) {
+            delay(latency().0);
+        }
+    }
+
+    /// Spin for approximately `ns` nanoseconds.
+    fn delay(ns: usize) {
+        for _ in 0..ns * spins() / 1000 {
+            spin_loop();
+        }
+    }
+    /// Returns the number of spin loops per µs, which is calibrated once.
+    fn spins() -> usize {
+        let spins = SPINS.load(Relaxed);
+        if spins != 0 {
+            return spins;
+        }
+        const N: usize = 1 << 20;
+        let start = std::time::Instant::now();
+        for _ in 0..N {
+            spin_loop();
+        }
+        let nanos = (start.elapsed().as_nanos() as usize).max(1);
+        let spins = (N * 1000 / nanos).max(1);
+        SPINS.store(spins, Relaxed);
+        spins
+    }
+}
+
 pub trait AtomArray<T: Copy, const L: usize> {
     /// Overwrite the content of the whole array non-atomically.
     ///
@@ -194,6 +381,71 @@ impl<T: Atomic, const L: usize> AtomArray<T, L> for [Atom<T>; L] {
     }
 }
 
+/// Overwrite `dst` with `v` non-atomically, using non-temporal stores if available.
+///
+/// The stores bypass the caches and are combined into whole cache line writes,
+/// which is much cheaper than individual stores on persistent memory.
+/// Like [AtomArray::atomic_fill], this does not handle race conditions.
+/// The stores are only ordered by a following [stream_fence], so that callers
+/// filling multiple ranges only need a single fence at the end.
+pub fn stream_fill(dst: &[Atom<u64>], v: u64) {
+    let ptr = dst.as_ptr().cast::<u64>().cast_mut();
+    #[cfg(target_arch = "x86_64")]
+    unsafe {
+        use core::arch::x86_64::_mm_stream_si64;
+
+        let mut i = 0;
+        if has_avx512f() {
+            // Single stores up to the first cache line
+            let head = ptr.align_offset(align_of::<Align>()).min(dst.len());
+            while i < head {
+                _mm_stream_si64(ptr.add(i).cast(), v as i64);
+                i += 1;
+            }
+            let lines = (dst.len() - head) / (align_of::<Align>() / size_of::<u64>());
+            stream_lines(ptr.add(head), lines, v);
+            i = head + lines * (align_of::<Align>() / size_of::<u64>());
+        }
+        // SSE2 is always available on x86_64
+        while i < dst.len() {
+            _mm_stream_si64(ptr.add(i).cast(), v as i64);
+            i += 1;
+        }
+    }
+    #[cfg(not(target_arch = "x86_64"))]
+    unsafe { core::slice::from_raw_parts_mut(ptr, dst.len()) }.fill(v);
+}
+
+/// Orders the stores of previous [stream_fill]s before all following stores.
+pub fn stream_fence() {
+    #[cfg(target_arch = "x86_64")]
+    unsafe {
+        core::arch::x86_64::_mm_sfence()
+    };
+    fence(Release);
+}
+
+/// Stores whole cache lines of `v`, starting at the cache aligned `ptr`
+#[cfg(target_arch = "x86_64")]
+#[target_feature(enable = "avx512f")]
+unsafe fn stream_lines(ptr: *mut u64, lines: usize, v: u64) {
+    use core::arch::x86_64::{_mm512_set1_epi64, _mm512_stream_si512};
+
+    let line = _mm512_set1_epi64(v as i64);
+    for l in 0..lines {
+        _mm512_stream_si512(ptr.add(l * 8).cast(), line);
+    }
+}
+
+/// Checks for AVX-512 at runtime if possible, at compile time otherwise
+#[cfg(target_arch = "x86_64")]
+fn has_avx512f() -> bool {
+    #[cfg(feature = "std")]
+    return std::arch::is_x86_feature_detected!("avx512f");
+    #[cfg(not(feature = "std"))]
+    return cfg!(target_feature = "avx512f");
+}
+
 /// Very simple spin lock implementation
 pub struct Spin<T> {
     lock: AtomicBool,
@@ -251,3 +503,43 @@ impl<'a, T> Drop for SpinGuard<'a, T> {
         self.spin.lock.store(false, Release);
     }
 }
+
+#[cfg(all(test, feature = "pmem_sim"))]
+mod test {
+    use std::time::Instant;
+    use std::vec::Vec;
+
+    use super::{pmem_sim, Atom};
+    use crate::{Alloc, Init, LLFree, MetaData, TREE_FRAMES};
+
+    #[test]
+    fn pmem_sim() {
+        let persistent: Vec<Atom<u64>> = (0..8).map(|_| Atom::new(0)).collect();
+        let range = persistent.as_ptr_range();
+        pmem_sim::register(range.start as usize..range.end as usize);
+        let volatile = Atom::<u64>::new(0);
+
+        pmem_sim::set(10_000, 10_000);
+        let start = Instant::now();
+        for v in 0..10 {
+            volatile.store(v);
+        }
+        let volatile_time = start.elapsed();
+        let start = Instant::now();
+        for (v, atom) in persistent.iter().enumerate() {
+            atom.store(v as _);
+            assert_eq!(atom.load(), v as _);
+        }
+        let persistent_time = start.elapsed();
+        pmem_sim::set(300, 100);
+        assert!(persistent_time.as_micros() >= 100, "{persistent_time:?}");
+        assert!(volatile_time * 10 < persistent_time, "{volatile_time:?}");
+
+        // Only the metadata of the lower allocator is persistent
+        let meta = MetaData::alloc(LLFree::metadata_size(1, TREE_FRAMES));
+        let (lower, trees) = (meta.lower.as_ptr(), meta.trees.as_ptr());
+        let _alloc = LLFree::new(1, TREE_FRAMES, Init::FreeAll, meta).unwrap();
+        assert!(pmem_sim::contains(lower));
+        assert!(!pmem_sim::contains(trees));
+    }
+}
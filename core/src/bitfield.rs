@@ -3,14 +3,64 @@
 use core::fmt;
 use core::mem::size_of;
 use core::ops::{Not, Range};
-use core::sync::atomic::AtomicU64;
+#[cfg(feature = "search_stats")]
+use core::sync::atomic::AtomicUsize;
+use core::sync::atomic::Ordering::SeqCst;
+use core::sync::atomic::{compiler_fence, AtomicU64};
 
-use crate::atomic::{Atom, Atomic};
+use log::error;
+
+use crate::atomic::{stream_fence, stream_fill, Atom, Atomic};
+use crate::util::align_down;
 use crate::{Error, Result};
 
+/// Number of entries searched for allocations, for benchmarking.
+#[cfg(feature = "search_stats")]
+pub static SEARCHED: AtomicUsize = AtomicUsize::new(0);
+
+#[cfg(all(feature = "search_stats", feature = "std"))]
+std::thread_local! {
+    /// Number of entries searched by this thread, which are also counted in [SEARCHED]
+    static SEARCHED_LOCAL: core::cell::Cell<usize> = const { core::cell::Cell::new(0) };
+}
+
+/// Returns the number of entries searched by the current thread,
+/// which is not influenced by concurrent searches, e.g., of other tests.
+#[cfg(all(feature = "search_stats", feature = "std"))]
+pub fn searched_local() -> usize {
+    SEARCHED_LOCAL.with(|s| s.get())
+}
+
+/// Counts `n` searched entries
+#[cfg(feature = "search_stats")]
+fn searched(n: usize) {
+    SEARCHED.fetch_add(n, core::sync::atomic::Ordering::Relaxed);
+    #[cfg(feature = "std")]
+    SEARCHED_LOCAL.with(|s| s.set(s.get() + n));
+}
+
 /// Bitfield replacing the level one table.
+///
+/// Besides the `data`, the bitfield has an advisory summary with a set bit for
+/// every entry that is completely allocated.
+/// It allows [Bitfield::set_first_zeros] to skip full entries without probing them.
+///
+/// The summary is maintained opportunistically:
+/// - Operations that fill an entry set its bit afterwards, and clear it again
+///   if the entry is no longer full on a second look.
+/// - Operations that clear bits of an entry clear its summary bit *after* they
+///   updated the entry. Clearing before would race with a concurrent allocation,
+///   which could fill and mark the entry between our clear and our update.
+///
+/// This way, a set summary bit for a non-full entry only exists between the
+/// update and the summary clear of a concurrent free (or the second look of a
+/// concurrent allocation), never in a quiescent state.
+/// A search that skipped entries and found nothing probes the skipped entries
+/// again, so it cannot miss free bits due to such a transient state.
+/// A missing summary bit for a full entry just costs an additional probe.
 pub struct Bitfield<const N: usize> {
     data: [Atom<u64>; N],
+    full: Atom<u64>,
 }
 
 const _: () = assert!(size_of::<Bitfield<64>>() >= 8);
@@ -20,8 +70,10 @@ const _: () = assert!(Bitfield::<2>::ORDER == 7);
 
 impl<const N: usize> Default for Bitfield<N> {
     fn default() -> Self {
+        const { assert!(N <= u64::BITS as usize, "summary too small") };
         Self {
             data: [const { Atom(AtomicU64::new(0)) }; N],
+            full: Atom(AtomicU64::new(0)),
         }
     }
 }
@@ -48,27 +100,120 @@ impl<const N: usize> Bitfield<N> {
     pub fn set(&self, range: Range<usize>, v: bool) {
         assert!(range.start <= range.end && range.end <= Self::LEN);
 
-        if range.start != range.end {
-            for ei in range.start / Self::ENTRY_BITS..=(range.end - 1) / Self::ENTRY_BITS {
-                let bit_off = ei * Self::ENTRY_BITS;
-                let bit_start = range.start.saturating_sub(bit_off);
-                let bit_end = (range.end - bit_off).min(Self::ENTRY_BITS);
-                let bits = bit_end - bit_start;
-                let byte = (u64::MAX >> (Self::ENTRY_BITS - bits)) << bit_start;
-                if v {
-                    self.data[ei].fetch_or(byte);
-                } else {
-                    self.data[ei].fetch_and(!byte);
-                }
+        for (ei, byte) in Self::range_masks(range) {
+            if v {
+                let old = self.data[ei].fetch_or(byte);
+                self.mark_full(ei, old | byte);
+            } else {
+                self.data[ei].fetch_and(!byte);
+                self.clear_full(ei);
+            }
+        }
+    }
+
+    /// Overwrite the `range` of bits with `v`, like [Bitfield::set], but with
+    /// non-temporal stores for the entries that are fully covered, see [stream_fill].
+    ///
+    /// The partially covered entries at the boundaries are updated atomically.
+    /// This is not thread-safe and intended for the initialization.
+    pub fn fill_range(&self, range: Range<usize>, v: bool) {
+        self.fill_range_unfenced(range, v);
+        stream_fence();
+    }
+
+    /// Like [Bitfield::fill_range], but without the final [stream_fence],
+    /// which has to be issued by the caller, e.g., after filling all bitfields.
+    pub fn fill_range_unfenced(&self, range: Range<usize>, v: bool) {
+        assert!(range.start <= range.end && range.end <= Self::LEN);
+
+        let first = range.start.div_ceil(Self::ENTRY_BITS);
+        let last = range.end / Self::ENTRY_BITS;
+        if first >= last {
+            // No fully covered entry
+            return self.set(range, v);
+        }
+        self.set(range.start..first * Self::ENTRY_BITS, v);
+        stream_fill(&self.data[first..last], if v { u64::MAX } else { 0 });
+        let full = (u64::MAX >> (u64::BITS as usize - (last - first))) << first;
+        if v {
+            self.full.fetch_or(full);
+        } else {
+            self.full.fetch_and(!full);
+        }
+        self.set(last * Self::ENTRY_BITS..range.end, v);
+    }
+
+    /// Sets the summary bit of the `i`-th entry if its new value `e` is full.
+    ///
+    /// If the entry was freed in the meantime, the bit is cleared again, as the
+    /// free might have cleared the summary before we have set it.
+    fn mark_full(&self, i: usize, e: u64) {
+        if e == u64::MAX {
+            self.full.fetch_or(1 << i);
+            if self.data[i].load() != u64::MAX {
+                self.full.fetch_and(!(1 << i));
             }
         }
     }
 
+    /// Clears the summary bit of the `i`-th entry, *after* bits of it have been cleared
+    fn clear_full(&self, i: usize) {
+        if self.full.load() & (1 << i) != 0 {
+            self.full.fetch_and(!(1 << i));
+        }
+    }
+
+    /// Updates the summary bit of the `i`-th entry that has been changed to `e`
+    fn update_full(&self, i: usize, e: u64) {
+        if e == u64::MAX {
+            self.mark_full(i, e);
+        } else {
+            self.clear_full(i);
+        }
+    }
+
+    /// Recomputes the summary from the entries, e.g., after a crash
+    pub fn reset_summary(&self) {
+        let full = (self.data.iter().enumerate())
+            .filter(|(_, e)| e.load() == u64::MAX)
+            .fold(0, |acc, (i, _)| acc | (1 << i));
+        self.full.store(full);
+    }
+
+    /// Returns the summary with a set bit for every (probably) full entry
+    pub fn summary(&self) -> u64 {
+        self.full.load()
+    }
+
+    /// Returns the entry indices and masks of the bits in `range`
+    fn range_masks(
+        range: Range<usize>,
+    ) -> impl ExactSizeIterator<Item = (usize, u64)> + DoubleEndedIterator {
+        let entries = if range.is_empty() {
+            0..0
+        } else {
+            range.start / Self::ENTRY_BITS..(range.end - 1) / Self::ENTRY_BITS + 1
+        };
+        entries.map(move |ei| {
+            let bit_off = ei * Self::ENTRY_BITS;
+            let bit_start = range.start.saturating_sub(bit_off);
+            let bit_end = (range.end - bit_off).min(Self::ENTRY_BITS);
+            let bits = bit_end - bit_start;
+            (ei, (u64::MAX >> (Self::ENTRY_BITS - bits)) << bit_start)
+        })
+    }
+
     /// Return the  `i`-th entry
     pub fn get_entry(&self, i: usize) -> u64 {
         self.data[i].load()
     }
 
+    /// Overwrite the `i`-th entry
+    pub fn set_entry(&self, i: usize, e: u64) {
+        self.data[i].store(e);
+        self.update_full(i, e);
+    }
+
     /// Toggle 2^`order` bits at the `i`-th place if they are all zero or one as expected
     ///
     /// # Warning
@@ -88,7 +233,14 @@ impl<const N: usize> Bitfield<N> {
                         (e & mask == 0).then_some(e | mask)
                     }
                 }) {
-                    Ok(_) => Ok(()),
+                    Ok(_) if expected => {
+                        self.clear_full(di);
+                        Ok(())
+                    }
+                    Ok(old) => {
+                        self.mark_full(di, old | mask);
+                        Ok(())
+                    }
                     Err(_) => Err(Error::Address),
                 }
             }
@@ -100,18 +252,23 @@ impl<const N: usize> Bitfield<N> {
                 // Update multiple entries
                 let num_entries = num_bits / Self::ENTRY_BITS;
                 let di = i / Self::ENTRY_BITS;
+                let value = if expected { !0 } else { 0 };
                 for i in di..di + num_entries {
-                    let expected = if expected { !0 } else { 0 };
-                    if let Err(_) = self.data[i].compare_exchange(expected, !expected) {
+                    if let Err(_) = self.data[i].compare_exchange(value, !value) {
                         // Undo changes
                         for j in (di..i).rev() {
-                            self.data[j]
-                                .compare_exchange(!expected, expected)
-                                .expect("Failed undo toggle");
+                            if self.data[j].compare_exchange(!value, value).is_err() {
+                                error!("Failed undo toggle {j}");
+                                return Err(Error::Corruption);
+                            }
+                            self.update_full(j, value);
                         }
                         return Err(Error::Address);
                     }
                 }
+                for i in di..di + num_entries {
+                    self.update_full(i, !value);
+                }
                 Ok(())
             }
         }
@@ -131,11 +288,72 @@ impl<const N: usize> Bitfield<N> {
         // Safety: Cast to smaller type atomic, keeping the same total bitfield size
         let atom = unsafe { &*self.data.as_ptr().cast::<Atom<I>>().add(idx) };
         match atom.compare_exchange(val, !val) {
-            Ok(_) => Ok(()),
+            Ok(_) => {
+                let di = i / Self::ENTRY_BITS;
+                self.update_full(di, self.data[di].load());
+                Ok(())
+            }
             Err(_) => Err(Error::Retry),
         }
     }
 
+    /// Toggle the bits at all `positions` if they are all zero or one as expected
+    ///
+    /// The positions can be in any order, but must not overlap.
+    /// Every affected entry is updated with a single compare exchange.
+    /// If an entry contains an unexpected bit, the already updated entries are
+    /// rolled back and the first unexpected position of this entry is returned as `Ok(Err(i))`.
+    ///
+    /// # Warning
+    /// The update is only atomic per entry, concurrent readers might see partial updates.
+    /// As for [Bitfield::fill_all_or_fail], a failed rollback returns [Error::Corruption].
+    pub fn bulk_toggle(
+        &self,
+        positions: &[usize],
+        expected: bool,
+    ) -> Result<core::result::Result<(), usize>> {
+        let mut masks = [0u64; N];
+        for &i in positions {
+            assert!(i < Self::LEN, "out of bounds");
+            let bit = 1 << (i % Self::ENTRY_BITS);
+            assert!(
+                masks[i / Self::ENTRY_BITS] & bit == 0,
+                "overlapping positions"
+            );
+            masks[i / Self::ENTRY_BITS] |= bit;
+        }
+        let expected = if expected { u64::MAX } else { 0 };
+
+        for (ei, &mask) in masks.iter().enumerate() {
+            if mask == 0 {
+                continue;
+            }
+            match self.data[ei].fetch_update(|e| ((e ^ expected) & mask == 0).then_some(e ^ mask)) {
+                Ok(old) => self.update_full(ei, old ^ mask),
+                Err(e) => {
+                    // Undo previous updates
+                    for (j, &mask) in masks[..ei].iter().enumerate().rev() {
+                        if mask == 0 {
+                            continue;
+                        }
+                        match self.data[j]
+                            .fetch_update(|e| ((e ^ !expected) & mask == 0).then_some(e ^ mask))
+                        {
+                            Ok(old) => self.update_full(j, old ^ mask),
+                            Err(_) => {
+                                error!("Failed undo toggle at {j}");
+                                return Err(Error::Corruption);
+                            }
+                        }
+                    }
+                    let bit = ((e ^ expected) & mask).trailing_zeros() as usize;
+                    return Ok(Err(ei * Self::ENTRY_BITS + bit));
+                }
+            }
+        }
+        Ok(Ok(()))
+    }
+
     pub fn is_zero(&self, i: usize, order: usize) -> bool {
         let num_bits = 1 << order;
         debug_assert!(i < Self::LEN && order <= Self::ORDER);
@@ -152,32 +370,138 @@ impl<const N: usize> Bitfield<N> {
         }
     }
 
+    /// Returns the largest order for which the aligned block containing the
+    /// zeroed 2^`order` bits at `i` is completely zero.
+    pub fn zero_order(&self, i: usize, order: usize) -> usize {
+        debug_assert!(i % (1 << order) == 0, "not aligned");
+        let mut order = order;
+        while order < Self::ORDER && self.is_zero(align_down(i, 2 << order), order + 1) {
+            order += 1;
+        }
+        order
+    }
+
     /// Set the first aligned 2^`order` zero bits, returning the bit offset
     ///
+    /// The search starts at the bit `start_bit` of the entry `start_entry`,
+    /// wrapping around to the beginning of this entry and then to the following entries.
+    ///
     /// # Warning
     /// Orders above 6 need multiple CAS operations, which might lead to race conditions!
-    pub fn set_first_zeros(&self, start_entry: usize, order: usize) -> Result<usize> {
+    pub fn set_first_zeros(
+        &self,
+        (start_entry, start_bit): (usize, usize),
+        order: usize,
+    ) -> Result<usize> {
         debug_assert!(start_entry < Self::ENTRIES);
+        debug_assert!(start_bit < Self::ENTRY_BITS);
 
         if order > Self::ENTRY_BITS.ilog2() as usize {
             return self.set_first_zero_entries(order);
         }
 
+        // Skip the entries that are known to be full
+        let full = self.full.load();
+        let mut skipped = 0u64;
         for i in 0..self.data.len() {
+            let start = if i == 0 { start_bit } else { 0 };
             let i = (i + start_entry) % self.data.len();
+            if full & (1 << i) != 0 {
+                skipped |= 1 << i;
+                continue;
+            }
+            if let Some(offset) = self.set_first_zeros_in(i, order, start) {
+                return Ok(i * Self::ENTRY_BITS + offset);
+            }
+        }
+        // The summary might have been outdated, retry the skipped entries
+        while skipped != 0 {
+            let i = skipped.trailing_zeros() as usize;
+            skipped &= skipped - 1;
+            if let Some(offset) = self.set_first_zeros_in(i, order, 0) {
+                return Ok(i * Self::ENTRY_BITS + offset);
+            }
+        }
+        Err(Error::Memory)
+    }
 
-            let mut offset = 0;
-            if let Ok(_) = self.data[i].fetch_update(|e| {
-                let (val, o) = first_zeros_aligned(e, order)?;
-                offset = o;
+    /// Set the first aligned 2^`order` zero bits in the `i`-th entry,
+    /// starting at the bit `start`, and return the bit offset
+    fn set_first_zeros_in(&self, i: usize, order: usize, start: usize) -> Option<usize> {
+        #[cfg(feature = "search_stats")]
+        searched(1);
+
+        let mut offset = 0;
+        let mut value = 0;
+        self.data[i]
+            .fetch_update(|e| {
+                let (val, o) = first_zeros_aligned(e, order, start)?;
+                (offset, value) = (o, val);
                 Some(val)
-            }) {
-                return Ok(i * Self::ENTRY_BITS + offset);
+            })
+            .ok()?;
+        self.mark_full(i, value);
+        Some(offset)
+    }
+
+    /// Set the first `k` zero bits that start at a multiple of `k`, returning the bit offset.
+    ///
+    /// Unlike [Bitfield::set_first_zeros], `k` does not have to be a power of two,
+    /// e.g., for allocating 768 frames.
+    /// As for [Bitfield::fill_all_or_fail], the entries are claimed one after the other,
+    /// and a failed rollback returns [Error::Corruption].
+    ///
+    /// # Warning
+    /// Runs spanning multiple entries need multiple CAS operations, which might lead to race conditions!
+    pub fn find_k_free_aligned(&self, k: usize) -> Result<usize> {
+        assert!(k > 0, "empty run");
+        if k > Self::LEN {
+            return Err(Error::Memory);
+        }
+        if k.is_power_of_two() {
+            return self.set_first_zeros((0, 0), k.ilog2() as _);
+        }
+
+        for start in (0..=Self::LEN - k).step_by(k) {
+            #[cfg(feature = "search_stats")]
+            searched(1);
+            let range = start..start + k;
+            // Check that these bits are free, otherwise continue with the next run
+            if Self::range_masks(range.clone()).all(|(i, mask)| self.data[i].load() & mask == 0)
+                && self.set_range_or_fail(range)?.is_ok()
+            {
+                return Ok(start);
             }
         }
         Err(Error::Memory)
     }
 
+    /// Set all bits in `range` if they are zero, one entry after the other.
+    ///
+    /// If an entry already has a set bit, the previously claimed entries are reset
+    /// and its index is returned as `Ok(Err(i))`.
+    fn set_range_or_fail(&self, range: Range<usize>) -> Result<core::result::Result<(), usize>> {
+        for (n, (i, mask)) in Self::range_masks(range.clone()).enumerate() {
+            let set = |e: u64| (e & mask == 0).then_some(e | mask);
+            match self.data[i].fetch_update(set) {
+                Ok(old) => self.mark_full(i, old | mask),
+                Err(_) => {
+                    // Undo previous updates
+                    for (j, mask) in Self::range_masks(range).take(n).rev() {
+                        let clear = |e: u64| (e & mask == mask).then_some(e & !mask);
+                        if self.data[j].fetch_update(clear).is_err() {
+                            error!("Failed undo set at {j}");
+                            return Err(Error::Corruption);
+                        }
+                        self.clear_full(j);
+                    }
+                    return Ok(Err(i));
+                }
+            }
+        }
+        Ok(Ok(()))
+    }
+
     /// Allocate multiple entries with multiple CAS
     ///
     /// # Warning
@@ -188,47 +512,135 @@ impl<const N: usize> Bitfield<N> {
 
         let num_entries = 1 << (order - Self::ENTRY_BITS.ilog2() as usize);
 
-        for (i, chunk) in self.data.chunks(num_entries).enumerate() {
-            // Check that these entries are free
-            if chunk.iter().all(|e| e.load() == 0) {
-                for (j, entry) in chunk.iter().enumerate() {
-                    if let Err(_) = entry.compare_exchange(0, u64::MAX) {
-                        // Undo previous updates
-                        for k in (0..j).rev() {
-                            chunk[k]
-                                .compare_exchange(u64::MAX, 0)
-                                .expect("Failed undo search");
-                        }
-                        break;
+        for start in (0..Self::ENTRIES).step_by(num_entries) {
+            #[cfg(feature = "search_stats")]
+            searched(num_entries);
+            let entries = start..start + num_entries;
+            // Check that these entries are free, otherwise continue with the next chunk
+            if self.data[entries.clone()].iter().all(|e| e.load() == 0)
+                && self.fill_entries_or_fail(entries)?.is_ok()
+            {
+                return Ok(start * Self::ENTRY_BITS);
+            }
+        }
+        Err(Error::Memory)
+    }
+
+    /// Atomically set all bits if they are all zero, e.g., to claim an entire huge frame.
+    ///
+    /// The entries are claimed one after the other with CAS.
+    /// If an entry already has a set bit, the previously claimed entries are reset
+    /// and its index is returned as `Ok(Err(i))`.
+    ///
+    /// # Warning
+    /// The rollback expects that no one else modified the already claimed entries.
+    /// Concurrent callers cannot do this, as they would have failed on the first
+    /// entry claimed by us, but a concurrent [Bitfield::toggle] on bits it does not own
+    /// can. In that case, the rollback stops and [Error::Corruption] is returned.
+    pub fn fill_all_or_fail(&self) -> Result<core::result::Result<(), usize>> {
+        self.fill_entries_or_fail(0..Self::ENTRIES)
+    }
+
+    /// Like [Bitfield::fill_all_or_fail], but only for the given `entries`
+    ///
+    /// The returned index is relative to the start of `entries`.
+    fn fill_entries_or_fail(
+        &self,
+        entries: Range<usize>,
+    ) -> Result<core::result::Result<(), usize>> {
+        for i in entries.clone() {
+            if self.data[i].compare_exchange(0, u64::MAX).is_err() {
+                // Undo previous updates
+                for j in (entries.start..i).rev() {
+                    if self.data[j].compare_exchange(u64::MAX, 0).is_err() {
+                        error!("Failed undo fill at {}", j - entries.start);
+                        return Err(Error::Corruption);
                     }
+                    self.clear_full(j);
                 }
-                return Ok(i * num_entries * Self::ENTRY_BITS);
+                return Ok(Err(i - entries.start));
             }
+            self.mark_full(i, u64::MAX);
         }
-        Err(Error::Memory)
+        Ok(Ok(()))
     }
 
     /// Fill this bitset with `v` ignoring any previous data.
     pub fn fill(&self, v: bool) {
+        let full = if v {
+            u64::MAX >> (u64::BITS as usize - N)
+        } else {
+            0
+        };
         let v = if v { u64::MAX } else { 0 };
         for row in &self.data {
             row.store(v);
         }
+        self.full.store(full);
+    }
+
+    /// Copies all entries, e.g., for diagnostics.
+    ///
+    /// Note: The entries are loaded one after the other, so this is not transactional.
+    /// Operations that are concurrent to the snapshot might be only partially visible.
+    pub fn snapshot(&self) -> [u64; N] {
+        let snapshot = core::array::from_fn(|i| self.data[i].0.load(SeqCst));
+        compiler_fence(SeqCst);
+        snapshot
     }
 
     /// Returns the number of zeros in this bitfield
     pub fn count_zeros(&self) -> usize {
-        self.data
+        self.snapshot()
             .iter()
-            .map(|v| v.load().count_zeros() as usize)
+            .map(|v| v.count_zeros() as usize)
             .sum()
     }
+
+    /// Counts the maximal free aligned blocks of each order up to [Bitfield::ORDER],
+    /// e.g., two free buddies of order 0 are counted as a single block of order 1.
+    pub fn free_orders(&self, orders: &mut [usize]) {
+        fn count(words: &[u64], start: usize, order: usize, orders: &mut [usize]) {
+            const BITS: usize = u64::BITS as usize;
+            let len = 1 << order;
+            let (free, full) = if len >= BITS {
+                let words = &words[start / BITS..(start + len) / BITS];
+                let free = words.iter().all(|w| *w == 0);
+                (free, words.iter().all(|w| *w == u64::MAX))
+            } else {
+                let mask = (u64::MAX >> (BITS - len)) << (start % BITS);
+                let bits = words[start / BITS] & mask;
+                (bits == 0, bits == mask)
+            };
+            if free {
+                orders[order] += 1;
+            } else if !full {
+                count(words, start, order - 1, orders);
+                count(words, start + len / 2, order - 1, orders);
+            }
+        }
+        count(&self.snapshot(), 0, Self::ORDER, orders);
+    }
+}
+
+/// Set the first aligned 2^`order` zero bits at or after the bit `start`,
+/// wrapping around to the lowest bits, and return the bit offset
+fn first_zeros_aligned(v: u64, order: usize, start: usize) -> Option<(u64, usize)> {
+    let start = align_down(start % u64::BITS as usize, 1 << order);
+    if start > 0 {
+        // Skip the lower bits by treating them as set
+        let below = (1 << start) - 1;
+        if let Some((val, offset)) = first_zeros_aligned_from_0(v | below, order) {
+            return Some(((val & !below) | v, offset));
+        }
+    }
+    first_zeros_aligned_from_0(v, order)
 }
 
 /// Set the first aligned 2^`order` zero bits, returning the bit offset
 ///
 /// - See <https://graphics.stanford.edu/~seander/bithacks.html#ZeroInWord>
-fn first_zeros_aligned(v: u64, order: usize) -> Option<(u64, usize)> {
+fn first_zeros_aligned_from_0(v: u64, order: usize) -> Option<(u64, usize)> {
     match order {
         0 => {
             let off = v.trailing_ones();
@@ -272,6 +684,234 @@ fn first_zeros_aligned(v: u64, order: usize) -> Option<(u64, usize)> {
 
 #[cfg(all(test, feature = "std"))]
 mod test {
+    use crate::util::WyRand;
+
+    #[test]
+    fn snapshot() {
+        let bitfield = super::Bitfield::<8>::default();
+        bitfield.set(3..70, true);
+        let snapshot = bitfield.snapshot();
+        for (i, e) in snapshot.iter().enumerate() {
+            assert_eq!(*e, bitfield.get_entry(i));
+        }
+        assert_eq!(bitfield.count_zeros(), 512 - 67);
+
+        // Concurrent allocations only decrease the zeros
+        bitfield.fill(false);
+        std::thread::scope(|s| {
+            s.spawn(|| {
+                for i in 0..512 {
+                    bitfield.toggle(i, 0, false).unwrap();
+                }
+            });
+            let mut last = 512;
+            while last > 0 {
+                let zeros = bitfield.count_zeros();
+                assert!(zeros <= last);
+                last = zeros;
+            }
+        });
+    }
+
+    #[test]
+    fn fill_all_or_fail() {
+        let bitfield = super::Bitfield::<8>::default();
+        assert_eq!(bitfield.fill_all_or_fail(), Ok(Ok(())));
+        assert_eq!(bitfield.snapshot(), [u64::MAX; 8]);
+        // Already claimed
+        assert_eq!(bitfield.fill_all_or_fail(), Ok(Err(0)));
+        assert_eq!(bitfield.snapshot(), [u64::MAX; 8]);
+
+        // A set bit in the middle rolls back the claimed entries
+        bitfield.fill(false);
+        bitfield.set(5 * 64 + 3..5 * 64 + 4, true);
+        assert_eq!(bitfield.fill_all_or_fail(), Ok(Err(5)));
+        assert_eq!(bitfield.snapshot(), [0, 0, 0, 0, 0, 1 << 3, 0, 0]);
+        bitfield.toggle(5 * 64 + 3, 0, true).unwrap();
+        assert_eq!(bitfield.count_zeros(), 512);
+
+        // Concurrent claims, exactly one of them succeeds
+        for _ in 0..64 {
+            bitfield.fill(false);
+            let results = std::thread::scope(|s| {
+                let threads = [(); 4].map(|_| s.spawn(|| bitfield.fill_all_or_fail()));
+                threads.map(|t| t.join().unwrap())
+            });
+            assert_eq!(results.iter().filter(|r| **r == Ok(Ok(()))).count(), 1);
+            assert!(results.iter().all(|r| r.is_ok()));
+            assert_eq!(bitfield.count_zeros(), 0);
+        }
+    }
+
+    #[test]
+    fn fill_range() {
+        type Bitfield = super::Bitfield<8>;
+        let mut rng = WyRand::new(42);
+        let offsets = [0, 1, 63, 64, 65, 127, 128, 200, 256, 447, 448, 511, 512];
+        for start in offsets {
+            for end in offsets.into_iter().filter(|e| *e >= start) {
+                for v in [false, true] {
+                    let (expected, actual) = (Bitfield::default(), Bitfield::default());
+                    for i in 0..Bitfield::ENTRIES {
+                        // Some full and empty entries for the summary
+                        let e = match i % 3 {
+                            0 => rng.gen(),
+                            1 => u64::MAX,
+                            _ => 0,
+                        };
+                        expected.set_entry(i, e);
+                        actual.set_entry(i, e);
+                    }
+                    expected.reset_summary();
+                    actual.reset_summary();
+
+                    expected.set(start..end, v);
+                    actual.fill_range(start..end, v);
+                    assert_eq!(actual.snapshot(), expected.snapshot(), "{start}..{end} {v}");
+                    assert_eq!(actual.summary(), expected.summary(), "{start}..{end} {v}");
+                }
+            }
+        }
+    }
+
+    #[test]
+    fn bulk_toggle() {
+        let bitfield = super::Bitfield::<4>::default();
+        // Unordered and across entries
+        let positions = [130, 3, 64, 5, 255, 0];
+        assert_eq!(bitfield.bulk_toggle(&positions, false), Ok(Ok(())));
+        assert_eq!(bitfield.snapshot(), [0b101001, 1, 1 << 2, 1 << 63]);
+        // Already set
+        assert_eq!(bitfield.bulk_toggle(&[1, 3], false), Ok(Err(3)));
+        assert_eq!(bitfield.snapshot(), [0b101001, 1, 1 << 2, 1 << 63]);
+
+        // A conflict in a later entry rolls back the earlier ones
+        assert_eq!(
+            bitfield.bulk_toggle(&[7, 70, 200, 130], false),
+            Ok(Err(130))
+        );
+        assert_eq!(bitfield.snapshot(), [0b101001, 1, 1 << 2, 1 << 63]);
+        assert_eq!(bitfield.bulk_toggle(&[0, 64, 129], true), Ok(Err(129)));
+        assert_eq!(bitfield.snapshot(), [0b101001, 1, 1 << 2, 1 << 63]);
+
+        assert_eq!(bitfield.bulk_toggle(&positions, true), Ok(Ok(())));
+        assert_eq!(bitfield.count_zeros(), 256);
+        assert_eq!(bitfield.bulk_toggle(&[], true), Ok(Ok(())));
+    }
+
+    #[test]
+    fn summary() {
+        let bitfield = super::Bitfield::<8>::default();
+        assert_eq!(bitfield.summary(), 0);
+        bitfield.set(32..128, true);
+        assert_eq!(bitfield.summary(), 0b10);
+        bitfield.set_entry(3, u64::MAX);
+        assert_eq!(bitfield.summary(), 0b1010);
+
+        // Freeing into a full entry clears its bit
+        bitfield.toggle(64, 0, true).unwrap();
+        assert_eq!(bitfield.summary(), 0b1000);
+        bitfield.toggle(64, 0, false).unwrap();
+        assert_eq!(bitfield.summary(), 0b1010);
+        bitfield.set(3 * 64..3 * 64 + 1, false);
+        assert_eq!(bitfield.summary(), 0b10);
+        bitfield.toggle(64, 6, true).unwrap();
+        assert_eq!(bitfield.summary(), 0);
+
+        // Filling the first entry by allocations
+        for i in 0..32 {
+            assert_eq!(bitfield.set_first_zeros((0, 0), 0), Ok(i));
+        }
+        assert_eq!(bitfield.summary(), 0b1);
+        bitfield.toggle(4 * 64, 8, false).unwrap();
+        assert_eq!(bitfield.summary(), 0xf1);
+        assert_eq!(
+            bitfield.bulk_toggle(&[5 * 64, 6 * 64 + 1], true),
+            Ok(Ok(()))
+        );
+        assert_eq!(bitfield.summary(), 0x91);
+        bitfield.fill(true);
+        assert_eq!(bitfield.summary(), 0xff);
+        bitfield.fill(false);
+        assert_eq!(bitfield.summary(), 0);
+
+        // A stale summary bit is skipped first, but retried
+        bitfield.set(64..512, true);
+        bitfield.full.store(0xff);
+        assert_eq!(bitfield.set_first_zeros((1, 0), 0), Ok(0));
+        bitfield.reset_summary();
+        assert_eq!(bitfield.summary(), 0xfe);
+
+        // Starting within an entry, wrapping around to its beginning and then to the next entry
+        bitfield.fill(false);
+        assert_eq!(bitfield.set_first_zeros((2, 10), 0), Ok(2 * 64 + 10));
+        assert_eq!(bitfield.set_first_zeros((2, 10), 0), Ok(2 * 64 + 11));
+        assert_eq!(bitfield.set_first_zeros((2, 10), 2), Ok(2 * 64 + 12));
+        bitfield.set(2 * 64 + 16..3 * 64, true);
+        assert_eq!(bitfield.set_first_zeros((2, 20), 0), Ok(2 * 64));
+        bitfield.set(2 * 64..3 * 64, true);
+        assert_eq!(bitfield.set_first_zeros((2, 20), 1), Ok(3 * 64));
+
+        // Concurrent allocations and frees, afterwards, only full entries are marked
+        for _ in 0..16 {
+            bitfield.fill(false);
+            bitfield.set(0..448, true);
+            std::thread::scope(|s| {
+                for t in 0..4 {
+                    let bitfield = &bitfield;
+                    s.spawn(move || {
+                        let mut frames = std::vec::Vec::new();
+                        for i in 0..256 {
+                            if let Ok(bit) = bitfield.set_first_zeros((t * 2, 0), 0) {
+                                frames.push(bit);
+                            }
+                            if i % 3 != 0 {
+                                continue;
+                            }
+                            if let Some(bit) = frames.pop() {
+                                bitfield.toggle(bit, 0, true).unwrap();
+                            }
+                        }
+                    });
+                }
+            });
+            let summary = bitfield.summary();
+            for i in 0..8 {
+                if summary & (1 << i) != 0 {
+                    assert_eq!(bitfield.get_entry(i), u64::MAX);
+                }
+            }
+        }
+    }
+
+    /// The summary reduces the number of probed entries in an almost full bitfield
+    #[cfg(feature = "search_stats")]
+    #[test]
+    fn summary_scan() {
+        use super::searched_local;
+
+        let bitfield = super::Bitfield::<8>::default();
+        let mut searched = [0; 2];
+        for (i, summary) in [true, false].into_iter().enumerate() {
+            // Only 26 frames (5%) are free in the first entry
+            bitfield.fill(false);
+            bitfield.set(26..512, true);
+            if !summary {
+                bitfield.full.store(0);
+            }
+            for _ in 0..26 {
+                let before = searched_local();
+                bitfield.set_first_zeros((1, 0), 0).unwrap();
+                searched[i] += searched_local() - before;
+                if !summary {
+                    bitfield.full.store(0);
+                }
+            }
+        }
+        std::println!("probes: summary={} none={}", searched[0], searched[1]);
+        assert!(searched[0] <= 2 * 26);
+        assert!(searched[1] >= 8 * 26);
+    }
 
     #[test]
     fn bit_set() {
@@ -345,7 +985,7 @@ mod test {
 
     #[test]
     fn first_zeros_aligned() {
-        use super::first_zeros_aligned as fza;
+        let fza = |v, order| super::first_zeros_aligned(v, order, 0);
 
         assert_eq!(fza(0b0, 0), Some((0b1, 0)));
         assert_eq!(fza(0b0, 1), Some((0b11, 0)));
@@ -400,6 +1040,35 @@ mod test {
         assert_eq!(fza(0, 6), Some((0xffffffffffffffff, 0)));
         assert_eq!(fza(1, 6), None);
         assert_eq!(fza(0xa000000000000000, 6), None);
+
+        // Starting in the middle of the entry
+        use super::first_zeros_aligned as fza_at;
+        assert_eq!(fza_at(0b0, 0, 5), Some((0b10_0000, 5)));
+        assert_eq!(fza_at(0b10_0000, 0, 5), Some((0b110_0000, 6)));
+        assert_eq!(fza_at(0b1, 0, 63), Some((0x8000000000000001, 63)));
+        // Aligned down to the order
+        assert_eq!(fza_at(0b0, 1, 5), Some((0b11_0000, 4)));
+        assert_eq!(fza_at(0b0, 2, 9), Some((0xf00, 8)));
+        assert_eq!(fza_at(0b0, 3, 17), Some((0xff_0000, 16)));
+        assert_eq!(fza_at(0b0, 4, 40), Some((0xffff_0000_0000, 32)));
+        assert_eq!(fza_at(0b0, 5, 40), Some((0xffffffff00000000, 32)));
+        assert_eq!(fza_at(0b0, 6, 40), Some((0xffffffffffffffff, 0)));
+        // The lower bits are not modified
+        assert_eq!(fza_at(0b1010, 0, 2), Some((0b1110, 2)));
+        assert_eq!(fza_at(0x0f0f, 2, 8), Some((0xff0f, 12)));
+        // Wrapping around to the beginning
+        assert_eq!(
+            fza_at(0xffff_ffff_ffff_fff0, 0, 8),
+            Some((0xffff_ffff_ffff_fff1, 0))
+        );
+        assert_eq!(
+            fza_at(0xffff_ffff_ffff_ff00, 2, 32),
+            Some((0xffff_ffff_ffff_ff0f, 0))
+        );
+        assert_eq!(fza_at(0xffff_ffff_0000_0000, 5, 32), Some((u64::MAX, 0)));
+        assert_eq!(fza_at(0xffff_ffff_ffff_ff00, 3, 8), Some((u64::MAX, 0)));
+        assert_eq!(fza_at(u64::MAX, 0, 8), None);
+        assert_eq!(fza_at(0x7fff_ffff_ffff_ffff, 1, 8), None);
     }
 
     #[test]
@@ -408,25 +1077,115 @@ mod test {
 
         // 9
         assert!(bitfield.data.iter().all(|e| e.load() == 0));
-        assert_eq!(0, bitfield.set_first_zeros(0, 9).unwrap());
+        assert_eq!(0, bitfield.set_first_zeros((0, 0), 9).unwrap());
         assert!(bitfield.data.iter().all(|e| e.load() == u64::MAX));
         bitfield.toggle(0, 9, true).unwrap();
         assert!(bitfield.data.iter().all(|e| e.load() == 0));
 
-        assert_eq!(0, bitfield.set_first_zeros(0, 7).unwrap());
+        assert_eq!(0, bitfield.set_first_zeros((0, 0), 7).unwrap());
         assert!(bitfield.data[0..2].iter().all(|e| e.load() == u64::MAX));
 
-        assert_eq!(4 * 64, bitfield.set_first_zeros(0, 8).unwrap());
+        assert_eq!(4 * 64, bitfield.set_first_zeros((0, 0), 8).unwrap());
         assert!(bitfield.data[4..8].iter().all(|e| e.load() == u64::MAX));
 
-        assert_eq!(2 * 64, bitfield.set_first_zeros(0, 6).unwrap());
+        assert_eq!(2 * 64, bitfield.set_first_zeros((0, 0), 6).unwrap());
         assert!(bitfield.get_entry(2) == u64::MAX);
-        assert_eq!(3 * 64, bitfield.set_first_zeros(0, 6).unwrap());
+        assert_eq!(3 * 64, bitfield.set_first_zeros((0, 0), 6).unwrap());
         assert!(bitfield.get_entry(3) == u64::MAX);
 
-        bitfield.set_first_zeros(0, 9).expect_err("no mem");
-        bitfield.set_first_zeros(0, 8).expect_err("no mem");
-        bitfield.set_first_zeros(0, 7).expect_err("no mem");
-        bitfield.set_first_zeros(0, 6).expect_err("no mem");
+        bitfield.set_first_zeros((0, 0), 9).expect_err("no mem");
+        bitfield.set_first_zeros((0, 0), 8).expect_err("no mem");
+        bitfield.set_first_zeros((0, 0), 7).expect_err("no mem");
+        bitfield.set_first_zeros((0, 0), 6).expect_err("no mem");
+    }
+
+    /// Failed CAS operations in the middle of multi-entry updates
+    #[test]
+    fn inject_undo() {
+        use crate::atomic::inject;
+        use crate::Error;
+
+        let bitfield = super::Bitfield::<8>::default();
+
+        // Toggle fails on the third entry, the first two are restored
+        inject::fail_nth(&bitfield.data[2], 0);
+        assert_eq!(bitfield.toggle(0, 8, false), Err(Error::Address));
+        assert!(bitfield.data.iter().all(|e| e.load() == 0));
+        // The undo of the second entry fails
+        inject::fail_nth(&bitfield.data[2], 0);
+        inject::fail_nth(&bitfield.data[1], 1);
+        assert_eq!(bitfield.toggle(0, 8, false), Err(Error::Corruption));
+        assert_eq!(inject::clear(), 0);
+        bitfield.fill(false);
+
+        // The search continues with the next chunk
+        inject::fail_nth(&bitfield.data[1], 0);
+        assert_eq!(bitfield.set_first_zeros((0, 0), 7), Ok(2 * 64));
+        assert!(bitfield.data[0..2].iter().all(|e| e.load() == 0));
+        assert!(bitfield.data[2..4].iter().all(|e| e.load() == u64::MAX));
+        // The undo of the first entry fails
+        inject::fail_nth(&bitfield.data[1], 0);
+        inject::fail_nth(&bitfield.data[0], 1);
+        assert_eq!(bitfield.set_first_zeros((0, 0), 7), Err(Error::Corruption));
+        assert_eq!(inject::clear(), 0);
+    }
+
+    #[test]
+    fn free_orders() {
+        let bitfield = super::Bitfield::<8>::default();
+        let mut orders = [0; 10];
+        bitfield.free_orders(&mut orders);
+        assert_eq!(orders, [0, 0, 0, 0, 0, 0, 0, 0, 0, 1]);
+
+        bitfield.set(1..2, true);
+        bitfield.set(64..128, true);
+        let mut orders = [0; 10];
+        bitfield.free_orders(&mut orders);
+        assert_eq!(orders, [1, 1, 1, 1, 1, 1, 0, 1, 1, 0]);
+
+        bitfield.fill(true);
+        let mut orders = [0; 10];
+        bitfield.free_orders(&mut orders);
+        assert_eq!(orders, [0; 10]);
+    }
+
+    #[test]
+    fn find_k_free_aligned() {
+        use crate::atomic::inject;
+        use crate::Error;
+
+        let bitfield = super::Bitfield::<8>::default();
+        assert_eq!(bitfield.find_k_free_aligned(3), Ok(0));
+        assert_eq!(bitfield.find_k_free_aligned(3), Ok(3));
+        assert_eq!(bitfield.get_entry(0), 0b111111);
+        // The next aligned run is occupied
+        bitfield.set(7..8, true);
+        assert_eq!(bitfield.find_k_free_aligned(3), Ok(9));
+
+        // Spanning multiple entries
+        assert_eq!(bitfield.find_k_free_aligned(96), Ok(96));
+        assert_eq!(bitfield.get_entry(1), u64::MAX << 32);
+        assert_eq!(bitfield.get_entry(2), u64::MAX);
+        // Power of two
+        assert_eq!(bitfield.find_k_free_aligned(64), Ok(3 * 64));
+
+        // A concurrent update is rolled back and the next run is used
+        inject::fail_nth(&bitfield.data[5], 0);
+        assert_eq!(bitfield.find_k_free_aligned(96), Ok(4 * 96));
+        assert_eq!(bitfield.get_entry(4), 0);
+        assert_eq!(bitfield.get_entry(5), 0);
+        assert_eq!(bitfield.get_entry(6), u64::MAX);
+        assert_eq!(bitfield.get_entry(7), u64::MAX >> 32);
+        // Failed rollback
+        inject::fail_nth(&bitfield.data[5], 0);
+        inject::fail_nth(&bitfield.data[4], 1);
+        assert_eq!(bitfield.find_k_free_aligned(96), Err(Error::Corruption));
+        assert_eq!(inject::clear(), 0);
+
+        assert_eq!(bitfield.find_k_free_aligned(768), Err(Error::Memory));
+        bitfield.fill(false);
+        assert_eq!(bitfield.find_k_free_aligned(384), Ok(0));
+        assert_eq!(bitfield.find_k_free_aligned(384), Err(Error::Memory));
+        assert_eq!(bitfield.count_zeros(), 128);
     }
 }
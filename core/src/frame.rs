@@ -1,10 +1,13 @@
 //! Page frame utilities
 
+use core::marker::PhantomData;
 use core::mem::{align_of, size_of, transmute};
 
-use crate::FRAME_SIZE;
+use crate::{Alloc, Flags, Result, FRAME_SIZE, FRAME_SIZE_BITS};
 
 /// Correctly sized and aligned page frame.
+///
+/// The alignment has to be a literal, it is checked against [Frame::SIZE] below.
 #[derive(Clone)]
 #[repr(align(0x1000))]
 pub struct Frame {
@@ -15,6 +18,7 @@ const _: () = assert!(size_of::<Frame>() == Frame::SIZE);
 const _: () = assert!(align_of::<Frame>() == Frame::SIZE);
 
 impl Frame {
+    pub const SIZE_BITS: usize = FRAME_SIZE_BITS;
     pub const SIZE: usize = FRAME_SIZE;
 
     pub const fn new() -> Self {
@@ -22,6 +26,28 @@ impl Frame {
             _data: [0; Self::SIZE],
         }
     }
+    /// Returns a pointer to the frame at `addr`.
+    pub fn from_addr(addr: usize) -> *mut Self {
+        addr as *mut Self
+    }
+    /// Returns a pointer to the frame at `addr`,
+    /// if it is aligned and within the `len` frames starting at `base`.
+    ///
+    /// Returns `None` if the end of the frames exceeds the address space.
+    pub fn try_from_addr(addr: usize, base: *const Self, len: usize) -> Option<*mut Self> {
+        let end = len
+            .checked_mul(Self::SIZE)
+            .and_then(|size| size.checked_add(base as usize))?;
+        let range = base as usize..end;
+        (range.contains(&addr) && addr & (Self::SIZE - 1) == 0).then_some(Self::from_addr(addr))
+    }
+    pub fn as_ptr(&self) -> *const u8 {
+        self._data.as_ptr()
+    }
+    pub fn as_mut_ptr(&mut self) -> *mut u8 {
+        self._data.as_mut_ptr()
+    }
+
     pub fn cast<T>(&self) -> &T {
         debug_assert!(size_of::<T>() <= size_of::<Self>());
         unsafe { transmute(self) }
@@ -31,3 +57,142 @@ impl Frame {
         unsafe { transmute(self) }
     }
 }
+
+/// Handle of an allocated frame, that is freed with its original order when dropped.
+pub struct AllocHandle<'b, 'a, A: Alloc<'a>> {
+    alloc: &'b A,
+    core: usize,
+    frame: usize,
+    flags: Flags,
+    _p: PhantomData<&'a ()>,
+}
+
+impl<'b, 'a, A: Alloc<'a>> AllocHandle<'b, 'a, A> {
+    /// Allocate a frame on the given `core`.
+    pub fn get(alloc: &'b A, core: usize, flags: Flags) -> Result<Self> {
+        let frame = alloc.get(core, flags)?;
+        Ok(Self {
+            alloc,
+            core,
+            frame,
+            flags,
+            _p: PhantomData,
+        })
+    }
+    /// Returns the frame number.
+    pub fn frame(&self) -> usize {
+        self.frame
+    }
+    pub fn order(&self) -> usize {
+        self.flags.order()
+    }
+    /// Returns a pointer to the frame, assuming frame numbers are identity mapped.
+    pub fn as_ptr(&self) -> *mut Frame {
+        debug_assert!(self.frame <= usize::MAX / Frame::SIZE, "frame overflow");
+        Frame::from_addr(self.frame.wrapping_mul(Frame::SIZE))
+    }
+    /// Release the handle without freeing the frame, returning its frame number.
+    pub fn forget(self) -> usize {
+        let frame = self.frame;
+        core::mem::forget(self);
+        frame
+    }
+}
+
+impl<'b, 'a, A: Alloc<'a>> Drop for AllocHandle<'b, 'a, A> {
+    fn drop(&mut self) {
+        if let Err(e) = self.alloc.put(self.core, self.frame, self.flags) {
+            panic!("free of {} o={} failed: {e:?}", self.frame, self.order());
+        }
+    }
+}
+
+#[cfg(all(test, feature = "std"))]
+mod test {
+    use super::{AllocHandle, Frame};
+    use crate::util::logging;
+    use crate::wrapper::ZoneAlloc;
+    use crate::{mmap, Alloc, Flags, Init, LLFree, MetaData, TREE_FRAMES};
+
+    #[test]
+    fn from_addr() {
+        logging();
+
+        type Zone = ZoneAlloc<'static, LLFree<'static>>;
+        let mut zone = mmap::anon::<Frame>(0x1300_0000_0000, TREE_FRAMES, false, false);
+        let base = zone.as_ptr();
+        let offset = base as usize / Frame::SIZE;
+
+        let meta = MetaData::alloc(Zone::metadata_size(1, zone.len()));
+        let alloc = Zone::create(1, offset, zone.len(), Init::FreeAll, meta).unwrap();
+        let frame = alloc.get(0, Flags::o(0)).unwrap();
+
+        // Round trip from the frame number to the frame and back
+        let addr = frame * Frame::SIZE;
+        let ptr = Frame::try_from_addr(addr, base, zone.len()).unwrap();
+        assert_eq!(ptr, Frame::from_addr(addr));
+        assert_eq!(ptr as usize / Frame::SIZE, frame);
+        let i = frame - offset;
+        assert_eq!(zone[i].as_ptr(), ptr as *const u8);
+        assert_eq!(zone[i].as_mut_ptr(), ptr as *mut u8);
+
+        // Outside the zone or unaligned
+        assert_eq!(Frame::try_from_addr(addr + 1, base, zone.len()), None);
+        let end = base as usize + zone.len() * Frame::SIZE;
+        assert_eq!(Frame::try_from_addr(end, base, zone.len()), None);
+        assert_eq!(
+            Frame::try_from_addr(base as usize - Frame::SIZE, base, zone.len()),
+            None
+        );
+
+        alloc.put(0, frame, Flags::o(0)).unwrap();
+    }
+
+    #[test]
+    fn from_addr_overflow() {
+        // The end of the frames would wrap around the address space
+        let base = Frame::from_addr(usize::MAX & !(Frame::SIZE - 1));
+        assert_eq!(Frame::try_from_addr(0, base, 2), None);
+        assert_eq!(Frame::try_from_addr(0, base, usize::MAX), None);
+        assert_eq!(
+            Frame::try_from_addr(Frame::SIZE, base, usize::MAX / 2),
+            None
+        );
+        // Exactly up to the last frame of the address space
+        let last = Frame::from_addr(usize::MAX - (2 * Frame::SIZE - 1));
+        assert_eq!(Frame::try_from_addr(last as usize, last, 1), Some(last));
+    }
+
+    #[test]
+    fn handle() {
+        logging();
+
+        type Zone = ZoneAlloc<'static, LLFree<'static>>;
+        let zone = mmap::anon::<Frame>(0x1400_0000_0000, TREE_FRAMES, false, false);
+        let offset = zone.as_ptr() as usize / Frame::SIZE;
+        let meta = MetaData::alloc(Zone::metadata_size(1, zone.len()));
+        let alloc = Zone::create(1, offset, zone.len(), Init::FreeAll, meta).unwrap();
+
+        let handle = AllocHandle::get(&alloc, 0, Flags::o(1)).unwrap();
+        let frame = handle.frame();
+        assert_eq!(handle.order(), 1);
+        assert!(!alloc.is_free(frame, 1));
+        // Access the frame through the handle
+        unsafe {
+            let data = (*handle.as_ptr()).as_mut_ptr();
+            data.add(Frame::SIZE - 1).write(42);
+            assert_eq!(
+                zone[frame - offset].as_ptr().add(Frame::SIZE - 1).read(),
+                42
+            );
+        }
+        // Freed with its order on drop
+        drop(handle);
+        assert!(alloc.is_free(frame, 1));
+        assert_eq!(alloc.free_frames(), alloc.frames());
+
+        let frame = AllocHandle::get(&alloc, 0, Flags::o(0)).unwrap().forget();
+        assert!(!alloc.is_free(frame, 0));
+        alloc.put(0, frame, Flags::o(0)).unwrap();
+    }
+}
@@ -0,0 +1,167 @@
+//! Rust global allocator backed by [LLFree].
+//!
+//! Every allocation takes at least one frame, rounded up to a power of two,
+//! so this is meant for testing and for programs with few, large allocations.
+//!
+//! ```ignore
+//! #[global_allocator]
+//! static ALLOC: GlobalLLFree = GlobalLLFree::new(4, 1 << 16);
+//! ```
+
+use core::alloc::{GlobalAlloc, Layout};
+use core::ops::Range;
+use core::ptr::null_mut;
+use std::boxed::Box;
+use std::sync::OnceLock;
+
+use crate::frame::Frame;
+use crate::util::{align_up, Bytes};
+use crate::wrapper::ZoneAlloc;
+use crate::{mmap, Alloc, Flags, Init, LLFree, MetaData, MAX_ORDER};
+
+type Inner = ZoneAlloc<'static, LLFree<'static>>;
+
+/// Global allocator that maps its memory and metadata on the first allocation.
+///
+/// The initialization must not allocate itself (e.g., by logging with a
+/// formatting logger), as this would recursively initialize the allocator.
+pub struct GlobalLLFree {
+    cores: usize,
+    frames: usize,
+    alloc: OnceLock<Inner>,
+}
+
+impl GlobalLLFree {
+    /// Creates an allocator for `frames` base frames, which is shared by `cores` cores.
+    pub const fn new(cores: usize, frames: usize) -> Self {
+        Self {
+            cores,
+            frames,
+            alloc: OnceLock::new(),
+        }
+    }
+
+    /// Returns the inner allocator, initializing it if necessary
+    pub fn inner(&self) -> &Inner {
+        self.alloc.get_or_init(|| {
+            // Over-map to align the zone to the largest order
+            let zone = mmap::anon::<Frame>(0, self.frames + (1 << MAX_ORDER), false, false);
+            let zone = Box::leak(zone);
+            let start = zone.as_ptr() as usize / Frame::SIZE;
+            let offset = align_up(start, 1 << MAX_ORDER);
+
+            let m = LLFree::metadata_size(self.cores, self.frames);
+            let meta = MetaData {
+                local: Box::leak(mmap::anon(0, m.local, false, false)),
+                trees: Box::leak(mmap::anon(0, m.trees, false, false)),
+                lower: Box::leak(mmap::anon(0, m.lower, false, false)),
+            };
+            match ZoneAlloc::create(self.cores, offset, self.frames, Init::FreeAll, meta) {
+                Ok(alloc) => alloc,
+                // Panicking would allocate
+                Err(_) => std::process::abort(),
+            }
+        })
+    }
+
+    /// Returns the address range of the managed memory
+    pub fn memory(&self) -> Range<usize> {
+        let alloc = self.inner();
+        let start = alloc.offset * Frame::SIZE;
+        start..start + alloc.frames() * Frame::SIZE
+    }
+
+    /// Returns the core of the current thread
+    fn core(&self) -> usize {
+        #[cfg(target_os = "linux")]
+        let core = unsafe { libc::sched_getcpu() }.max(0) as usize;
+        #[cfg(not(target_os = "linux"))]
+        let core = CORE.with(|core| *core);
+        core % self.cores
+    }
+}
+
+/// Cores that are assigned round-robin to new threads
+#[cfg(not(target_os = "linux"))]
+static NEXT_CORE: core::sync::atomic::AtomicUsize = core::sync::atomic::AtomicUsize::new(0);
+
+#[cfg(not(target_os = "linux"))]
+std::thread_local! {
+    static CORE: usize = NEXT_CORE.fetch_add(1, core::sync::atomic::Ordering::Relaxed);
+}
+
+/// Returns the smallest order that holds `layout` and is aligned to it,
+/// or `None` if this exceeds [MAX_ORDER]
+pub fn order(layout: Layout) -> Option<usize> {
+    // Frames are aligned to their size
+    let size = layout.size().max(layout.align());
+    let order = Bytes(size).frames_ceil().order_ceil().ok()?;
+    Some(order.0)
+}
+
+unsafe impl GlobalAlloc for GlobalLLFree {
+    unsafe fn alloc(&self, layout: Layout) -> *mut u8 {
+        let Some(order) = order(layout) else {
+            return null_mut();
+        };
+        match self.inner().get(self.core(), Flags::o(order)) {
+            Ok(frame) => (frame * Frame::SIZE) as *mut u8,
+            Err(_) => null_mut(),
+        }
+    }
+
+    unsafe fn dealloc(&self, ptr: *mut u8, layout: Layout) {
+        let Some(order) = order(layout) else {
+            return;
+        };
+        let frame = ptr as usize / Frame::SIZE;
+        let core = self.core();
+        if self.inner().put(core, frame, Flags::o(order)).is_err() {
+            // Panicking would allocate
+            std::process::abort();
+        }
+    }
+}
+
+#[cfg(all(test, feature = "std"))]
+mod test {
+    use core::alloc::{GlobalAlloc, Layout};
+
+    use super::{order, GlobalLLFree};
+    use crate::frame::Frame;
+    use crate::{Alloc, MAX_ORDER};
+
+    #[test]
+    fn orders() {
+        let layout = |size, align| Layout::from_size_align(size, align).unwrap();
+        assert_eq!(order(layout(0, 1)), Some(0));
+        assert_eq!(order(layout(8, 8)), Some(0));
+        assert_eq!(order(layout(Frame::SIZE, 8)), Some(0));
+        assert_eq!(order(layout(Frame::SIZE + 1, 8)), Some(1));
+        assert_eq!(order(layout(3 * Frame::SIZE, 8)), Some(2));
+        // Over-allocate for larger alignments
+        assert_eq!(order(layout(8, 4 * Frame::SIZE)), Some(2));
+        assert_eq!(order(layout(Frame::SIZE << MAX_ORDER, 8)), Some(MAX_ORDER));
+        assert_eq!(order(layout((Frame::SIZE << MAX_ORDER) + 1, 8)), None);
+        assert_eq!(order(layout(8, Frame::SIZE << (MAX_ORDER + 1))), None);
+    }
+
+    #[test]
+    fn alloc_dealloc() {
+        let global = GlobalLLFree::new(2, 1 << 12);
+        let memory = global.memory();
+        assert_eq!(memory.start % (Frame::SIZE << MAX_ORDER), 0);
+
+        let layout = Layout::from_size_align(100, 4 * Frame::SIZE).unwrap();
+        let ptr = unsafe { global.alloc(layout) };
+        assert!(memory.contains(&(ptr as usize)));
+        assert!(ptr as usize % layout.align() == 0);
+        assert_eq!(global.inner().allocated_frames(), 4);
+
+        let too_large = Layout::from_size_align(Frame::SIZE << (MAX_ORDER + 1), 8).unwrap();
+        assert!(unsafe { global.alloc(too_large) }.is_null());
+
+        unsafe { global.dealloc(ptr, layout) };
+        assert_eq!(global.inner().allocated_frames(), 0);
+    }
+}
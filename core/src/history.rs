@@ -0,0 +1,200 @@
+//! Fragmentation history of an allocator.
+//!
+//! The caller decides the sampling cadence by calling [History::sample],
+//! there is no background thread.
+//! The samples are kept in a ring buffer, evicting the oldest ones.
+
+use std::collections::VecDeque;
+use std::io;
+use std::time::{Duration, Instant};
+
+use crate::{Alloc, LLFree, MAX_ORDER};
+
+/// Free memory of an allocator at one point in time
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Sample {
+    /// Time since the creation of the [History]
+    pub time: Duration,
+    /// Number of free frames
+    pub free_frames: usize,
+    /// Number of entirely free huge frames
+    pub free_huge: usize,
+    /// Number of trees that are neither entirely free nor entirely allocated
+    pub partial_trees: usize,
+    /// Number of maximal free aligned blocks of each order, see [LLFree::free_orders]
+    pub orders: [usize; MAX_ORDER + 1],
+}
+
+/// Ring buffer of [Sample]s
+#[derive(Debug)]
+pub struct History {
+    start: Instant,
+    capacity: usize,
+    samples: VecDeque<Sample>,
+}
+
+impl Default for History {
+    fn default() -> Self {
+        Self::new(Self::DEFAULT_CAPACITY)
+    }
+}
+
+impl History {
+    /// Default number of samples that are kept
+    pub const DEFAULT_CAPACITY: usize = 1024;
+
+    /// Creates a history that keeps the last `capacity` samples
+    pub fn new(capacity: usize) -> Self {
+        assert!(capacity > 0, "empty history");
+        Self {
+            start: Instant::now(),
+            capacity,
+            samples: VecDeque::with_capacity(capacity),
+        }
+    }
+
+    /// Records the current state of `alloc`, evicting the oldest sample if full.
+    ///
+    /// This walks the metadata once, see [LLFree::free_orders].
+    pub fn sample(&mut self, alloc: &LLFree) -> &Sample {
+        if self.samples.len() == self.capacity {
+            self.samples.pop_front();
+        }
+        self.samples.push_back(Sample {
+            time: self.start.elapsed(),
+            free_frames: alloc.free_frames(),
+            free_huge: alloc.free_huge(),
+            partial_trees: alloc.partial_trees(),
+            orders: alloc.free_orders(),
+        });
+        self.samples.back().unwrap()
+    }
+
+    /// Returns the samples from oldest to newest, split in two slices as stored in the ring buffer
+    pub fn as_slices(&self) -> (&[Sample], &[Sample]) {
+        self.samples.as_slices()
+    }
+    /// Iterates over the samples from oldest to newest
+    pub fn iter(&self) -> impl Iterator<Item = &Sample> {
+        self.samples.iter()
+    }
+    /// Number of recorded samples
+    pub fn len(&self) -> usize {
+        self.samples.len()
+    }
+    pub fn is_empty(&self) -> bool {
+        self.samples.is_empty()
+    }
+    /// Maximum number of samples
+    pub fn capacity(&self) -> usize {
+        self.capacity
+    }
+    /// Removes all samples
+    pub fn clear(&mut self) {
+        self.samples.clear();
+    }
+
+    /// Writes the samples as CSV, with a header and one line per sample
+    pub fn write_csv(&self, out: &mut impl io::Write) -> io::Result<()> {
+        write!(out, "time,free_frames,free_huge,partial_trees")?;
+        for order in 0..=MAX_ORDER {
+            write!(out, ",o{order}")?;
+        }
+        writeln!(out)?;
+        for sample in &self.samples {
+            write!(
+                out,
+                "{:.6},{},{},{}",
+                sample.time.as_secs_f64(),
+                sample.free_frames,
+                sample.free_huge,
+                sample.partial_trees
+            )?;
+            for count in sample.orders {
+                write!(out, ",{count}")?;
+            }
+            writeln!(out)?;
+        }
+        Ok(())
+    }
+}
+
+#[cfg(all(test, feature = "std"))]
+mod test {
+    use std::string::String;
+    use std::vec::Vec;
+
+    use super::History;
+    use crate::test::TestAlloc;
+    use crate::{Alloc, Flags, Init, LLFree, HUGE_FRAMES, HUGE_ORDER, MAX_ORDER, TREE_FRAMES};
+
+    #[test]
+    fn evict() {
+        let alloc = TestAlloc::<LLFree>::create(1, TREE_FRAMES, Init::FreeAll).unwrap();
+        let mut history = History::new(4);
+        for i in 0..6 {
+            history.sample(&alloc);
+            assert_eq!(history.len(), (i + 1).min(4));
+        }
+        let (a, b) = history.as_slices();
+        assert_eq!(a.len() + b.len(), 4);
+        // Oldest first
+        let times: Vec<_> = history.iter().map(|s| s.time).collect();
+        assert!(times.is_sorted());
+
+        let mut csv = Vec::new();
+        history.write_csv(&mut csv).unwrap();
+        let csv = String::from_utf8(csv).unwrap();
+        assert_eq!(csv.lines().count(), 1 + 4);
+        assert!(csv.starts_with("time,free_frames,free_huge,partial_trees,o0,"));
+        assert!(csv
+            .lines()
+            .skip(1)
+            .all(|l| l.split(',').count() == 4 + MAX_ORDER + 1));
+    }
+
+    #[test]
+    fn trend() {
+        const FRAMES: usize = 2 * TREE_FRAMES;
+        let alloc = TestAlloc::<LLFree>::create(1, FRAMES, Init::FreeAll).unwrap();
+        let mut history = History::default();
+
+        let sample = history.sample(&alloc).clone();
+        assert_eq!(sample.free_frames, FRAMES);
+        assert_eq!(sample.free_huge, FRAMES / HUGE_FRAMES);
+        assert_eq!(sample.partial_trees, 0);
+        let mut orders = [0; MAX_ORDER + 1];
+        orders[MAX_ORDER] = FRAMES >> MAX_ORDER;
+        assert_eq!(sample.orders, orders);
+
+        // Fragment a huge frame
+        let small: Vec<_> = (0..3).map(|_| alloc.get(0, Flags::o(0)).unwrap()).collect();
+        let sample = history.sample(&alloc).clone();
+        assert_eq!(sample.free_frames, FRAMES - 3);
+        assert_eq!(sample.free_huge, FRAMES / HUGE_FRAMES - 1);
+        assert_eq!(sample.partial_trees, 1);
+        assert_eq!(sample.orders[MAX_ORDER], FRAMES / (2 * HUGE_FRAMES) - 1);
+        assert_eq!(sample.orders[HUGE_ORDER], 1);
+        // 3 of 512 allocated: one free frame and one block of each order 2 to 8
+        assert_eq!(sample.orders[0], 1);
+        assert_eq!(sample.orders[1], 0);
+        assert!(sample.orders[2..HUGE_ORDER].iter().all(|c| *c == 1));
+
+        // Allocate a huge frame
+        let huge = alloc.get(0, Flags::o(HUGE_ORDER)).unwrap();
+        let sample = history.sample(&alloc).clone();
+        assert_eq!(sample.free_huge, FRAMES / HUGE_FRAMES - 2);
+
+        // Free everything again
+        for frame in small {
+            alloc.put(0, frame, Flags::o(0)).unwrap();
+        }
+        alloc.put(0, huge, Flags::o(HUGE_ORDER)).unwrap();
+        let sample = history.sample(&alloc).clone();
+        assert_eq!(sample.free_frames, FRAMES);
+        assert_eq!(sample.orders, orders);
+
+        let free: Vec<_> = history.iter().map(|s| s.free_frames).collect();
+        assert_eq!(free, [FRAMES, FRAMES - 3, FRAMES - 3 - HUGE_FRAMES, FRAMES]);
+    }
+}
@@ -0,0 +1,168 @@
+//! Raw access to the allocator metadata, e.g., for out-of-tree fuzzers.
+//!
+//! This interface is explicitly unstable, but versioned:
+//! Any change of the metadata layout or of this module increments [VERSION].
+//!
+//! All accesses are atomic loads and stores.
+//! Writing metadata is only sound while the allocator is quiesced,
+//! meaning no other thread uses it concurrently.
+//! Afterwards, [LLFree::check] reports inconsistencies and a deep recovery
+//! ([crate::Init::Recover]) rebuilds a consistent state.
+//!
+//! ## Tree entries
+//! The tree entries (one per [TREE_FRAMES] frames) are returned as plain `u64`,
+//! with the fields at the `TREE_*_OFFSET` bits:
+//! ```text
+//! [ free: 13 | huge: 4 | reserved: 1 | kind: 2 | inflated: 1 | generation: 4 | 0.. ]
+//! ```
+//!
+//! ## Tables
+//! Every tree has a table of [HP] entries, one per huge frame:
+//! ```text
+//! [ count: 15 | split: 1 ]
+//! ```
+//! The count is the number of free frames or [ENTRY_HUGE] for an allocated huge frame.
+//!
+//! ## Bitfields
+//! Every huge frame has a bitfield of [BITFIELD_WORDS] 64-bit words,
+//! with a set bit for each allocated frame.
+//! The words are followed by a summary word with a set bit for each full word,
+//! which is not exposed, but updated when a word is overwritten.
+
+use crate::lower::Lower;
+pub use crate::lower::{ENTRY_COUNT_BITS, ENTRY_COUNT_OFFSET, ENTRY_HUGE, ENTRY_SPLIT_OFFSET};
+use crate::trees::Tree;
+pub use crate::trees::{
+    TREE_FREE_BITS, TREE_FREE_OFFSET, TREE_GENERATION_BITS, TREE_GENERATION_OFFSET, TREE_HUGE_BITS,
+    TREE_HUGE_OFFSET, TREE_INFLATED_OFFSET, TREE_KIND_BITS, TREE_KIND_OFFSET, TREE_RESERVED_OFFSET,
+};
+use crate::{LLFree, TREE_FRAMES, TREE_HUGE};
+
+/// Version of this interface and the metadata layout
+pub const VERSION: u32 = 3;
+
+/// Number of huge frames per tree, which is the number of entries per table
+pub const HP: usize = TREE_HUGE;
+/// Number of frames per bitfield
+pub const BITFIELD_LEN: usize = <Lower<'static>>::HUGE_FRAMES;
+/// Number of 64-bit words per bitfield
+pub const BITFIELD_WORDS: usize = BITFIELD_LEN / u64::BITS as usize;
+
+/// Raw view on the metadata of an allocator.
+pub struct Introspect<'b, 'a> {
+    alloc: &'b LLFree<'a>,
+}
+
+impl<'b, 'a> Introspect<'b, 'a> {
+    pub fn new(alloc: &'b LLFree<'a>) -> Self {
+        Self { alloc }
+    }
+
+    /// Number of tree entries, which is also the number of tables
+    pub fn trees(&self) -> usize {
+        self.alloc.trees.len()
+    }
+    /// Raw tree entry `i`
+    pub fn tree(&self, i: usize) -> u64 {
+        u32::from(self.alloc.trees.entries[i].load()) as u64
+    }
+    /// Overwrite the tree entry `i`, only the lower 32 bits are used
+    pub fn set_tree(&self, i: usize, raw: u64) {
+        self.alloc.trees.store(i, Tree::from(raw as u32))
+    }
+
+    /// Raw entry `j` of the table of tree `i`
+    pub fn table_entry(&self, i: usize, j: usize) -> u64 {
+        self.alloc.lower.table_entry(i, j) as u64
+    }
+    /// Overwrite the entry `j` of the table of tree `i`, only the lower 16 bits are used
+    pub fn set_table_entry(&self, i: usize, j: usize, raw: u64) {
+        self.alloc.lower.set_table_entry(i, j, raw as u16)
+    }
+
+    /// Number of bitfields
+    pub fn bitfields(&self) -> usize {
+        self.alloc.lower.bitfields()
+    }
+    /// Raw word `w` of the bitfield `i`
+    pub fn bitfield_word(&self, i: usize, w: usize) -> u64 {
+        self.alloc.lower.bitfield_word(i, w)
+    }
+    /// Overwrite the word `w` of the bitfield `i`
+    pub fn set_bitfield_word(&self, i: usize, w: usize, raw: u64) {
+        self.alloc.lower.set_bitfield_word(i, w, raw)
+    }
+}
+
+const _: () = assert!(TREE_FRAMES == HP * BITFIELD_LEN);
+
+#[cfg(all(test, feature = "std"))]
+mod test {
+    use std::vec::Vec;
+
+    use super::*;
+    use crate::util::WyRand;
+    use crate::{Alloc, Error, Flags, Init, MetaData};
+
+    /// Flips random metadata bits of a quiesced allocator,
+    /// which has to be detected by the check and fixed by a deep recovery.
+    #[test]
+    fn fuzz_metadata() {
+        const FRAMES: usize = 8 * TREE_FRAMES;
+        const CORES: usize = 2;
+
+        let mut rng = WyRand::new(42);
+        for round in 0..256 {
+            let meta = MetaData::alloc(LLFree::metadata_size(CORES, FRAMES));
+            let mut alloc = LLFree::new(CORES, FRAMES, Init::FreeAll, meta).unwrap();
+            let mut frames = Vec::new();
+            for i in 0..rng.range(0..2048) {
+                let order = [0, 0, 0, 3, 9][rng.range(0..5) as usize];
+                match alloc.get(i as usize % CORES, Flags::o(order)) {
+                    Ok(frame) => frames.push((frame, order)),
+                    Err(Error::Memory | Error::Fragmented) => break,
+                    Err(e) => panic!("{e:?}"),
+                }
+            }
+            for _ in 0..frames.len() / 2 {
+                let (frame, order) = frames.swap_remove(rng.range(0..frames.len() as _) as _);
+                alloc.put(0, frame, Flags::o(order)).unwrap();
+            }
+            alloc.check().unwrap();
+
+            let raw = Introspect::new(&alloc);
+            // Tree kinds and split markers are only hints, so not every flip is detectable
+            let detectable = match rng.range(0..3) {
+                0 => {
+                    let i = rng.range(0..raw.trees() as _) as usize;
+                    let bit = rng.range(0..32);
+                    raw.set_tree(i, raw.tree(i) ^ (1 << bit));
+                    false
+                }
+                1 => {
+                    let i = rng.range(0..raw.trees() as _) as usize;
+                    let j = rng.range(0..HP as _) as usize;
+                    let bit = rng.range(0..16);
+                    raw.set_table_entry(i, j, raw.table_entry(i, j) ^ (1 << bit));
+                    bit != ENTRY_SPLIT_OFFSET as u64
+                }
+                _ => {
+                    let i = rng.range(0..raw.bitfields() as _) as usize;
+                    let w = rng.range(0..BITFIELD_WORDS as _) as usize;
+                    let bit = rng.range(0..64);
+                    raw.set_bitfield_word(i, w, raw.bitfield_word(i, w) ^ (1 << bit));
+                    true
+                }
+            };
+            // Reports, but must not panic
+            match alloc.check() {
+                Ok(()) => assert!(!detectable, "undetected {round}"),
+                Err(e) => assert_eq!(e, Error::Corruption, "{round}"),
+            }
+
+            let alloc = LLFree::new(CORES, FRAMES, Init::Recover(true), alloc.metadata()).unwrap();
+            alloc.check().unwrap();
+            alloc.validate();
+        }
+    }
+}
@@ -0,0 +1,209 @@
+//! Latency and retry histograms of the allocations of [crate::LLFree], for benchmarking.
+//!
+//! The latencies are measured with [std::time::Instant] or, without `std`,
+//! with the clock given to [Latency::set_clock].
+
+use core::sync::atomic::Ordering::Relaxed;
+use core::sync::atomic::{AtomicU64, AtomicUsize};
+
+use crate::MAX_ORDER;
+
+/// Number of latency buckets, bucket `i` counts latencies in `2^(i-1)..2^i` ns
+/// (the first one only 0 ns), and the last one all the larger ones
+pub const BUCKETS: usize = 21;
+/// Number of retry buckets, bucket `i` counts allocations with `i` retries,
+/// and the last one all with more
+pub const RETRY_BUCKETS: usize = 8;
+/// Number of order classes, see [order_class]
+pub const ORDER_CLASSES: usize = 4;
+/// The orders of the classes
+pub const CLASS_ORDERS: [core::ops::RangeInclusive<usize>; ORDER_CLASSES] =
+    [0..=0, 1..=6, 7..=9, 10..=MAX_ORDER];
+
+/// Histograms of the allocations of an allocator, per order class
+pub struct Latency {
+    /// Returns the current time in ns
+    clock: Option<fn() -> u64>,
+    latency: [[AtomicUsize; BUCKETS]; ORDER_CLASSES],
+    latency_sum: [AtomicU64; ORDER_CLASSES],
+    retries: [[AtomicUsize; RETRY_BUCKETS]; ORDER_CLASSES],
+    retries_sum: [AtomicU64; ORDER_CLASSES],
+}
+
+impl Default for Latency {
+    fn default() -> Self {
+        #[cfg(feature = "std")]
+        let clock = Some(std_clock as fn() -> u64);
+        #[cfg(not(feature = "std"))]
+        let clock = None;
+        Self {
+            clock,
+            latency: [const { [const { AtomicUsize::new(0) }; BUCKETS] }; ORDER_CLASSES],
+            latency_sum: [const { AtomicU64::new(0) }; ORDER_CLASSES],
+            retries: [const { [const { AtomicUsize::new(0) }; RETRY_BUCKETS] }; ORDER_CLASSES],
+            retries_sum: [const { AtomicU64::new(0) }; ORDER_CLASSES],
+        }
+    }
+}
+
+/// Nanoseconds since the first call
+#[cfg(feature = "std")]
+fn std_clock() -> u64 {
+    static EPOCH: std::sync::OnceLock<std::time::Instant> = std::sync::OnceLock::new();
+    let epoch = EPOCH.get_or_init(std::time::Instant::now);
+    epoch.elapsed().as_nanos() as u64
+}
+
+impl Latency {
+    /// Sets the clock returning nanoseconds, which replaces [std::time::Instant].
+    ///
+    /// This is required without `std`, otherwise only the retries are recorded.
+    pub fn set_clock(&mut self, now_ns: fn() -> u64) {
+        self.clock = Some(now_ns);
+    }
+
+    /// Returns the current time in ns, if a clock is available
+    #[inline]
+    pub(crate) fn now(&self) -> Option<u64> {
+        self.clock.map(|clock| clock())
+    }
+
+    /// Records a successful allocation of `order` that started at `start` and needed `retries`
+    #[inline]
+    pub(crate) fn record(&self, order: usize, start: Option<u64>, retries: usize) {
+        let class = order_class(order);
+        self.retries[class][retries.min(RETRY_BUCKETS - 1)].fetch_add(1, Relaxed);
+        self.retries_sum[class].fetch_add(retries as _, Relaxed);
+        if let (Some(start), Some(end)) = (start, self.now()) {
+            let ns = end.saturating_sub(start);
+            self.latency[class][bucket(ns)].fetch_add(1, Relaxed);
+            self.latency_sum[class].fetch_add(ns, Relaxed);
+        }
+    }
+
+    /// Loads the histograms, which is racy with concurrent allocations
+    pub fn load(&self) -> Histograms {
+        Histograms {
+            latency: (self.latency.each_ref()).map(|c| c.each_ref().map(|b| b.load(Relaxed))),
+            latency_sum: self.latency_sum.each_ref().map(|s| s.load(Relaxed)),
+            retries: (self.retries.each_ref()).map(|c| c.each_ref().map(|b| b.load(Relaxed))),
+            retries_sum: self.retries_sum.each_ref().map(|s| s.load(Relaxed)),
+        }
+    }
+
+    /// Resets the histograms, e.g., between benchmark runs
+    pub fn reset(&self) {
+        for class in 0..ORDER_CLASSES {
+            self.latency[class].iter().for_each(|b| b.store(0, Relaxed));
+            self.latency_sum[class].store(0, Relaxed);
+            self.retries[class].iter().for_each(|b| b.store(0, Relaxed));
+            self.retries_sum[class].store(0, Relaxed);
+        }
+    }
+}
+
+/// Returns the class of `order`: 0, 1-6, 7-9, and 10+
+pub fn order_class(order: usize) -> usize {
+    match order {
+        0 => 0,
+        1..=6 => 1,
+        7..=9 => 2,
+        _ => 3,
+    }
+}
+
+/// Returns the latency bucket for `ns` nanoseconds
+pub fn bucket(ns: u64) -> usize {
+    (u64::BITS - ns.leading_zeros()).min(BUCKETS as u32 - 1) as usize
+}
+
+/// Snapshot of the histograms, per order class
+#[derive(Debug, Default, Clone, PartialEq, Eq)]
+pub struct Histograms {
+    /// Number of allocations per latency bucket
+    pub latency: [[usize; BUCKETS]; ORDER_CLASSES],
+    /// Sum of the latencies in ns
+    pub latency_sum: [u64; ORDER_CLASSES],
+    /// Number of allocations per number of retries
+    pub retries: [[usize; RETRY_BUCKETS]; ORDER_CLASSES],
+    /// Sum of the retries
+    pub retries_sum: [u64; ORDER_CLASSES],
+}
+
+impl Histograms {
+    /// Number of recorded allocations of the order `class`
+    pub fn count(&self, class: usize) -> usize {
+        self.retries[class].iter().sum()
+    }
+
+    /// Writes the histograms as CSV, with a header and one line per order class
+    #[cfg(feature = "std")]
+    pub fn write_csv(&self, out: &mut impl std::io::Write) -> std::io::Result<()> {
+        write!(out, "orders,latency_sum,retries_sum")?;
+        for i in 0..BUCKETS {
+            write!(out, ",l{i}")?;
+        }
+        for i in 0..RETRY_BUCKETS {
+            write!(out, ",r{i}")?;
+        }
+        writeln!(out)?;
+        for (class, orders) in CLASS_ORDERS.iter().enumerate() {
+            write!(
+                out,
+                "{}-{},{},{}",
+                orders.start(),
+                orders.end(),
+                self.latency_sum[class],
+                self.retries_sum[class]
+            )?;
+            for count in self.latency[class].iter().chain(&self.retries[class]) {
+                write!(out, ",{count}")?;
+            }
+            writeln!(out)?;
+        }
+        Ok(())
+    }
+}
+
+#[cfg(all(test, feature = "std"))]
+mod test {
+    #[test]
+    fn buckets() {
+        use super::{bucket, order_class, BUCKETS, CLASS_ORDERS};
+
+        assert_eq!(bucket(0), 0);
+        assert_eq!(bucket(1), 1);
+        assert_eq!(bucket(2), 2);
+        assert_eq!(bucket(3), 2);
+        assert_eq!(bucket(4), 3);
+        assert_eq!(bucket((1 << 19) - 1), 19);
+        assert_eq!(bucket(1 << 19), 20);
+        assert_eq!(bucket(u64::MAX), BUCKETS - 1);
+
+        for (class, orders) in CLASS_ORDERS.into_iter().enumerate() {
+            assert!(orders.into_iter().all(|o| order_class(o) == class));
+        }
+    }
+
+    #[test]
+    fn csv() {
+        use std::string::String;
+        use std::vec::Vec;
+
+        use super::{Histograms, BUCKETS, ORDER_CLASSES, RETRY_BUCKETS};
+
+        let mut histograms = Histograms::default();
+        histograms.latency[1][3] = 2;
+        histograms.retries[1][0] = 2;
+
+        let mut csv = Vec::new();
+        histograms.write_csv(&mut csv).unwrap();
+        let csv = String::from_utf8(csv).unwrap();
+        assert_eq!(csv.lines().count(), 1 + ORDER_CLASSES);
+        assert!(csv.starts_with("orders,latency_sum,retries_sum,l0,"));
+        let columns = 3 + BUCKETS + RETRY_BUCKETS;
+        assert!(csv.lines().all(|l| l.split(',').count() == columns));
+        let line = csv.lines().nth(2).unwrap();
+        assert!(line.starts_with("1-6,0,0,0,0,0,2,"));
+    }
+}
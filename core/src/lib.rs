@@ -10,7 +10,6 @@
 #![feature(allocator_api)]
 #![feature(c_size_t)]
 #![feature(let_chains)]
-#![feature(pointer_is_aligned_to)]
 // Don't warn for compile-time checks
 #![allow(clippy::assertions_on_constants)]
 #![allow(clippy::redundant_pattern_matching)]
@@ -19,7 +18,13 @@
 #[cfg(feature = "std")]
 #[macro_use]
 extern crate std;
+#[cfg(feature = "alloc")]
+extern crate alloc;
 
+#[cfg(feature = "std")]
+pub mod global;
+#[cfg(feature = "std")]
+pub mod history;
 #[cfg(feature = "std")]
 pub mod mmap;
 #[cfg(feature = "std")]
@@ -29,11 +34,26 @@ pub mod atomic;
 pub mod frame;
 pub mod util;
 pub mod wrapper;
+#[cfg(feature = "alloc")]
+pub mod zones;
+
+#[cfg(feature = "introspect")]
+pub mod introspect;
+#[cfg(feature = "latency")]
+pub mod latency;
+#[cfg(feature = "metrics")]
+pub mod metrics;
+#[cfg(feature = "slab")]
+pub mod slab;
 
 mod bitfield;
+#[cfg(not(feature = "minimal"))]
 mod llfree;
 use bitfield_struct::bitfield;
+#[cfg(not(feature = "minimal"))]
 pub use llfree::LLFree;
+mod single;
+pub use single::Single;
 
 #[cfg(feature = "llc")]
 mod llc;
@@ -41,16 +61,33 @@ mod llc;
 pub use llc::LLC;
 use util::Align;
 
+#[cfg(not(feature = "minimal"))]
 mod local;
 mod lower;
+#[cfg(not(feature = "minimal"))]
 mod trees;
 
+// The minimal profile only contains the lower allocator and [Single]
+#[cfg(all(
+    feature = "minimal",
+    any(feature = "std", feature = "llc", feature = "introspect")
+))]
+compile_error!("the minimal feature excludes std, llc, and introspect");
+
 use core::fmt;
 use core::mem::align_of;
 use core::ops::Range;
 
-/// Order of a physical frame
-pub const FRAME_SIZE: usize = 0x1000;
+#[cfg(feature = "counters")]
+pub use lower::EventCounts;
+#[cfg(feature = "std")]
+pub use lower::{ChunkState, HugeState, HugeStateKind, MemoryMap};
+pub use lower::{FixedCounter, RecoverMode, RecoveryReport};
+
+/// Number of address bits within a physical frame
+pub const FRAME_SIZE_BITS: usize = 12;
+/// Size of a physical frame
+pub const FRAME_SIZE: usize = 1 << FRAME_SIZE_BITS;
 /// Order of a huge frame
 pub const HUGE_ORDER: usize = 9;
 /// Number of frames in a huge frame
@@ -66,8 +103,12 @@ pub const TREE_FRAMES: usize = TREE_HUGE << HUGE_ORDER;
 /// Number of retries if an atomic operation fails.
 pub const RETRIES: usize = 4;
 
+// Frame numbers and the local tree copies need 64-bit words
+const _: () = assert!(usize::BITS == 64, "LLFree only supports 64-bit targets");
+
 /// Allocation error
 #[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[repr(u8)]
 pub enum Error {
     /// Not enough memory
     Memory = 1,
@@ -77,11 +118,30 @@ pub enum Error {
     Address = 3,
     /// Allocator not initialized or initialization failed
     Initialization = 4,
+    /// The order is larger than supported by the allocator
+    InvalidOrder { requested: usize, max: usize } = 5,
+    /// Inconsistent metadata, e.g., frames modified by a concurrent operation that did not own them
+    Corruption = 6,
+    /// Enough frames are free, but not a contiguous block of the requested order.
+    ///
+    /// In contrast to [Error::Memory], compaction or smaller orders might help instead of reclaim.
+    Fragmented = 7,
+    /// Some of the requested frames are already allocated
+    Busy = 8,
 }
 
 /// Allocation result
 pub type Result<T> = core::result::Result<T, Error>;
 
+/// Result of a successful free
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct PutResult {
+    /// Order of the freed frame
+    pub freed_order: usize,
+    /// Largest order for which the aligned block containing the frame is now completely free
+    pub now_free_up_to: usize,
+}
+
 /// The general interface of the allocator implementations.
 pub trait Alloc<'a>: Sized + Sync + Send + fmt::Debug {
     /// Return the name of the allocator.
@@ -102,10 +162,69 @@ pub trait Alloc<'a>: Sized + Sync + Send + fmt::Debug {
     fn metadata(&mut self) -> MetaData<'a>;
 
     /// Allocate a new frame of `order` on the given `core`.
+    ///
+    /// The `core` only selects the core-local data (modulo the number of cores).
+    /// Using the same `core` from multiple threads is safe, but leads to contention.
     fn get(&self, core: usize, flags: Flags) -> Result<usize>;
     /// Free the `frame` of `order` on the given `core`..
     fn put(&self, core: usize, frame: usize, flags: Flags) -> Result<()>;
+    /// Free the `frame` of `order` on the given `core`, reporting the largest
+    /// free block around it.
+    fn put_report(&self, core: usize, frame: usize, flags: Flags) -> Result<PutResult> {
+        self.put(core, frame, flags)?;
+        Ok(PutResult {
+            freed_order: flags.order(),
+            now_free_up_to: flags.order(),
+        })
+    }
+    /// Resize the `frame` of `order` in place to `new_order`, e.g., for
+    /// [core::alloc::Allocator::grow] and [core::alloc::Allocator::shrink].
+    ///
+    /// Growing allocates the frames following `frame`, which has to be aligned to `new_order`.
+    /// If any of them is allocated, this fails with [Error::Memory] without modifying anything.
+    /// Shrinking frees the tail of the frame.
+    /// By default, only shrinking is supported.
+    fn try_resize(
+        &self,
+        core: usize,
+        frame: usize,
+        order: usize,
+        new_order: usize,
+    ) -> Result<usize> {
+        if new_order > order {
+            check_resize(self, frame, order, new_order)?;
+            return Err(Error::Memory);
+        }
+        shrink(self, core, frame, order, new_order)
+    }
+
+    /// Allocate a huge frame ([HUGE_ORDER]) on the given `core`.
+    fn get_huge(&self, core: usize) -> Result<usize> {
+        self.get(core, Flags::o(HUGE_ORDER))
+    }
+    /// Free the huge frame ([HUGE_ORDER]) on the given `core`.
+    fn put_huge(&self, core: usize, frame: usize) -> Result<()> {
+        self.put(core, frame, Flags::o(HUGE_ORDER))
+    }
+    /// Allocate a frame of the largest order ([Alloc::max_order]) on the given `core`.
+    fn get_max(&self, core: usize) -> Result<usize> {
+        self.get(core, Flags::o(self.max_order()))
+    }
+    /// Free the frame of the largest order ([Alloc::max_order]) on the given `core`.
+    fn put_max(&self, core: usize, frame: usize) -> Result<()> {
+        self.put(core, frame, Flags::o(self.max_order()))
+    }
 
+    /// Return the largest order that can be allocated.
+    fn max_order(&self) -> usize {
+        MAX_ORDER
+    }
+    /// Return whether the allocator is ready to be used.
+    ///
+    /// Allocators that cannot be in an uninitialized state always return true.
+    fn is_initialized(&self) -> bool {
+        true
+    }
     /// Return the total number of frames the allocator manages.
     fn frames(&self) -> usize;
     /// Return the core count the allocator was initialized with.
@@ -123,20 +242,164 @@ pub trait Alloc<'a>: Sized + Sync + Send + fmt::Debug {
     /// Free frames in the given chunk. Only TREE_ORDER and HUGE_ORDER are supported.
     fn free_at(&self, frame: usize, order: usize) -> usize;
 
+    /// Return the number of free frames below the `boundary` frame.
+    ///
+    /// For tiered memory, these are the free frames of the fast memory.
+    /// The free frames of trees that span the boundary are apportioned proportionally.
+    #[cold]
+    fn free_frames_below(&self, boundary: usize) -> usize {
+        let boundary = boundary.min(self.frames());
+        let mut free = 0;
+        for start in (0..boundary).step_by(TREE_FRAMES) {
+            let len = (self.frames() - start).min(TREE_FRAMES);
+            let below = (boundary - start).min(len);
+            free += self.free_at(start, TREE_FRAMES) * below / len;
+        }
+        free
+    }
+
     /// Return the number of allocated frames.
     fn allocated_frames(&self) -> usize {
         self.frames() - self.free_frames()
     }
+    /// Return the number of allocated frames within the `frames` range.
+    ///
+    /// Entirely covered trees are counted with their free counters,
+    /// partially covered trees at the boundaries frame by frame.
+    /// The result is only exact if there are no concurrent operations.
+    #[cold]
+    fn allocated_frames_in(&self, frames: Range<usize>) -> usize {
+        let end = frames.end.min(self.frames());
+        let mut allocated = 0;
+        let mut start = frames.start;
+        while start < end {
+            let tree = start / TREE_FRAMES * TREE_FRAMES;
+            let tree_end = (tree + TREE_FRAMES).min(self.frames());
+            let covered = tree_end.min(end);
+            if start == tree && covered == tree_end {
+                allocated += (tree_end - tree) - self.free_at(tree, TREE_FRAMES);
+            } else {
+                allocated += (start..covered).filter(|f| !self.is_free(*f, 0)).count();
+            }
+            start = covered;
+        }
+        allocated
+    }
+    /// Count the free aligned blocks of each order, `buf[k]` are the blocks of order `k`
+    /// that [Alloc::get] could return without waiting, e.g., to check whether
+    /// 10 more huge frames can be allocated without allocating them.
+    ///
+    /// Other than [LLFree::free_orders], blocks within larger free blocks are also
+    /// counted, and orders above [Alloc::max_order] have no blocks.
+    /// The result is only exact if there are no concurrent operations.
+    /// By default, every aligned block is checked with [Alloc::is_free].
+    #[cold]
+    fn count_allocatable_spans(&self, buf: &mut [usize]) {
+        for (order, count) in buf.iter_mut().enumerate() {
+            *count = if order <= self.max_order() {
+                (0..self.frames() >> order)
+                    .filter(|i| self.is_free(i << order, order))
+                    .count()
+            } else {
+                0
+            };
+        }
+    }
     /// Unreserve cpu-local frames
     fn drain(&self, _core: usize) -> Result<()> {
         Ok(())
     }
+    /// Unreserve the cpu-local frames of all cores, e.g., before a clean shutdown
+    fn drain_all(&self) -> Result<()> {
+        for core in 0..self.cores() {
+            self.drain(core)?;
+        }
+        Ok(())
+    }
+    /// Latency and retry histograms of the allocations, if they are recorded
+    #[cfg(feature = "latency")]
+    fn histograms(&self) -> Option<latency::Histograms> {
+        None
+    }
 
     /// Validate the internal state
     #[cold]
     fn validate(&self) {}
+
+    /// Serialize the allocator state into an opaque snapshot,
+    /// or `None` if the allocator does not support snapshots.
+    ///
+    /// The allocator should not be used concurrently while taking the snapshot.
+    #[cfg(feature = "std")]
+    #[cold]
+    fn snapshot(&self) -> Option<AllocSnapshot> {
+        None
+    }
+    /// Re-create a live allocator from a [Alloc::snapshot] without rescanning the bitfields.
+    ///
+    /// Fails if the snapshot does not belong to an allocator managing `frames`
+    /// or if the allocator does not support snapshots.
+    #[cfg(feature = "std")]
+    #[cold]
+    fn restore(_snapshot: AllocSnapshot, _frames: usize, _meta: MetaData<'a>) -> Result<Self> {
+        log::error!("{} does not support snapshots", Self::name());
+        Err(Error::Initialization)
+    }
+}
+
+/// Converts the maximal free blocks of each order into the [Alloc::count_allocatable_spans],
+/// a maximal block of order `j` contains `1 << (j - k)` blocks of order `k`.
+fn spans_of_blocks(blocks: &[usize; MAX_ORDER + 1], buf: &mut [usize]) {
+    for (order, count) in buf.iter_mut().enumerate() {
+        *count = (blocks.iter().enumerate().skip(order))
+            .map(|(j, n)| n << (j - order))
+            .sum();
+    }
+}
+
+/// Checks the orders and alignment of a [Alloc::try_resize]
+fn check_resize<'a>(
+    alloc: &impl Alloc<'a>,
+    frame: usize,
+    order: usize,
+    new_order: usize,
+) -> Result<()> {
+    let max = alloc.max_order();
+    if order.max(new_order) > max {
+        log::error!("invalid order");
+        return Err(Error::InvalidOrder {
+            requested: order.max(new_order),
+            max,
+        });
+    }
+    if frame % (1 << order) != 0 {
+        log::error!("unaligned frame");
+        return Err(Error::Address);
+    }
+    Ok(())
+}
+
+/// Shrinks the `frame` of `order` to `new_order` by freeing its tail
+fn shrink<'a>(
+    alloc: &impl Alloc<'a>,
+    core: usize,
+    frame: usize,
+    order: usize,
+    new_order: usize,
+) -> Result<usize> {
+    check_resize(alloc, frame, order, new_order)?;
+    // The tail consists of one frame of every order in between
+    for o in new_order..order {
+        alloc.put(core, frame + (1 << o), Flags::o(o))?;
+    }
+    Ok(frame)
 }
 
+/// Serialized allocator state, see [Alloc::snapshot] and [Alloc::restore].
+#[cfg(feature = "std")]
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct AllocSnapshot(pub std::vec::Vec<u8>);
+
 /// Size of the required metadata
 pub struct MetaSize {
     /// Size of the volatile CPU-local data.
@@ -167,23 +430,24 @@ impl<'a> MetaData<'a> {
 }
 
 impl<'a> MetaData<'a> {
-    /// Check for alignment and overlap
+    /// Check for alignment and overlap, ignoring empty buffers (e.g., of [Single])
     fn valid(&self, m: MetaSize) -> bool {
-        fn overlap(a: Range<*const u8>, b: Range<*const u8>) -> bool {
-            a.contains(&b.start)
-                || a.contains(&unsafe { b.end.sub(1) })
-                || b.contains(&a.start)
-                || b.contains(&unsafe { a.end.sub(1) })
+        fn overlap(a: &[u8], b: &[u8]) -> bool {
+            let (a, b) = (a.as_ptr_range(), b.as_ptr_range());
+            a.start < b.end && b.start < a.end
+        }
+        fn aligned(a: &[u8]) -> bool {
+            a.is_empty() || a.as_ptr() as usize % align_of::<Align>() == 0
         }
         self.local.len() >= m.local
             && self.trees.len() >= m.trees
             && self.lower.len() >= m.lower
-            && self.local.as_ptr().is_aligned_to(align_of::<Align>())
-            && self.trees.as_ptr().is_aligned_to(align_of::<Align>())
-            && self.lower.as_ptr().is_aligned_to(align_of::<Align>())
-            && !overlap(self.local.as_ptr_range(), self.trees.as_ptr_range())
-            && !overlap(self.trees.as_ptr_range(), self.lower.as_ptr_range())
-            && !overlap(self.lower.as_ptr_range(), self.local.as_ptr_range())
+            && aligned(self.local)
+            && aligned(self.trees)
+            && aligned(self.lower)
+            && !overlap(self.local, self.trees)
+            && !overlap(self.trees, self.lower)
+            && !overlap(self.lower, self.local)
     }
 }
 
@@ -204,7 +468,15 @@ pub struct Flags {
     #[bits(8)]
     pub order: usize,
     pub movable: bool,
-    #[bits(55)]
+    /// Fail immediately if the local tree cannot serve the allocation,
+    /// without retries or reserving a new tree (e.g., in interrupt context)
+    pub fast: bool,
+    /// Retry with backoff instead of failing as long as enough frames are free.
+    ///
+    /// **Note:** This still fails if no frames are freed while retrying,
+    /// e.g., if the memory is too fragmented for the order.
+    pub nofail: bool,
+    #[bits(53)]
     __: (),
 }
 impl Flags {
@@ -227,12 +499,16 @@ mod test {
     use super::*;
     use crate::frame::Frame;
     use crate::util::{aligned_buf, logging, WyRand};
-    use crate::wrapper::NvmAlloc;
+    use crate::wrapper::{
+        BlockingAlloc, CompoundAlloc, NvmAlloc, NvmLogAlloc, Persistent, TieredAlloc,
+        WatermarkAlloc, ZoneAlloc,
+    };
 
     #[cfg(feature = "llc")]
-    type Allocator = TestAlloc<LLC>;
+    type Inner = LLC;
     #[cfg(not(feature = "llc"))]
-    type Allocator = TestAlloc<LLFree<'static>>;
+    type Inner = LLFree<'static>;
+    type Allocator = TestAlloc<Inner>;
 
     pub struct TestAlloc<A: Alloc<'static>>(ManuallyDrop<A>);
 
@@ -263,7 +539,7 @@ mod test {
                 drop(ManuallyDrop::take(&mut self.0));
                 // free metadata buffers
                 Vec::from_raw_parts(local.as_mut_ptr(), local.len(), local.len());
-                Vec::from_raw_parts(trees.as_mut_ptr(), trees.len(), local.len());
+                Vec::from_raw_parts(trees.as_mut_ptr(), trees.len(), trees.len());
                 Vec::from_raw_parts(lower.as_mut_ptr(), lower.len(), lower.len());
             }
         }
@@ -280,6 +556,44 @@ mod test {
         }
     }
 
+    /// Tracks the allocated frames of concurrent tests,
+    /// detecting frames that are allocated twice as soon as they are returned.
+    pub struct FrameTracker {
+        frames: usize,
+        allocated: std::sync::Mutex<std::collections::HashSet<usize>>,
+    }
+
+    impl FrameTracker {
+        pub fn new(frames: usize) -> Self {
+            Self {
+                frames,
+                allocated: Default::default(),
+            }
+        }
+        /// Track the newly allocated `frame`, checking that none of its frames is already allocated
+        pub fn insert(&self, frame: usize, order: usize) {
+            assert!(
+                frame + (1 << order) <= self.frames,
+                "out of bounds {frame} o={order}"
+            );
+            let mut allocated = self.allocated.lock().unwrap();
+            for f in frame..frame + (1 << order) {
+                assert!(allocated.insert(f), "allocated twice {f} o={order}");
+            }
+        }
+        /// Untrack the `frame` before it is freed
+        pub fn remove(&self, frame: usize, order: usize) {
+            let mut allocated = self.allocated.lock().unwrap();
+            for f in frame..frame + (1 << order) {
+                assert!(allocated.remove(&f), "not allocated {f} o={order}");
+            }
+        }
+        /// Number of allocated frames
+        pub fn len(&self) -> usize {
+            self.allocated.lock().unwrap().len()
+        }
+    }
+
     #[test]
     fn minimal() {
         logging();
@@ -309,314 +623,1821 @@ mod test {
         alloc.validate();
     }
 
-    #[test]
-    fn simple() {
+    /// Degenerate memory sizes, from no frames to a single partial tree
+    fn boundary_sizes<A: Alloc<'static>>() {
         logging();
-        // 8GiB
-        const MEM_SIZE: usize = 1 << 30;
-        const FRAMES: usize = MEM_SIZE / Frame::SIZE;
-
-        let alloc = Allocator::create(1, FRAMES, Init::FreeAll).unwrap();
 
-        assert_eq!(alloc.free_frames(), alloc.frames());
+        for frames in [
+            0,
+            1,
+            HUGE_FRAMES - 1,
+            HUGE_FRAMES,
+            TREE_FRAMES - 1,
+            TREE_FRAMES,
+            TREE_FRAMES + 1,
+        ] {
+            let meta = MetaData::alloc(A::metadata_size(1, frames));
+            let alloc = A::new(1, frames, Init::FreeAll, meta);
+            if frames == 0 {
+                assert_eq!(alloc.err(), Some(Error::Initialization));
+                continue;
+            }
+            let mut alloc = alloc.unwrap();
+            assert_eq!(alloc.free_frames(), frames);
+            assert_eq!(alloc.free_huge(), frames / HUGE_FRAMES);
 
-        warn!("start alloc...");
-        let small = alloc.get(0, Flags::o(0)).unwrap();
+            let mut allocated = Vec::new();
+            loop {
+                match alloc.get(0, Flags::o(0)) {
+                    Ok(frame) => allocated.push(frame),
+                    Err(Error::Memory) => break,
+                    Err(e) => panic!("{e:?} f={frames}"),
+                }
+            }
+            assert_eq!(allocated.len(), frames);
+            assert!(allocated.iter().all(|f| *f < frames));
+            for frame in allocated.drain(..frames / 2) {
+                alloc.put(0, frame, Flags::o(0)).unwrap();
+            }
+            alloc.validate();
 
-        assert_eq!(alloc.allocated_frames(), 1, "{alloc:?}");
-        warn!("stress test...");
+            // Recover the partially allocated frames
+            let alloc = A::new(1, frames, Init::Recover(true), alloc.metadata()).unwrap();
+            assert_eq!(alloc.allocated_frames(), allocated.len());
+            alloc.validate();
 
-        // Stress test
-        let mut frames = Vec::new();
-        loop {
-            match alloc.get(0, Flags::o(0)) {
-                Ok(frame) => frames.push(frame),
-                Err(Error::Memory) => break,
-                Err(e) => panic!("{e:?}"),
+            for frame in allocated {
+                alloc.put(0, frame, Flags::o(0)).unwrap();
             }
+            assert_eq!(alloc.free_frames(), frames);
+            // The frees might have reserved the tree for small frames
+            alloc.drain(0).unwrap();
+            // Huge frames only if they are entirely covered
+            match alloc.get(0, Flags::o(HUGE_ORDER)) {
+                Ok(frame) => {
+                    assert!(frames >= HUGE_FRAMES && frame + HUGE_FRAMES <= frames);
+                    alloc.put(0, frame, Flags::o(HUGE_ORDER)).unwrap();
+                }
+                Err(e) => assert!(frames < HUGE_FRAMES && e == Error::Memory, "{e:?}"),
+            }
+            alloc.validate();
         }
+    }
 
-        warn!("allocated {}", 1 + frames.len());
-        warn!("check...");
-        alloc.validate();
+    #[test]
+    fn boundary_sizes_llfree() {
+        boundary_sizes::<LLFree<'static>>();
+    }
 
-        assert_eq!(alloc.allocated_frames(), 1 + frames.len());
-        assert_eq!(alloc.allocated_frames(), alloc.frames());
-        frames.sort_unstable();
+    #[test]
+    fn boundary_sizes_single() {
+        boundary_sizes::<Single<'static>>();
+    }
 
-        // Check that the same frame was not allocated twice
-        for &[a, b] in frames.array_windows() {
-            assert_ne!(a, b);
-            assert!(a < FRAMES && b < FRAMES);
-        }
+    #[test]
+    fn huge_and_max() {
+        logging();
 
-        warn!("realloc...");
+        let alloc = Allocator::create(1, 2 * TREE_FRAMES, Init::FreeAll).unwrap();
 
-        // Free some
-        const FREE_NUM: usize = HUGE_FRAMES * HUGE_FRAMES - 10;
-        for frame in &frames[..FREE_NUM] {
-            alloc.put(0, *frame, Flags::o(0)).unwrap();
-        }
+        let huge = alloc.get_huge(0).unwrap();
+        assert_eq!(huge % HUGE_FRAMES, 0);
+        assert!(!alloc.is_free(huge, HUGE_ORDER));
+        assert_eq!(alloc.free_frames(), alloc.frames() - HUGE_FRAMES);
 
+        let max = alloc.get_max(0).unwrap();
+        assert_eq!(max % (1 << alloc.max_order()), 0);
+        assert!(!alloc.is_free(max, alloc.max_order()));
         assert_eq!(
-            alloc.allocated_frames(),
-            1 + frames.len() - FREE_NUM,
-            "{alloc:?}"
+            alloc.free_frames(),
+            alloc.frames() - HUGE_FRAMES - (1 << alloc.max_order())
         );
-        alloc.validate();
 
-        // Realloc
-        for frame in &mut frames[..FREE_NUM] {
-            *frame = alloc.get(0, Flags::o(0)).unwrap();
-        }
+        // Freeing with the explicit order is equivalent
+        alloc.put(0, huge, Flags::o(HUGE_ORDER)).unwrap();
+        alloc.put_max(0, max).unwrap();
+        let huge = alloc.get(0, Flags::o(HUGE_ORDER)).unwrap();
+        alloc.put_huge(0, huge).unwrap();
+        assert_eq!(alloc.free_frames(), alloc.frames());
+        alloc.validate();
+    }
 
-        warn!("free...");
+    #[test]
+    fn put_report() {
+        logging();
 
-        alloc.put(0, small, Flags::o(0)).unwrap();
-        // Free all
-        for frame in &frames {
-            alloc.put(0, *frame, Flags::o(0)).unwrap();
+        let alloc =
+            TestAlloc::<LLFree<'static>>::create(1, 1 << MAX_ORDER, Init::AllocAll).unwrap();
+
+        let put = |frame, order| {
+            let res = alloc.put_report(0, frame, Flags::o(order)).unwrap();
+            assert_eq!(res.freed_order, order);
+            res.now_free_up_to
+        };
+
+        // Intermediate frees report smaller orders
+        assert_eq!(put(0, 0), 0);
+        assert_eq!(put(1, 0), 1);
+        assert_eq!(put(3, 0), 0);
+        assert_eq!(put(2, 0), 2);
+        for frame in 4..HUGE_FRAMES - 1 {
+            assert!(put(frame, 0) < HUGE_ORDER);
         }
+        // Last frame of the huge frame
+        assert_eq!(put(HUGE_FRAMES - 1, 0), HUGE_ORDER);
+        assert_eq!(alloc.free_huge(), 1);
 
-        assert_eq!(alloc.allocated_frames(), 0);
+        // Completes the pair
+        assert_eq!(put(HUGE_FRAMES, HUGE_ORDER), MAX_ORDER);
+        assert_eq!(alloc.free_frames(), alloc.frames());
         alloc.validate();
     }
 
     #[test]
-    fn rand() {
+    fn try_resize() {
         logging();
-        // 8GiB
-        const MEM_SIZE: usize = 4 << 30;
-        const FRAMES: usize = MEM_SIZE / Frame::SIZE;
 
-        let alloc = Allocator::create(1, FRAMES, Init::FreeAll).unwrap();
+        let alloc =
+            TestAlloc::<LLFree<'static>>::create(1, 1 << MAX_ORDER, Init::AllocAll).unwrap();
+        alloc.put(0, 4, Flags::o(2)).unwrap();
+        alloc.put(0, 8, Flags::o(3)).unwrap();
 
-        warn!("start alloc...");
-        const ALLOCS: usize = MEM_SIZE / Frame::SIZE / 2;
-        let mut frames = Vec::with_capacity(ALLOCS);
-        for _ in 0..ALLOCS {
-            frames.push(alloc.get(0, Flags::o(0)).unwrap());
-        }
-        warn!("allocated {}", frames.len());
+        // Grow in place
+        assert_eq!(alloc.try_resize(0, 0, 2, 4), Ok(0));
+        assert_eq!(alloc.free_frames(), 0);
+        // Fails if a following frame is allocated
+        assert_eq!(alloc.try_resize(0, 0, 4, 5), Err(Error::Memory));
+        assert_eq!(alloc.free_frames(), 0);
 
-        warn!("check...");
-        assert_eq!(alloc.allocated_frames(), frames.len());
+        // Shrinking frees the tail
+        assert_eq!(alloc.try_resize(0, 0, 4, 1), Ok(0));
+        assert_eq!(alloc.free_frames(), (1 << 4) - (1 << 1));
+        assert!(alloc.is_free(2, 1) && alloc.is_free(4, 2) && alloc.is_free(8, 3));
+
+        // Invalid arguments
+        assert_eq!(alloc.try_resize(0, 1, 1, 2), Err(Error::Address));
+        let max = alloc.max_order();
+        assert!(matches!(
+            alloc.try_resize(0, 0, 1, max + 1),
+            Err(Error::InvalidOrder { .. })
+        ));
+        // Huge frames are not grown in place
+        assert_eq!(alloc.try_resize(0, 0, 1, HUGE_ORDER), Err(Error::Memory));
+        assert_eq!(alloc.free_frames(), (1 << 4) - (1 << 1));
         alloc.validate();
+    }
 
-        // Check that the same frame was not allocated twice
-        frames.sort_unstable();
-        for &[a, b] in frames.array_windows() {
-            assert_ne!(a, b);
-            assert!(a < FRAMES && b < FRAMES);
-        }
+    #[test]
+    fn get_at() {
+        logging();
 
-        warn!("reallocate rand...");
-        let mut rng = WyRand::new(100);
-        rng.shuffle(&mut frames);
+        const FRAMES: usize = 4 * TREE_FRAMES;
+        let alloc = TestAlloc::<LLFree<'static>>::create(2, FRAMES, Init::FreeAll).unwrap();
+
+        // Claim free frames of different orders
+        let claimed = [
+            (5, 0),
+            (2 * HUGE_FRAMES, HUGE_ORDER),
+            (4 * HUGE_FRAMES, MAX_ORDER),
+        ];
+        for (frame, order) in claimed {
+            alloc.get_at(0, frame, order).unwrap();
+            assert!(!alloc.is_free(frame, 0));
+        }
+        let allocated = 1 + HUGE_FRAMES + (1 << MAX_ORDER);
+        assert_eq!(alloc.allocated_frames(), allocated);
+        assert_eq!(alloc.free_huge(), FRAMES / HUGE_FRAMES - 4);
+        alloc.validate();
 
-        for _ in 0..frames.len() {
-            let i = rng.range(0..frames.len() as _) as usize;
-            alloc.put(0, frames[i], Flags::o(0)).unwrap();
-            frames[i] = alloc.get(0, Flags::o(0)).unwrap();
+        // Partially allocated blocks are not modified
+        let busy = [
+            (0, 3),
+            (0, HUGE_ORDER),
+            (0, MAX_ORDER),
+            (2 * HUGE_FRAMES + 8, 0),
+            (5, 0),
+        ];
+        for (frame, order) in busy {
+            assert_eq!(
+                alloc.get_at(0, frame, order),
+                Err(Error::Busy),
+                "{frame} o{order}"
+            );
         }
+        assert!(alloc.is_free(0, 2) && alloc.is_free(8, 3));
+        assert_eq!(alloc.allocated_frames(), allocated);
+        assert_eq!(alloc.free_huge(), FRAMES / HUGE_FRAMES - 4);
+        alloc.validate();
 
-        warn!("check...");
-        assert_eq!(alloc.allocated_frames(), frames.len());
+        // Invalid arguments
+        assert_eq!(alloc.get_at(0, 1, 1), Err(Error::Address));
+        assert_eq!(alloc.get_at(0, FRAMES, 0), Err(Error::Address));
+        assert!(matches!(
+            alloc.get_at(0, 0, MAX_ORDER + 1),
+            Err(Error::InvalidOrder { .. })
+        ));
+
+        // Inside a tree that is reserved by another core
+        let other = alloc.get(1, Flags::o(0)).unwrap();
+        let tree = other / TREE_FRAMES * TREE_FRAMES;
+        let frame = (tree..tree + TREE_FRAMES)
+            .find(|f| alloc.is_free(*f, 0))
+            .unwrap();
+        alloc.get_at(0, frame, 0).unwrap();
+        assert_eq!(alloc.allocated_frames(), allocated + 2);
         alloc.validate();
-        // Check that the same frame was not allocated twice
-        frames.sort_unstable();
-        for &[a, b] in frames.array_windows() {
-            assert_ne!(a, b);
-            assert!(a < FRAMES && b < FRAMES);
-        }
 
-        warn!("free...");
-        rng.shuffle(&mut frames);
-        for frame in &frames {
-            alloc.put(0, *frame, Flags::o(0)).unwrap();
+        alloc.put(0, frame, Flags::o(0)).unwrap();
+        alloc.put(1, other, Flags::o(0)).unwrap();
+        for (frame, order) in claimed {
+            alloc.put(0, frame, Flags::o(order)).unwrap();
         }
-        assert_eq!(alloc.allocated_frames(), 0);
-        alloc.validate()
+        assert_eq!(alloc.free_frames(), FRAMES);
+        assert_eq!(alloc.free_huge(), FRAMES / HUGE_FRAMES);
+        alloc.validate();
     }
 
     #[test]
-    fn multirand() {
-        const THREADS: usize = 4;
-        const FRAMES: usize = (8 << 30) / Frame::SIZE;
-        const ALLOCS: usize = ((FRAMES / THREADS) / 4) * 3;
-
+    fn get_at_split_counters() {
         logging();
 
-        let alloc = Allocator::create(THREADS, FRAMES, Init::FreeAll).unwrap();
-
-        let barrier = Barrier::new(THREADS);
-        thread::parallel(0..THREADS, |t| {
-            thread::pin(t);
-
-            barrier.wait();
-            warn!("start alloc...");
-            let mut frames = Vec::with_capacity(ALLOCS);
-            for _ in 0..ALLOCS {
-                frames.push(alloc.get(t, Flags::o(0)).unwrap());
-            }
-            warn!("allocated {}", frames.len());
-
-            warn!("check...");
-            // Check that the same frame was not allocated twice
-            frames.sort_unstable();
-            for &[a, b] in frames.array_windows() {
-                assert_ne!(a, b);
-                assert!(a < FRAMES && b < FRAMES);
-            }
-
-            barrier.wait();
-            warn!("reallocate rand...");
-            let mut rng = WyRand::new(t as _);
-            rng.shuffle(&mut frames);
+        const FRAMES: usize = 4 * TREE_FRAMES;
+        let alloc = TestAlloc::<LLFree<'static>>::create(2, FRAMES, Init::FreeAll).unwrap();
 
-            for _ in 0..frames.len() {
-                let i = rng.range(0..frames.len() as _) as usize;
-                alloc.put(t, frames[i], Flags::o(0)).unwrap();
-                frames[i] = alloc.get(t, Flags::o(0)).unwrap();
-            }
+        // Core 1 reserves a tree and allocates all but one of its huge frames
+        let mut huge = vec![alloc.get(1, Flags::o(HUGE_ORDER)).unwrap()];
+        let tree = huge[0] / TREE_FRAMES * TREE_FRAMES;
+        for _ in 1..TREE_HUGE - 1 {
+            let frame = alloc.get(1, Flags::o(HUGE_ORDER)).unwrap();
+            assert_eq!(frame / TREE_FRAMES * TREE_FRAMES, tree);
+            huge.push(frame);
+        }
+        let free = (tree..tree + TREE_FRAMES)
+            .step_by(HUGE_FRAMES)
+            .find(|f| alloc.is_free(*f, HUGE_ORDER))
+            .unwrap();
+        let buddy = free ^ HUGE_FRAMES;
+        assert!(huge.contains(&buddy));
+
+        // Freed by another core into the global counters of the reserved tree
+        alloc.put(0, buddy, Flags::o(HUGE_ORDER)).unwrap();
+        huge.retain(|f| *f != buddy);
+
+        // Needs the frames of both the global and the reserved counters
+        let frame = free.min(buddy);
+        alloc.get_at(0, frame, MAX_ORDER).unwrap();
+        assert!(!alloc.is_free(free, 0) && !alloc.is_free(buddy, 0));
+        alloc.validate();
 
-            warn!("check...");
-            // Check that the same frame was not allocated twice
-            frames.sort_unstable();
-            for &[a, b] in frames.array_windows() {
-                assert_ne!(a, b);
-                assert!(a < FRAMES && b < FRAMES);
-            }
+        alloc.put(0, frame, Flags::o(MAX_ORDER)).unwrap();
+        for frame in huge {
+            alloc.put(1, frame, Flags::o(HUGE_ORDER)).unwrap();
+        }
+        assert_eq!(alloc.free_frames(), FRAMES);
+        alloc.validate();
+    }
 
-            if barrier.wait().is_leader() {
-                alloc.validate();
-            }
-            barrier.wait();
+    #[test]
+    fn free_frames_below() {
+        logging();
 
-            warn!("free...");
-            rng.shuffle(&mut frames);
-            for frame in &frames {
-                alloc.put(t, *frame, Flags::o(0)).unwrap();
-            }
-        });
+        let alloc = Allocator::create(1, 4 * TREE_FRAMES, Init::AllocAll).unwrap();
+        // Free frames on both sides of the boundary
+        for frame in 0..10 {
+            alloc.put(0, frame, Flags::o(0)).unwrap();
+        }
+        for frame in 3 * TREE_FRAMES..3 * TREE_FRAMES + 20 {
+            alloc.put(0, frame, Flags::o(0)).unwrap();
+        }
+        assert_eq!(alloc.free_frames_below(2 * TREE_FRAMES), 10);
+        assert_eq!(alloc.free_frames_below(4 * TREE_FRAMES), 30);
+        assert_eq!(alloc.free_frames_below(usize::MAX), alloc.free_frames());
 
-        assert_eq!(alloc.free_huge(), FRAMES / HUGE_FRAMES);
-        assert_eq!(alloc.allocated_frames(), 0);
+        // The spanning tree is apportioned
+        for frame in TREE_FRAMES..TREE_FRAMES + 8 {
+            alloc.put(0, frame, Flags::o(0)).unwrap();
+        }
+        assert_eq!(alloc.free_frames_below(TREE_FRAMES + TREE_FRAMES / 2), 14);
+        assert_eq!(alloc.free_frames_below(0), 0);
         alloc.validate();
     }
 
     #[test]
-    fn init() {
+    fn allocated_frames_in() {
         logging();
-        // 8GiB
-        const MEM_SIZE: usize = 2 << 30;
-        const FRAMES: usize = MEM_SIZE / Frame::SIZE;
 
+        const FRAMES: usize = 4 * TREE_FRAMES + 100;
         let alloc = Allocator::create(1, FRAMES, Init::FreeAll).unwrap();
+        assert_eq!(alloc.allocated_frames_in(0..FRAMES), 0);
 
-        assert_eq!(alloc.allocated_frames(), 0);
+        let mut frames = Vec::new();
+        for _ in 0..300 {
+            frames.push(alloc.get(0, Flags::o(0)).unwrap());
+        }
+        let huge = alloc.get(0, Flags::o(HUGE_ORDER)).unwrap();
+        let count = |range: Range<usize>| {
+            let huge = huge..huge + HUGE_FRAMES;
+            frames.iter().filter(|f| range.contains(f)).count()
+                + huge.filter(|f| range.contains(f)).count()
+        };
+
+        for range in [
+            0..FRAMES,
+            0..usize::MAX,
+            // Partial trees at both ends
+            7..TREE_FRAMES + 13,
+            huge + 5..huge + 100,
+            huge.saturating_sub(3 * TREE_FRAMES / 2)..huge + 3 * TREE_FRAMES / 2,
+            // Last, partial tree
+            4 * TREE_FRAMES..FRAMES,
+            10..10,
+        ] {
+            let allocated = alloc.allocated_frames_in(range.clone());
+            assert_eq!(allocated, count(range.clone()), "{range:?}");
+        }
+        let allocated = alloc.allocated_frames();
+        assert_eq!(alloc.allocated_frames_in(0..FRAMES), allocated);
+        alloc.validate();
+    }
 
-        warn!("start alloc");
-        let small = alloc.get(0, Flags::o(0)).unwrap();
-        let huge = alloc.get(0, Flags::o(9)).unwrap();
+    #[test]
+    fn balloon() {
+        logging();
 
-        let expected_frames = 1 + (1 << 9);
-        assert_eq!(alloc.allocated_frames(), expected_frames);
-        assert!(small != huge);
+        let alloc =
+            TestAlloc::<LLFree<'static>>::create(1, 8 * TREE_FRAMES, Init::FreeAll).unwrap();
+        // Reserves a tree, which is not inflated
+        let first = alloc.get(0, Flags::o(0)).unwrap();
 
-        warn!("start stress test");
+        let ranges = alloc.inflate(6 * TREE_FRAMES - 10).unwrap();
+        let inflated = ranges.iter().map(|r| r.len()).sum::<usize>();
+        assert_eq!(inflated, 6 * TREE_FRAMES);
+        assert!(ranges.iter().all(|r| !r.contains(&first)));
+        assert_eq!(alloc.free_frames(), 2 * TREE_FRAMES - 1);
+        alloc.validate();
 
-        // Stress test
-        let mut frames = vec![0; HUGE_FRAMES * HUGE_FRAMES];
+        // Only the remaining trees can be allocated
+        let mut frames = vec![first];
+        for _ in 1..2 * TREE_FRAMES {
+            let frame = alloc.get(0, Flags::o(0)).unwrap();
+            assert!(ranges.iter().all(|r| !r.contains(&frame)));
+            frames.push(frame);
+        }
+        assert_eq!(alloc.get(0, Flags::o(0)), Err(Error::Memory));
+        assert_eq!(alloc.inflate(TREE_FRAMES), Err(Error::Memory));
+
+        // Not inflated or unaligned
+        let occupied = first / TREE_FRAMES * TREE_FRAMES;
+        let occupied = occupied..occupied + TREE_FRAMES;
+        assert_eq!(alloc.deflate(&[occupied]), Err(Error::Address));
+        let unaligned = ranges[0].start + 1..ranges[0].end;
+        assert_eq!(alloc.deflate(&[unaligned]), Err(Error::Address));
+
+        alloc.deflate(&ranges).unwrap();
+        assert_eq!(alloc.free_frames(), 6 * TREE_FRAMES);
+        for _ in 0..6 * TREE_FRAMES {
+            frames.push(alloc.get(0, Flags::o(0)).unwrap());
+        }
+        for frame in frames {
+            alloc.put(0, frame, Flags::o(0)).unwrap();
+        }
+        assert_eq!(alloc.free_frames(), alloc.frames());
+        alloc.validate();
+    }
+
+    #[test]
+    fn drop_inflated() {
+        logging();
+
+        let alloc =
+            TestAlloc::<LLFree<'static>>::create(2, 4 * TREE_FRAMES, Init::FreeAll).unwrap();
+        let frame = alloc.get(0, Flags::o(0)).unwrap();
+        let ranges = alloc.inflate(2 * TREE_FRAMES).unwrap();
+        let inflated = ranges.iter().map(|r| r.len()).sum::<usize>();
+        assert_eq!(inflated, 2 * TREE_FRAMES);
+        alloc.put(0, frame, Flags::o(0)).unwrap();
+        // Dropped without deflating the trees
+        drop(alloc);
+    }
+
+    #[test]
+    fn allocatable_spans() {
+        logging();
+
+        const FRAMES: usize = 4 * TREE_FRAMES;
+        let alloc = TestAlloc::<LLFree<'static>>::create(1, FRAMES, Init::FreeAll).unwrap();
+        // Compare with checking every aligned block, one order more than supported
+        let check = |alloc: &LLFree| {
+            let mut spans = [0; MAX_ORDER + 2];
+            alloc.count_allocatable_spans(&mut spans);
+            for (order, count) in spans.iter().enumerate() {
+                let blocks = (0..FRAMES >> order).filter(|i| alloc.is_free(i << order, order));
+                assert_eq!(*count, blocks.count(), "o{order}");
+            }
+            spans
+        };
+        let spans = check(&alloc);
+        assert_eq!(spans[0], FRAMES);
+        assert_eq!(spans[MAX_ORDER], FRAMES >> MAX_ORDER);
+        assert_eq!(spans[MAX_ORDER + 1], 0);
+
+        // Inflated trees cannot be allocated
+        let ranges = alloc.inflate(TREE_FRAMES).unwrap();
+        let mut spans = [0; MAX_ORDER + 1];
+        alloc.count_allocatable_spans(&mut spans);
+        assert_eq!(spans[0], FRAMES - TREE_FRAMES);
+        assert_eq!(spans[HUGE_ORDER], (FRAMES - TREE_FRAMES) / HUGE_FRAMES);
+        alloc.deflate(&ranges).unwrap();
+
+        // All huge frames are allocated, except for a free pair and a single one
+        let mut huge = Vec::new();
+        while let Ok(frame) = alloc.get(0, Flags::o(HUGE_ORDER)) {
+            huge.push(frame);
+        }
+        assert_eq!(huge.len(), FRAMES / HUGE_FRAMES);
+        for frame in [0, HUGE_FRAMES, 3 * HUGE_FRAMES] {
+            alloc.put(0, frame, Flags::o(HUGE_ORDER)).unwrap();
+        }
+        let spans = check(&alloc);
+        assert_eq!(spans[0], 3 * HUGE_FRAMES);
+        assert_eq!(spans[HUGE_ORDER], 3);
+        assert_eq!(spans[MAX_ORDER], 1);
+
+        // Small frames fragment the free huge frames
+        let small = [(8, 3), (3 * HUGE_FRAMES + 1, 0)];
+        for (frame, order) in small {
+            alloc.get_at(0, frame, order).unwrap();
+        }
+        let spans = check(&alloc);
+        assert_eq!(spans[HUGE_ORDER], 1);
+        assert_eq!(spans[MAX_ORDER], 0);
+        for (frame, order) in small {
+            alloc.put(0, frame, Flags::o(order)).unwrap();
+        }
+        check(&alloc);
+        alloc.validate();
+    }
+
+    #[test]
+    fn compound() {
+        logging();
+
+        type Compound = CompoundAlloc<'static, LLFree<'static>>;
+        let meta = MetaData::alloc(Compound::metadata_size(1, TREE_FRAMES));
+        let alloc = Compound::new(1, TREE_FRAMES, Init::FreeAll, meta).unwrap();
+
+        let head = alloc.get_compound(0, 4).unwrap();
+        let small = alloc.get(0, Flags::o(0)).unwrap();
+        assert_eq!(alloc.compound_order(head + 3), Some((head, 4)));
+        assert_eq!(alloc.compound_order(small), None);
+
+        // Only the whole compound frame can be freed
+        assert_eq!(alloc.put_compound(0, head, 3), Err(Error::Address));
+        assert_eq!(alloc.put(0, head + 1, Flags::o(0)), Err(Error::Address));
+        assert_eq!(alloc.put(0, small, Flags::o(1)), Err(Error::Address));
+        assert_eq!(alloc.allocated_frames(), 17);
+
+        alloc.put_compound(0, head, 4).unwrap();
+        assert_eq!(alloc.compound_order(head), None);
+        assert_eq!(alloc.put_compound(0, head, 4), Err(Error::Address));
+        alloc.put(0, small, Flags::o(0)).unwrap();
+        assert_eq!(alloc.free_frames(), alloc.frames());
+        alloc.validate();
+    }
+
+    #[test]
+    fn blocking() {
+        use std::time::Duration;
+
+        logging();
+
+        type Blocking = BlockingAlloc<'static, LLFree<'static>>;
+        let meta = MetaData::alloc(Blocking::metadata_size(1, TREE_FRAMES));
+        let alloc = Blocking::new(1, TREE_FRAMES, Init::FreeAll, meta).unwrap();
+
+        let mut frames = Vec::new();
+        while let Ok(frame) = alloc.get(0, Flags::o(0)) {
+            frames.push(frame);
+        }
+        assert_eq!(alloc.free_frames(), 0);
+        let timeout = Duration::from_millis(10);
+        assert_eq!(alloc.get_blocking(0, 0, timeout), Err(Error::Memory));
+
+        // Consumers wait until the producer frees their frames
+        let consumed: Vec<usize> = std::thread::scope(|s| {
+            let consumers: Vec<_> = (0..4)
+                .map(|_| s.spawn(|| alloc.get_blocking(0, 0, Duration::from_secs(10))))
+                .collect();
+            for frame in frames.drain(..consumers.len()) {
+                std::thread::sleep(timeout);
+                alloc.put(0, frame, Flags::o(0)).unwrap();
+            }
+            consumers
+                .into_iter()
+                .map(|c| c.join().unwrap().unwrap())
+                .collect()
+        });
+        assert_eq!(consumed.len(), 4);
+        assert_eq!(alloc.free_frames(), 0);
+        frames.extend(consumed);
+
+        for frame in frames {
+            alloc.put(0, frame, Flags::o(0)).unwrap();
+        }
+        assert_eq!(alloc.free_frames(), alloc.frames());
+        alloc.validate();
+    }
+
+    #[test]
+    fn blocking_merged() {
+        use std::time::Duration;
+
+        logging();
+
+        type Blocking = BlockingAlloc<'static, LLFree<'static>>;
+        let meta = MetaData::alloc(Blocking::metadata_size(1, TREE_FRAMES));
+        let alloc = Blocking::new(1, TREE_FRAMES, Init::FreeAll, meta).unwrap();
+
+        let mut frames = Vec::new();
+        while let Ok(frame) = alloc.get(0, Flags::o(0)) {
+            frames.push(frame);
+        }
+        frames.sort_unstable();
+
+        // Two base frames are merged into the block the waiter is waiting for
+        let frame = std::thread::scope(|s| {
+            let waiter = s.spawn(|| alloc.get_blocking(0, 1, Duration::from_secs(10)));
+            for frame in frames.drain(..2) {
+                std::thread::sleep(Duration::from_millis(10));
+                alloc.put(0, frame, Flags::o(0)).unwrap();
+            }
+            waiter.join().unwrap().unwrap()
+        });
+        assert_eq!(frame, 0);
+
+        alloc.put(0, frame, Flags::o(1)).unwrap();
+        for frame in frames {
+            alloc.put(0, frame, Flags::o(0)).unwrap();
+        }
+        assert_eq!(alloc.free_frames(), alloc.frames());
+        alloc.validate();
+    }
+
+    #[test]
+    fn blocking_async() {
+        use core::future::Future;
+        use core::task::{Context, Poll, Waker};
+        use std::sync::Arc;
+
+        /// Minimal executor, that parks the thread until woken
+        struct Unpark(std::thread::Thread);
+        impl std::task::Wake for Unpark {
+            fn wake(self: Arc<Self>) {
+                self.0.unpark();
+            }
+        }
+        fn block_on<F: Future>(future: F) -> F::Output {
+            let waker = Waker::from(Arc::new(Unpark(std::thread::current())));
+            let mut cx = Context::from_waker(&waker);
+            let mut future = core::pin::pin!(future);
+            loop {
+                if let Poll::Ready(result) = future.as_mut().poll(&mut cx) {
+                    return result;
+                }
+                std::thread::park();
+            }
+        }
+
+        logging();
+
+        type Blocking = BlockingAlloc<'static, LLFree<'static>>;
+        let meta = MetaData::alloc(Blocking::metadata_size(1, TREE_FRAMES));
+        let alloc = Blocking::new(1, TREE_FRAMES, Init::FreeAll, meta).unwrap();
+
+        let mut frames = Vec::new();
+        while let Ok(frame) = alloc.get(0, Flags::o(HUGE_ORDER)) {
+            frames.push(frame);
+        }
+
+        let waker = Waker::from(Arc::new(Unpark(std::thread::current())));
+        let mut cx = Context::from_waker(&waker);
+        let mut future = core::pin::pin!(alloc.get_async(0, HUGE_ORDER));
+        assert!(future.as_mut().poll(&mut cx).is_pending());
+
+        // The free wakes the waiting allocation
+        let huge = frames.pop().unwrap();
+        let frame = std::thread::scope(|s| {
+            s.spawn(|| {
+                std::thread::sleep(std::time::Duration::from_millis(10));
+                alloc.put(0, huge, Flags::o(HUGE_ORDER)).unwrap();
+            });
+            block_on(future)
+        })
+        .unwrap();
+        assert_eq!(frame, huge);
+        assert_eq!(alloc.free_frames(), 0);
+
+        frames.push(frame);
+        for frame in frames {
+            alloc.put(0, frame, Flags::o(HUGE_ORDER)).unwrap();
+        }
+        assert_eq!(alloc.free_frames(), alloc.frames());
+        alloc.validate();
+    }
+
+    /// Oversized orders are distinguished from missing memory
+    #[test]
+    fn watermark() {
+        use std::sync::atomic::{AtomicUsize, Ordering};
+        use std::sync::Arc;
+
+        logging();
+
+        const FRAMES: usize = 2 * TREE_FRAMES;
+        type Watermark = WatermarkAlloc<'static, LLFree<'static>>;
+        let meta = MetaData::alloc(Watermark::metadata_size(1, FRAMES));
+        let alloc = Watermark::new(1, FRAMES, Init::FreeAll, meta).unwrap();
+
+        let calls = Arc::new(AtomicUsize::new(0));
+        let c = calls.clone();
+        alloc.set_watermark_oom(FRAMES / 10, move || {
+            c.fetch_add(1, Ordering::Relaxed);
+        });
+
+        // Down to the watermark
+        let mut frames = Vec::new();
+        while alloc.free_frames() > FRAMES / 10 {
+            frames.push(alloc.get(0, Flags::o(0)).unwrap());
+        }
+        assert_eq!(calls.load(Ordering::Relaxed), 0);
+        // Just below
+        frames.push(alloc.get(0, Flags::o(0)).unwrap());
+        assert_eq!(calls.load(Ordering::Relaxed), 1);
+
+        // Exhaust the memory, freeing in between
+        let frame = frames.pop().unwrap();
+        alloc.put(0, frame, Flags::o(0)).unwrap();
+        while let Ok(frame) = alloc.get(0, Flags::o(0)) {
+            frames.push(frame);
+        }
+        assert_eq!(alloc.free_frames(), 0);
+        assert_eq!(calls.load(Ordering::Relaxed), 1);
+
+        // A new callback is called on the next allocation below the watermark
+        let c = calls.clone();
+        alloc.set_watermark_oom(FRAMES / 10, move || {
+            c.fetch_add(10, Ordering::Relaxed);
+        });
+        let frame = frames.pop().unwrap();
+        alloc.put(0, frame, Flags::o(0)).unwrap();
+        frames.push(alloc.get(0, Flags::o(0)).unwrap());
+        assert_eq!(calls.load(Ordering::Relaxed), 11);
+
+        for frame in frames {
+            alloc.put(0, frame, Flags::o(0)).unwrap();
+        }
+        assert_eq!(alloc.free_frames(), alloc.frames());
+
+        // Growing in place allocates the tail
+        let frame = alloc.get(0, Flags::o(3)).unwrap();
+        assert_eq!(alloc.try_resize(0, frame, 3, 0), Ok(frame));
+        let c = calls.clone();
+        alloc.set_watermark_oom(FRAMES - 4, move || {
+            c.fetch_add(100, Ordering::Relaxed);
+        });
+        assert_eq!(alloc.try_resize(0, frame, 0, 3), Ok(frame));
+        assert_eq!(calls.load(Ordering::Relaxed), 111);
+        alloc.put(0, frame, Flags::o(3)).unwrap();
+        assert_eq!(alloc.free_frames(), alloc.frames());
+        alloc.validate();
+    }
+
+    #[test]
+    fn invalid_order() {
+        fn check<'a>(alloc: &impl Alloc<'a>) {
+            let max = alloc.max_order();
+            let invalid = Error::InvalidOrder {
+                requested: max + 1,
+                max,
+            };
+            assert_eq!(alloc.get(0, Flags::o(max + 1)), Err(invalid));
+
+            let frame = alloc.get(0, Flags::o(max)).unwrap();
+            assert_eq!(alloc.put(0, frame, Flags::o(max + 1)), Err(invalid));
+            alloc.put(0, frame, Flags::o(max)).unwrap();
+
+            let mut frames = Vec::new();
+            let err = loop {
+                match alloc.get(0, Flags::o(max)) {
+                    Ok(frame) => frames.push(frame),
+                    Err(e) => break e,
+                }
+            };
+            assert_eq!(err, Error::Memory);
+            for frame in frames {
+                alloc.put(0, frame, Flags::o(max)).unwrap();
+            }
+            assert_eq!(alloc.free_frames(), alloc.frames());
+        }
+
+        logging();
+
+        let alloc = Allocator::create(1, TREE_FRAMES, Init::FreeAll).unwrap();
+        assert_eq!(alloc.max_order(), MAX_ORDER);
+        check(&*alloc);
+
+        type Blocking = BlockingAlloc<'static, LLFree<'static>>;
+        let meta = MetaData::alloc(Blocking::metadata_size(1, TREE_FRAMES));
+        check(&Blocking::new(1, TREE_FRAMES, Init::FreeAll, meta).unwrap());
+    }
+
+    #[test]
+    fn get_modes() {
+        logging();
+
+        let alloc =
+            TestAlloc::<LLFree<'static>>::create(1, 2 * TREE_FRAMES, Init::FreeAll).unwrap();
+        let fast = Flags::o(0).with_fast(true);
+
+        // Nothing reserved yet
+        assert_eq!(alloc.get(0, fast), Err(Error::Memory));
+        let first = alloc.get(0, Flags::o(0)).unwrap();
+
+        // Only the local tree is used
+        for _ in 1..TREE_FRAMES {
+            let frame = alloc.get(0, fast).unwrap();
+            assert_eq!(frame / TREE_FRAMES, first / TREE_FRAMES);
+        }
+        assert_eq!(alloc.get(0, fast), Err(Error::Memory));
+        assert_eq!(alloc.free_frames(), TREE_FRAMES);
+
+        // Reserves the next tree
+        let nofail = Flags::o(0).with_nofail(true);
+        let frame = alloc.get(0, nofail).unwrap();
+        assert_ne!(frame / TREE_FRAMES, first / TREE_FRAMES);
+        for _ in 1..TREE_FRAMES {
+            alloc.get(0, nofail).unwrap();
+        }
+        // Fails if there are no free frames
+        assert_eq!(alloc.get(0, nofail), Err(Error::Memory));
+        assert_eq!(alloc.get(0, fast), Err(Error::Memory));
+        alloc.validate();
+    }
+
+    #[test]
+    fn nofail_fragmented() {
+        logging();
+
+        let alloc = TestAlloc::<LLFree<'static>>::create(1, TREE_FRAMES, Init::FreeAll).unwrap();
+        let frames = (0..TREE_FRAMES)
+            .map(|_| alloc.get(0, Flags::o(0)).unwrap())
+            .collect::<Vec<_>>();
+        for frame in frames.iter().filter(|f| *f % 2 == 0) {
+            alloc.put(0, *frame, Flags::o(0)).unwrap();
+        }
+        // Gives up instead of waiting for frees that never happen
+        let nofail = Flags::o(1).with_nofail(true);
+        assert_eq!(alloc.get(0, nofail), Err(Error::Fragmented));
+        alloc.validate();
+    }
+
+    /// The fast path does not search the tree array
+    #[cfg(feature = "search_stats")]
+    #[test]
+    fn get_fast_reads() {
+        logging();
+
+        let alloc =
+            TestAlloc::<LLFree<'static>>::create(1, 2 * TREE_FRAMES, Init::FreeAll).unwrap();
+        let fast = Flags::o(0).with_fast(true);
+        alloc.get(0, Flags::o(0)).unwrap();
+
+        // The local entry suffices
+        let reads = alloc.trees.reads();
+        for _ in 1..TREE_FRAMES {
+            alloc.get(0, fast).unwrap();
+        }
+        assert_eq!(alloc.trees.reads(), reads);
+
+        // A single pass over the local entry, without syncing or reserving
+        assert_eq!(alloc.get(0, fast), Err(Error::Memory));
+        assert_eq!(alloc.trees.reads(), reads);
+
+        // Only the normal allocation reserves a new tree
+        alloc.get(0, Flags::o(0)).unwrap();
+        assert!(alloc.trees.reads() > reads);
+        alloc.validate();
+    }
+
+    #[test]
+    fn snapshot_restore() {
+        logging();
+
+        const FRAMES: usize = 4 * TREE_FRAMES;
+        type A = LLFree<'static>;
+        let alloc = TestAlloc::<A>::create(2, FRAMES, Init::FreeAll).unwrap();
+
+        let mut frames = Vec::new();
+        for core in 0..2 {
+            for order in [0, 0, 3, HUGE_ORDER, MAX_ORDER] {
+                frames.push((alloc.get(core, Flags::o(order)).unwrap(), order));
+            }
+        }
+        let snapshot = alloc.snapshot().unwrap();
+
+        let meta = MetaData::alloc(A::metadata_size(2, FRAMES));
+        assert_eq!(
+            A::restore(snapshot.clone(), FRAMES - 1, meta).err(),
+            Some(Error::Initialization)
+        );
+
+        let meta = MetaData::alloc(A::metadata_size(2, FRAMES));
+        let restored = TestAlloc(ManuallyDrop::new(
+            A::restore(snapshot, FRAMES, meta).unwrap(),
+        ));
+        restored.validate();
+        assert_eq!(restored.free_frames(), alloc.free_frames());
+        assert_eq!(restored.free_huge(), alloc.free_huge());
+
+        // The restored allocator is independent of the original one
+        drop(alloc);
+        for (frame, order) in frames {
+            assert!(!restored.is_free(frame, order));
+            restored.put(0, frame, Flags::o(order)).unwrap();
+        }
+        assert_eq!(restored.free_frames(), FRAMES);
+        restored.validate();
+    }
+
+    #[test]
+    fn simple() {
+        logging();
+        // 8GiB
+        const MEM_SIZE: usize = 1 << 30;
+        const FRAMES: usize = MEM_SIZE / Frame::SIZE;
+
+        let alloc = Allocator::create(1, FRAMES, Init::FreeAll).unwrap();
+
+        assert_eq!(alloc.free_frames(), alloc.frames());
+
+        warn!("start alloc...");
+        let small = alloc.get(0, Flags::o(0)).unwrap();
+
+        assert_eq!(alloc.allocated_frames(), 1, "{alloc:?}");
+        warn!("stress test...");
+
+        // Stress test
+        let mut frames = Vec::new();
+        loop {
+            match alloc.get(0, Flags::o(0)) {
+                Ok(frame) => frames.push(frame),
+                Err(Error::Memory) => break,
+                Err(e) => panic!("{e:?}"),
+            }
+        }
+
+        warn!("allocated {}", 1 + frames.len());
+        warn!("check...");
+        alloc.validate();
+
+        assert_eq!(alloc.allocated_frames(), 1 + frames.len());
+        assert_eq!(alloc.allocated_frames(), alloc.frames());
+        frames.sort_unstable();
+
+        // Check that the same frame was not allocated twice
+        for &[a, b] in frames.array_windows() {
+            assert_ne!(a, b);
+            assert!(a < FRAMES && b < FRAMES);
+        }
+
+        warn!("realloc...");
+
+        // Free some
+        const FREE_NUM: usize = HUGE_FRAMES * HUGE_FRAMES - 10;
+        for frame in &frames[..FREE_NUM] {
+            alloc.put(0, *frame, Flags::o(0)).unwrap();
+        }
+
+        assert_eq!(
+            alloc.allocated_frames(),
+            1 + frames.len() - FREE_NUM,
+            "{alloc:?}"
+        );
+        alloc.validate();
+
+        // Realloc
+        for frame in &mut frames[..FREE_NUM] {
+            *frame = alloc.get(0, Flags::o(0)).unwrap();
+        }
+
+        warn!("free...");
+
+        alloc.put(0, small, Flags::o(0)).unwrap();
+        // Free all
+        for frame in &frames {
+            alloc.put(0, *frame, Flags::o(0)).unwrap();
+        }
+
+        assert_eq!(alloc.allocated_frames(), 0);
+        alloc.validate();
+    }
+
+    #[test]
+    fn init() {
+        logging();
+        // 8GiB
+        const MEM_SIZE: usize = 2 << 30;
+        const FRAMES: usize = MEM_SIZE / Frame::SIZE;
+
+        let alloc = Allocator::create(1, FRAMES, Init::FreeAll).unwrap();
+
+        assert_eq!(alloc.allocated_frames(), 0);
+
+        warn!("start alloc");
+        let small = alloc.get(0, Flags::o(0)).unwrap();
+        let huge = alloc.get(0, Flags::o(9)).unwrap();
+
+        let expected_frames = 1 + (1 << 9);
+        assert_eq!(alloc.allocated_frames(), expected_frames);
+        assert!(small != huge);
+
+        warn!("start stress test");
+
+        // Stress test
+        let mut frames = vec![0; HUGE_FRAMES * HUGE_FRAMES];
         for frame in &mut frames {
             *frame = alloc.get(0, Flags::o(0)).unwrap();
         }
 
-        warn!("check");
-        assert_eq!(alloc.allocated_frames(), expected_frames + frames.len());
+        warn!("check");
+        assert_eq!(alloc.allocated_frames(), expected_frames + frames.len());
+        alloc.validate();
+
+        frames.sort_unstable();
+
+        // Check that the same frame was not allocated twice
+        for &[a, b] in frames.array_windows() {
+            assert_ne!(a, b);
+            assert!(a < FRAMES && b < FRAMES);
+        }
+
+        warn!("free some...");
+
+        // Free some
+        for frame in &frames[10..HUGE_FRAMES + 10] {
+            alloc.put(0, *frame, Flags::o(0)).unwrap();
+        }
+        alloc.validate();
+
+        warn!("free special...");
+
+        alloc.put(0, small, Flags::o(0)).unwrap();
+        alloc.put(0, huge, Flags::o(9)).unwrap();
+        alloc.validate();
+
+        warn!("realloc...");
+
+        // Realloc
+        for frame in &mut frames[10..HUGE_FRAMES + 10] {
+            *frame = alloc.get(0, Flags::o(0)).unwrap();
+        }
+        alloc.validate();
+
+        warn!("free...");
+        // Free all
+        for frame in &frames {
+            alloc.put(0, *frame, Flags::o(0)).unwrap();
+        }
+
+        assert_eq!(alloc.allocated_frames(), 0);
+        assert_eq!(alloc.free_huge(), FRAMES / HUGE_FRAMES);
+        alloc.validate();
+    }
+
+    #[test]
+    fn parallel_alloc() {
+        logging();
+
+        const THREADS: usize = 4;
+        const ALLOC_PER_THREAD: usize = HUGE_FRAMES * (HUGE_FRAMES - 2 * THREADS);
+        const FRAMES: usize = 2 * THREADS * HUGE_FRAMES * HUGE_FRAMES;
+
+        let alloc = Allocator::create(THREADS, FRAMES, Init::FreeAll).unwrap();
+        let tracker = FrameTracker::new(FRAMES);
+
+        // Stress test
+        let barrier = Barrier::new(THREADS);
+        let timer = Instant::now();
+
+        thread::parallel(0..THREADS, |t| {
+            thread::try_pin(t);
+            barrier.wait();
+
+            for _ in 0..ALLOC_PER_THREAD {
+                tracker.insert(alloc.get(t, Flags::o(0)).unwrap(), 0);
+            }
+        });
+        warn!("Allocation finished in {}ms", timer.elapsed().as_millis());
+
+        assert_eq!(alloc.allocated_frames(), ALLOC_PER_THREAD * THREADS);
+        assert_eq!(tracker.len(), ALLOC_PER_THREAD * THREADS);
+        warn!("allocated frames: {}", tracker.len());
+        alloc.validate();
+    }
+
+    /// Search length for interleaved allocations of different orders
+    #[cfg(feature = "search_stats")]
+    #[test]
+    fn search_length() {
+        use crate::bitfield::searched_local;
+
+        logging();
+
+        const N: usize = 16;
+        let alloc = TestAlloc::<LLFree<'static>>::create(1, TREE_FRAMES, Init::AllocAll).unwrap();
+        // Fragment the first half and keep the second half free
+        for frame in 0..TREE_FRAMES {
+            if frame >= TREE_FRAMES / 2 || frame % 8 == 0 {
+                alloc.put(0, frame, Flags::o(0)).unwrap();
+            }
+        }
+
+        let mut searched = [0; 2];
+        for _ in 0..N {
+            for (i, order) in [0, 6].into_iter().enumerate() {
+                let before = searched_local();
+                alloc.get(0, Flags::o(order)).unwrap();
+                searched[i] += searched_local() - before;
+            }
+        }
+        warn!(
+            "search length: o=0 {} o=6 {}",
+            searched[0] as f64 / N as f64,
+            searched[1] as f64 / N as f64
+        );
+        // Only the first order 6 allocation has to search the fragmented half
+        assert!(searched[1] <= 4 * N);
+    }
+
+    /// Replaying the same operations returns the same frames
+    #[cfg(not(feature = "llc"))]
+    #[test]
+    fn deterministic() {
+        logging();
+
+        const FRAMES: usize = 16 * TREE_FRAMES;
+        const CORES: usize = 4;
+
+        let replay = || {
+            let meta = MetaData::alloc(LLFree::metadata_size(CORES, FRAMES));
+            let mut alloc = LLFree::new(CORES, FRAMES, Init::FreeAll, meta).unwrap();
+            alloc.set_deterministic(true);
+
+            let mut rng = WyRand::new(42);
+            let mut frames = Vec::new();
+            let mut trace = Vec::new();
+            for i in 0..10_000 {
+                let core = i % CORES;
+                if frames.is_empty() || rng.range(0..3) > 0 {
+                    let order = [0, 0, 0, 1, 3, HUGE_ORDER][rng.range(0..6) as usize];
+                    if let Ok(frame) = alloc.get(core, Flags::o(order)) {
+                        frames.push((frame, order));
+                        trace.push(frame);
+                    }
+                } else {
+                    let (frame, order) = frames.swap_remove(rng.range(0..frames.len() as _) as _);
+                    alloc.put(core, frame, Flags::o(order)).unwrap();
+                }
+            }
+            alloc.validate();
+            trace
+        };
+        let trace = replay();
+        assert!(trace.len() > 5_000);
+        assert!(trace == replay());
+
+        // The lowest free tree is reserved
+        let meta = MetaData::alloc(LLFree::metadata_size(CORES, FRAMES));
+        let mut alloc = LLFree::new(CORES, FRAMES, Init::FreeAll, meta).unwrap();
+        alloc.set_deterministic(true);
+        assert_eq!(alloc.get(3, Flags::o(0)).unwrap() / TREE_FRAMES, 0);
+        assert_eq!(alloc.get(1, Flags::o(0)).unwrap() / TREE_FRAMES, 1);
+    }
+
+    /// Frees in low memory drag the reservations of all cores there, unless they are rebalanced
+    #[cfg(not(feature = "llc"))]
+    #[test]
+    fn rebalance() {
+        logging();
+
+        const CORES: usize = 4;
+        const SHARE: usize = 8;
+        const FRAMES: usize = CORES * SHARE * TREE_FRAMES;
+        const ROUNDS: usize = 32;
+
+        for balanced in [false, true] {
+            let meta = MetaData::alloc(LLFree::metadata_size(CORES, FRAMES));
+            let mut alloc = LLFree::new(CORES, FRAMES, Init::FreeAll, meta).unwrap();
+            if balanced {
+                alloc.set_rebalance_interval(4);
+            }
+
+            // Fill the memory from all cores
+            let mut frames = Vec::new();
+            let mut failed = 0;
+            for core in (0..CORES).cycle() {
+                match alloc.get(core, Flags::o(HUGE_ORDER)) {
+                    Ok(frame) => {
+                        frames.push(frame);
+                        failed = 0;
+                    }
+                    Err(Error::Memory | Error::Fragmented) if failed < CORES => failed += 1,
+                    Err(Error::Memory | Error::Fragmented) => break,
+                    Err(e) => panic!("{e:?}"),
+                }
+            }
+            assert_eq!(alloc.free_frames(), 0);
+
+            let mut drained = 0;
+            for _ in 0..ROUNDS {
+                // Skewed frees, only in the home of the first core
+                let (low, high) = frames
+                    .into_iter()
+                    .partition::<Vec<_>, _>(|f| f / TREE_FRAMES < SHARE);
+                frames = high;
+                for frame in &low {
+                    alloc.put(0, *frame, Flags::o(HUGE_ORDER)).unwrap();
+                }
+                for core in (0..CORES).cycle().take(low.len()) {
+                    frames.push(alloc.get(core, Flags::o(HUGE_ORDER)).unwrap());
+                }
+
+                if balanced {
+                    drained += alloc.rebalance();
+                    assert!(alloc.reservation_spread() <= SHARE);
+                }
+            }
+            let spread = alloc.reservation_spread();
+            warn!("balanced={balanced} spread={spread} drained={drained}");
+            if balanced {
+                assert!(drained > 0);
+            } else {
+                // The last core reserves trees in the home of the first one
+                assert!(spread > 2 * SHARE, "{spread}");
+            }
+            alloc.validate();
+        }
+    }
+
+    /// The homes cover all trees, even if there are fewer trees than cores
+    #[cfg(not(feature = "llc"))]
+    #[test]
+    fn rebalance_few_trees() {
+        logging();
+
+        // Cores, trees and the expected spread after the first reservations
+        for (cores, trees, spread) in [(8, 3, 1), (2, 3, 0)] {
+            let frames = trees * TREE_FRAMES;
+            let meta = MetaData::alloc(LLFree::metadata_size(cores, frames));
+            let alloc = LLFree::new(cores, frames, Init::FreeAll, meta).unwrap();
+
+            let frames: Vec<_> = (0..cores)
+                .map(|core| alloc.get(core, Flags::o(0)).unwrap())
+                .collect();
+            assert_eq!(alloc.reservation_spread(), spread, "c={cores} t={trees}");
+            assert_eq!(alloc.rebalance(), 0);
+
+            for frame in frames {
+                alloc.put(0, frame, Flags::o(0)).unwrap();
+            }
+            alloc.validate();
+        }
+    }
+
+    #[cfg(all(feature = "counters", not(feature = "llc")))]
+    #[test]
+    fn counters() {
+        logging();
+
+        const THREADS: usize = 4;
+        const FRAMES: usize = 16 * TREE_FRAMES;
+        const ALLOCS: usize = TREE_FRAMES / 2;
+
+        let meta = MetaData::alloc(LLFree::metadata_size(THREADS, FRAMES));
+        let alloc = LLFree::new(THREADS, FRAMES, Init::FreeAll, meta).unwrap();
+
+        // Exact counts on a single core
+        let frames: Vec<_> = (0..3).map(|_| alloc.get(0, Flags::o(0)).unwrap()).collect();
+        let huge = alloc.get(0, Flags::o(HUGE_ORDER)).unwrap();
+        alloc.put(0, huge, Flags::o(HUGE_ORDER)).unwrap();
+        alloc.put(0, frames[1], Flags::o(0)).unwrap();
+        let counters = alloc.counters(0);
+        assert_eq!(counters.allocs[0], 3);
+        assert_eq!(counters.allocs[HUGE_ORDER], 1);
+        assert_eq!(counters.frees[0], 1);
+        assert_eq!(counters.frees[HUGE_ORDER], 1);
+        assert_eq!(counters.failed, [0; MAX_ORDER + 1]);
+        assert_eq!(counters.reverts, [0; MAX_ORDER + 1]);
+        assert_eq!(alloc.counters_total(), counters);
+        assert_eq!(alloc.counters(1), EventCounts::default());
+
+        thread::parallel(0..THREADS, |t| {
+            thread::try_pin(t);
+            let mut rng = WyRand::new(t as _);
+            let mut frames = Vec::new();
+            for _ in 0..ALLOCS {
+                let order = [0, 0, 1, 3][rng.range(0..4) as usize];
+                frames.push((alloc.get(t, Flags::o(order)).unwrap(), order));
+            }
+            for (frame, order) in frames {
+                alloc.put(t, frame, Flags::o(order)).unwrap();
+            }
+        });
+
+        let total = alloc.counters_total();
+        let mut sum = EventCounts::default();
+        for t in 0..THREADS {
+            let counters = alloc.counters(t);
+            assert_eq!(
+                counters.allocs.iter().sum::<usize>(),
+                ALLOCS + 4 * (t == 0) as usize
+            );
+            sum += &counters;
+        }
+        assert_eq!(sum, total);
+        assert_eq!(total.allocs.iter().sum::<usize>(), THREADS * ALLOCS + 4);
+        // Every allocation is freed, except for two of the first
+        for order in 0..=MAX_ORDER {
+            let remaining = if order == 0 { 2 } else { 0 };
+            assert_eq!(total.allocs[order], total.frees[order] + remaining);
+        }
+    }
+
+    #[cfg(all(feature = "latency", not(feature = "llc")))]
+    #[test]
+    fn latency() {
+        use core::sync::atomic::AtomicU64;
+        use core::sync::atomic::Ordering::Relaxed;
+
+        use crate::latency::{bucket, order_class, ORDER_CLASSES};
+
+        logging();
+
+        const THREADS: usize = 4;
+        const FRAMES: usize = 16 * TREE_FRAMES;
+        const ALLOCS: usize = TREE_FRAMES / 2;
+        const ORDERS: [usize; 4] = [0, 3, 8, MAX_ORDER];
+
+        let meta = MetaData::alloc(LLFree::metadata_size(THREADS, FRAMES));
+        let mut alloc = LLFree::new(THREADS, FRAMES, Init::FreeAll, meta).unwrap();
+
+        // Contended allocations of all order classes
+        let counts = thread::parallel(0..THREADS, |t| {
+            thread::try_pin(t);
+            let mut rng = WyRand::new(t as _);
+            let mut counts = [0; ORDER_CLASSES];
+            let mut frames = Vec::new();
+            for i in 0..ALLOCS {
+                let order = ORDERS[rng.range(0..4) as usize];
+                let Ok(frame) = alloc.get(t, Flags::o(order)) else {
+                    continue;
+                };
+                counts[order_class(order)] += 1;
+                frames.push((frame, order));
+                if i % 2 == 0 {
+                    let (frame, order) = frames.swap_remove(rng.range(0..frames.len() as _) as _);
+                    alloc.put(t, frame, Flags::o(order)).unwrap();
+                }
+            }
+            for (frame, order) in frames {
+                alloc.put(t, frame, Flags::o(order)).unwrap();
+            }
+            counts
+        });
+
+        let histograms = alloc.histograms().unwrap();
+        for class in 0..ORDER_CLASSES {
+            let count = counts.iter().map(|c| c[class]).sum::<usize>();
+            assert!(count > 0);
+            assert_eq!(histograms.count(class), count);
+            assert_eq!(histograms.latency[class].iter().sum::<usize>(), count);
+            assert!(histograms.latency_sum[class] > 0);
+        }
+        warn!("{histograms:?}");
+
+        // A custom clock that advances by 100ns per call
+        static CLOCK: AtomicU64 = AtomicU64::new(0);
+        alloc.latency.set_clock(|| CLOCK.fetch_add(100, Relaxed));
+        alloc.latency.reset();
+        alloc.get(0, Flags::o(0)).unwrap();
+        alloc.get(0, Flags::o(HUGE_ORDER)).unwrap();
+        let histograms = alloc.histograms().unwrap();
+        assert_eq!(histograms.latency[0][bucket(100)], 1);
+        assert_eq!(histograms.latency[2][bucket(100)], 1);
+        assert_eq!(histograms.latency_sum, [100, 0, 100, 0]);
+        assert_eq!(histograms.retries[0][0], 1);
+        assert_eq!(histograms.retries_sum, [0; ORDER_CLASSES]);
+    }
+
+    /// The latency measurements are compiled out without the feature
+    #[test]
+    fn latency_symbols() {
+        let exe = std::fs::read(std::env::current_exe().unwrap()).unwrap();
+        // Reversed, so that the mangled module path is not part of the test binary itself
+        let symbol: Vec<u8> = b"ycnetal7eerfll6".iter().rev().copied().collect();
+        let found = exe.windows(symbol.len()).any(|w| w == symbol);
+        assert_eq!(found, cfg!(feature = "latency"));
+    }
+
+    /// Cores prefer the trees of their share of the memory
+    #[cfg(not(feature = "llc"))]
+    #[test]
+    fn numa_penalty() {
+        logging();
+
+        const FRAMES: usize = 16 * TREE_FRAMES;
+        const CORES: usize = 4;
+
+        let meta = MetaData::alloc(LLFree::metadata_size(CORES, FRAMES));
+        let mut alloc = LLFree::new(CORES, FRAMES, Init::FreeAll, meta).unwrap();
+        alloc.set_numa_penalty(256);
+
+        let share = FRAMES / CORES;
+        for core in 0..CORES {
+            // Fill more than a tree
+            for _ in 0..TREE_FRAMES + 1 {
+                let frame = alloc.get(core, Flags::o(0)).unwrap();
+                assert_eq!(frame / share, core, "{frame}");
+            }
+        }
+        alloc.validate();
+    }
+
+    /// The placement is reproducible with the same seed and differs otherwise
+    #[cfg(not(feature = "llc"))]
+    #[test]
+    fn randomized() {
+        logging();
+
+        const FRAMES: usize = 16 * TREE_FRAMES;
+        const CORES: usize = 4;
+        const N: usize = 256;
+
+        let trace = |seed: Option<u64>| {
+            let meta = MetaData::alloc(LLFree::metadata_size(CORES, FRAMES));
+            let mut alloc = LLFree::new(CORES, FRAMES, Init::FreeAll, meta).unwrap();
+            alloc.set_randomized(seed);
+
+            let mut rng = WyRand::new(7);
+            let mut frames = Vec::new();
+            for i in 0..N {
+                let order = [0, 0, 1, 3, HUGE_ORDER][rng.range(0..5) as usize];
+                let frame = alloc.get(i % CORES, Flags::o(order)).unwrap();
+                assert_eq!(frame % (1 << order), 0);
+                frames.push((frame, order));
+            }
+            alloc.validate();
+            assert_eq!(
+                alloc.allocated_frames(),
+                frames.iter().map(|(_, o)| 1 << o).sum::<usize>()
+            );
+            for (i, (frame, order)) in frames.iter().enumerate() {
+                alloc.put(i % CORES, *frame, Flags::o(*order)).unwrap();
+            }
+            assert_eq!(alloc.free_frames(), FRAMES);
+            alloc.validate();
+            frames.into_iter().map(|(f, _)| f).collect::<Vec<_>>()
+        };
+
+        let a = trace(Some(1));
+        assert!(a == trace(Some(1)));
+
+        // Only a few frames are in the same place
+        let b = trace(Some(2));
+        let same = a.iter().zip(&b).filter(|(a, b)| a == b).count();
+        assert!(same < N / 8, "{same} of {N}");
+        let sequential = trace(None);
+        let same = a.iter().zip(&sequential).filter(|(a, b)| a == b).count();
+        assert!(same < N / 8, "{same} of {N}");
+
+        // Not just shifted, small frames are not adjacent
+        let adjacent = a.windows(2).filter(|w| w[1] == w[0] + 1).count();
+        assert!(adjacent < N / 8, "{adjacent} of {N}");
+    }
+
+    /// Ranges that are already in use during boot
+    #[cfg(not(feature = "llc"))]
+    #[test]
+    fn init_carve_outs() {
+        logging();
+
+        const FRAMES: usize = 4 * TREE_FRAMES;
+        let reserved = [
+            // Within a bitfield word
+            3..5,
+            // Across bitfield words and huge frames
+            60..HUGE_FRAMES + 70,
+            // Entire huge frames across a tree boundary
+            TREE_FRAMES - 2 * HUGE_FRAMES..TREE_FRAMES + HUGE_FRAMES,
+            // Across a tree boundary, partially overlapping
+            2 * TREE_FRAMES - 7..2 * TREE_FRAMES + 9,
+            2 * TREE_FRAMES..2 * TREE_FRAMES + 20,
+            // Up to the end
+            FRAMES - 1..FRAMES,
+        ];
+        let total = 2 + (HUGE_FRAMES + 10) + 3 * HUGE_FRAMES + 27 + 1;
+        let is_reserved = |f: usize| reserved.iter().any(|r| r.contains(&f));
+
+        let meta = MetaData::alloc(LLFree::metadata_size(1, FRAMES));
+        let alloc = LLFree::with_reserved(1, FRAMES, &reserved, meta).unwrap();
+        assert_eq!(alloc.allocated_frames(), total);
         alloc.validate();
+        for range in &reserved {
+            assert!(range.clone().all(|f| !alloc.is_free(f, 0)));
+        }
+        assert!(!alloc.is_free(TREE_FRAMES - 2 * HUGE_FRAMES, HUGE_ORDER));
 
-        frames.sort_unstable();
+        // Allocations never land inside them
+        let mut frames = Vec::new();
+        while let Ok(frame) = alloc.get(0, Flags::o(0)) {
+            assert!(!is_reserved(frame), "{frame}");
+            frames.push(frame);
+        }
+        assert_eq!(frames.len(), FRAMES - total);
+        for frame in frames {
+            alloc.put(0, frame, Flags::o(0)).unwrap();
+        }
 
-        // Check that the same frame was not allocated twice
-        for &[a, b] in frames.array_windows() {
-            assert_ne!(a, b);
-            assert!(a < FRAMES && b < FRAMES);
+        // Released carve-outs can be allocated
+        for frame in reserved[1].clone() {
+            alloc.put(0, frame, Flags::o(0)).unwrap();
+        }
+        alloc
+            .put(0, TREE_FRAMES - 2 * HUGE_FRAMES, Flags::o(HUGE_ORDER))
+            .unwrap();
+        for frame in (TREE_FRAMES - HUGE_FRAMES..TREE_FRAMES + HUGE_FRAMES).step_by(2) {
+            alloc.put(0, frame, Flags::o(1)).unwrap();
+        }
+        assert_eq!(alloc.allocated_frames(), 2 + 27 + 1);
+        alloc.validate();
+        assert!(alloc.is_free(TREE_FRAMES - 2 * HUGE_FRAMES, HUGE_ORDER));
+        assert!(alloc.is_free(TREE_FRAMES, HUGE_ORDER));
+        let mut frames = 0;
+        while let Ok(frame) = alloc.get(0, Flags::o(0)) {
+            assert!(!is_reserved(frame) || reserved[1..3].iter().any(|r| r.contains(&frame)));
+            frames += 1;
         }
+        assert_eq!(frames, FRAMES - (2 + 27 + 1));
 
-        warn!("free some...");
+        // Out of range
+        let meta = MetaData::alloc(LLFree::metadata_size(1, FRAMES));
+        assert!(LLFree::with_reserved(1, FRAMES, &[0..1, FRAMES - 1..FRAMES + 1], meta).is_err());
+    }
 
-        // Free some
-        for frame in &frames[10..HUGE_FRAMES + 10] {
-            alloc.put(0, *frame, Flags::o(0)).unwrap();
+    /// Frees to a last tree that is smaller than [TREE_FRAMES], after syncing with it
+    #[cfg(not(feature = "llc"))]
+    #[test]
+    fn last_tree_sync() {
+        logging();
+
+        const FRAMES: usize = TREE_FRAMES + 7;
+        let meta = MetaData::alloc(LLFree::metadata_size(2, FRAMES));
+        let alloc = LLFree::new(2, FRAMES, Init::FreeAll, meta).unwrap();
+        assert_eq!(alloc.trees.max(1), (7, 0));
+
+        // Core 0 ends up with the last tree
+        let mut frames = Vec::new();
+        while let Ok(frame) = alloc.get(0, Flags::o(0)) {
+            frames.push(frame);
+        }
+        assert_eq!(frames.len(), FRAMES);
+        let last: Vec<_> = frames
+            .iter()
+            .copied()
+            .filter(|f| *f >= TREE_FRAMES)
+            .collect();
+        assert_eq!(last.len(), 7);
+
+        // Frees from another core go to the global counter
+        for &frame in &last[..4] {
+            alloc.put(1, frame, Flags::o(0)).unwrap();
+        }
+        // Core 0 syncs with it
+        for _ in 0..4 {
+            let frame = alloc.get(0, Flags::o(0)).unwrap();
+            assert!(frame >= TREE_FRAMES);
+        }
+        // And frees all of them into its local tree
+        for &frame in &last {
+            alloc.put(0, frame, Flags::o(0)).unwrap();
+        }
+        alloc.drain(0).unwrap();
+        alloc.drain(1).unwrap();
+        assert!(!alloc.trees.corrupted());
+        assert_eq!(alloc.trees.get(1).free(), 7);
+
+        for frame in frames.into_iter().filter(|f| *f < TREE_FRAMES) {
+            alloc.put(0, frame, Flags::o(0)).unwrap();
         }
         alloc.validate();
+        alloc.check().unwrap();
+        assert_eq!(alloc.free_frames(), FRAMES);
+    }
 
-        warn!("free special...");
+    /// Leak checks while another thread allocates and frees frames
+    #[cfg(not(feature = "llc"))]
+    #[test]
+    fn allocated_bounded() {
+        use core::sync::atomic::{AtomicBool, AtomicUsize, Ordering};
 
-        alloc.put(0, small, Flags::o(0)).unwrap();
-        alloc.put(0, huge, Flags::o(9)).unwrap();
+        logging();
+
+        const FRAMES: usize = 8 * TREE_FRAMES;
+        let alloc = Allocator::create(2, FRAMES, Init::FreeAll).unwrap();
+        assert_eq!(alloc.allocated_frames_bounded(), (0, 0));
+
+        // Incremented after each get and decremented before each put
+        let allocated = AtomicUsize::new(0);
+        // Incremented when switching between allocating and freeing
+        let phase = AtomicUsize::new(0);
+        let done = AtomicBool::new(false);
+        std::thread::scope(|s| {
+            s.spawn(|| {
+                let mut frames = Vec::new();
+                for round in 0..16 {
+                    let order = round % 3;
+                    while let Ok(frame) = alloc.get(0, Flags::o(order)) {
+                        allocated.fetch_add(1 << order, Ordering::SeqCst);
+                        frames.push(frame);
+                    }
+                    phase.fetch_add(1, Ordering::SeqCst);
+                    for frame in frames.drain(..) {
+                        allocated.fetch_sub(1 << order, Ordering::SeqCst);
+                        alloc.put(0, frame, Flags::o(order)).unwrap();
+                    }
+                    phase.fetch_add(1, Ordering::SeqCst);
+                }
+                done.store(true, Ordering::SeqCst);
+            });
+            s.spawn(|| {
+                let mut checks = 0;
+                while !done.load(Ordering::SeqCst) {
+                    let start = phase.load(Ordering::SeqCst);
+                    let before = allocated.load(Ordering::SeqCst);
+                    let (lower, upper) = alloc.allocated_frames_bounded();
+                    let after = allocated.load(Ordering::SeqCst);
+                    // Only monotonic if the phase did not change
+                    if phase.load(Ordering::SeqCst) != start {
+                        continue;
+                    }
+                    assert!(
+                        lower <= before.max(after) && before.min(after) <= upper,
+                        "{before}..{after} not in {lower}..{upper}"
+                    );
+                    checks += 1;
+                }
+                warn!("checks {checks}");
+            });
+        });
+
+        assert_eq!(alloc.allocated_frames_bounded(), (0, 0));
         alloc.validate();
+    }
 
-        warn!("realloc...");
+    /// High-water mark of allocated frames with concurrent allocations and frees
+    #[cfg(not(feature = "llc"))]
+    #[test]
+    fn peak_allocated() {
+        use core::sync::atomic::{AtomicUsize, Ordering};
 
-        // Realloc
-        for frame in &mut frames[10..HUGE_FRAMES + 10] {
-            *frame = alloc.get(0, Flags::o(0)).unwrap();
-        }
+        logging();
+
+        const THREADS: usize = 4;
+        const FRAMES: usize = 4 * THREADS * TREE_FRAMES;
+        // Reserved trees of all kinds and cores, and one in-flight get per thread
+        const SLACK: usize = THREADS * 3 * TREE_FRAMES + THREADS;
+
+        let alloc = Allocator::create(THREADS, FRAMES, Init::FreeAll).unwrap();
+        assert_eq!(alloc.dbg_peak_allocated(), 0);
+
+        let allocated = AtomicUsize::new(0);
+        let peak = AtomicUsize::new(0);
+        thread::parallel(0..THREADS, |t| {
+            thread::try_pin(t);
+            let mut rng = WyRand::new(t as _);
+            let mut frames = Vec::new();
+            for _ in 0..4 {
+                for _ in 0..TREE_FRAMES / 2 {
+                    frames.push(alloc.get(t, Flags::o(0)).unwrap());
+                    let prev = allocated.fetch_add(1, Ordering::SeqCst);
+                    peak.fetch_max(prev + 1, Ordering::SeqCst);
+                }
+                rng.shuffle(&mut frames);
+                for frame in frames.drain(..frames.len() / 2) {
+                    allocated.fetch_sub(1, Ordering::SeqCst);
+                    alloc.put(t, frame, Flags::o(0)).unwrap();
+                }
+            }
+            for frame in frames {
+                allocated.fetch_sub(1, Ordering::SeqCst);
+                alloc.put(t, frame, Flags::o(0)).unwrap();
+            }
+        });
+
+        let peak = peak.load(Ordering::SeqCst);
+        let reported = alloc.dbg_peak_allocated();
+        warn!("peak {peak} reported {reported}");
+        assert!(
+            peak <= reported && reported <= peak + SLACK,
+            "{peak} {reported}"
+        );
+
+        // The peak is monotonic until reset
+        assert_eq!(alloc.allocated_frames(), 0);
+        assert_eq!(alloc.dbg_peak_allocated(), reported);
+        alloc.drain_all().unwrap();
+        alloc.reset_peak();
+        assert_eq!(alloc.dbg_peak_allocated(), 0);
+
+        let frame = alloc.get(0, Flags::o(0)).unwrap();
+        assert!((1..=TREE_FRAMES).contains(&alloc.dbg_peak_allocated()));
+        alloc.put(0, frame, Flags::o(0)).unwrap();
         alloc.validate();
+    }
 
-        warn!("free...");
-        // Free all
-        for frame in &frames {
+    /// Failing allocations distinguish fragmentation from exhaustion
+    #[cfg(not(feature = "llc"))]
+    #[test]
+    fn fragmented() {
+        logging();
+
+        const FRAMES: usize = 2 * TREE_FRAMES;
+        let alloc = Allocator::create(1, FRAMES, Init::FreeAll).unwrap();
+
+        // Alternating frees leave half of the frames free, but no huge frame
+        let mut frames = Vec::new();
+        while let Ok(frame) = alloc.get(0, Flags::o(0)) {
+            frames.push(frame);
+        }
+        assert_eq!(alloc.get(0, Flags::o(0)), Err(Error::Memory));
+        assert_eq!(alloc.get(0, Flags::o(HUGE_ORDER)), Err(Error::Memory));
+        frames.sort_unstable();
+        for frame in frames.iter().step_by(2) {
             alloc.put(0, *frame, Flags::o(0)).unwrap();
         }
+        assert_eq!(alloc.free_frames(), FRAMES / 2);
+        assert_eq!(alloc.get(0, Flags::o(HUGE_ORDER)), Err(Error::Fragmented));
+        assert_eq!(alloc.get(0, Flags::o(1)), Err(Error::Fragmented));
+
+        // Base frames are never fragmented
+        let mut rest = Vec::new();
+        while let Ok(frame) = alloc.get(0, Flags::o(0)) {
+            rest.push(frame);
+        }
+        assert_eq!(rest.len(), FRAMES / 2);
+        assert_eq!(alloc.get(0, Flags::o(0)), Err(Error::Memory));
+        assert_eq!(alloc.get(0, Flags::o(1)), Err(Error::Memory));
 
-        assert_eq!(alloc.allocated_frames(), 0);
-        assert_eq!(alloc.free_huge(), FRAMES / HUGE_FRAMES);
+        for frame in frames.into_iter().skip(1).step_by(2).chain(rest) {
+            alloc.put(0, frame, Flags::o(0)).unwrap();
+        }
+        assert_eq!(alloc.free_frames(), FRAMES);
         alloc.validate();
     }
 
+    /// Multiple threads using the same core-local data
     #[test]
-    fn parallel_alloc() {
+    fn shared_core() {
         logging();
 
         const THREADS: usize = 4;
-        const ALLOC_PER_THREAD: usize = HUGE_FRAMES * (HUGE_FRAMES - 2 * THREADS);
-        const FRAMES: usize = 2 * THREADS * HUGE_FRAMES * HUGE_FRAMES;
+        const ALLOC_PER_THREAD: usize = 4 * TREE_FRAMES;
 
-        let alloc = Allocator::create(THREADS, FRAMES, Init::FreeAll).unwrap();
+        let alloc = Allocator::create(1, 2 * THREADS * ALLOC_PER_THREAD, Init::FreeAll).unwrap();
 
-        // Stress test
         let mut frames = vec![0; ALLOC_PER_THREAD * THREADS];
         let barrier = Barrier::new(THREADS);
-        let timer = Instant::now();
-
         thread::parallel(
             frames.chunks_mut(ALLOC_PER_THREAD).enumerate(),
             |(t, frames)| {
-                thread::pin(t);
                 barrier.wait();
-
-                for frame in frames {
-                    *frame = alloc.get(t, Flags::o(0)).unwrap();
+                // Mixing gets and puts on the same core-local data
+                for chunk in frames.chunks_mut(8) {
+                    for frame in chunk.iter_mut() {
+                        *frame = alloc.get(t * 7, Flags::o(0)).unwrap();
+                    }
+                    alloc.put(0, chunk[0], Flags::o(0)).unwrap();
+                    chunk[0] = alloc.get(0, Flags::o(0)).unwrap();
                 }
             },
         );
-        warn!("Allocation finished in {}ms", timer.elapsed().as_millis());
-
         assert_eq!(alloc.allocated_frames(), frames.len());
-        warn!("allocated frames: {}", frames.len());
         alloc.validate();
 
-        // Check that the same frame was not allocated twice
         frames.sort_unstable();
         for &[a, b] in frames.array_windows() {
             assert_ne!(a, b);
-            assert!(a < FRAMES && b < FRAMES);
         }
     }
 
+    /// Stealing reserved trees from other cores when running out of memory
+    #[test]
+    fn steal() {
+        logging();
+
+        let alloc = Allocator::create(2, 2 * TREE_FRAMES, Init::FreeAll).unwrap();
+        // Both cores reserve a tree
+        let first = alloc.get(0, Flags::o(0)).unwrap();
+        let mut frames = vec![first, alloc.get(1, Flags::o(0)).unwrap()];
+        assert_ne!(first / TREE_FRAMES, frames[1] / TREE_FRAMES);
+
+        // Core 1 takes over the tree of core 0 after its own one is full
+        #[cfg(feature = "search_stats")]
+        let stolen = crate::llfree::STOLEN.load(core::sync::atomic::Ordering::Relaxed);
+        for _ in 2..2 * TREE_FRAMES {
+            frames.push(alloc.get(1, Flags::o(0)).unwrap());
+        }
+        #[cfg(all(feature = "search_stats", not(feature = "llc")))]
+        assert!(crate::llfree::STOLEN.load(core::sync::atomic::Ordering::Relaxed) > stolen);
+        assert_eq!(alloc.free_frames(), 0);
+        assert_eq!(alloc.get(0, Flags::o(0)), Err(Error::Memory));
+        alloc.validate();
+
+        for frame in frames {
+            alloc.put(0, frame, Flags::o(0)).unwrap();
+        }
+        assert_eq!(alloc.free_frames(), 2 * TREE_FRAMES);
+        alloc.validate();
+    }
+
+    /// More cores than local data, which are assigned in blocks or explicitly
+    #[cfg(not(feature = "llc"))]
+    #[test]
+    fn core_map() {
+        logging();
+
+        const FRAMES: usize = 2 * TREE_FRAMES;
+        let meta = || MetaData::alloc(LLFree::metadata_size(4, FRAMES));
+
+        // Cores 0 and 1 share the first, 2 and 3 the second local data
+        let alloc = LLFree::new(4, FRAMES, Init::FreeAll, meta()).unwrap();
+        assert_eq!(alloc.cores(), 2);
+        let a = alloc.get(0, Flags::o(0)).unwrap();
+        let b = alloc.get(1, Flags::o(0)).unwrap();
+        let c = alloc.get(2, Flags::o(0)).unwrap();
+        assert_eq!(a / TREE_FRAMES, b / TREE_FRAMES);
+        assert_ne!(a / TREE_FRAMES, c / TREE_FRAMES);
+        assert_eq!(alloc.foreign_ops(), 1);
+        alloc.put(3, c, Flags::o(0)).unwrap();
+        assert_eq!(alloc.foreign_ops(), 2);
+        alloc.put(0, a, Flags::o(0)).unwrap();
+        assert_eq!(alloc.foreign_ops(), 2);
+
+        // Cores 0, 1 and 3 share the first local data
+        let map = [0, 0, 1, 0];
+        let alloc = LLFree::with_core_map(&map, FRAMES, Init::FreeAll, meta()).unwrap();
+        let a = alloc.get(3, Flags::o(0)).unwrap();
+        let b = alloc.get(2, Flags::o(0)).unwrap();
+        assert_ne!(a / TREE_FRAMES, b / TREE_FRAMES);
+        assert_eq!(alloc.foreign_ops(), 1);
+        let c = alloc.get(0, Flags::o(0)).unwrap();
+        assert_eq!(a / TREE_FRAMES, c / TREE_FRAMES);
+        assert_eq!(alloc.foreign_ops(), 1);
+
+        // Invalid local data index or no cores
+        for map in [&[0, 2, 1, 1][..], &[]] {
+            let alloc = LLFree::with_core_map(map, FRAMES, Init::FreeAll, meta());
+            assert_eq!(alloc.err(), Some(Error::Initialization));
+        }
+    }
+
+    /// Interleaved orders with start hints over multiple trees
+    #[test]
+    fn interleaved_orders() {
+        logging();
+
+        let alloc = Allocator::create(1, 4 * TREE_FRAMES, Init::FreeAll).unwrap();
+        let mut frames = Vec::new();
+        for order in [0, 6, 3, 0, 8, 1].into_iter().cycle() {
+            match alloc.get(0, Flags::o(order)) {
+                Ok(frame) => {
+                    assert!(frame % (1 << order) == 0 && frame < alloc.frames());
+                    frames.push((frame, order));
+                }
+                Err(Error::Memory | Error::Fragmented) => break,
+                Err(e) => panic!("{e:?}"),
+            }
+        }
+        let allocated = frames.iter().map(|(_, o)| 1 << o).sum::<usize>();
+        assert_eq!(alloc.allocated_frames(), allocated);
+        alloc.validate();
+
+        for (frame, order) in frames {
+            alloc.put(0, frame, Flags::o(order)).unwrap();
+        }
+        assert_eq!(alloc.free_frames(), alloc.frames());
+    }
+
     #[test]
     fn alloc_all() {
         logging();
@@ -668,7 +2489,7 @@ mod test {
         let timer = Instant::now();
 
         thread::parallel(frames.iter_mut().enumerate(), |(t, frames)| {
-            thread::pin(t);
+            thread::try_pin(t);
             barrier.wait();
 
             loop {
@@ -714,7 +2535,7 @@ mod test {
         thread::parallel(
             frames.chunks_mut(ALLOC_PER_THREAD).enumerate(),
             |(t, frames)| {
-                thread::pin(t);
+                thread::try_pin(t);
                 barrier.wait();
 
                 for (i, frame) in frames.iter_mut().enumerate() {
@@ -743,7 +2564,7 @@ mod test {
         thread::parallel(
             frames.chunks(ALLOC_PER_THREAD).enumerate(),
             |(t, frames)| {
-                thread::pin(t);
+                thread::try_pin(t);
                 barrier.wait();
 
                 for frame in frames {
@@ -775,7 +2596,7 @@ mod test {
         thread::parallel(
             frames.chunks_mut(ALLOC_PER_THREAD).enumerate(),
             |(t, frames)| {
-                thread::pin(t);
+                thread::try_pin(t);
                 barrier.wait();
 
                 for frame in frames {
@@ -818,7 +2639,7 @@ mod test {
         thread::parallel(
             frames.chunks_mut(ALLOC_PER_THREAD).enumerate(),
             |(t, frames)| {
-                thread::pin(t);
+                thread::try_pin(t);
                 barrier.wait();
 
                 for frame in frames {
@@ -858,7 +2679,7 @@ mod test {
         thread::parallel(
             frames.chunks_mut(ALLOC_PER_THREAD).enumerate(),
             |(t, frames)| {
-                thread::pin(t);
+                thread::try_pin(t);
                 barrier.wait();
 
                 for frame in frames {
@@ -890,43 +2711,6 @@ mod test {
         }
     }
 
-    #[test]
-    fn parallel_free() {
-        logging();
-
-        const THREADS: usize = 4;
-        const ALLOC_PER_THREAD: usize = HUGE_FRAMES * (HUGE_FRAMES - 2 * THREADS);
-        const MEM_SIZE: usize = 2 * THREADS * HUGE_FRAMES * HUGE_FRAMES * Frame::SIZE;
-
-        let area = MEM_SIZE / Frame::SIZE;
-
-        let alloc = Allocator::create(THREADS, area, Init::FreeAll).unwrap();
-        let barrier = Barrier::new(THREADS);
-
-        // Stress test
-        thread::parallel(0..THREADS, |t| {
-            thread::pin(t);
-            barrier.wait();
-
-            let mut frames = vec![0; ALLOC_PER_THREAD];
-
-            for frame in &mut frames {
-                *frame = alloc.get(t, Flags::o(0)).unwrap();
-            }
-
-            let mut rng = WyRand::new(t as _);
-            rng.shuffle(&mut frames);
-
-            for frame in frames {
-                alloc.put(t, frame, Flags::o(0)).unwrap();
-            }
-        });
-
-        warn!("check");
-        assert_eq!(alloc.allocated_frames(), 0);
-        alloc.validate();
-    }
-
     #[test]
     fn alloc_free() {
         logging();
@@ -938,7 +2722,7 @@ mod test {
         warn!("{alloc:?}");
 
         // Alloc on first thread
-        thread::pin(0);
+        thread::try_pin(0);
         let mut frames = vec![0; ALLOC_PER_THREAD];
         for frame in &mut frames {
             *frame = alloc.get(0, Flags::o(0)).unwrap();
@@ -948,7 +2732,7 @@ mod test {
         let barrier = Barrier::new(THREADS);
         std::thread::scope(|s| {
             s.spawn(|| {
-                thread::pin(1);
+                thread::try_pin(1);
                 barrier.wait();
                 // Free on another thread
                 for frame in &frames {
@@ -964,15 +2748,105 @@ mod test {
             for frame in &mut frames {
                 *frame = alloc.get(0, Flags::o(0)).unwrap();
             }
-        });
+        });
+
+        warn!("check {alloc:?}");
+        assert_eq!(alloc.allocated_frames(), ALLOC_PER_THREAD);
+        alloc.validate();
+    }
+
+    /// Recovery with one or both metadata copies corrupted by torn writes
+    #[test]
+    fn recover_torn_meta() {
+        #[cfg(feature = "llc")]
+        type Allocator<'a> = NvmAlloc<'a, LLC>;
+        #[cfg(not(feature = "llc"))]
+        type Allocator<'a> = NvmAlloc<'a, LLFree<'a>>;
+
+        logging();
+
+        const FRAMES: usize = 8 * TREE_FRAMES;
+
+        let mut zone = mmap::anon(0x1200_0000_0000, FRAMES, false, false);
+        let m = Allocator::metadata_size(1, FRAMES);
+        // The copies are stored before and after the lower allocator
+        let copies = [FRAMES - 2 - m.lower.div_ceil(Frame::SIZE), FRAMES - 1];
+
+        {
+            let local = aligned_buf(m.local).leak();
+            let trees = aligned_buf(m.trees).leak();
+            let alloc = Allocator::create(1, &mut zone, 0, false, local, trees).unwrap();
+            alloc.get(0, Flags::o(0)).unwrap();
+            // leak (crash)
+            std::mem::forget(alloc);
+        }
+
+        for copy in copies {
+            // Corrupt the frame count of a single copy
+            zone[copy].cast_mut::<[usize; 2]>()[1] ^= 1;
+
+            let local = aligned_buf(m.local).leak();
+            let trees = aligned_buf(m.trees).leak();
+            let alloc = Allocator::create(1, &mut zone, 0, true, local, trees).unwrap();
+            assert_eq!(alloc.allocated_frames(), 1);
+            alloc.validate();
+            std::mem::forget(alloc);
+        }
+
+        // Corrupting both copies makes the instance unrecoverable, it is initialized from scratch
+        for copy in copies {
+            zone[copy].cast_mut::<[usize; 2]>()[1] ^= 1;
+        }
+        let local = aligned_buf(m.local).leak();
+        let trees = aligned_buf(m.trees).leak();
+        let alloc = Allocator::create(1, &mut zone, 0, true, local, trees).unwrap();
+        assert!(alloc.init() == Init::FreeAll);
+        assert_eq!(alloc.allocated_frames(), 0);
+        recoverable(&alloc);
+    }
+
+    /// A clean shutdown with reservations on several cores is recovered without a deep recovery
+    #[test]
+    fn recover_clean_shutdown() {
+        #[cfg(feature = "llc")]
+        type Allocator<'a> = NvmAlloc<'a, LLC>;
+        #[cfg(not(feature = "llc"))]
+        type Allocator<'a> = NvmAlloc<'a, LLFree<'a>>;
+
+        logging();
+
+        const FRAMES: usize = 8 * TREE_FRAMES;
+        const CORES: usize = 4;
+
+        let mut zone = mmap::anon(0x1800_0000_0000, FRAMES, false, false);
+        let m = Allocator::metadata_size(CORES, FRAMES);
+
+        let allocated = {
+            let local = aligned_buf(m.local).leak();
+            let trees = aligned_buf(m.trees).leak();
+            let alloc = Allocator::create(CORES, &mut zone, 0, false, local, trees).unwrap();
+            assert!(alloc.init() == Init::FreeAll);
+            for core in 0..CORES {
+                alloc.get(core, Flags::o(0)).unwrap();
+                alloc.get(core, Flags::o(HUGE_ORDER)).unwrap();
+            }
+            alloc.allocated_frames()
+            // Dropping returns the reservations and marks the shutdown as clean
+        };
+        assert_eq!(allocated, CORES * (1 + HUGE_FRAMES));
 
-        warn!("check {alloc:?}");
-        assert_eq!(alloc.allocated_frames(), ALLOC_PER_THREAD);
+        let local = aligned_buf(m.local).leak();
+        let trees = aligned_buf(m.trees).leak();
+        let alloc = Allocator::create(CORES, &mut zone, 0, true, local, trees).unwrap();
+        assert!(alloc.init() == Init::Recover(false));
+        assert_eq!(alloc.allocated_frames(), allocated);
         alloc.validate();
+        recoverable(&alloc);
     }
 
+    /// The reserved head frames are never allocated and have to match on recovery
     #[test]
-    fn recover() {
+    fn recover_reserved_head() {
         #[cfg(feature = "llc")]
         type Allocator<'a> = NvmAlloc<'a, LLC>;
         #[cfg(not(feature = "llc"))]
@@ -980,139 +2854,346 @@ mod test {
 
         logging();
 
-        const FRAMES: usize = 8 << 18;
-
-        thread::pin(0);
-
-        let expected_frames = (HUGE_FRAMES + 2) * (1 + (1 << 9));
+        const FRAMES: usize = 8 * TREE_FRAMES;
+        const RESERVED: usize = 3;
 
-        let mut zone = mmap::anon(0x1000_0000_0000, FRAMES, false, false);
+        let mut zone = mmap::anon(0x1900_0000_0000, FRAMES, false, false);
+        let base = zone.as_ptr() as usize / Frame::SIZE;
         let m = Allocator::metadata_size(1, FRAMES);
-        let local = aligned_buf(m.local).leak();
-        let trees = aligned_buf(m.trees).leak();
 
-        {
-            let alloc = Allocator::create(1, &mut zone, false, local, trees).unwrap();
+        let allocated = {
+            let local = aligned_buf(m.local).leak();
+            let trees = aligned_buf(m.trees).leak();
+            let alloc = Allocator::create(1, &mut zone, RESERVED, false, local, trees).unwrap();
+            assert_eq!(alloc.reserved_frames(), 1 << MAX_ORDER);
+            assert!(alloc.frames() < FRAMES - alloc.reserved_frames());
+            let head = base..base + alloc.reserved_frames();
 
-            for _ in 0..HUGE_FRAMES + 2 {
-                alloc.get(0, Flags::o(0)).unwrap();
-                alloc.get(0, Flags::o(9)).unwrap();
+            // Allocate everything, nothing lands in the head
+            let mut frames = Vec::new();
+            while let Ok(frame) = alloc.get(0, Flags::o(0)) {
+                assert!(!head.contains(&frame), "{frame}");
+                frames.push(frame);
             }
-
-            assert_eq!(alloc.allocated_frames(), expected_frames);
-            alloc.validate();
-
-            // leak (crash)
-            std::mem::forget(alloc);
+            assert_eq!(frames.len(), alloc.frames());
+            assert_eq!(alloc.put(0, base, Flags::o(0)), Err(Error::Address));
+            assert_eq!(
+                alloc.put(0, base + RESERVED, Flags::o(0)),
+                Err(Error::Address)
+            );
+            for frame in frames.drain(1..) {
+                alloc.put(0, frame, Flags::o(0)).unwrap();
+            }
+            recoverable(&alloc);
+            alloc.allocated_frames()
+        };
+        assert_eq!(allocated, 1);
+
+        // A different reservation is rejected
+        for reserved in [0, RESERVED + 1] {
+            let local = aligned_buf(m.local).leak();
+            let trees = aligned_buf(m.trees).leak();
+            let alloc = Allocator::create(1, &mut zone, reserved, true, local, trees);
+            assert!(matches!(alloc, Err(Error::Initialization)));
         }
 
         let local = aligned_buf(m.local).leak();
         let trees = aligned_buf(m.trees).leak();
-        let alloc = Allocator::create(1, &mut zone, true, local, trees).unwrap();
-        assert_eq!(alloc.allocated_frames(), expected_frames);
+        let alloc = Allocator::create(1, &mut zone, RESERVED, true, local, trees).unwrap();
+        assert_eq!(alloc.allocated_frames(), allocated);
         alloc.validate();
+        recoverable(&alloc);
+    }
+
+    /// Heap buffer that stands in for persistent memory, e.g., under Miri, which cannot mmap
+    struct FakeNvm(core::ptr::NonNull<u8>, std::alloc::Layout);
+
+    impl FakeNvm {
+        fn new(size: usize, align: usize) -> Self {
+            let layout = std::alloc::Layout::from_size_align(size.max(1), align).unwrap();
+            let ptr = unsafe { std::alloc::alloc_zeroed(layout) };
+            Self(core::ptr::NonNull::new(ptr).expect("out of memory"), layout)
+        }
+        fn slice<T>(&mut self) -> &mut [T] {
+            let len = self.1.size() / core::mem::size_of::<T>();
+            unsafe { core::slice::from_raw_parts_mut(self.0.as_ptr().cast(), len) }
+        }
+    }
+    impl Drop for FakeNvm {
+        fn drop(&mut self) {
+            unsafe { std::alloc::dealloc(self.0.as_ptr(), self.1) }
+        }
     }
 
+    /// Failed initializations neither free memory they do not own,
+    /// nor leave metadata behind that would be recovered.
+    ///
+    /// The memory is on the heap, so that this can be checked with Miri
+    /// (`cargo miri test nvm_init_errors`).
+    #[cfg(not(feature = "llc"))]
     #[test]
-    fn different_orders() {
-        const THREADS: usize = 4;
-        const FRAMES: usize = ((MAX_ORDER + 1) << MAX_ORDER) * (THREADS * 2 + 1);
+    fn nvm_init_errors() {
+        type Allocator<'a> = NvmAlloc<'a, LLFree<'a>>;
 
         logging();
 
-        let alloc = Allocator::create(THREADS, FRAMES, Init::FreeAll).unwrap();
+        const FRAMES: usize = TREE_FRAMES / 4;
+        let m = Allocator::metadata_size(1, FRAMES);
+        let mut nvm = FakeNvm::new(FRAMES * Frame::SIZE, Frame::SIZE << MAX_ORDER);
+        let mut local = FakeNvm::new(m.local, Frame::SIZE);
+        let mut trees = FakeNvm::new(m.trees, Frame::SIZE);
+
+        // Returns whether the allocator was recovered
+        let mut create = |frames: Range<usize>, reserved: usize, recover: bool| {
+            let zone = &mut nvm.slice::<Frame>()[frames];
+            let alloc = Allocator::create(1, zone, reserved, recover, local.slice(), trees.slice());
+            alloc.map(|a| a.init() != Init::FreeAll)
+        };
+
+        // Too small for the metadata
+        assert_eq!(create(0..1, 0, false), Err(Error::Initialization));
+        // Not aligned to the largest order
+        assert_eq!(create(1..FRAMES, 0, false), Err(Error::Initialization));
+        // The head takes all frames
+        assert_eq!(create(0..FRAMES, FRAMES, false), Err(Error::Initialization));
+        // Only the metadata fits, the allocator itself fails without frames
+        let small = Allocator::metadata_size(1, 3).lower.div_ceil(Frame::SIZE) + 2;
+        assert_eq!(create(0..small, 0, false), Err(Error::Initialization));
+        // ...and has not marked the memory as initialized
+        assert_eq!(create(0..small, 0, true), Err(Error::Initialization));
+        assert_eq!(create(0..FRAMES, 0, true), Ok(false));
+
+        // The same memory can still be used
+        assert_eq!(create(0..FRAMES, 0, false), Ok(false));
+        assert_eq!(create(0..FRAMES, 0, true), Ok(true));
+
+        // Re-initializing a cleanly shut down instance invalidates its metadata first,
+        // so that a crash during the initialization (here a panic) is not recovered
+        let zone = nvm.slice::<Frame>();
+        let crash = std::panic::catch_unwind(std::panic::AssertUnwindSafe(|| {
+            Allocator::create(1, zone, 0, false, &mut [], trees.slice()).map(drop)
+        }));
+        assert!(crash.is_err());
+        let zone = nvm.slice::<Frame>();
+        let alloc = Allocator::create(1, zone, 0, true, local.slice(), trees.slice()).unwrap();
+        assert!(alloc.init() == Init::FreeAll);
+    }
 
-        let barrier = Barrier::new(THREADS);
+    /// Only compiles for persistent allocators
+    fn recoverable<'a>(alloc: &impl Persistent<'a>) {
+        alloc.recover().unwrap();
+    }
+
+    #[test]
+    fn recover_log() {
+        #[cfg(feature = "llc")]
+        type Allocator<'a> = NvmLogAlloc<'a, LLC>;
+        #[cfg(not(feature = "llc"))]
+        type Allocator<'a> = NvmLogAlloc<'a, LLFree<'a>>;
+
+        logging();
+
+        const FRAMES: usize = 8 * TREE_FRAMES;
+
+        let mut zone = mmap::anon(0x1100_0000_0000, FRAMES, false, false);
+        let m = || Allocator::metadata_size(1, FRAMES);
+
+        // Has to be created from a persistent zone
+        let meta = MetaData::alloc(m());
+        let alloc = Allocator::new(1, FRAMES, Init::FreeAll, meta);
+        assert_eq!(alloc.err(), Some(Error::Initialization));
+
+        let (freed, lost) = {
+            let mut alloc = Allocator::create(1, &mut zone, false, MetaData::alloc(m())).unwrap();
 
-        thread::parallel(0..THREADS, |t| {
-            thread::pin(t);
-            let mut rng = WyRand::new(42 + t as u64);
-            let mut num_frames = 0;
             let mut frames = Vec::new();
-            for order in 0..=MAX_ORDER {
-                for _ in 0..1 << (MAX_ORDER - order) {
-                    frames.push((order, 0));
-                    num_frames += 1 << order;
-                }
+            for _ in 0..8 {
+                frames.push((alloc.get(0, Flags::o(0)).unwrap(), 0));
+                frames.push((alloc.get(0, Flags::o(HUGE_ORDER)).unwrap(), HUGE_ORDER));
             }
-            rng.shuffle(&mut frames);
-
-            warn!("allocate {num_frames} frames up to order <{MAX_ORDER}");
-            barrier.wait();
+            alloc.checkpoint();
 
-            for (order, frame) in &mut frames {
-                *frame = match alloc.get(t, Flags::o(*order)) {
-                    Ok(frame) => frame,
-                    Err(e) => panic!("{e:?} o={order} {alloc:?}"),
-                };
-                assert!(*frame % (1 << *order) == 0, "{frame} {:x}", 1 << *order);
+            // Logged frees
+            let freed = frames.split_off(frames.len() / 2);
+            for &(frame, order) in &freed {
+                alloc.put(0, frame, Flags::o(order)).unwrap();
             }
+            // Logged tail of a huge frame
+            let (huge, _) = frames[1];
+            assert_eq!(alloc.try_resize(0, huge, HUGE_ORDER, 0), Ok(huge));
+            // Not logged allocations
+            let lost = alloc.get(0, Flags::o(HUGE_ORDER)).unwrap();
 
-            let mut rng = WyRand::new(t as _);
-            rng.shuffle(&mut frames);
+            // leak (crash)
+            std::mem::forget(alloc);
+            (freed, lost)
+        };
 
-            for (order, frame) in frames {
-                match alloc.put(t, frame, Flags::o(order)) {
-                    Ok(_) => {}
-                    Err(e) => panic!("{e:?} o={order} {alloc:?}"),
-                }
+        let expected_frames = 5 + 3 * HUGE_FRAMES;
+        {
+            let alloc = Allocator::create(1, &mut zone, true, MetaData::alloc(m())).unwrap();
+            assert_eq!(alloc.allocated_frames(), expected_frames);
+            for (frame, order) in freed {
+                assert!(alloc.is_free(frame, order));
             }
-        });
+            assert!(alloc.is_free(lost, HUGE_ORDER));
 
-        assert_eq!(alloc.allocated_frames(), 0);
+            alloc.get(0, Flags::o(0)).unwrap();
+            // Clean shutdown
+        }
+
+        let alloc = Allocator::create(1, &mut zone, true, MetaData::alloc(m())).unwrap();
+        assert_eq!(alloc.allocated_frames(), expected_frames + 1);
         alloc.validate();
+        recoverable(&alloc);
     }
 
+    /// Frees that wrap around the log are replayed in order, but must not overwrite
+    /// records that are newer than the last checkpoint
     #[test]
-    fn init_reserved() {
+    fn recover_log_full() {
+        #[cfg(feature = "llc")]
+        type Allocator<'a> = NvmLogAlloc<'a, LLC>;
+        #[cfg(not(feature = "llc"))]
+        type Allocator<'a> = NvmLogAlloc<'a, LLFree<'a>>;
+
         logging();
 
-        const THREADS: usize = 2;
-        const FRAMES: usize = 8 << 18;
+        const FRAMES: usize = 8 * TREE_FRAMES;
+        const LOG_LEN: usize =
+            Allocator::LOG_FRAMES * Frame::SIZE / (2 * core::mem::size_of::<usize>());
 
-        let alloc = Allocator::create(THREADS, FRAMES, Init::AllocAll).unwrap();
-        assert_eq!(alloc.frames(), FRAMES);
-        assert_eq!(alloc.allocated_frames(), FRAMES);
+        let mut zone = mmap::anon(0x1300_0000_0000, FRAMES, false, false);
+        let m = || Allocator::metadata_size(1, FRAMES);
 
-        for frame in (0..FRAMES).step_by(1 << HUGE_ORDER) {
-            alloc.put(0, frame, Flags::o(HUGE_ORDER)).unwrap();
-        }
-        assert_eq!(alloc.allocated_frames(), 0);
+        let allocated = {
+            let mut alloc = Allocator::create(1, &mut zone, false, MetaData::alloc(m())).unwrap();
 
-        thread::parallel(0..THREADS, |core| {
-            thread::pin(core);
-            for _ in (0..FRAMES / THREADS).step_by(1 << HUGE_ORDER) {
-                alloc.get(core, Flags::o(HUGE_ORDER)).unwrap();
+            let mut frames = (0..2 * LOG_LEN)
+                .map(|_| alloc.get(0, Flags::o(0)).unwrap())
+                .collect::<Vec<_>>();
+            for frame in frames.drain(..LOG_LEN / 2) {
+                alloc.put(0, frame, Flags::o(0)).unwrap();
             }
-        });
-        assert_eq!(alloc.allocated_frames(), FRAMES);
+            alloc.checkpoint();
+
+            // Fill the log, wrapping around its end
+            for frame in frames.drain(..LOG_LEN) {
+                alloc.put(0, frame, Flags::o(0)).unwrap();
+            }
+            let frame = frames.pop().unwrap();
+            assert_eq!(alloc.put(0, frame, Flags::o(0)), Err(Error::Memory));
+            assert!(!alloc.is_free(frame, 0));
+
+            alloc.checkpoint();
+            alloc.put(0, frame, Flags::o(0)).unwrap();
+            for frame in frames.drain(..LOG_LEN / 4) {
+                alloc.put(0, frame, Flags::o(0)).unwrap();
+            }
+            let allocated = alloc.allocated_frames();
+            assert_eq!(allocated, frames.len());
+
+            // leak (crash)
+            std::mem::forget(alloc);
+            allocated
+        };
+
+        let alloc = Allocator::create(1, &mut zone, true, MetaData::alloc(m())).unwrap();
+        assert_eq!(alloc.allocated_frames(), allocated);
         alloc.validate();
+        recoverable(&alloc);
     }
 
     #[test]
-    fn fragmentation_retry() {
-        logging();
+    fn tiered() {
+        type Zone = ZoneAlloc<'static, LLFree<'static>>;
 
-        const FRAMES: usize = TREE_FRAMES * 2;
-        let alloc = Allocator::create(1, FRAMES, Init::FreeAll).unwrap();
+        logging();
 
-        // Alloc a whole subtree
-        let mut frames = Vec::with_capacity(TREE_FRAMES / 2);
-        for i in 0..TREE_FRAMES {
-            if i % 2 == 0 {
-                frames.push(alloc.get(0, Flags::o(0)).unwrap());
-            } else {
-                alloc.get(0, Flags::o(0)).unwrap();
-            }
+        const FAST: usize = TREE_FRAMES;
+        const SLOW: usize = 4 * TREE_FRAMES;
+        const BOUNDARY: usize = 2 * TREE_FRAMES;
+
+        let zone = |offset, frames| {
+            let meta = MetaData::alloc(Zone::metadata_size(1, frames));
+            Zone::create(1, offset, frames, Init::FreeAll, meta).unwrap()
+        };
+        // The tiers must not overlap the boundary
+        let fast = TieredAlloc::create(zone(0, BOUNDARY + 1), zone(BOUNDARY, SLOW), BOUNDARY);
+        assert_eq!(fast.err(), Some(Error::Initialization));
+        let slow = TieredAlloc::create(zone(0, FAST), zone(0, SLOW), BOUNDARY);
+        assert_eq!(slow.err(), Some(Error::Initialization));
+
+        let alloc = TieredAlloc::create(zone(0, FAST), zone(BOUNDARY, SLOW), BOUNDARY).unwrap();
+        assert_eq!(alloc.frames(), FAST + SLOW);
+
+        // Fill the fast tier first
+        let fast = (0..FAST)
+            .map(|_| alloc.get(0, Flags::o(0)).unwrap())
+            .collect::<Vec<_>>();
+        assert!(fast.iter().all(|f| *f < FAST));
+        let mut frames = (0..64)
+            .map(|_| alloc.get(0, Flags::o(0)).unwrap())
+            .collect::<Vec<_>>();
+        assert!(frames.iter().all(|f| *f >= BOUNDARY));
+        assert_eq!(alloc.slow.allocated_frames(), 64);
+        assert_eq!(alloc.hot_free_frames(), 0);
+        assert_eq!(alloc.cold_free_frames(), SLOW - 64);
+        // The gap between the tiers is not managed
+        assert_eq!(alloc.allocated_frames_in(0..BOUNDARY + SLOW), FAST + 64);
+        assert_eq!(alloc.allocated_frames_in(FAST..BOUNDARY), 0);
+        assert_eq!(alloc.free_frames_below(BOUNDARY + SLOW), SLOW - 64);
+
+        // Routed to the right tier
+        for frame in fast {
+            alloc.put(0, frame, Flags::o(0)).unwrap();
         }
-        // Free every second one -> fragmentation
+        assert_eq!(alloc.fast.allocated_frames(), 0);
+        let huge = alloc.get(0, Flags::o(HUGE_ORDER)).unwrap();
+        assert_eq!(alloc.try_resize(0, huge, HUGE_ORDER, 0), Ok(huge));
+        assert_eq!(alloc.try_resize(0, huge, 0, 1), Ok(huge));
+        alloc.put(0, huge, Flags::o(1)).unwrap();
+        assert_eq!(alloc.fast.allocated_frames(), 0);
+
+        let mut moved = Vec::new();
+        let mut migrate = |old, new| {
+            moved.push((old, new));
+            true
+        };
+
+        // Promote
+        assert_eq!(alloc.balance(0, 0.5, &mut frames, &mut migrate), Ok(32));
+        assert_eq!(alloc.fast.allocated_frames(), 32);
+        assert_eq!(alloc.slow.allocated_frames(), 32);
+        assert_eq!(frames.iter().filter(|f| **f < BOUNDARY).count(), 32);
+        assert_eq!(alloc.hot_count(), 32);
+
+        // Demote
+        assert_eq!(alloc.balance(0, 0.25, &mut frames, &mut migrate), Ok(16));
+        assert_eq!(alloc.fast.allocated_frames(), 16);
+        assert_eq!(alloc.slow.allocated_frames(), 48);
+        assert_eq!(alloc.hot_count(), 16);
+
+        // Already balanced
+        assert_eq!(alloc.balance(0, 0.25, &mut frames, &mut migrate), Ok(0));
+        assert_eq!(moved.len(), 48);
+
+        // Refused migrations keep the frames
+        assert_eq!(alloc.balance(0, 1.0, &mut frames, |_, _| false), Ok(0));
+        assert_eq!(alloc.fast.allocated_frames(), 16);
+
+        // The old frame cannot be freed, but the new one is in use
+        let mut free = [BOUNDARY + SLOW - 1];
+        let res = alloc.balance(0, 1.0, &mut free, |_, _| true);
+        assert_eq!(res, Err(Error::Address));
+        assert!(free[0] < BOUNDARY);
+        assert_eq!(alloc.fast.allocated_frames(), 17);
+        frames.extend(free);
+
         for frame in frames {
             alloc.put(0, frame, Flags::o(0)).unwrap();
         }
-
-        let huge = alloc.get(0, Flags::o(9)).unwrap();
-        warn!("huge = {huge}");
-        warn!("{alloc:?}");
+        assert_eq!(alloc.free_frames(), alloc.frames());
         alloc.validate();
     }
 
@@ -1134,6 +3215,12 @@ mod test {
         // next allocation should trigger drain+reservation (no subtree left)
         println!("{:?}", alloc.get(0, Flags::o(0)));
         alloc.validate();
+
+        // Returns the frames of all cores to the trees
+        let free = alloc.free_frames();
+        alloc.drain_all().unwrap();
+        alloc.validate();
+        assert_eq!(alloc.free_frames(), free);
     }
 
     #[test]
@@ -1151,7 +3238,7 @@ mod test {
         let barrier = Barrier::new(THREADS);
 
         let allocated = thread::parallel(0..THREADS, |t| {
-            thread::pin(t);
+            thread::try_pin(t);
             let mut rng = WyRand::new(rand + t as u64);
             let mut frames = Vec::with_capacity(FRAMES / THREADS);
             barrier.wait();
@@ -1179,4 +3266,499 @@ mod test {
         );
         alloc.validate();
     }
+
+    /// Generic test bodies for all allocators, instantiated by [alloc_tests].
+    mod suite {
+        use super::*;
+
+        /// Number of frames for the smoke-sized variants
+        pub const SMOKE: usize = 16 * TREE_FRAMES;
+
+        /// Lower and upper bound of the allocated frames, e.g., [LLFree::allocated_frames_bounded]
+        pub type Bounded<A> = Option<fn(&A) -> (usize, usize)>;
+
+        pub fn rand<A: Alloc<'static>>(frames: usize) {
+            logging();
+
+            let alloc = TestAlloc::<A>::create(1, frames, Init::FreeAll).unwrap();
+
+            warn!("start alloc...");
+            let allocs = frames / 2;
+            let mut allocated = Vec::with_capacity(allocs);
+            for _ in 0..allocs {
+                allocated.push(alloc.get(0, Flags::o(0)).unwrap());
+            }
+            warn!("allocated {}", allocated.len());
+
+            warn!("check...");
+            assert_eq!(alloc.allocated_frames(), allocated.len());
+            alloc.validate();
+
+            // Check that the same frame was not allocated twice
+            allocated.sort_unstable();
+            for &[a, b] in allocated.array_windows() {
+                assert_ne!(a, b);
+                assert!(a < frames && b < frames);
+            }
+
+            warn!("reallocate rand...");
+            let mut rng = WyRand::new(100);
+            rng.shuffle(&mut allocated);
+
+            for _ in 0..allocated.len() {
+                let i = rng.range(0..allocated.len() as _) as usize;
+                alloc.put(0, allocated[i], Flags::o(0)).unwrap();
+                allocated[i] = alloc.get(0, Flags::o(0)).unwrap();
+            }
+
+            warn!("check...");
+            assert_eq!(alloc.allocated_frames(), allocated.len());
+            alloc.validate();
+            // Check that the same frame was not allocated twice
+            allocated.sort_unstable();
+            for &[a, b] in allocated.array_windows() {
+                assert_ne!(a, b);
+                assert!(a < frames && b < frames);
+            }
+
+            warn!("free...");
+            rng.shuffle(&mut allocated);
+            for frame in &allocated {
+                alloc.put(0, *frame, Flags::o(0)).unwrap();
+            }
+            assert_eq!(alloc.allocated_frames(), 0);
+            alloc.validate()
+        }
+
+        /// Concurrent random reallocations.
+        ///
+        /// If the allocator can give `bounded` estimates of its allocated frames,
+        /// they are checked while reallocating.
+        pub fn multirand<A: Alloc<'static>>(cores: usize, frames: usize, bounded: Bounded<A>) {
+            let allocs = ((frames / cores) / 4) * 3;
+
+            logging();
+
+            let alloc = TestAlloc::<A>::create(cores, frames, Init::FreeAll).unwrap();
+            let tracker = FrameTracker::new(frames);
+
+            let barrier = Barrier::new(cores);
+            thread::parallel(0..cores, |t| {
+                thread::try_pin(t);
+
+                barrier.wait();
+                warn!("start alloc...");
+                let mut allocated = Vec::with_capacity(allocs);
+                for _ in 0..allocs {
+                    let frame = alloc.get(t, Flags::o(0)).unwrap();
+                    tracker.insert(frame, 0);
+                    allocated.push(frame);
+                }
+                warn!("allocated {}", allocated.len());
+
+                barrier.wait();
+                warn!("reallocate rand...");
+                let mut rng = WyRand::new(t as _);
+                // Reallocate a random subset of the frames in each round
+                let subset = (allocs / 64).max(1);
+                for _ in 0..allocs / subset {
+                    rng.shuffle_partial(&mut allocated, subset);
+                    for frame in &allocated[..subset] {
+                        tracker.remove(*frame, 0);
+                        alloc.put(t, *frame, Flags::o(0)).unwrap();
+                    }
+                    for frame in &mut allocated[..subset] {
+                        *frame = alloc.get(t, Flags::o(0)).unwrap();
+                        tracker.insert(*frame, 0);
+                    }
+
+                    // Every thread holds up to one subset less while reallocating
+                    if let Some(bounded) = bounded
+                        && t == 0
+                    {
+                        let (lower, upper) = bounded(&alloc);
+                        assert!(lower <= cores * allocs && cores * (allocs - subset) <= upper);
+                    }
+                }
+
+                if barrier.wait().is_leader() {
+                    alloc.validate();
+                }
+                barrier.wait();
+
+                warn!("free...");
+                rng.shuffle(&mut allocated);
+                for frame in &allocated {
+                    tracker.remove(*frame, 0);
+                    alloc.put(t, *frame, Flags::o(0)).unwrap();
+                }
+            });
+
+            assert_eq!(alloc.free_huge(), frames / HUGE_FRAMES);
+            assert_eq!(alloc.allocated_frames(), 0);
+            alloc.validate();
+        }
+
+        /// Concurrent allocations and frees of `huge` huge frames per thread
+        pub fn parallel_free<A: Alloc<'static>>(cores: usize, huge: usize) {
+            logging();
+
+            let alloc_per_thread = HUGE_FRAMES * (huge - 2 * cores);
+            let area = 2 * cores * huge * HUGE_FRAMES;
+
+            let alloc = TestAlloc::<A>::create(cores, area, Init::FreeAll).unwrap();
+            let barrier = Barrier::new(cores);
+
+            // Stress test
+            thread::parallel(0..cores, |t| {
+                thread::try_pin(t);
+                barrier.wait();
+
+                let mut frames = vec![0; alloc_per_thread];
+
+                for frame in &mut frames {
+                    *frame = alloc.get(t, Flags::o(0)).unwrap();
+                }
+
+                let mut rng = WyRand::new(t as _);
+                rng.shuffle(&mut frames);
+
+                for frame in frames {
+                    alloc.put(t, frame, Flags::o(0)).unwrap();
+                }
+            });
+
+            warn!("check");
+            assert_eq!(alloc.allocated_frames(), 0);
+            alloc.validate();
+        }
+
+        pub fn different_orders<A: Alloc<'static>>(cores: usize) {
+            let frames = ((MAX_ORDER + 1) << MAX_ORDER) * (cores * 2 + 1);
+
+            logging();
+
+            let alloc = TestAlloc::<A>::create(cores, frames, Init::FreeAll).unwrap();
+            let tracker = FrameTracker::new(frames);
+
+            let barrier = Barrier::new(cores);
+
+            thread::parallel(0..cores, |t| {
+                thread::try_pin(t);
+                let mut rng = WyRand::new(42 + t as u64);
+                let mut num_frames = 0;
+                let mut frames = Vec::new();
+                for order in 0..=MAX_ORDER {
+                    for _ in 0..1 << (MAX_ORDER - order) {
+                        frames.push((order, 0));
+                        num_frames += 1 << order;
+                    }
+                }
+                rng.shuffle(&mut frames);
+
+                warn!("allocate {num_frames} frames up to order <{MAX_ORDER}");
+                barrier.wait();
+
+                for (order, frame) in &mut frames {
+                    *frame = match alloc.get(t, Flags::o(*order)) {
+                        Ok(frame) => frame,
+                        Err(e) => panic!("{e:?} o={order} {alloc:?}"),
+                    };
+                    assert!(*frame % (1 << *order) == 0, "{frame} {:x}", 1 << *order);
+                    tracker.insert(*frame, *order);
+                }
+
+                let mut rng = WyRand::new(t as _);
+                rng.shuffle(&mut frames);
+
+                for (order, frame) in frames {
+                    tracker.remove(frame, order);
+                    match alloc.put(t, frame, Flags::o(order)) {
+                        Ok(_) => {}
+                        Err(e) => panic!("{e:?} o={order} {alloc:?}"),
+                    }
+                }
+            });
+
+            assert_eq!(alloc.allocated_frames(), 0);
+            assert_eq!(tracker.len(), 0);
+            alloc.validate();
+        }
+
+        /// Frees [Init::AllocAll] frames and reallocates them on up to two cores
+        pub fn init_reserved<A: Alloc<'static>>(cores: usize, frames: usize) {
+            logging();
+
+            let cores = cores.min(2);
+
+            let alloc = TestAlloc::<A>::create(cores, frames, Init::AllocAll).unwrap();
+            assert_eq!(alloc.frames(), frames);
+            assert_eq!(alloc.allocated_frames(), frames);
+
+            for frame in (0..frames).step_by(1 << HUGE_ORDER) {
+                alloc.put(0, frame, Flags::o(HUGE_ORDER)).unwrap();
+            }
+            assert_eq!(alloc.allocated_frames(), 0);
+
+            thread::parallel(0..cores, |core| {
+                thread::try_pin(core);
+                for _ in (0..frames / cores).step_by(1 << HUGE_ORDER) {
+                    alloc.get(core, Flags::o(HUGE_ORDER)).unwrap();
+                }
+            });
+            assert_eq!(alloc.allocated_frames(), frames);
+            alloc.validate();
+        }
+
+        pub fn fragmentation_retry<A: Alloc<'static>>() {
+            logging();
+
+            const FRAMES: usize = TREE_FRAMES * 2;
+            let alloc = TestAlloc::<A>::create(1, FRAMES, Init::FreeAll).unwrap();
+
+            // Alloc a whole subtree
+            let mut frames = Vec::with_capacity(TREE_FRAMES / 2);
+            for i in 0..TREE_FRAMES {
+                if i % 2 == 0 {
+                    frames.push(alloc.get(0, Flags::o(0)).unwrap());
+                } else {
+                    alloc.get(0, Flags::o(0)).unwrap();
+                }
+            }
+            // Free every second one -> fragmentation
+            for frame in frames {
+                alloc.put(0, frame, Flags::o(0)).unwrap();
+            }
+
+            let huge = alloc.get(0, Flags::o(9)).unwrap();
+            warn!("huge = {huge}");
+            warn!("{alloc:?}");
+
+            // The second tree is exhausted, the first one only fragmented
+            let mut huge = Vec::new();
+            let err = loop {
+                match alloc.get(0, Flags::o(9)) {
+                    Ok(frame) => huge.push(frame),
+                    Err(e) => break e,
+                }
+            };
+            assert_eq!(huge.len(), TREE_HUGE - 1);
+            assert!(alloc.free_frames() >= 1 << 9);
+            #[cfg(not(feature = "llc"))]
+            assert_eq!(err, Error::Fragmented);
+            #[cfg(feature = "llc")]
+            assert_eq!(err, Error::Memory);
+            alloc.validate();
+        }
+
+        /// Resizing in place keeps the frame usable with its new order
+        pub fn resize<A: Alloc<'static>>() {
+            logging();
+
+            let alloc = TestAlloc::<A>::create(1, TREE_FRAMES, Init::FreeAll).unwrap();
+            let frame = alloc.get(0, Flags::o(3)).unwrap();
+
+            // Shrinking frees the tail
+            assert_eq!(alloc.try_resize(0, frame, 3, 0), Ok(frame));
+            assert_eq!(alloc.allocated_frames(), 1);
+            // Growing in place is not supported by all allocators
+            #[cfg(not(feature = "llc"))]
+            {
+                assert_eq!(alloc.try_resize(0, frame, 0, 2), Ok(frame));
+                assert_eq!(alloc.try_resize(0, frame, 2, 1), Ok(frame));
+                assert_eq!(alloc.allocated_frames(), 1 << 1);
+                alloc.put(0, frame, Flags::o(1)).unwrap();
+            }
+            #[cfg(feature = "llc")]
+            alloc.put(0, frame, Flags::o(0)).unwrap();
+            assert_eq!(alloc.allocated_frames(), 0);
+            alloc.validate();
+        }
+
+        /// Recovery of an [NvmAlloc] after a crash
+        pub fn recover<A: Alloc<'static>>() {
+            logging();
+
+            const FRAMES: usize = 8 << 18;
+
+            thread::try_pin(0);
+
+            let expected_frames = (HUGE_FRAMES + 2) * (1 + (1 << 9));
+
+            // The allocators borrow the zone for their lifetime, which is 'static
+            let zone: *mut [Frame] =
+                std::boxed::Box::leak(mmap::anon(0x1000_0000_0000, FRAMES, false, false));
+            let m = NvmAlloc::<A>::metadata_size(1, FRAMES);
+            let local = aligned_buf(m.local).leak();
+            let trees = aligned_buf(m.trees).leak();
+
+            {
+                let zone = unsafe { &mut *zone };
+                let alloc = NvmAlloc::<A>::create(1, zone, 0, false, local, trees).unwrap();
+
+                for _ in 0..HUGE_FRAMES + 2 {
+                    alloc.get(0, Flags::o(0)).unwrap();
+                    alloc.get(0, Flags::o(9)).unwrap();
+                }
+
+                assert_eq!(alloc.allocated_frames(), expected_frames);
+                alloc.validate();
+
+                // leak (crash)
+                std::mem::forget(alloc);
+            }
+
+            let zone = unsafe { &mut *zone };
+            let local = aligned_buf(m.local).leak();
+            let trees = aligned_buf(m.trees).leak();
+            let alloc = NvmAlloc::<A>::create(1, zone, 0, true, local, trees).unwrap();
+            assert_eq!(alloc.allocated_frames(), expected_frames);
+            alloc.validate();
+        }
+    }
+
+    /// Instantiates the tests of [suite] for an allocator.
+    ///
+    /// The heavy variants, which use gigabytes of memory, get the `heavy` attributes,
+    /// e.g., `[ignore]` for the wrappers, while the smoke-sized variants always run.
+    /// Tests with multiple orders, freeing frames of [Init::AllocAll], and recovery
+    /// are only generated if the allocator supports them.
+    /// The concurrent tests use `cores` cores, four by default.
+    macro_rules! alloc_tests {
+        (
+            mod $name:ident, type Alloc = $alloc:ty;
+            supports_orders = $orders:tt;
+            supports_init_reserved = $reserved:tt;
+            supports_persistence = $persistence:tt;
+            heavy = [$($heavy:meta)?];
+            $(cores = $cores:expr;)?
+            $(bounded = $bounded:expr;)?
+        ) => {
+            mod $name {
+                use super::*;
+
+                type A = $alloc;
+                const CORES: usize = alloc_tests!(@cores $($cores)?);
+                const BOUNDED: suite::Bounded<A> = alloc_tests!(@or $($bounded)?);
+
+                #[test]
+                fn rand() {
+                    suite::rand::<A>(suite::SMOKE);
+                }
+                $(#[$heavy])?
+                #[test]
+                fn rand_full() {
+                    suite::rand::<A>((4 << 30) / Frame::SIZE);
+                }
+                #[test]
+                fn multirand() {
+                    suite::multirand::<A>(CORES, suite::SMOKE, BOUNDED);
+                }
+                $(#[$heavy])?
+                #[test]
+                fn multirand_full() {
+                    suite::multirand::<A>(CORES, (8 << 30) / Frame::SIZE, BOUNDED);
+                }
+                #[test]
+                fn parallel_free() {
+                    suite::parallel_free::<A>(CORES, 16);
+                }
+                $(#[$heavy])?
+                #[test]
+                fn parallel_free_full() {
+                    suite::parallel_free::<A>(CORES, HUGE_FRAMES);
+                }
+
+                alloc_tests!(@if $orders {
+                    #[test]
+                    fn different_orders() {
+                        suite::different_orders::<A>(CORES);
+                    }
+                    #[test]
+                    fn fragmentation_retry() {
+                        suite::fragmentation_retry::<A>();
+                    }
+                    #[test]
+                    fn resize() {
+                        suite::resize::<A>();
+                    }
+                });
+                alloc_tests!(@if $reserved {
+                    #[test]
+                    fn init_reserved() {
+                        suite::init_reserved::<A>(CORES, suite::SMOKE);
+                    }
+                    $(#[$heavy])?
+                    #[test]
+                    fn init_reserved_full() {
+                        suite::init_reserved::<A>(CORES, 8 << 18);
+                    }
+                });
+                alloc_tests!(@if $persistence {
+                    #[test]
+                    fn recover() {
+                        suite::recover::<A>();
+                    }
+                });
+            }
+        };
+        (@if true { $($t:tt)* }) => { $($t)* };
+        (@if false { $($t:tt)* }) => {};
+        (@or) => { None };
+        (@or $bounded:expr) => { $bounded };
+        (@cores) => { 4 };
+        (@cores $cores:expr) => { $cores };
+    }
+
+    /// Concurrently checked bounds of the allocated frames
+    #[cfg(not(feature = "llc"))]
+    const BOUNDED: suite::Bounded<Inner> = Some(LLFree::allocated_frames_bounded);
+    #[cfg(feature = "llc")]
+    const BOUNDED: suite::Bounded<Inner> = None;
+
+    alloc_tests! {
+        mod suite_core, type Alloc = Inner;
+        supports_orders = true;
+        supports_init_reserved = true;
+        supports_persistence = true;
+        heavy = [];
+        bounded = super::BOUNDED;
+    }
+    alloc_tests! {
+        mod suite_zone, type Alloc = ZoneAlloc<'static, Inner>;
+        supports_orders = true;
+        supports_init_reserved = true;
+        supports_persistence = false;
+        heavy = [ignore];
+    }
+    alloc_tests! {
+        mod suite_compound, type Alloc = CompoundAlloc<'static, Inner>;
+        supports_orders = true;
+        // Only frames allocated by it can be freed as compound frames
+        supports_init_reserved = false;
+        supports_persistence = false;
+        heavy = [ignore];
+    }
+    alloc_tests! {
+        mod suite_blocking, type Alloc = BlockingAlloc<'static, Inner>;
+        supports_orders = true;
+        supports_init_reserved = true;
+        supports_persistence = false;
+        heavy = [ignore];
+    }
+    alloc_tests! {
+        mod suite_watermark, type Alloc = WatermarkAlloc<'static, Inner>;
+        supports_orders = true;
+        supports_init_reserved = true;
+        supports_persistence = false;
+        heavy = [ignore];
+    }
+    alloc_tests! {
+        mod suite_single, type Alloc = Single<'static>;
+        supports_orders = true;
+        supports_init_reserved = true;
+        supports_persistence = false;
+        heavy = [ignore];
+        cores = 1;
+    }
 }
@@ -4,7 +4,7 @@ use core::{fmt, slice};
 
 use super::{Alloc, Init};
 use crate::util::Align;
-use crate::{Error, Flags, Result};
+use crate::{Error, Flags, Result, MAX_ORDER};
 
 /// C implementation of LLFree
 ///
@@ -70,11 +70,13 @@ impl<'a> Alloc<'a> for LLC {
     }
 
     fn get(&self, core: usize, flags: Flags) -> Result<usize> {
+        check_order(flags)?;
         let ret = unsafe { llfree_get(self.raw.as_ptr().cast(), core as _, flags.into()) };
         Ok(ret.ok()? as _)
     }
 
     fn put(&self, core: usize, frame: usize, flags: Flags) -> Result<()> {
+        check_order(flags)?;
         let ret = unsafe {
             llfree_put(
                 self.raw.as_ptr().cast(),
@@ -159,6 +161,17 @@ impl From<Flags> for flags_t {
     }
 }
 
+/// The C implementation does not distinguish invalid orders from missing memory
+fn check_order(flags: Flags) -> Result<()> {
+    if flags.order() > MAX_ORDER {
+        return Err(Error::InvalidOrder {
+            requested: flags.order(),
+            max: MAX_ORDER,
+        });
+    }
+    Ok(())
+}
+
 #[repr(C)]
 #[allow(non_camel_case_types)]
 struct result_t {
@@ -1,19 +1,30 @@
 //! Upper allocator implementation
 
+use core::ops::Range;
+use core::sync::atomic::{AtomicUsize, Ordering};
 use core::{fmt, slice};
 
 use log::{error, info, warn};
-use spin::mutex::SpinMutex;
+use spin::mutex::{SpinMutex, SpinMutexGuard};
 
 use crate::local::{Local, LocalTree};
-use crate::lower::Lower;
-use crate::trees::{Kind, Trees};
-use crate::util::{size_of_slice, Align, FmtFn};
+#[cfg(feature = "counters")]
+use crate::lower::{Counters, EventCounts};
+use crate::lower::{Lower, RecoverMode, RecoveryReport};
+use crate::trees::{Kind, NumaHint, Trees};
+use crate::util::{align_down, log_throttled, size_of_slice, spin_wait, Align, FmtFn, WyRand};
 use crate::{
-    Alloc, Error, Flags, Init, MetaData, MetaSize, Result, HUGE_FRAMES, HUGE_ORDER, MAX_ORDER,
-    RETRIES, TREE_FRAMES,
+    spans_of_blocks, Alloc, Error, Flags, Init, MetaData, MetaSize, PutResult, Result, HUGE_FRAMES,
+    HUGE_ORDER, MAX_ORDER, RETRIES, TREE_FRAMES, TREE_HUGE,
 };
 
+/// Number of trees stolen from other cores
+#[cfg(feature = "search_stats")]
+pub static STOLEN: core::sync::atomic::AtomicUsize = core::sync::atomic::AtomicUsize::new(0);
+
+/// Rounds without new free frames after which a [GetMode::NoFail] allocation gives up
+const NOFAIL_STALLS: usize = 64;
+
 /// This allocator splits its memory range into chunks.
 /// These chunks are reserved by CPUs to reduce sharing.
 /// Allocations/frees within the chunk are handed over to the
@@ -39,6 +50,25 @@ pub struct LLFree<'a> {
     pub lower: Lower<'a>,
     /// Manages the allocators trees
     pub trees: Trees<'a>,
+    /// Number of cores that are mapped to the local data
+    cores: usize,
+    /// Optional mapping from cores to their local data, see [LLFree::with_core_map]
+    core_map: Option<&'a [usize]>,
+    /// Number of operations on local data owned by another core
+    foreign: AtomicUsize,
+    /// Reproducible placement, see [LLFree::set_deterministic]
+    deterministic: bool,
+    /// Randomized placement, see [LLFree::set_randomized]
+    randomized: bool,
+    /// Locality bias for reserving trees, see [LLFree::set_numa_penalty]
+    numa_penalty: u32,
+    /// Frees that reserve a tree, see [LLFree::set_frees_threshold]
+    frees_threshold: usize,
+    /// Reservations after which a search restarts at home, see [LLFree::set_rebalance_interval]
+    rebalance_interval: usize,
+    /// Latency and retry histograms of the allocations
+    #[cfg(feature = "latency")]
+    pub latency: crate::latency::Latency,
 }
 
 unsafe impl Send for LLFree<'_> {}
@@ -53,43 +83,21 @@ impl<'a> Alloc<'a> for LLFree<'a> {
 
     /// Initialize the allocator.
     #[cold]
-    fn new(mut cores: usize, frames: usize, init: Init, meta: MetaData<'a>) -> Result<Self> {
-        info!(
-            "initializing c={cores} f={frames} {:?} {:?} {:?}",
-            meta.local.as_ptr_range(),
-            meta.trees.as_ptr_range(),
-            meta.lower.as_ptr_range()
-        );
-        assert!(meta.valid(Self::metadata_size(cores, frames)));
-
-        if frames < TREE_FRAMES * cores {
-            warn!("memory {} < {}", frames, TREE_FRAMES * cores);
-            cores = frames.div_ceil(TREE_FRAMES);
-        }
-
-        // Create lower allocator
-        let lower = Lower::new(frames, init, meta.lower)?;
-
-        // Init per-cpu data
-        let local = unsafe { slice::from_raw_parts_mut(meta.local.as_mut_ptr().cast(), cores) };
-        local.fill_with(Default::default);
-
-        // Init tree array
-        let trees = Trees::new(frames, meta.trees, |start| lower.free_in_tree(start));
-
-        Ok(Self {
-            local,
-            lower,
-            trees,
-        })
+    fn new(cores: usize, frames: usize, init: Init, meta: MetaData<'a>) -> Result<Self> {
+        Self::init(cores, frames, init, meta, None, |_| Ok(()))
     }
 
     fn metadata_size(cores: usize, frames: usize) -> MetaSize {
-        let cores = cores.clamp(1, frames.div_ceil(TREE_FRAMES));
+        let cores = cores.clamp(1, frames.div_ceil(TREE_FRAMES).max(1));
+        #[cfg(feature = "counters")]
+        let counters = size_of_slice::<Align<Counters>>(cores);
+        #[cfg(not(feature = "counters"))]
+        let counters = 0;
         MetaSize {
-            local: size_of_slice::<Align<SpinMutex<Local>>>(cores),
+            // The counters are stored behind the local data
+            local: size_of_slice::<Align<SpinMutex<Local>>>(cores) + counters,
             trees: Trees::metadata_size(frames),
-            lower: Lower::metadata_size(frames),
+            lower: Lower::<TREE_HUGE>::metadata_size(frames),
         }
     }
 
@@ -105,44 +113,49 @@ impl<'a> Alloc<'a> for LLFree<'a> {
     }
 
     fn get(&self, core: usize, flags: Flags) -> Result<usize> {
-        if flags.order() > MAX_ORDER {
-            error!("invalid order");
-            return Err(Error::Memory);
-        }
-        // We might have more cores than cpu-local data
-        let core = core % self.local.len();
+        #[cfg(feature = "latency")]
+        let start = self.latency.now();
+        let (frame, _retries) = self.get_retry(core, flags)?;
+        #[cfg(feature = "latency")]
+        self.latency.record(flags.order(), start, _retries);
+        Ok(frame)
+    }
 
-        // Retry allocation up to n times if it fails due to a concurrent update
-        for _ in 0..RETRIES {
-            match self.get_inner(core, flags) {
-                Ok(frame) => return Ok(frame),
-                Err(Error::Retry) => continue,
-                Err(e) => return Err(e),
-            }
-        }
-        error!("Exceeding retries");
-        Err(Error::Memory)
+    fn put(&self, core: usize, frame: usize, flags: Flags) -> Result<()> {
+        self.put_report(core, frame, flags).map(|_| ())
     }
 
-    fn put(&self, core: usize, frame: usize, mut flags: Flags) -> Result<()> {
+    fn put_report(&self, core: usize, frame: usize, mut flags: Flags) -> Result<PutResult> {
+        debug_assert!(self.is_initialized());
         if frame >= self.lower.frames() {
             error!("invalid frame number");
             return Err(Error::Memory);
         }
+        if flags.order() > MAX_ORDER {
+            error!("invalid order");
+            return Err(Error::InvalidOrder {
+                requested: flags.order(),
+                max: MAX_ORDER,
+            });
+        }
         // Put usually does not know about movability
         flags.set_movable(false);
 
         // First free the frame in the lower allocator
-        let huge = self.lower.put(frame, flags)?;
+        let free_order = self.lower.put(self.slot(core), frame, flags)?;
+        let result = PutResult {
+            freed_order: flags.order(),
+            now_free_up_to: free_order,
+        };
         // Could be multiple huge frames depending on the allocation size
-        let huge = (huge as usize).max((1 << flags.order()) / HUGE_FRAMES);
+        let huge = ((free_order >= HUGE_ORDER) as usize).max((1 << flags.order()) / HUGE_FRAMES);
 
         // Then update local / global counters
         let i = frame / TREE_FRAMES;
-        let mut local = self.local[core % self.local.len()].lock();
+        let (_, mut local) = self.lock_local(core);
 
         // Update the put-reserve heuristic
-        let may_reserve = local.frees_push(i);
+        let may_reserve = !self.deterministic && local.frees_push(i, self.frees_threshold);
 
         // Try update own trees first
         let num_frames = 1usize << flags.order();
@@ -152,7 +165,8 @@ impl<'a> Alloc<'a> for LLFree<'a> {
             {
                 preferred.set_free(preferred.free() + num_frames);
                 preferred.set_huge(preferred.huge() + huge);
-                return Ok(());
+                self.debug_check_local(preferred);
+                return Ok(result);
             }
         } else {
             // Might be movable or fixed
@@ -162,7 +176,8 @@ impl<'a> Alloc<'a> for LLFree<'a> {
                 {
                     preferred.set_free(preferred.free() + num_frames);
                     preferred.set_huge(preferred.huge() + huge);
-                    return Ok(());
+                    self.debug_check_local(preferred);
+                    return Ok(result);
                 }
             }
         }
@@ -174,14 +189,17 @@ impl<'a> Alloc<'a> for LLFree<'a> {
                 i * TREE_FRAMES,
                 tree.free() + num_frames,
                 tree.huge() + huge,
-            );
+            )
+            .with_generation(tree.next_generation());
+            self.debug_check_local(&entry);
             let kind = flags.with_movable(tree.kind() == Kind::Movable).into();
             self.swap_reserved(local.preferred_mut(kind), Some(entry), kind);
         }
-        Ok(())
+        Ok(result)
     }
 
     fn is_free(&self, frame: usize, order: usize) -> bool {
+        debug_assert!(self.is_initialized());
         if frame < self.lower.frames() {
             self.lower.is_free(frame, order)
         } else {
@@ -189,6 +207,10 @@ impl<'a> Alloc<'a> for LLFree<'a> {
         }
     }
 
+    fn is_initialized(&self) -> bool {
+        !self.local.is_empty() && self.lower.is_initialized() && !self.trees.corrupted()
+    }
+
     fn frames(&self) -> usize {
         self.lower.frames()
     }
@@ -202,7 +224,7 @@ impl<'a> Alloc<'a> for LLFree<'a> {
     }
 
     fn drain(&self, core: usize) -> Result<()> {
-        if let Some(mut local) = self.local[core % self.local.len()].try_lock() {
+        if let Some(mut local) = self.local[self.slot(core)].try_lock() {
             for kind in [Kind::Fixed, Kind::Movable, Kind::Huge] {
                 self.swap_reserved(&mut local.preferred_mut(kind), None, kind);
             }
@@ -210,20 +232,78 @@ impl<'a> Alloc<'a> for LLFree<'a> {
         Ok(())
     }
 
-    fn free_frames(&self) -> usize {
-        // Global array
-        let mut frames = self.trees.free_frames();
-        // Frames allocated in reserved trees
-        for local in self.local.iter() {
-            if let Some(local) = local.try_lock() {
+    fn drain_all(&self) -> Result<()> {
+        // Drain the local data directly, as the core map might not reach all of them
+        for local in self.local {
+            if let Some(mut local) = local.try_lock() {
                 for kind in [Kind::Fixed, Kind::Movable, Kind::Huge] {
-                    if let Some(tree) = local.preferred(kind) {
-                        frames += tree.free();
-                    }
+                    self.swap_reserved(local.preferred_mut(kind), None, kind);
                 }
             }
         }
-        frames
+        Ok(())
+    }
+
+    #[cfg(feature = "latency")]
+    fn histograms(&self) -> Option<crate::latency::Histograms> {
+        Some(self.latency.load())
+    }
+
+    /// Growing is only supported below [HUGE_ORDER], within a bitfield of the lower allocator.
+    fn try_resize(
+        &self,
+        core: usize,
+        frame: usize,
+        order: usize,
+        new_order: usize,
+    ) -> Result<usize> {
+        if new_order <= order {
+            return crate::shrink(self, core, frame, order, new_order);
+        }
+        crate::check_resize(self, frame, order, new_order)?;
+        if new_order >= HUGE_ORDER
+            || frame % (1 << new_order) != 0
+            || frame + (1 << new_order) > self.lower.frames()
+        {
+            return Err(Error::Memory);
+        }
+        let num_frames = (1 << new_order) - (1 << order);
+        let i = frame / TREE_FRAMES;
+
+        // First take the frames from the local or global counters of the tree
+        let (_, mut local) = self.lock_local(core);
+        let mut taken = None;
+        for kind in [Kind::Movable, Kind::Fixed] {
+            if let Some(preferred) = local.preferred_mut(kind)
+                && preferred.frame() / TREE_FRAMES == i
+                && preferred.free() >= num_frames
+            {
+                preferred.set_free(preferred.free() - num_frames);
+                taken = Some(kind);
+                break;
+            }
+        }
+        if taken.is_none() && !self.trees.dec(i, num_frames, 0) {
+            return Err(Error::Memory);
+        }
+
+        // Then allocate them in the lower allocator
+        if let Err(e) = self.lower.grow(frame, order, new_order) {
+            if let Some(kind) = taken
+                && let Some(preferred) = local.preferred_mut(kind)
+            {
+                preferred.set_free(preferred.free() + num_frames);
+            } else {
+                self.trees.inc_or_reserve(i, num_frames, 0, false);
+            }
+            return Err(e);
+        }
+        Ok(frame)
+    }
+
+    fn free_frames(&self) -> usize {
+        // Global array and reserved trees
+        self.trees.free_frames() + self.reserved_free()
     }
 
     fn free_huge(&self) -> usize {
@@ -266,14 +346,39 @@ impl<'a> Alloc<'a> for LLFree<'a> {
         }
     }
 
+    #[cold]
+    fn count_allocatable_spans(&self, buf: &mut [usize]) {
+        spans_of_blocks(&self.free_orders(), buf);
+        // Inflated trees are free in the lower allocator, but cannot be allocated
+        for (i, tree) in self.trees.entries.iter().enumerate() {
+            if tree.load().inflated() {
+                let len = (self.frames() - i * TREE_FRAMES).min(TREE_FRAMES);
+                for (order, count) in buf.iter_mut().enumerate() {
+                    *count = count.saturating_sub(len >> order);
+                }
+            }
+        }
+    }
+
     fn validate(&self) {
         warn!("validate");
-        assert_eq!(self.free_frames(), self.lower.free_frames());
-        assert_eq!(self.free_huge(), self.lower.free_huge());
+        let inflated = self.trees.entries.iter().filter(|e| e.load().inflated());
+        let inflated = inflated.count();
+        assert_eq!(
+            self.free_frames() + inflated * TREE_FRAMES,
+            self.lower.free_frames()
+        );
+        assert_eq!(
+            self.free_huge() + inflated * TREE_HUGE,
+            self.lower.free_huge()
+        );
         let mut reserved = 0;
         for (i, tree) in self.trees.entries.iter().enumerate() {
             let tree = tree.load();
-            if !tree.reserved() {
+            if tree.inflated() {
+                let free = self.lower.free_in_tree(i * TREE_FRAMES);
+                assert_eq!(free, (TREE_FRAMES, TREE_HUGE));
+            } else if !tree.reserved() {
                 let (free, huge) = self.lower.free_in_tree(i * TREE_FRAMES);
                 assert_eq!(tree.free(), free);
                 assert_eq!(tree.huge(), huge);
@@ -295,11 +400,737 @@ impl<'a> Alloc<'a> for LLFree<'a> {
         }
         assert!(reserved == 0);
     }
+
+    #[cold]
+    #[cfg(feature = "std")]
+    fn snapshot(&self) -> Option<crate::AllocSnapshot> {
+        use crate::trees::Tree;
+
+        let mut out = std::vec::Vec::new();
+        for v in [Self::SNAPSHOT_MAGIC, self.frames(), self.cores()] {
+            out.extend_from_slice(&(v as u64).to_le_bytes());
+        }
+
+        // Return the reserved trees, so that the snapshot does not depend on the local data
+        // The generations only matter for the reservations of the running allocator
+        let mut trees: std::vec::Vec<Tree> = self
+            .trees
+            .entries
+            .iter()
+            .map(|e| e.load().with_generation(0))
+            .collect();
+        for local in self.local {
+            let local = local.lock();
+            for kind in [Kind::Fixed, Kind::Movable, Kind::Huge] {
+                if let Some(tree) = local.preferred(kind) {
+                    let global = &mut trees[tree.frame() / TREE_FRAMES];
+                    *global = Tree::with(
+                        global.free() + tree.free(),
+                        global.huge() + tree.huge(),
+                        false,
+                        global.kind(),
+                    );
+                }
+            }
+        }
+        for tree in trees {
+            out.extend_from_slice(&u32::from(tree).to_le_bytes());
+        }
+
+        self.lower.snapshot(&mut out);
+        Some(crate::AllocSnapshot(out))
+    }
+
+    #[cold]
+    #[cfg(feature = "std")]
+    fn restore(snapshot: crate::AllocSnapshot, frames: usize, meta: MetaData<'a>) -> Result<Self> {
+        use crate::trees::Tree;
+        use crate::util::take_bytes;
+
+        let mut data = snapshot.0.as_slice();
+        let mut header = [0; 3];
+        for v in &mut header {
+            let bytes = take_bytes(&mut data).ok_or(Error::Initialization)?;
+            *v = u64::from_le_bytes(bytes) as usize;
+        }
+        let [magic, snapshot_frames, cores] = header;
+        if magic != Self::SNAPSHOT_MAGIC || snapshot_frames != frames {
+            error!("invalid snapshot f={snapshot_frames} != {frames}");
+            return Err(Error::Initialization);
+        }
+        if !meta.valid(Self::metadata_size(cores, frames)) {
+            error!("invalid metadata");
+            return Err(Error::Initialization);
+        }
+
+        // Skip the initialization, everything is overwritten by the snapshot
+        #[allow(unused_mut)]
+        let mut lower = Lower::new(cores, frames, Init::Recover(false), meta.lower)?;
+
+        let local = unsafe { slice::from_raw_parts_mut(meta.local.as_mut_ptr().cast(), cores) };
+        local.fill_with(Default::default);
+        #[cfg(feature = "counters")]
+        lower.set_counters(unsafe { Self::counters_in(meta.local, cores) });
+
+        let trees = Trees::new(frames, meta.trees, |_| (0, 0));
+        for entry in trees.entries {
+            let bytes = take_bytes(&mut data).ok_or(Error::Initialization)?;
+            entry.store(Tree::from(u32::from_le_bytes(bytes)));
+        }
+        trees.recount();
+
+        lower.restore(&mut data)?;
+        if !data.is_empty() {
+            error!("invalid snapshot size");
+            return Err(Error::Initialization);
+        }
+
+        let alloc = Self {
+            local,
+            lower,
+            trees,
+            cores,
+            core_map: None,
+            foreign: AtomicUsize::new(0),
+            deterministic: false,
+            randomized: false,
+            numa_penalty: 0,
+            frees_threshold: Local::L,
+            rebalance_interval: 0,
+            #[cfg(feature = "latency")]
+            latency: Default::default(),
+        };
+        alloc.assign_owners();
+        Ok(alloc)
+    }
 }
 
-impl LLFree<'_> {
-    fn lower_get(&self, mut tree: LocalTree, flags: Flags) -> Result<LocalTree> {
-        let (frame, huge) = self.lower.get(tree.frame(), flags)?;
+impl<'a> LLFree<'a> {
+    /// Initialize the allocator with an explicit mapping from cores to their local data.
+    ///
+    /// The local data is limited to one per tree, so with little memory multiple
+    /// cores have to share it.
+    /// `core_map[core]` is the local data index of each core and has to be
+    /// smaller than [Alloc::cores].
+    /// Without a map, the cores are assigned in blocks, so that cores with
+    /// adjacent ids, e.g., SMT siblings, share the same local data.
+    #[cold]
+    pub fn with_core_map(
+        core_map: &'a [usize],
+        frames: usize,
+        init: Init,
+        meta: MetaData<'a>,
+    ) -> Result<Self> {
+        Self::init(core_map.len(), frames, init, meta, Some(core_map), |_| {
+            Ok(())
+        })
+    }
+
+    /// Initialize the allocator with all frames free, except for the `reserved` ranges.
+    ///
+    /// This excludes memory that is already in use during boot, like the kernel image
+    /// or the initrd. The reserved frames are allocated and can be freed later
+    /// with any order.
+    #[cold]
+    pub fn with_reserved(
+        cores: usize,
+        frames: usize,
+        reserved: &[Range<usize>],
+        meta: MetaData<'a>,
+    ) -> Result<Self> {
+        Self::init(cores, frames, Init::FreeAll, meta, None, |lower| {
+            for range in reserved {
+                lower.reserve_frames(range.clone())?;
+            }
+            Ok(())
+        })
+    }
+
+    /// Recovers the allocator like a deep recovery ([Init::Recover]), but also verifies
+    /// metadata that is not covered by it, like the frames beyond the end of memory.
+    ///
+    /// This is slower, but also handles writes that were torn by a crash,
+    /// e.g., if the persistent memory is not protected by ADR.
+    /// Returns [Error::Corruption] if the metadata could not be repaired.
+    #[cold]
+    pub fn recover_deep_verify(cores: usize, frames: usize, meta: MetaData<'a>) -> Result<Self> {
+        Self::init(cores, frames, Init::Recover(false), meta, None, |lower| {
+            let report = lower.recover_deep_verify(cores)?;
+            if !report.is_clean() {
+                warn!("recovered {report}");
+            }
+            Ok(())
+        })
+    }
+
+    /// Allocates exactly the `frame` of `order` if it is free, e.g., for legacy DMA
+    /// at a fixed address or to re-establish a previous layout after a migration.
+    ///
+    /// Fails with [Error::Busy] without modifying anything if any of the frames is allocated,
+    /// or if their tree counters are contended.
+    /// The frame might be in a tree that is reserved by any core.
+    /// In contrast to [Alloc::get], the frees history and the search start of the
+    /// reserved trees are not changed.
+    ///
+    /// The tree counters are updated after the frames are claimed, without locking
+    /// the local data of `core`, so [LLFree::allocated_frames_bounded] is not
+    /// guaranteed to hold while this runs.
+    pub fn get_at(&self, core: usize, frame: usize, order: usize) -> Result<()> {
+        if order > MAX_ORDER {
+            error!("invalid order");
+            return Err(Error::InvalidOrder {
+                requested: order,
+                max: MAX_ORDER,
+            });
+        }
+        if frame % (1 << order) != 0 || frame + (1 << order) > self.lower.frames() {
+            error!("invalid frame {frame:x} o={order}");
+            return Err(Error::Address);
+        }
+
+        // First claim the frames in the lower allocator
+        let fragmented = self.lower.get_at(self.slot(core), frame, order)?;
+        let huge = if order >= HUGE_ORDER {
+            1 << (order - HUGE_ORDER)
+        } else {
+            fragmented as usize
+        };
+
+        // Then take them from the tree counters, which might be in transit
+        let i = frame / TREE_FRAMES;
+        if spin_wait(RETRIES, || self.take_counters(i, 1 << order, huge)) {
+            return Ok(());
+        }
+        warn!("no counters for {frame:x} o={order} in tree {i}");
+        self.lower.put(self.slot(core), frame, Flags::o(order))?;
+        Err(Error::Busy)
+    }
+
+    /// Takes `free` frames and `huge` huge frames from the counters of tree `i`,
+    /// which are either global or in a reserved tree of any core.
+    fn take_counters(&self, i: usize, free: usize, huge: usize) -> bool {
+        if self.trees.dec(i, free, huge) {
+            return true;
+        }
+        // Only one local is locked at a time, so that we cannot deadlock
+        for local in self.local {
+            let mut local = local.lock();
+            for kind in [Kind::Fixed, Kind::Movable, Kind::Huge] {
+                if let Some(tree) = local.preferred_mut(kind)
+                    && tree.frame() / TREE_FRAMES == i
+                {
+                    // The frames might be split between the global and the reserved counters
+                    if let Some(global) = self.trees.sync(i, tree.generation(), 0, 0) {
+                        tree.set_free(tree.free() + global.free());
+                        tree.set_huge(tree.huge() + global.huge());
+                        self.debug_check_local(tree);
+                    }
+                    if tree.free() >= free && tree.huge() >= huge {
+                        tree.set_free(tree.free() - free);
+                        tree.set_huge(tree.huge() - huge);
+                        return true;
+                    }
+                }
+            }
+        }
+        false
+    }
+
+    /// Returns the states of all trees and huge frames, see [Lower::memory_map].
+    #[cold]
+    #[cfg(feature = "std")]
+    pub fn memory_map(&self) -> crate::MemoryMap {
+        self.lower.memory_map()
+    }
+
+    /// Performs the deep recovery of the persistent metadata in `meta`,
+    /// returning a report of the corrupted metadata instead of logging it.
+    ///
+    /// With [RecoverMode::DryRun], the metadata is only inspected.
+    /// After [RecoverMode::Fix], the allocator can be initialized
+    /// with [Init::Recover] without deep recovery (`false`).
+    #[cold]
+    pub fn recover_report(
+        cores: usize,
+        frames: usize,
+        meta: MetaData<'a>,
+        mode: RecoverMode,
+    ) -> Result<RecoveryReport> {
+        assert!(meta.valid(Self::metadata_size(cores, frames)));
+        let lower: Lower = Lower::new(cores, frames, Init::Recover(false), meta.lower)?;
+        Ok(lower.recover(cores, mode))
+    }
+
+    #[cold]
+    fn init(
+        mut cores: usize,
+        frames: usize,
+        init: Init,
+        meta: MetaData<'a>,
+        core_map: Option<&'a [usize]>,
+        prepare: impl FnOnce(&Lower<'a>) -> Result<()>,
+    ) -> Result<Self> {
+        info!(
+            "initializing c={cores} f={frames} {:?} {:?} {:?}",
+            meta.local.as_ptr_range(),
+            meta.trees.as_ptr_range(),
+            meta.lower.as_ptr_range()
+        );
+        assert!(meta.valid(Self::metadata_size(cores, frames)));
+
+        if frames > LocalTree::MAX_FRAMES {
+            error!("memory {frames} > {}", LocalTree::MAX_FRAMES);
+            return Err(Error::Initialization);
+        }
+
+        let logical = cores.max(1);
+        if frames < TREE_FRAMES * cores {
+            warn!("memory {} < {}", frames, TREE_FRAMES * cores);
+            cores = frames.div_ceil(TREE_FRAMES);
+        }
+        if let Some(map) = core_map
+            && (map.len() != logical || map.iter().any(|&slot| slot >= cores))
+        {
+            error!("invalid core map {map:?} for {cores} local");
+            return Err(Error::Initialization);
+        }
+
+        // Create lower allocator
+        #[allow(unused_mut)]
+        let mut lower = Lower::new(cores, frames, init, meta.lower)?;
+        prepare(&lower)?;
+
+        // Init per-cpu data
+        let local = unsafe { slice::from_raw_parts_mut(meta.local.as_mut_ptr().cast(), cores) };
+        local.fill_with(Default::default);
+        #[cfg(feature = "counters")]
+        lower.set_counters(unsafe { Self::counters_in(meta.local, cores) });
+
+        // Init tree array
+        let trees = Trees::new(frames, meta.trees, |start| lower.free_in_tree(start));
+
+        let alloc = Self {
+            local,
+            lower,
+            trees,
+            cores: logical,
+            core_map,
+            foreign: AtomicUsize::new(0),
+            deterministic: false,
+            randomized: false,
+            numa_penalty: 0,
+            frees_threshold: Local::L,
+            rebalance_interval: 0,
+            #[cfg(feature = "latency")]
+            latency: Default::default(),
+        };
+        alloc.assign_owners();
+        Ok(alloc)
+    }
+
+    /// Initializes the event counters, which are stored behind the local data of `cores`.
+    ///
+    /// Safety: `local` has to be large enough and must not be accessed otherwise.
+    #[cfg(feature = "counters")]
+    unsafe fn counters_in(local: &mut [u8], cores: usize) -> &'a [Align<Counters>] {
+        let offset = size_of_slice::<Align<SpinMutex<Local>>>(cores);
+        let counters: &'a mut [Align<Counters>] =
+            unsafe { slice::from_raw_parts_mut(local.as_mut_ptr().add(offset).cast(), cores) };
+        counters.fill_with(Default::default);
+        counters
+    }
+
+    /// Returns the events of the lower allocator of the local data `core` is mapped to.
+    ///
+    /// Cores that share their local data also share their counters.
+    #[cfg(feature = "counters")]
+    pub fn counters(&self, core: usize) -> EventCounts {
+        self.lower.counters(self.slot(core))
+    }
+    /// Returns the events of the lower allocator of all cores
+    #[cfg(feature = "counters")]
+    pub fn counters_total(&self) -> EventCounts {
+        let mut total = EventCounts::default();
+        for slot in 0..self.local.len() {
+            total += &self.lower.counters(slot);
+        }
+        total
+    }
+
+    /// The first core that is mapped to a local data owns it.
+    fn assign_owners(&self) {
+        for core in (0..self.cores).rev() {
+            if let Some(local) = self.local.get(self.slot(core)) {
+                local.lock().set_owner(core);
+            }
+        }
+    }
+
+    /// Returns the number of recovered and total trees of the deep recovery of this allocator.
+    pub fn recovery_progress(&self) -> (usize, usize) {
+        self.lower.recovery_progress()
+    }
+
+    /// Returns the high-water mark of allocated frames since the initialization or [LLFree::reset_peak].
+    ///
+    /// The peak is only maintained on the slow paths, when trees are reserved
+    /// or their global counters are synchronized, and it counts the free frames
+    /// of the reserved trees as allocated.
+    /// Thus, it is never below the actual peak, but might exceed it by the
+    /// frames of all reserved trees, which are at most `cores * 3 * TREE_FRAMES`
+    /// for the three [Kind]s of reservations per core.
+    pub fn dbg_peak_allocated(&self) -> usize {
+        self.trees.peak_allocated()
+    }
+    /// Resets the high-water mark to the currently allocated frames, see [LLFree::dbg_peak_allocated]
+    pub fn reset_peak(&self) {
+        self.trees.reset_peak()
+    }
+
+    /// Number of operations on local data, that is shared with and owned by another core.
+    pub fn foreign_ops(&self) -> usize {
+        self.foreign.load(Ordering::Relaxed)
+    }
+
+    /// Returns the trees of the per-core share of the local data `slot`,
+    /// where its searches for new trees initially start.
+    ///
+    /// The last share might be smaller, and slots beyond the trees share the last one.
+    fn home(&self, slot: usize) -> Range<usize> {
+        let trees = self.trees.len();
+        let share = trees.div_ceil(self.local.len());
+        let start = (share * slot).min(trees - 1);
+        start..(start + share).min(trees)
+    }
+
+    /// Returns how many trees the tree `i` is away from the home of the local data `slot`
+    fn home_distance(&self, slot: usize, i: usize) -> usize {
+        let home = self.home(slot);
+        if i < home.start {
+            home.start - i
+        } else {
+            (i + 1).saturating_sub(home.end)
+        }
+    }
+
+    /// Returns the index of the local data `core` is mapped to.
+    fn slot(&self, core: usize) -> usize {
+        // We might have more cores than cpu-local data
+        let core = core % self.cores;
+        if let Some(map) = self.core_map {
+            map[core]
+        } else {
+            core * self.local.len() / self.cores
+        }
+    }
+
+    /// Lock the local data of `core`, counting accesses to local data of other cores.
+    fn lock_local(&self, core: usize) -> (usize, SpinMutexGuard<'_, Local>) {
+        let slot = self.slot(core);
+        let local = self.local[slot].lock();
+        if local.owner() != core % self.cores {
+            self.foreign.fetch_add(1, Ordering::Relaxed);
+        }
+        (slot, local)
+    }
+
+    /// Returns a lower and upper bound for the number of allocated frames.
+    ///
+    /// In contrast to [Alloc::allocated_frames], this can be used while other
+    /// threads allocate and free frames, e.g., for periodic leak checks.
+    /// All local data is locked at once, which freezes the counters of the reserved trees.
+    /// Frees return their frames to the lower allocator before updating the counters,
+    /// so that every free in flight increases the result by up to `1 << MAX_ORDER` frames.
+    /// The lower bound subtracts one such free per core, so the number of frames
+    /// allocated in the lower allocator is only guaranteed to be within the bounds
+    /// if no more than one thread frees on each core at a time.
+    ///
+    /// Note: [LLFree::get_at] updates the counters after claiming the frames and without
+    /// locking the local data, so the result might be too small while it runs.
+    /// Inflating or deflating the balloon concurrently also breaks the bounds.
+    pub fn allocated_frames_bounded(&self) -> (usize, usize) {
+        let mut free = 0;
+        for local in self.local {
+            let local = local.lock();
+            free += [Kind::Fixed, Kind::Movable, Kind::Huge]
+                .into_iter()
+                .filter_map(|kind| local.preferred(kind))
+                .map(|tree| tree.free())
+                .sum::<usize>();
+            // Keep it locked until the global counters are summed up
+            core::mem::forget(local);
+        }
+        free += self.trees.free_frames();
+        for local in self.local {
+            // Safety: Locked by the loop above
+            unsafe { local.force_unlock() };
+        }
+
+        let allocated = self.frames() - free;
+        let in_flight = self.cores << MAX_ORDER;
+        (allocated.saturating_sub(in_flight), allocated)
+    }
+
+    /// Checks the consistency of the metadata like [Alloc::validate],
+    /// but reports corruptions with [Error::Corruption] instead of panicking.
+    ///
+    /// The allocator has to be quiesced, no concurrent operations are allowed.
+    pub fn check(&self) -> Result<()> {
+        self.lower.check()?;
+
+        let mut reserved = 0;
+        for (i, tree) in self.trees.entries.iter().enumerate() {
+            let tree = tree.load();
+            if !tree.is_valid() {
+                warn!("Corrupted tree {i}: {:x}", u32::from(tree));
+                return Err(Error::Corruption);
+            }
+            let (free, huge) = self.lower.free_in_tree(i * TREE_FRAMES);
+            if tree.inflated() {
+                if (free, huge) != (TREE_FRAMES, TREE_HUGE) {
+                    warn!("Corrupted inflated tree {i}: {free} {huge}");
+                    return Err(Error::Corruption);
+                }
+            } else if tree.reserved() {
+                reserved += 1;
+            } else if (tree.free(), tree.huge()) != (free, huge) {
+                warn!("Corrupted tree {i}: {} != {free}", tree.free());
+                return Err(Error::Corruption);
+            }
+        }
+        for local in self.local {
+            let local = local.lock();
+            for kind in [Kind::Movable, Kind::Fixed, Kind::Huge] {
+                if let Some(tree) = local.preferred(kind) {
+                    let global = self.trees.get(tree.frame() / TREE_FRAMES);
+                    let (free, huge) = self.lower.free_in_tree(tree.frame());
+                    if tree.free() + global.free() != free || tree.huge() + global.huge() != huge {
+                        warn!("Corrupted reserved tree {}", tree.frame() / TREE_FRAMES);
+                        return Err(Error::Corruption);
+                    }
+                    reserved -= 1;
+                }
+            }
+        }
+        if reserved != 0 {
+            warn!("Corrupted reservations: {reserved} without local data");
+            return Err(Error::Corruption);
+        }
+        Ok(())
+    }
+
+    /// Makes the placement of allocations reproducible, e.g., for differential testing.
+    ///
+    /// New trees are reserved with the lowest index instead of searching near
+    /// the previous tree of the core, and trees are not reserved on frees.
+    /// Thus, replaying the same operations on a new allocator returns the same frames,
+    /// if they are executed sequentially or with the same interleaving.
+    ///
+    /// Remaining nondeterminism: Concurrent operations race for trees and table
+    /// entries, draining or stealing depends on which cores currently hold trees,
+    /// and a recovered allocator starts with the recovered counters.
+    pub fn set_deterministic(&mut self, deterministic: bool) {
+        self.deterministic = deterministic;
+    }
+
+    /// Randomizes the placement of allocations, if a `seed` is given.
+    ///
+    /// This hardens against attacks that depend on predictable frame placement,
+    /// like heap feng shui, where an attacker arranges its frames next to a victim's.
+    /// New trees are reserved at a random start, scanning all trees with a random stride,
+    /// and allocations search their tree from a random offset instead of the last allocation.
+    /// Alignment is unaffected and every search examines the same entries, just permuted.
+    ///
+    /// The tradeoff is performance and fragmentation: allocations of a core are
+    /// spread over their tree, which breaks up more huge frames, the start hints
+    /// are not used, and reserving a tree skips the search near the previous one.
+    /// The same `seed` reproduces the placement under the same conditions as
+    /// [LLFree::set_deterministic], which takes precedence for reserving trees.
+    pub fn set_randomized(&mut self, seed: Option<u64>) {
+        self.randomized = seed.is_some();
+        if let Some(seed) = seed {
+            let mut rng = WyRand::new(seed);
+            for local in self.local {
+                local.lock().set_seed(rng.gen());
+            }
+        }
+    }
+
+    /// Biases the reservation of trees towards the memory of a core, e.g., its NUMA node.
+    ///
+    /// Each core prefers the trees around the middle of its equal share of the memory,
+    /// as if the NUMA nodes were contiguous and the cores evenly distributed over them.
+    /// A tree that is one step further away costs `penalty`, and a tree of a
+    /// worse class (like an entirely free instead of a partially filled one)
+    /// costs [Trees::CLASS_COST], see [Trees::reserve_near].
+    /// This scans the trees within `3 * CLASS_COST / penalty` steps on every reservation,
+    /// which is slower than the default search for small penalties.
+    /// A `penalty` of 0 (default) disables the bias.
+    pub fn set_numa_penalty(&mut self, penalty: u32) {
+        self.numa_penalty = penalty;
+    }
+
+    /// Sets how many of the last [Local::L] frees of a core have to be in the same tree,
+    /// before that tree is reserved for the core.
+    ///
+    /// The default of [Local::L] only reserves on a run of related frees,
+    /// `Local::L / 2 + 1` on a majority, and `1` on every free.
+    /// The threshold is clamped to `1..=Local::L`.
+    pub fn set_frees_threshold(&mut self, threshold: usize) {
+        self.frees_threshold = threshold.clamp(1, Local::L);
+    }
+
+    /// Sets after how many reservations of a core its search for a new tree
+    /// restarts at its home, instead of near its previous tree.
+    ///
+    /// The homes are evenly spread over the memory, but frees (e.g., of long-lived
+    /// allocations in low memory) drag the reservations of all cores towards the same trees
+    /// over time, which increases contention.
+    /// The default of 0 never restarts, see also [LLFree::rebalance].
+    pub fn set_rebalance_interval(&mut self, interval: usize) {
+        self.rebalance_interval = interval;
+    }
+
+    /// Returns the largest distance of a reserved tree to the home of its core, in trees.
+    ///
+    /// The home of a core are the trees of its share of the memory.
+    pub fn reservation_spread(&self) -> usize {
+        let mut spread = 0;
+        for (slot, local) in self.local.iter().enumerate() {
+            let local = local.lock();
+            for kind in [Kind::Fixed, Kind::Movable, Kind::Huge] {
+                if let Some(tree) = local.preferred(kind) {
+                    spread = spread.max(self.home_distance(slot, tree.frame() / TREE_FRAMES));
+                }
+            }
+        }
+        spread
+    }
+
+    /// Unreserves the trees that are more than a share of the memory
+    /// away from the home of their core, see [LLFree::reservation_spread].
+    ///
+    /// The next reservation of these cores then starts at their home.
+    /// This can be called periodically by a maintenance thread.
+    /// Local data that is currently locked is skipped.
+    /// Returns the number of local data with returned trees.
+    pub fn rebalance(&self) -> usize {
+        let threshold = self.home(0).len();
+        let mut drained = 0;
+        for (slot, local) in self.local.iter().enumerate() {
+            let Some(mut local) = local.try_lock() else {
+                continue;
+            };
+            let mut any = false;
+            for kind in [Kind::Fixed, Kind::Movable, Kind::Huge] {
+                if let Some(tree) = local.preferred(kind)
+                    && self.home_distance(slot, tree.frame() / TREE_FRAMES) > threshold
+                {
+                    self.swap_reserved(local.preferred_mut(kind), None, kind);
+                    any = true;
+                }
+            }
+            drained += any as usize;
+        }
+        drained
+    }
+
+    /// Removes about `frames` free frames from circulation, e.g., for a balloon driver.
+    ///
+    /// Only entirely free trees are inflated, so `frames` is rounded up to whole trees.
+    /// Inflated trees are skipped by allocations and not counted as free.
+    /// Returns the inflated frame ranges, which might be less than requested.
+    ///
+    /// Note: Recovering a persistent allocator treats inflated trees as free.
+    #[cfg(feature = "std")]
+    #[cold]
+    pub fn inflate(&self, frames: usize) -> Result<std::vec::Vec<Range<usize>>> {
+        let mut ranges: std::vec::Vec<Range<usize>> = std::vec::Vec::new();
+        let mut inflated = 0;
+        for i in 0..self.trees.len() {
+            if inflated >= frames {
+                break;
+            }
+            if self.trees.inflate(i) {
+                let start = i * TREE_FRAMES;
+                match ranges.last_mut() {
+                    Some(last) if last.end == start => last.end += TREE_FRAMES,
+                    _ => ranges.push(start..start + TREE_FRAMES),
+                }
+                inflated += TREE_FRAMES;
+            }
+        }
+        if ranges.is_empty() && frames > 0 {
+            return Err(Error::Memory);
+        }
+        Ok(ranges)
+    }
+
+    /// Returns the frame `ranges` of [LLFree::inflate] to the free pool.
+    #[cold]
+    pub fn deflate(&self, ranges: &[Range<usize>]) -> Result<()> {
+        for range in ranges {
+            if range.start % TREE_FRAMES != 0
+                || range.end % TREE_FRAMES != 0
+                || range.end > self.trees.len() * TREE_FRAMES
+            {
+                error!("invalid range {range:?}");
+                return Err(Error::Address);
+            }
+            for i in range.start / TREE_FRAMES..range.end / TREE_FRAMES {
+                if !self.trees.deflate(i) {
+                    error!("tree {i} not inflated");
+                    return Err(Error::Address);
+                }
+            }
+        }
+        Ok(())
+    }
+
+    /// Clears the split markers of the huge frames in the tree at `start`,
+    /// that are no longer split because they are entirely free or allocated as a whole.
+    #[cold]
+    pub fn clear_split_markers(&self, start: usize) {
+        self.lower.clear_split_markers(start)
+    }
+
+    /// Calls `f` with the first frame of every huge frame that was split for small
+    /// allocations since the last [LLFree::clear_split_markers] and has free frames.
+    ///
+    /// This can be used to find candidates for collapsing huge frames.
+    #[cold]
+    pub fn for_each_split_huge(&self, f: impl FnMut(usize)) {
+        self.lower.for_each_split_huge(f)
+    }
+
+    /// Returns the number of maximal free aligned blocks of each order,
+    /// e.g., for monitoring the fragmentation.
+    ///
+    /// This walks the lower allocator once and is not atomic with concurrent operations.
+    #[cold]
+    pub fn free_orders(&self) -> [usize; MAX_ORDER + 1] {
+        let mut orders = [0; MAX_ORDER + 1];
+        self.lower.free_orders(&mut orders);
+        orders
+    }
+
+    /// Returns the number of trees that are neither entirely free nor entirely allocated.
+    #[cold]
+    pub fn partial_trees(&self) -> usize {
+        (0..self.trees.len())
+            .filter(|&i| {
+                let free = self.free_at(i * TREE_FRAMES, TREE_FRAMES);
+                free > 0 && free < self.trees.max(i).0
+            })
+            .count()
+    }
+
+    /// Identifies the [AllocSnapshot] of this allocator
+    #[cfg(feature = "std")]
+    const SNAPSHOT_MAGIC: usize = 0x11f4_ee5a;
+
+    fn lower_get(&self, core: usize, mut tree: LocalTree, flags: Flags) -> Result<LocalTree> {
+        let (frame, huge) = self.lower.get(core, tree.frame(), flags)?;
         tree.set_frame(frame);
         tree.set_free(tree.free() - (1 << flags.order()));
         let huge = (huge as usize).max((1 << flags.order()) / HUGE_FRAMES);
@@ -310,49 +1141,167 @@ impl LLFree<'_> {
         Ok(tree)
     }
 
-    /// Steal a tree from another core
-    fn steal_tree(&self, core: usize, flags: Flags) -> Result<LocalTree> {
-        for i in 1..self.local.len() {
-            let target_core = (core + i) % self.local.len();
+    /// Steal a tree from another core.
+    ///
+    /// The victims are scanned from a random offset,
+    /// so that cores running out of memory at the same time do not all target the same one.
+    fn steal_tree(&self, core: usize, flags: Flags, rand: u64) -> Result<LocalTree> {
+        let others = self.local.len() - 1;
+        let offset = rand as usize % others.max(1);
+        for i in 0..others {
+            let target_core = (core + 1 + (offset + i) % others) % self.local.len();
             if let Some(mut target) = self.local[target_core].try_lock()
                 && let Some(tree) = target.preferred_mut(flags.into())
                 && tree.free() >= (1 << flags.order())
                 && tree.huge() >= (1 << flags.order()) / HUGE_FRAMES
-                && let Ok(new) = self.lower_get(*tree, flags)
+                && let Ok(new) = self.lower_get(core, *tree, flags)
             {
                 assert!(new.frame() / TREE_FRAMES == tree.frame() / TREE_FRAMES);
                 *target.preferred_mut(flags.into()) = None;
+                #[cfg(feature = "search_stats")]
+                STOLEN.fetch_add(1, core::sync::atomic::Ordering::Relaxed);
                 return Ok(new);
             }
         }
         Err(Error::Memory)
     }
 
+    /// Allocates a frame, returning it and the number of retries that were needed
+    fn get_retry(&self, core: usize, flags: Flags) -> Result<(usize, usize)> {
+        debug_assert!(self.is_initialized());
+        if flags.order() > MAX_ORDER {
+            error!("invalid order");
+            return Err(Error::InvalidOrder {
+                requested: flags.order(),
+                max: MAX_ORDER,
+            });
+        }
+        match GetMode::from(flags) {
+            GetMode::Fast => match self.get_inner(core, flags, GetMode::Fast) {
+                Ok(frame) => Ok((frame, 0)),
+                Err(Error::Retry) => Err(Error::Memory),
+                Err(e) => Err(e),
+            },
+            GetMode::Normal => {
+                // Retry allocation up to n times if it fails due to a concurrent update
+                for retries in 0..RETRIES {
+                    match self.get_inner(core, flags, GetMode::Normal) {
+                        Ok(frame) => return Ok((frame, retries)),
+                        Err(Error::Retry) => continue,
+                        Err(Error::Memory) => {
+                            let free = self.free_frames_cached();
+                            return Err(Self::classify_failure(flags.order(), free));
+                        }
+                        Err(e) => return Err(e),
+                    }
+                }
+                log_throttled!(error, "Exceeding retries");
+                Err(Error::Memory)
+            }
+            GetMode::NoFail => {
+                let mut backoff = 1;
+                let mut retries = 0;
+                let mut free = self.free_frames_cached();
+                let mut stalled = 0;
+                loop {
+                    match self.get_inner(core, flags, GetMode::NoFail) {
+                        Ok(frame) => return Ok((frame, retries)),
+                        Err(Error::Retry) => {}
+                        Err(Error::Memory) => {
+                            // Only wait as long as other cores free frames,
+                            // fragmented memory might otherwise never serve this order
+                            let now = self.free_frames_cached();
+                            stalled = if now > free { 0 } else { stalled + 1 };
+                            if now < 1 << flags.order() || stalled > NOFAIL_STALLS {
+                                return Err(Self::classify_failure(flags.order(), now));
+                            }
+                            free = now;
+                        }
+                        Err(e) => return Err(e),
+                    }
+                    // Exponential backoff
+                    for _ in 0..backoff {
+                        core::hint::spin_loop();
+                    }
+                    backoff = (backoff * 2).min(1 << 12);
+                    retries += 1;
+                }
+            }
+        }
+    }
+
+    /// Returns the free frames of the reserved trees, skipping locked ones
+    fn reserved_free(&self) -> usize {
+        let mut frames = 0;
+        for local in self.local.iter() {
+            if let Some(local) = local.try_lock() {
+                for kind in [Kind::Fixed, Kind::Movable, Kind::Huge] {
+                    if let Some(tree) = local.preferred(kind) {
+                        frames += tree.free();
+                    }
+                }
+            }
+        }
+        frames
+    }
+
+    /// Like [Alloc::free_frames], but with the running sum of the global counters,
+    /// which avoids loading all trees on the failure paths
+    fn free_frames_cached(&self) -> usize {
+        self.trees.free_frames_cached() + self.reserved_free()
+    }
+
+    /// Distinguishes fragmentation from exhaustion after an allocation of `order` failed,
+    /// with the `free` frames that were counted after the failure.
+    ///
+    /// Base frames cannot be fragmented, they might only be reserved by other cores.
+    #[cold]
+    fn classify_failure(order: usize, free: usize) -> Error {
+        if order > 0 && free >= 1 << order {
+            Error::Fragmented
+        } else {
+            Error::Memory
+        }
+    }
+
     /// Try to allocate a frame with the given order
-    fn get_inner(&self, core: usize, flags: Flags) -> Result<usize> {
-        let mut local = self.local[core].lock();
+    fn get_inner(&self, core: usize, flags: Flags, mode: GetMode) -> Result<usize> {
+        let (core, mut local) = self.lock_local(core);
 
         let min_huge = (1 << flags.order()) / HUGE_FRAMES;
 
         // Try decrementing the local counter
-        if let Some(tree) = local.preferred_mut(flags.into())
+        if let Some(tree) = local.preferred(flags.into())
             && tree.free() >= 1 << flags.order()
             && tree.huge() >= min_huge
         {
-            match self.lower_get(*tree, flags) {
+            let start = if self.randomized {
+                // Start at a random frame of the tree
+                let base = align_down(tree.frame(), TREE_FRAMES);
+                let len = self.trees.max(base / TREE_FRAMES).0;
+                tree.with_frame(base + local.random() as usize % len)
+            } else {
+                // Start where the last allocation of a similar order ended
+                tree.with_frame(local.hint(flags, tree))
+            };
+            match self.lower_get(core, start, flags) {
                 Ok(new) => {
                     assert!(new.frame() / TREE_FRAMES == tree.frame() / TREE_FRAMES);
-                    *tree = new;
+                    *local.preferred_mut(flags.into()) = Some(new);
+                    local.set_hint(flags, new.frame());
                     Ok(new.frame())
                 }
-                Err(Error::Memory) => {
+                Err(Error::Memory) if mode != GetMode::Fast => {
                     // Failure due to fragmentation
                     // Reset counters, reserve new entry and retry allocation
-                    info!("alloc failed {flags:?} => retry");
+                    log_throttled!(info, "alloc failed {flags:?} => retry");
                     self.reserve_and_get(&mut local, core, flags)
                 }
                 Err(e) => Err(e),
             }
+        } else if mode == GetMode::Fast {
+            // Leave syncing and reserving to a later allocation
+            Err(Error::Memory)
         } else {
             // Try sync with global counter
             if let Some(tree) = local.preferred_mut(flags.into()) {
@@ -372,42 +1321,73 @@ impl LLFree<'_> {
     /// Returns if the global counter was large enough
     fn sync_with_global(&self, tree: &mut LocalTree, order: usize) -> bool {
         let i = tree.frame() / TREE_FRAMES;
-        let min = Trees::MIN_FREE.saturating_sub(tree.free());
+        // Scaled down for a smaller last tree, which might never reach the threshold otherwise
+        let min = Trees::MIN_FREE * self.trees.max(i).0 / TREE_FRAMES;
+        let min = min.saturating_sub(tree.free());
         let min_huge = ((1 << order) / HUGE_FRAMES).saturating_sub(tree.huge());
-        if let Some(global) = self.trees.sync(i, min, min_huge) {
+        if let Some(global) = self.trees.sync(i, tree.generation(), min, min_huge) {
             tree.set_free(tree.free() + global.free());
             tree.set_huge(tree.huge() + global.huge());
+            self.debug_check_local(tree);
             true
         } else {
             false
         }
     }
 
+    /// Checks that the counters of a reserved tree do not exceed the tree
+    fn debug_check_local(&self, tree: &LocalTree) {
+        let max = self.trees.max(tree.frame() / TREE_FRAMES);
+        debug_assert!(
+            tree.free() <= max.0 && tree.huge() <= max.1,
+            "exceeds {max:?}: {tree:?}"
+        );
+    }
+
     /// Reserve a new tree and allocate the frame in it
     fn reserve_and_get(&self, local: &mut Local, core: usize, flags: Flags) -> Result<usize> {
+        // Periodically restart at home, as the previous trees drift away
+        let rebalance = local.count_reservation(self.rebalance_interval);
+
         // Try reserve new tree
-        let preferred = local.preferred_mut(flags.into());
-        let start = if let Some(tree) = *preferred {
+        let start = if self.randomized {
+            local.random() as usize % self.trees.len()
+        } else if !rebalance && let Some(tree) = local.preferred(flags.into()) {
             tree.frame() / TREE_FRAMES
         } else {
             // Different initial starting point for every core
-            self.trees.len() / self.local.len() * core
+            self.home(core).start
         };
 
         // Reserved a new tree an allocate a frame in it
         let cores = self.local.len();
-        match self
-            .trees
-            .reserve(cores, start, flags, |t, f| self.lower_get(t, f))
-        {
+        let get_lower = |t, f| self.lower_get(core, t, f);
+        let reserved = if self.deterministic {
+            self.trees.reserve_lowest(flags, get_lower)
+        } else if self.randomized {
+            let step = self.trees.coprime_step(local.random());
+            self.trees.reserve_permuted(start, step, flags, get_lower)
+        } else if self.numa_penalty > 0 {
+            let hint = NumaHint {
+                preferred_start: self.trees.len() * (2 * core + 1) / (2 * self.local.len()),
+                penalty_per_step: self.numa_penalty,
+            };
+            self.trees.reserve_near(hint, flags, get_lower)
+        } else {
+            self.trees.reserve(cores, start, flags, get_lower)
+        };
+        let preferred = local.preferred_mut(flags.into());
+        match reserved {
             Ok(new) => {
                 self.swap_reserved(preferred, Some(new), flags.into());
+                local.set_hint(flags, new.frame());
                 Ok(new.frame())
             }
             Err(Error::Memory) => {
                 // Fall back to stealing from other cores
-                let new = self.steal_tree(core, flags)?;
-                self.swap_reserved(preferred, Some(new), flags.into());
+                let new = self.steal_tree(core, flags, local.random())?;
+                self.swap_reserved(local.preferred_mut(flags.into()), Some(new), flags.into());
+                local.set_hint(flags, new.frame());
                 Ok(new.frame())
             }
             Err(e) => Err(e),
@@ -420,8 +1400,51 @@ impl LLFree<'_> {
     fn swap_reserved(&self, preferred: &mut Option<LocalTree>, new: Option<LocalTree>, kind: Kind) {
         let old_tree = core::mem::replace(preferred, new);
         if let Some(tree) = old_tree {
+            let i = tree.frame() / TREE_FRAMES;
             self.trees
-                .unreserve(tree.frame() / TREE_FRAMES, tree.free(), tree.huge(), kind);
+                .unreserve(i, tree.free(), tree.huge(), tree.generation(), kind);
+        }
+    }
+}
+
+/// How hard [LLFree::get] tries to allocate a frame
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum GetMode {
+    /// Only try the local tree once
+    Fast,
+    /// Retry up to [RETRIES] times if interrupted by concurrent updates
+    Normal,
+    /// Retry with backoff while there are enough free frames.
+    ///
+    /// This does not wait forever: if no frames are freed for [NOFAIL_STALLS] rounds,
+    /// e.g., because the memory is too fragmented, it fails like [GetMode::Normal].
+    NoFail,
+}
+
+impl From<Flags> for GetMode {
+    fn from(flags: Flags) -> Self {
+        if flags.fast() {
+            Self::Fast
+        } else if flags.nofail() {
+            Self::NoFail
+        } else {
+            Self::Normal
+        }
+    }
+}
+
+impl Drop for LLFree<'_> {
+    /// Returns the reserved trees of all cores, so that no reservations are leaked
+    /// if the allocator is recreated over the same metadata.
+    fn drop(&mut self) {
+        let _ = self.drain_all();
+        if cfg!(debug_assertions) && !self.trees.corrupted() {
+            for i in 0..self.trees.len() {
+                // Inflated trees are marked as reserved
+                let tree = self.trees.get(i);
+                let leaked = tree.reserved() && !tree.inflated();
+                assert!(!leaked, "leaked reservation {i}");
+            }
         }
     }
 }
@@ -456,6 +1479,7 @@ impl fmt::Debug for LLFree<'_> {
                     f.finish()
                 }),
             )
+            .field("foreign", &self.foreign_ops())
             .finish()?;
         Ok(())
     }
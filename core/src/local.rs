@@ -1,21 +1,44 @@
 use bitfield_struct::bitfield;
 
-use crate::trees::Kind;
+use crate::trees::{Kind, Tree};
+use crate::util::WyRand;
+use crate::{Flags, TREE_FRAMES, TREE_HUGE};
 
 /// Core-local data
 #[derive(Default, Debug)]
 pub struct Local {
     /// Reserved trees for each [Kind]
     preferred: [Option<LocalTree>; Kind::LEN],
-    /// Tree index of the last freed frame
-    last_idx: usize,
-    /// Last frees counter
-    last_frees: u8,
+    /// Frames of the last allocations for each [Kind] and order bucket
+    hints: [[usize; Local::BUCKETS]; Kind::LEN],
+    /// Tree indices (+1, 0 is empty) of the last [Local::L] frees, as ring buffer
+    last_frees: [usize; Local::L],
+    /// Next position in `last_frees`
+    last_pos: u8,
+    /// Random state, used for choosing victims when stealing and for randomized placement
+    seed: u64,
+    /// First core that is mapped to this local data
+    owner: usize,
+    /// Number of reserved trees, see [LLFree::set_rebalance_interval]
+    ///
+    /// [LLFree::set_rebalance_interval]: crate::LLFree::set_rebalance_interval
+    reservations: usize,
 }
 
 impl Local {
-    /// Threshold for the number of frees after which a tree is reserved
-    const F: u8 = 4;
+    /// Number of recent frees that are considered for reserving a tree on free
+    pub const L: usize = 5;
+    /// Number of order buckets with separate start hints
+    const BUCKETS: usize = 3;
+
+    /// Returns the start hint bucket for `order`
+    fn bucket(order: usize) -> usize {
+        match order {
+            0..=2 => 0,
+            3..=6 => 1,
+            _ => 2,
+        }
+    }
 
     pub fn preferred(&self, kind: Kind) -> Option<LocalTree> {
         self.preferred[kind as usize]
@@ -24,18 +47,54 @@ impl Local {
         &mut self.preferred[kind as usize]
     }
 
-    /// Add a tree index to the history, returing if there are enough frees
-    pub fn frees_push(&mut self, tree_idx: usize) -> bool {
-        if self.last_idx == tree_idx {
-            if self.last_frees >= Self::F {
-                return true;
-            }
-            self.last_frees += 1;
+    /// Returns where to start searching for an allocation with `flags` in `tree`.
+    ///
+    /// This is the last allocation of a similar order if it is within the tree.
+    pub fn hint(&self, flags: Flags, tree: LocalTree) -> usize {
+        let hint = self.hints[Kind::from(flags) as usize][Self::bucket(flags.order())];
+        if hint / TREE_FRAMES == tree.frame() / TREE_FRAMES {
+            hint
         } else {
-            self.last_idx = tree_idx;
-            self.last_frees = 0;
+            tree.frame()
         }
-        false
+    }
+    /// Remember the `frame` of the last allocation with `flags`.
+    pub fn set_hint(&mut self, flags: Flags, frame: usize) {
+        self.hints[Kind::from(flags) as usize][Self::bucket(flags.order())] = frame;
+    }
+
+    /// Returns the first core that is mapped to this local data
+    pub fn owner(&self) -> usize {
+        self.owner
+    }
+    pub fn set_owner(&mut self, core: usize) {
+        self.owner = core;
+    }
+
+    pub fn set_seed(&mut self, seed: u64) {
+        self.seed = seed;
+    }
+    /// Returns a new random number
+    pub fn random(&mut self) -> u64 {
+        let mut rng = WyRand::new(self.seed);
+        let val = rng.gen();
+        self.seed = rng.seed;
+        val
+    }
+
+    /// Count a reservation, returning if it is the `interval`-th since the last one that did
+    pub fn count_reservation(&mut self, interval: usize) -> bool {
+        self.reservations = self.reservations.wrapping_add(1);
+        interval > 0 && self.reservations % interval == 0
+    }
+
+    /// Add a tree index to the history, returning if at least `threshold`
+    /// of the last [Local::L] frees (including this one) were in this tree
+    pub fn frees_push(&mut self, tree_idx: usize, threshold: usize) -> bool {
+        let entry = tree_idx + 1;
+        self.last_frees[self.last_pos as usize] = entry;
+        self.last_pos = (self.last_pos + 1) % Self::L as u8;
+        self.last_frees.iter().filter(|e| **e == entry).count() >= threshold
     }
 }
 
@@ -43,14 +102,26 @@ impl Local {
 #[bitfield(u64)]
 #[derive(PartialEq, Eq)]
 pub struct LocalTree {
-    #[bits(45)]
+    #[bits(41)]
     pub frame: usize,
     #[bits(15)]
     pub free: usize,
     #[bits(4)]
     pub huge: usize,
+    /// Generation of the reservation, see [Tree::generation]
+    #[bits(4)]
+    pub generation: usize,
 }
+const _: () = assert!(1 << LocalTree::FREE_BITS >= TREE_FRAMES);
+const _: () = assert!(1 << LocalTree::HUGE_BITS >= TREE_HUGE);
+const _: () = assert!(1 << LocalTree::GENERATION_BITS == Tree::GENERATIONS);
+// The frees heuristic needs at least one free and has to fit the position
+const _: () = assert!(Local::L > 0 && Local::L < u8::MAX as usize);
+
 impl LocalTree {
+    /// Number of frames that can be addressed by a local tree
+    pub const MAX_FRAMES: usize = 1 << Self::FRAME_BITS;
+
     pub fn with(frame: usize, free: usize, huge: usize) -> Self {
         Self::new()
             .with_frame(frame)
@@ -60,25 +131,69 @@ impl LocalTree {
 }
 #[cfg(all(test, feature = "std"))]
 mod test {
-    use super::Local;
+    use super::{Local, LocalTree};
+    use crate::{Flags, TREE_FRAMES};
+
+    #[test]
+    fn hints() {
+        let mut local = Local::default();
+        let tree = LocalTree::with(TREE_FRAMES + 5, TREE_FRAMES, 0);
+        // No hint within this tree
+        assert_eq!(local.hint(Flags::o(0), tree), TREE_FRAMES + 5);
+
+        local.set_hint(Flags::o(0), TREE_FRAMES + 100);
+        local.set_hint(Flags::o(6), TREE_FRAMES + 1024);
+        assert_eq!(local.hint(Flags::o(1), tree), TREE_FRAMES + 100);
+        assert_eq!(local.hint(Flags::o(4), tree), TREE_FRAMES + 1024);
+        assert_eq!(local.hint(Flags::o(8), tree), TREE_FRAMES + 5);
+        // Separate for movable frames
+        assert_eq!(
+            local.hint(Flags::o(0).with_movable(true), tree),
+            TREE_FRAMES + 5
+        );
+
+        // Never outside the reserved tree
+        let tree = LocalTree::with(3 * TREE_FRAMES, TREE_FRAMES, 0);
+        assert_eq!(local.hint(Flags::o(0), tree), 3 * TREE_FRAMES);
+        assert_eq!(local.hint(Flags::o(6), tree), 3 * TREE_FRAMES);
+    }
 
     /// Testing the related frames heuristic for frees
     #[test]
     fn last_frees() {
+        const L: usize = Local::L;
         let mut local = Local::default();
         let frame1 = 43;
         let i1 = frame1 / (512 * 512);
-        assert!(!local.frees_push(i1));
-        assert!(!local.frees_push(i1));
-        assert!(!local.frees_push(i1));
-        assert!(!local.frees_push(i1));
-        assert!(local.frees_push(i1));
-        assert!(local.frees_push(i1));
+        assert!(!local.frees_push(i1, L));
+        assert!(!local.frees_push(i1, L));
+        assert!(!local.frees_push(i1, L));
+        assert!(!local.frees_push(i1, L));
+        assert!(local.frees_push(i1, L));
+        assert!(local.frees_push(i1, L));
         let frame2 = 512 * 512 + 43;
         let i2 = frame2 / (512 * 512);
         assert_ne!(i1, i2);
-        assert!(!local.frees_push(i2));
-        assert!(!local.frees_push(i2));
-        assert!(!local.frees_push(i1));
+        assert!(!local.frees_push(i2, L));
+        assert!(!local.frees_push(i2, L));
+        assert!(!local.frees_push(i1, L));
+    }
+
+    /// Only a majority of the recent frees has to be in the tree
+    #[test]
+    fn last_frees_threshold() {
+        const T: usize = Local::L / 2 + 1;
+        let mut local = Local::default();
+        assert!(!local.frees_push(1, T));
+        assert!(!local.frees_push(2, T));
+        assert!(!local.frees_push(1, T));
+        assert!(local.frees_push(1, T));
+        assert!(!local.frees_push(2, T));
+        assert!(local.frees_push(1, T));
+
+        // Every free is enough
+        let mut local = Local::default();
+        assert!(local.frees_push(3, 1));
+        assert!(local.frees_push(4, 1));
     }
 }
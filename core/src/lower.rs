@@ -1,24 +1,28 @@
 //! Lower allocator implementations
 
 use core::mem::{align_of, size_of};
-use core::slice;
-use core::sync::atomic::{AtomicU16, AtomicU32};
+use core::ops::Range;
+use core::sync::atomic::Ordering::Relaxed;
+use core::sync::atomic::{AtomicU16, AtomicU32, AtomicUsize};
+use core::{fmt, slice};
 
 use bitfield_struct::bitfield;
 use log::{error, info, warn};
 
-use crate::atomic::{Atom, AtomArray, Atomic};
-use crate::util::{align_down, size_of_slice, spin_wait, Align};
-use crate::{
-    Error, Flags, Init, Result, HUGE_FRAMES, HUGE_ORDER, MAX_ORDER, RETRIES, TREE_FRAMES, TREE_HUGE,
-};
+use crate::atomic::{stream_fence, Atom, AtomArray, Atomic};
+use crate::util::{align_down, log_throttled, size_of_slice, spin_wait, Align};
+use crate::{Error, Flags, Init, Result, FRAME_SIZE, HUGE_ORDER, MAX_ORDER, RETRIES, TREE_HUGE};
 
-type Bitfield = crate::bitfield::Bitfield<8>;
+type Bitfield<const N: usize> = crate::bitfield::Bitfield<N>;
+
+/// Number of failed updates of table entries for small frames, for benchmarking.
+#[cfg(feature = "search_stats")]
+pub static CONFLICTS: AtomicUsize = AtomicUsize::new(0);
 
 /// Lower-level frame allocator.
 ///
 /// This level implements the actual allocation/free operations.
-/// Each allocation/free is limited to a chunk of [LowerAlloc::N] frames.
+/// Each allocation/free is limited to a chunk of [Lower::N] frames.
 ///
 /// Here the bitfields are 512 bit large -> strong focus on huge frames.
 /// Upon that is a table for each tree, with an entry per bitfield.
@@ -26,6 +30,10 @@ type Bitfield = crate::bitfield::Bitfield<8>;
 /// The parameter `HP` configures the number of table entries (huge frames per tree).
 /// It has to be a multiple of 2!
 ///
+/// The parameter `BF` configures the number of 64 bit entries per bitfield.
+/// Smaller bitfields, like 128 bits, reduce the contention on the table entries
+/// for small allocations, but also the size of [Lower::HUGE_ORDER] frames.
+///
 /// ## Memory Layout
 /// **persistent:**
 /// ```text
@@ -36,16 +44,234 @@ type Bitfield = crate::bitfield::Bitfield<8>;
 /// RAM: [ Frames ], Bitfields and Tables are allocated elswhere
 /// ```
 #[derive(Default, Debug)]
-pub struct Lower<'a> {
+pub struct Lower<'a, const HP: usize = TREE_HUGE, const BF: usize = 8> {
     len: usize,
-    bitfields: &'a [Align<Bitfield>],
-    children: &'a [Align<[Atom<HugeEntry>; TREE_HUGE]>],
+    bitfields: &'a [Align<Bitfield<BF>>],
+    children: &'a [Align<[Atom<HugeEntry>; HP]>],
+    /// Number of recovered tables of the current or last deep recovery
+    recovered: AtomicUsize,
+    /// Per-core event counters, see [Lower::set_counters]
+    #[cfg(feature = "counters")]
+    counters: &'a [Align<Counters>],
+}
+
+unsafe impl<const HP: usize, const BF: usize> Send for Lower<'_, HP, BF> {}
+unsafe impl<const HP: usize, const BF: usize> Sync for Lower<'_, HP, BF> {}
+
+// The default configuration matches the orders of the upper allocator
+const _: () = assert!(<Lower<'static>>::HUGE_ORDER == HUGE_ORDER);
+const _: () = assert!(<Lower<'static>>::MAX_ORDER == MAX_ORDER);
+
+/// Events that are counted per core and order with the `counters` feature
+#[derive(Debug, Clone, Copy)]
+enum Event {
+    Alloc,
+    Free,
+    /// A search returned [Error::Memory]
+    Failed,
+    /// A table entry was decremented, but the bitfield had no matching frame
+    Revert,
+}
+
+impl Event {
+    #[allow(unused)]
+    const LEN: usize = 4;
+}
+
+/// Number of events of each order, like `/proc/buddyinfo` for the operations
+#[cfg(feature = "counters")]
+#[derive(Debug, Default, Clone, PartialEq, Eq)]
+pub struct EventCounts {
+    pub allocs: [usize; MAX_ORDER + 1],
+    pub frees: [usize; MAX_ORDER + 1],
+    /// Searches that found no free frame
+    pub failed: [usize; MAX_ORDER + 1],
+    /// Reverted table entry updates, because the bitfield was too fragmented
+    pub reverts: [usize; MAX_ORDER + 1],
+}
+
+#[cfg(feature = "counters")]
+impl core::ops::AddAssign<&EventCounts> for EventCounts {
+    fn add_assign(&mut self, other: &EventCounts) {
+        for (a, b) in [
+            (&mut self.allocs, &other.allocs),
+            (&mut self.frees, &other.frees),
+            (&mut self.failed, &other.failed),
+            (&mut self.reverts, &other.reverts),
+        ] {
+            a.iter_mut().zip(b).for_each(|(a, b)| *a += b);
+        }
+    }
 }
 
-unsafe impl Send for Lower<'_> {}
-unsafe impl Sync for Lower<'_> {}
+/// Relaxed event counters of a core
+#[cfg(feature = "counters")]
+#[derive(Debug, Default)]
+pub struct Counters([[AtomicUsize; MAX_ORDER + 1]; Event::LEN]);
+
+#[cfg(feature = "counters")]
+impl Counters {
+    pub fn load(&self) -> EventCounts {
+        let load = |event: Event| self.0[event as usize].each_ref().map(|c| c.load(Relaxed));
+        EventCounts {
+            allocs: load(Event::Alloc),
+            frees: load(Event::Free),
+            failed: load(Event::Failed),
+            reverts: load(Event::Revert),
+        }
+    }
+}
 
-const _: () = assert!(TREE_HUGE < (1 << (u16::BITS as usize - HUGE_ORDER)));
+/// Whether a deep recovery repairs the metadata or only reports what it would repair
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum RecoverMode {
+    /// Repair the metadata
+    Fix,
+    /// Only inspect the metadata, without modifying it
+    DryRun,
+}
+
+/// A table entry whose free counter diverged from its bitfield
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq)]
+pub struct FixedCounter {
+    /// Index of the table (tree)
+    pub table: usize,
+    /// Index of the entry (huge frame) within the table
+    pub entry: usize,
+    /// Number of free frames in the bitfield
+    pub expected: usize,
+    /// Number of free frames in the entry
+    pub found: usize,
+}
+
+/// Damage found by a deep recovery, see [RecoverMode]
+#[derive(Debug, Default, Clone, PartialEq, Eq)]
+pub struct RecoveryReport {
+    /// Free frames after the recovery
+    pub free_frames: usize,
+    /// The first [RecoveryReport::MAX_FIXED] diverging counters
+    fixed: [FixedCounter; RecoveryReport::MAX_FIXED],
+    /// Number of diverging counters, including those beyond [RecoveryReport::MAX_FIXED]
+    pub fixed_counters: usize,
+    /// Bitfields of huge frames that were not empty
+    pub cleared_bitfields: usize,
+    /// Entries of allocated huge frames
+    pub huge_frames: usize,
+    /// Torn entries beyond the end of memory (only [Lower::recover_deep_verify])
+    pub torn_entries: usize,
+}
+
+impl RecoveryReport {
+    /// Maximum number of recorded diverging counters
+    pub const MAX_FIXED: usize = 32;
+
+    /// Returns the recorded diverging counters
+    pub fn fixed(&self) -> &[FixedCounter] {
+        &self.fixed[..self.fixed_counters.min(Self::MAX_FIXED)]
+    }
+    /// Returns if nothing had to be repaired
+    pub fn is_clean(&self) -> bool {
+        self.fixed_counters == 0 && self.cleared_bitfields == 0 && self.torn_entries == 0
+    }
+    fn push_fixed(&mut self, fixed: FixedCounter) {
+        if let Some(slot) = self.fixed.get_mut(self.fixed_counters) {
+            *slot = fixed;
+        }
+        self.fixed_counters += 1;
+    }
+}
+
+impl core::ops::AddAssign<&RecoveryReport> for RecoveryReport {
+    fn add_assign(&mut self, other: &RecoveryReport) {
+        for fixed in other.fixed() {
+            self.push_fixed(*fixed);
+        }
+        // The unrecorded ones
+        self.fixed_counters += other.fixed_counters - other.fixed().len();
+        self.free_frames += other.free_frames;
+        self.cleared_bitfields += other.cleared_bitfields;
+        self.huge_frames += other.huge_frames;
+        self.torn_entries += other.torn_entries;
+    }
+}
+
+impl fmt::Display for RecoveryReport {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "free={} huge={} counters={} bitfields={} torn={}",
+            self.free_frames,
+            self.huge_frames,
+            self.fixed_counters,
+            self.cleared_bitfields,
+            self.torn_entries
+        )
+    }
+}
+
+/// State of a huge frame in a [MemoryMap]
+#[cfg(feature = "std")]
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum HugeStateKind {
+    /// Entirely free
+    Free,
+    /// Allocated as a whole
+    Huge,
+    /// Split into smaller frames, with the given number of free frames
+    Partial(usize),
+}
+
+/// A huge frame of a [ChunkState]
+#[cfg(feature = "std")]
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct HugeState {
+    /// First frame of the huge frame
+    pub frame: usize,
+    pub state: HugeStateKind,
+}
+
+/// A chunk (tree) of a [MemoryMap]
+#[cfg(feature = "std")]
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ChunkState {
+    /// First frame of the chunk
+    pub start: usize,
+    /// Number of frames, which is smaller for the last chunk
+    pub len: usize,
+    /// Number of free frames
+    pub free: usize,
+    /// States of the huge frames within the memory
+    pub huge_entries: std::vec::Vec<HugeState>,
+}
+
+/// States of all chunks and huge frames, see [Lower::memory_map]
+#[cfg(feature = "std")]
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct MemoryMap {
+    pub chunks: std::vec::Vec<ChunkState>,
+}
+
+/// One line per chunk, with `.` for free, `H` for huge, `+` for partially
+/// and `#` for entirely allocated split huge frames
+#[cfg(feature = "std")]
+impl fmt::Display for MemoryMap {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        for chunk in &self.chunks {
+            write!(f, "{:>10} [", chunk.start)?;
+            for huge in &chunk.huge_entries {
+                let c = match huge.state {
+                    HugeStateKind::Free => '.',
+                    HugeStateKind::Huge => 'H',
+                    HugeStateKind::Partial(0) => '#',
+                    HugeStateKind::Partial(_) => '+',
+                };
+                write!(f, "{c}")?;
+            }
+            writeln!(f, "] {}/{}", chunk.free, chunk.len)?;
+        }
+        Ok(())
+    }
+}
 
 /// Size of the dynamic metadata
 struct Metadata {
@@ -56,28 +282,69 @@ struct Metadata {
 }
 
 impl Metadata {
-    fn new(frames: usize) -> Self {
-        let bitfield_len = frames.div_ceil(Bitfield::LEN);
-        let table_len = frames.div_ceil(TREE_FRAMES);
+    const fn new<const HP: usize, const BF: usize>(frames: usize) -> Self {
+        let bitfield_len = frames.div_ceil(Bitfield::<BF>::LEN);
+        let table_len = frames.div_ceil(HP * Bitfield::<BF>::LEN);
         Self {
             bitfield_len,
             // This also respects the cache line alignment
-            bitfield_size: size_of_slice::<Bitfield>(bitfield_len),
+            bitfield_size: size_of_slice::<Align<Bitfield<BF>>>(bitfield_len),
             table_len,
-            table_size: size_of_slice::<Align<[HugeEntry; TREE_HUGE]>>(table_len),
+            table_size: size_of_slice::<Align<[HugeEntry; HP]>>(table_len),
         }
     }
 }
 
-impl<'a> Lower<'a> {
-    pub fn metadata_size(frames: usize) -> usize {
-        let m = Metadata::new(frames);
+impl<'a, const HP: usize, const BF: usize> Lower<'a, HP, BF> {
+    /// Number of frames managed by a table (tree)
+    pub const N: usize = HP * Bitfield::<BF>::LEN;
+    /// Order of an entire bitfield, which can be allocated as huge frame
+    pub const HUGE_ORDER: usize = Bitfield::<BF>::ORDER;
+    /// Number of frames in a huge frame
+    pub const HUGE_FRAMES: usize = 1 << Self::HUGE_ORDER;
+    /// Order of two huge frames, the largest supported allocation
+    pub const MAX_ORDER: usize = Self::HUGE_ORDER + 1;
+    /// Minimum number of tables for a parallel recovery,
+    /// below which spawning threads costs more than it saves
+    const PARALLEL_RECOVERY: usize = 64;
+
+    pub const fn metadata_size(frames: usize) -> usize {
+        let m = Metadata::new::<HP, BF>(frames);
         m.bitfield_size + m.table_size
     }
 
+    /// Size of the metadata if it is stored at the end of a persistent
+    /// memory region of `len` frames, rounded up to whole frames.
+    ///
+    /// The remaining `len - size / FRAME_SIZE` frames can be allocated.
+    /// If the metadata size grows exactly at this boundary, one frame remains unused.
+    pub const fn persistent_metadata_size(len: usize) -> usize {
+        let mut meta = Self::metadata_size(len).div_ceil(FRAME_SIZE);
+        if meta > len {
+            return len * FRAME_SIZE;
+        }
+        // Shrink as long as the metadata of the remaining frames still fits
+        while meta > 0 && Self::metadata_size(len - meta + 1).div_ceil(FRAME_SIZE) < meta {
+            meta -= 1;
+        }
+        meta * FRAME_SIZE
+    }
+
     /// Create a new lower allocator.
-    pub fn new(frames: usize, init: Init, primary: &'a mut [u8]) -> Result<Self> {
-        let m = Metadata::new(frames);
+    ///
+    /// A deep recovery is distributed over `cores` threads if possible.
+    pub fn new(cores: usize, frames: usize, init: Init, primary: &'a mut [u8]) -> Result<Self> {
+        const {
+            assert!(HP % 2 == 0 && HP > 0);
+            assert!(BF.is_power_of_two() && BF >= 2);
+            assert!(Bitfield::<BF>::LEN < HugeEntry::HUGE as usize);
+            assert!(HP < (1 << (u16::BITS as usize - Self::HUGE_ORDER)));
+        }
+        if frames == 0 {
+            error!("no frames to manage");
+            return Err(Error::Initialization);
+        }
+        let m = Metadata::new::<HP, BF>(frames);
 
         if primary.len() < m.bitfield_size + m.table_size
             || primary.as_ptr() as usize % align_of::<Align>() != 0
@@ -85,6 +352,13 @@ impl<'a> Lower<'a> {
             error!("primary metadata");
             return Err(Error::Initialization);
         }
+        #[cfg(feature = "pmem_flush")]
+        let persistent = (primary.as_ptr(), m.bitfield_size + m.table_size);
+        #[cfg(feature = "pmem_sim")]
+        {
+            let start = primary.as_ptr() as usize;
+            crate::atomic::pmem_sim::register(start..start + m.bitfield_size + m.table_size);
+        }
         let (bitfields, children) = primary.split_at_mut(m.bitfield_size);
 
         // Start of the l1 table array
@@ -99,13 +373,26 @@ impl<'a> Lower<'a> {
             len: frames,
             bitfields,
             children,
+            recovered: AtomicUsize::new(0),
+            #[cfg(feature = "counters")]
+            counters: &[],
         };
 
         match init {
             Init::FreeAll => alloc.free_all(),
             Init::AllocAll => alloc.reserve_all(),
             Init::Recover(false) => {} // skip, assuming everything is valid
-            Init::Recover(true) => alloc.recover(),
+            Init::Recover(true) => {
+                let report = alloc.recover(cores, RecoverMode::Fix);
+                if !report.is_clean() {
+                    warn!("recovered {report}");
+                }
+            }
+        }
+        // Make the (persistent) metadata durable
+        #[cfg(feature = "pmem_flush")]
+        unsafe {
+            crate::util::persist(persistent.0, persistent.1);
         }
         Ok(alloc)
     }
@@ -114,39 +401,225 @@ impl<'a> Lower<'a> {
         self.len
     }
 
+    /// Sets the per-core event counters, the operations of a `core` are counted
+    /// in `counters[core % counters.len()]`, nothing is counted if empty.
+    #[cfg(feature = "counters")]
+    pub fn set_counters(&mut self, counters: &'a [Align<Counters>]) {
+        self.counters = counters;
+    }
+    /// Returns the events counted for `core`
+    #[cfg(feature = "counters")]
+    pub fn counters(&self, core: usize) -> EventCounts {
+        match self.counters.len() {
+            0 => EventCounts::default(),
+            len => self.counters[core % len].0.load(),
+        }
+    }
+    /// Counts an `event` of `order` on `core`, this is a noop without the `counters` feature
+    #[inline]
+    fn count(&self, core: usize, event: Event, order: usize) {
+        #[cfg(feature = "counters")]
+        if !self.counters.is_empty() {
+            let counters = &self.counters[core % self.counters.len()].0;
+            counters.0[event as usize][order].fetch_add(1, Relaxed);
+        }
+        #[cfg(not(feature = "counters"))]
+        let _ = (core, event, order);
+    }
+
+    /// Number of frames occupied by the metadata, see [Lower::persistent_metadata_size]
+    pub fn metadata_frames(&self) -> usize {
+        Self::metadata_size(self.len).div_ceil(FRAME_SIZE)
+    }
+
+    /// Returns if the metadata covers all frames, which is not the case
+    /// for a default constructed allocator that manages frames.
+    pub fn is_initialized(&self) -> bool {
+        self.bitfields.len() == self.len.div_ceil(Bitfield::<BF>::LEN)
+            && self.children.len() == self.len.div_ceil(Self::N)
+    }
+
     pub fn metadata(&mut self) -> &'a mut [u8] {
         let len = Self::metadata_size(self.frames());
         unsafe { slice::from_raw_parts_mut(self.bitfields.as_ptr().cast_mut().cast(), len) }
     }
 
-    /// Recovers the data structures for the [LowerAlloc::N] sized chunk at `start`.
-    /// This corrects any data corrupted by a crash.
-    pub fn recover(&self) {
-        for (i, table) in self.children.iter().enumerate() {
+    /// Recovers the data structures, correcting any data corrupted by a crash.
+    ///
+    /// The tables are independent, so they are split between `threads`
+    /// if threads are available (std) and there are more than 64 tables.
+    /// The progress can be observed with [Lower::recovery_progress].
+    ///
+    /// Returns what was repaired, or with [RecoverMode::DryRun]
+    /// what would be repaired, without modifying anything.
+    pub fn recover(&self, threads: usize, mode: RecoverMode) -> RecoveryReport {
+        let tables = self.children.len();
+        self.recovered.store(0, Relaxed);
+
+        #[cfg(feature = "std")]
+        if threads > 1 && tables > Self::PARALLEL_RECOVERY {
+            let chunk = tables.div_ceil(threads);
+            let reports = crate::thread::parallel((0..tables).step_by(chunk), |start| {
+                self.recover_tables(start..(start + chunk).min(tables), mode)
+            });
+            let mut report = RecoveryReport::default();
+            for r in &reports {
+                report += r;
+            }
+            return report;
+        }
+        #[cfg(not(feature = "std"))]
+        let _ = threads;
+        self.recover_tables(0..tables, mode)
+    }
+
+    /// Returns the number of recovered and total tables of the current or last deep recovery.
+    ///
+    /// This can be polled from another thread during [Lower::recover].
+    pub fn recovery_progress(&self) -> (usize, usize) {
+        (self.recovered.load(Relaxed), self.children.len())
+    }
+
+    /// Recovers the `tables` one after another
+    fn recover_tables(&self, tables: Range<usize>, mode: RecoverMode) -> RecoveryReport {
+        let fix = mode == RecoverMode::Fix;
+        let mut report = RecoveryReport::default();
+        for i in tables {
+            let table = &self.children[i];
             for (j, a_entry) in table.iter().enumerate() {
-                let start = i * TREE_FRAMES + j * Bitfield::LEN;
+                let start = i * Self::N + j * Bitfield::<BF>::LEN;
+                // The last table might be only partially backed by bitfields
+                let Some(bitfield) = self.bitfields.get(start / Bitfield::<BF>::LEN) else {
+                    break;
+                };
                 let entry = a_entry.load();
+                let zeros = bitfield.count_zeros();
 
                 if entry.huge() {
+                    report.huge_frames += 1;
                     // Check that underlying bitfield is empty
-                    let p = self.bitfields[start / Bitfield::LEN].count_zeros();
-                    if p != Bitfield::LEN {
-                        warn!("Invalid L2 start=0x{start:x} i{i}: h != {p}");
-                        self.bitfields[start / Bitfield::LEN].fill(false);
+                    if zeros != Bitfield::<BF>::LEN {
+                        report.cleared_bitfields += 1;
+                        if fix {
+                            bitfield.fill(false);
+                        }
                     }
                 } else {
                     // Check the bitfield has the same number of zero bits
-                    let zeros = self.bitfields[start / Bitfield::LEN].count_zeros();
                     if entry.free() != zeros {
-                        warn!(
-                            "Invalid L2 start=0x{start:x} i{i}: {} != {zeros}",
-                            entry.free()
-                        );
-                        a_entry.store(HugeEntry::new_free(zeros));
+                        report.push_fixed(FixedCounter {
+                            table: i,
+                            entry: j,
+                            expected: zeros,
+                            found: entry.free(),
+                        });
+                        if fix {
+                            a_entry.store(HugeEntry::new_free(zeros));
+                        }
                     }
+                    report.free_frames += zeros;
+                }
+                // The summary might be outdated after a crash
+                if fix {
+                    bitfield.reset_summary();
+                }
+            }
+            self.recovered.fetch_add(1, Relaxed);
+        }
+        report
+    }
+
+    /// Recovers the data structures like [Lower::recover], but first restores
+    /// the metadata beyond the end of memory, which is not covered by it.
+    ///
+    /// Without atomicity guarantees for the persistent memory (e.g., without ADR),
+    /// a crash might tear any write. The frames beyond the end of memory have to stay
+    /// allocated in the last bitfield, which also cannot be allocated as huge frame,
+    /// and table entries without bitfield have to be empty.
+    /// Afterwards, the result is verified with [Lower::check].
+    pub fn recover_deep_verify(&self, threads: usize) -> Result<RecoveryReport> {
+        let mut torn = 0;
+        let len = Bitfield::<BF>::LEN;
+        let last = self.frames() / len;
+        if let Some(bitfield) = self.bitfields.get(last) {
+            let entry = &self.children[last / HP][last % HP];
+            if entry.load().huge() {
+                torn += 1;
+                // Recounted by the recovery
+                entry.store(HugeEntry::new_free(0));
+            }
+            bitfield.set(self.frames() - last * len..len, true);
+        }
+        for i in self.bitfields.len()..self.children.len() * HP {
+            let entry = &self.children[i / HP][i % HP];
+            if entry.load() != HugeEntry::new_free(0) {
+                torn += 1;
+                entry.store(HugeEntry::new_free(0));
+            }
+        }
+        let mut report = self.recover(threads, RecoverMode::Fix);
+        report.torn_entries = torn;
+        self.check()?;
+        Ok(report)
+    }
+
+    /// Checks that the tables match the bitfields, without modifying or
+    /// panicking on corrupted metadata.
+    pub fn check(&self) -> Result<()> {
+        for (i, table) in self.children.iter().enumerate() {
+            for (j, entry) in table.iter().enumerate() {
+                let Some(bitfield) = self.bitfields.get(i * HP + j) else {
+                    break;
+                };
+                let entry = entry.load();
+                let zeros = bitfield.count_zeros();
+                let valid = if entry.huge() {
+                    zeros == Bitfield::<BF>::LEN
+                } else {
+                    entry.free() == zeros
+                };
+                if !valid {
+                    warn!("Corrupted L2 i{i} j{j}: {entry:?} != {zeros}");
+                    return Err(Error::Corruption);
                 }
             }
         }
+        Ok(())
+    }
+
+    /// Marks the `frames` as allocated, e.g., for carve-outs during boot.
+    ///
+    /// Entirely free and covered huge frames are allocated as huge frames,
+    /// the other frames are set in the bitfields.
+    /// Both can be freed later with any order.
+    /// This is not thread-safe and has to be done before the allocator is used.
+    /// Like the initialization, it writes whole bitfield entries with non-temporal stores.
+    pub fn reserve_frames(&self, frames: Range<usize>) -> Result<()> {
+        if frames.end > self.frames() {
+            error!("invalid range {frames:?}");
+            return Err(Error::Address);
+        }
+        let mut start = frames.start;
+        while start < frames.end {
+            let i = start / Bitfield::<BF>::LEN;
+            let end = ((i + 1) * Bitfield::<BF>::LEN).min(frames.end);
+            let entry = &self.children[i / HP][i % HP];
+            let old = entry.load();
+
+            if end - start == Bitfield::<BF>::LEN && old.free() == Bitfield::<BF>::LEN {
+                entry.store(HugeEntry::new_huge());
+            } else if !old.huge() {
+                let offset = align_down(start, Bitfield::<BF>::LEN);
+                self.bitfields[i].fill_range_unfenced(start - offset..end - offset, true);
+                // Overlapping ranges are not counted twice
+                let free = self.bitfields[i].count_zeros();
+                let split = old.split() || old.free() == Bitfield::<BF>::LEN;
+                entry.store(old.with_count(free as _).with_split(split));
+            }
+            start = end;
+        }
+        stream_fence();
+        Ok(())
     }
 
     /// Return the number of free frames in the tree at `start`.
@@ -154,54 +627,175 @@ impl<'a> Lower<'a> {
         assert!(start < self.frames());
         let mut free = 0;
         let mut huge = 0;
-        for entry in self.children[start / TREE_FRAMES].iter() {
+        for entry in self.children[start / Self::N].iter() {
             free += entry.load().free();
-            huge += (entry.load().free() == HUGE_FRAMES) as usize;
+            huge += (entry.load().free() == Self::HUGE_FRAMES) as usize;
         }
         (free, huge)
     }
 
-    /// Try allocating a new `frame` in the [LowerAlloc::N] sized chunk at `start`.
+    /// Try allocating a new `frame` in the [Lower::N] sized chunk at `start`.
     ///
     /// Returns the allocated frame and whether a new huge frame was fragmented.
-    pub fn get(&self, start: usize, flags: Flags) -> Result<(usize, bool)> {
-        debug_assert!(flags.order() <= MAX_ORDER);
+    /// The `core` only selects the event counters.
+    pub fn get(&self, core: usize, start: usize, flags: Flags) -> Result<(usize, bool)> {
+        debug_assert!(flags.order() <= Self::MAX_ORDER);
         debug_assert!(start < self.frames());
 
-        match flags.order() {
-            MAX_ORDER => self.get_max(start).map(|f| (f, true)),
-            HUGE_ORDER => self.get_huge(start).map(|f| (f, true)),
-            _ => self.get_small(start, flags.order()),
+        let res = if flags.order() == Self::MAX_ORDER {
+            self.get_max(start).map(|f| (f, true))
+        } else if flags.order() == Self::HUGE_ORDER {
+            self.get_huge(start).map(|f| (f, true))
+        } else {
+            self.get_small(core, start, flags.order())
+        };
+        match res {
+            Ok(_) => self.count(core, Event::Alloc, flags.order()),
+            Err(Error::Memory) => self.count(core, Event::Failed, flags.order()),
+            Err(_) => {}
+        }
+        res
+    }
+
+    /// Free single frame, returning the largest order for which the aligned
+    /// block containing `frame` is now completely free.
+    ///
+    /// The `core` only selects the event counters.
+    pub fn put(&self, core: usize, frame: usize, flags: Flags) -> Result<usize> {
+        let res = self.put_inner(frame, flags);
+        if res.is_ok() {
+            self.count(core, Event::Free, flags.order());
+        }
+        res
+    }
+
+    /// Allocates the frames following the `frame` of `order` in place,
+    /// turning it into a frame of `new_order`, which is smaller than a huge frame.
+    ///
+    /// Fails with [Error::Memory] without modifying anything if any of them is allocated.
+    pub fn grow(&self, frame: usize, order: usize, new_order: usize) -> Result<()> {
+        debug_assert!(order < new_order && new_order < Self::HUGE_ORDER);
+        debug_assert!(frame % (1 << new_order) == 0 && frame < self.frames());
+
+        let num_frames = (1 << new_order) - (1 << order);
+        let i = (frame / Bitfield::<BF>::LEN) % HP;
+        let table = &self.children[frame / Self::N];
+        // The frame is allocated, so the entry is neither entirely free nor huge
+        let span = Bitfield::<BF>::LEN;
+        let reserved = table[i].fetch_update(counted(|v| v.dec(span, num_frames)));
+        if reserved.is_err() {
+            return Err(Error::Memory);
+        }
+
+        // The tail consists of one frame of every order in between
+        let bitfield = &self.bitfields[frame / Bitfield::<BF>::LEN];
+        let offset = frame % Bitfield::<BF>::LEN;
+        for o in order..new_order {
+            if bitfield.toggle(offset + (1 << o), o, false).is_err() {
+                // Unwind everything, even if a step fails
+                let mut corrupted = false;
+                for o in (order..o).rev() {
+                    if bitfield.toggle(offset + (1 << o), o, true).is_err() {
+                        error!("Failed undo grow p={:x} o={o}", frame + (1 << o));
+                        corrupted = true;
+                    }
+                }
+                let undo = table[i].fetch_update(|v| v.inc(span, num_frames));
+                if let Err(entry) = undo {
+                    error!("Failed undo grow i{i} o={new_order} {entry:?}");
+                    corrupted = true;
+                }
+                return Err(if corrupted {
+                    Error::Corruption
+                } else {
+                    Error::Memory
+                });
+            }
         }
+        Ok(())
     }
 
-    /// Free single frame, returning whether a while huge page has become free.
-    pub fn put(&self, frame: usize, flags: Flags) -> Result<bool> {
-        debug_assert!(flags.order() <= MAX_ORDER);
+    /// Allocates exactly the `frame` of `order`, e.g., for a device that needs a fixed address.
+    ///
+    /// Returns whether an entirely free huge frame was fragmented.
+    /// Fails with [Error::Busy] without modifying anything if any of the frames is allocated.
+    /// The `core` only selects the event counters.
+    pub fn get_at(&self, core: usize, frame: usize, order: usize) -> Result<bool> {
+        debug_assert!(order <= Self::MAX_ORDER && frame % (1 << order) == 0);
+        debug_assert!(frame + (1 << order) <= self.frames());
+
+        let span = Bitfield::<BF>::LEN;
+        let i = (frame / span) % HP;
+        let table = &self.children[frame / Self::N];
+        let fragmented = if order == Self::MAX_ORDER {
+            let pair = &self.table_pair(frame)[i / 2];
+            if pair.fetch_update(|v| v.map(|v| v.mark_huge(span))).is_err() {
+                return Err(Error::Busy);
+            }
+            false
+        } else if order == Self::HUGE_ORDER {
+            if table[i].fetch_update(|v| v.mark_huge(span)).is_err() {
+                return Err(Error::Busy);
+            }
+            false
+        } else {
+            let Ok(old) = table[i].fetch_update(counted(|v| v.dec(span, 1 << order))) else {
+                return Err(Error::Busy);
+            };
+            let bitfield = &self.bitfields[frame / span];
+            if let Err(e) = bitfield.toggle(frame % span, order, false) {
+                // Revert counter (and split marker)
+                let revert = table[i].fetch_update(|v| {
+                    let v = v.inc(span, 1 << order)?;
+                    if v.free() == span {
+                        Some(v.with_split(old.split()))
+                    } else {
+                        Some(v)
+                    }
+                });
+                if let Err(entry) = revert {
+                    error!("Failed undo get_at i{i} o={order} {entry:?}");
+                    return Err(Error::Corruption);
+                }
+                return Err(if e == Error::Corruption {
+                    e
+                } else {
+                    Error::Busy
+                });
+            }
+            old.free() == span
+        };
+        self.count(core, Event::Alloc, order);
+        Ok(fragmented)
+    }
+
+    fn put_inner(&self, frame: usize, flags: Flags) -> Result<usize> {
+        debug_assert!(flags.order() <= Self::MAX_ORDER);
         debug_assert!(frame < self.frames());
 
-        if flags.order() == MAX_ORDER {
-            self.put_max(frame).map(|_| true)
-        } else if flags.order() == HUGE_ORDER {
-            let i = (frame / Bitfield::LEN) % TREE_HUGE;
-            let table = &self.children[frame / TREE_FRAMES];
+        if flags.order() == Self::MAX_ORDER {
+            self.put_max(frame).map(|_| Self::MAX_ORDER)
+        } else if flags.order() == Self::HUGE_ORDER {
+            let i = (frame / Bitfield::<BF>::LEN) % HP;
+            let table = &self.children[frame / Self::N];
 
-            if let Err(old) =
-                table[i].compare_exchange(HugeEntry::new_huge(), HugeEntry::new_free(Bitfield::LEN))
-            {
+            if let Err(old) = table[i].compare_exchange(
+                HugeEntry::new_huge(),
+                HugeEntry::new_free(Bitfield::<BF>::LEN),
+            ) {
                 error!("Addr p={frame:x} o={} {old:?}", flags.order());
                 Err(Error::Address)
             } else {
-                Ok(true)
+                Ok(self.huge_free_order(frame))
             }
         } else {
-            let i = (frame / Bitfield::LEN) % TREE_HUGE;
-            let table = &self.children[frame / TREE_FRAMES];
+            let i = (frame / Bitfield::<BF>::LEN) % HP;
+            let table = &self.children[frame / Self::N];
 
             let old = table[i].load();
             if old.huge() {
                 self.partial_put_huge(old, frame, flags.order())
-            } else if old.free() <= Bitfield::LEN - (1 << flags.order()) {
+            } else if old.free() <= Bitfield::<BF>::LEN - (1 << flags.order()) {
                 self.put_small(frame, flags.order())
             } else {
                 error!("Addr p={frame:x} o={} {old:?}", flags.order());
@@ -213,28 +807,28 @@ impl<'a> Lower<'a> {
     /// Returns if the frame is free. This might be racy!
     pub fn is_free(&self, frame: usize, order: usize) -> bool {
         debug_assert!(frame % (1 << order) == 0);
-        if order > MAX_ORDER || frame + (1 << order) > self.frames() {
+        if order > Self::MAX_ORDER || frame + (1 << order) > self.frames() {
             return false;
         }
 
-        if order > Bitfield::ORDER {
+        if order > Bitfield::<BF>::ORDER {
             // multiple huge frames
-            let i = (frame / Bitfield::LEN) % TREE_HUGE;
+            let i = (frame / Bitfield::<BF>::LEN) % HP;
             self.table_pair(frame)[i / 2]
                 .load()
-                .all(|e| e.free() == Bitfield::LEN)
+                .all(|e| e.free() == Bitfield::<BF>::LEN)
         } else {
-            let table = &self.children[frame / TREE_FRAMES];
-            let i = (frame / Bitfield::LEN) % TREE_HUGE;
+            let table = &self.children[frame / Self::N];
+            let i = (frame / Bitfield::<BF>::LEN) % HP;
             let entry = table[i].load();
 
             if entry.free() < (1 << order) {
                 false
-            } else if entry.free() == Bitfield::LEN {
+            } else if entry.free() == Bitfield::<BF>::LEN {
                 true
             } else {
-                let bitfield = &self.bitfields[frame / Bitfield::LEN];
-                bitfield.is_zero(frame % Bitfield::LEN, order)
+                let bitfield = &self.bitfields[frame / Bitfield::<BF>::LEN];
+                bitfield.is_zero(frame % Bitfield::<BF>::LEN, order)
             }
         }
     }
@@ -249,7 +843,7 @@ impl<'a> Lower<'a> {
     #[allow(unused)]
     pub fn free_huge(&self) -> usize {
         let mut huge = 0;
-        self.for_each_huge_frame(|_, f| huge += (f == HUGE_FRAMES) as usize);
+        self.for_each_huge_frame(|_, f| huge += (f == Self::HUGE_FRAMES) as usize);
         huge
     }
 
@@ -257,27 +851,84 @@ impl<'a> Lower<'a> {
     pub fn for_each_huge_frame<F: FnMut(usize, usize)>(&self, mut f: F) {
         for (ti, table) in self.children.iter().enumerate() {
             for (ci, child) in table.iter().enumerate() {
-                f(ti * TREE_HUGE + ci, child.load().free())
+                f(ti * HP + ci, child.load().free())
             }
         }
     }
 
+    /// Counts the maximal free aligned blocks of each order, in a single pass over
+    /// the tables and the bitfields of partially free huge frames.
+    pub fn free_orders(&self, orders: &mut [usize; MAX_ORDER + 1]) {
+        for (ti, table) in self.children.iter().enumerate() {
+            for (pi, pair) in table.chunks(2).enumerate() {
+                let entries = [pair[0].load(), pair[1].load()];
+                if entries.iter().all(|e| e.free() == Bitfield::<BF>::LEN) {
+                    orders[Self::MAX_ORDER] += 1;
+                    continue;
+                }
+                for (j, entry) in entries.into_iter().enumerate() {
+                    if entry.free() == Bitfield::<BF>::LEN {
+                        orders[Self::HUGE_ORDER] += 1;
+                    } else if entry.free() > 0 {
+                        // The last table might be only partially backed by bitfields
+                        if let Some(bitfield) = self.bitfields.get(ti * HP + 2 * pi + j) {
+                            bitfield.free_orders(orders);
+                        }
+                    }
+                }
+            }
+        }
+    }
+
+    /// Calls `f` with the first frame of every entirely free huge frame that starts within `range`.
+    pub fn for_each_free_huge_frame<F: FnMut(usize)>(&self, range: Range<usize>, mut f: F) {
+        self.for_each_huge_frame(|i, free| {
+            let frame = i * Bitfield::<BF>::LEN;
+            if free == Bitfield::<BF>::LEN && range.contains(&frame) {
+                f(frame)
+            }
+        });
+    }
+
     pub fn free_at(&self, frame: usize, order: usize) -> usize {
-        match order {
-            0 => self.is_free(frame, 0) as _,
-            HUGE_ORDER => {
-                let i = (frame / Bitfield::LEN) % TREE_HUGE;
-                let child = self.children[frame / TREE_FRAMES][i].load();
-                child.free()
+        if order == 0 {
+            self.is_free(frame, 0) as _
+        } else if order == Self::HUGE_ORDER {
+            let i = (frame / Bitfield::<BF>::LEN) % HP;
+            let child = self.children[frame / Self::N][i].load();
+            child.free()
+        } else {
+            0
+        }
+    }
+
+    /// Clears the split markers of the huge frames in the tree at `start`,
+    /// that are no longer split because they are entirely free or allocated as a whole.
+    pub fn clear_split_markers(&self, start: usize) {
+        for entry in self.children[start / Self::N].iter() {
+            let _ = entry.fetch_update(|v| {
+                (v.split() && (v.huge() || v.free() == Bitfield::<BF>::LEN))
+                    .then(|| v.with_split(false))
+            });
+        }
+    }
+
+    /// Calls `f` with the first frame of every split huge frame that has free frames.
+    pub fn for_each_split_huge(&self, mut f: impl FnMut(usize)) {
+        for (ti, table) in self.children.iter().enumerate() {
+            for (ci, child) in table.iter().enumerate() {
+                let child = child.load();
+                if child.split() && child.free() > 0 {
+                    f((ti * HP + ci) * Bitfield::<BF>::LEN)
+                }
             }
-            _ => 0,
         }
     }
 
     /// Returns the table with pair entries that can be updated at once.
-    fn table_pair(&self, frame: usize) -> &[Atom<HugePair>; TREE_HUGE / 2] {
-        let table = &self.children[frame / TREE_FRAMES];
-        unsafe { &*table.as_ptr().cast() }
+    fn table_pair(&self, frame: usize) -> &[Atom<HugePair>] {
+        let table = &self.children[frame / Self::N];
+        unsafe { slice::from_raw_parts(table.as_ptr().cast(), HP / 2) }
     }
 
     fn free_all(&self) {
@@ -285,34 +936,35 @@ impl<'a> Lower<'a> {
         let (last, tables) = self.children.split_last().unwrap();
         // Table is fully included in the memory range
         for table in tables {
-            table.atomic_fill(HugeEntry::new_free(Bitfield::LEN));
+            table.atomic_fill(HugeEntry::new_free(Bitfield::<BF>::LEN));
         }
         // Table is only partially included in the memory range
         for (i, entry) in last.iter().enumerate() {
-            let frame = tables.len() * TREE_FRAMES + i * Bitfield::LEN;
-            let free = self.frames().saturating_sub(frame).min(Bitfield::LEN);
+            let frame = tables.len() * Self::N + i * Bitfield::<BF>::LEN;
+            let free = self.frames().saturating_sub(frame).min(Bitfield::<BF>::LEN);
             entry.store(HugeEntry::new_free(free));
         }
 
         // Init bitfields
-        let last_i = self.frames() / Bitfield::LEN;
+        let last_i = self.frames() / Bitfield::<BF>::LEN;
         let (included, mut remainder) = self.bitfields.split_at(last_i);
         // Bitfield is fully included in the memory range
         for bitfield in included {
-            bitfield.fill(false);
+            bitfield.fill_range_unfenced(0..Bitfield::<BF>::LEN, false);
         }
         // Bitfield might be only partially included in the memory range
         if let Some((last, excluded)) = remainder.split_first() {
-            let end = self.frames() - included.len() * Bitfield::LEN;
-            debug_assert!(end <= Bitfield::LEN);
-            last.set(0..end, false);
-            last.set(end..Bitfield::LEN, true);
+            let end = self.frames() - included.len() * Bitfield::<BF>::LEN;
+            debug_assert!(end <= Bitfield::<BF>::LEN);
+            last.fill_range_unfenced(0..end, false);
+            last.fill_range_unfenced(end..Bitfield::<BF>::LEN, true);
             remainder = excluded;
         }
         // Not part of the final memory range
         for bitfield in remainder {
-            bitfield.fill(true);
+            bitfield.fill_range_unfenced(0..Bitfield::<BF>::LEN, true);
         }
+        stream_fence();
     }
 
     fn reserve_all(&self) {
@@ -323,7 +975,7 @@ impl<'a> Lower<'a> {
             table.atomic_fill(HugeEntry::new_huge());
         }
         // Table is only partially included in the memory range
-        let last_i = (self.frames() / Bitfield::LEN) - tables.len() * TREE_HUGE;
+        let last_i = (self.frames() / Bitfield::<BF>::LEN) - tables.len() * HP;
         let (included, remainder) = last.split_at(last_i);
         for entry in included {
             entry.store(HugeEntry::new_huge());
@@ -334,128 +986,174 @@ impl<'a> Lower<'a> {
         }
 
         // Init bitfields
-        let last_i = self.frames() / Bitfield::LEN;
+        let last_i = self.frames() / Bitfield::<BF>::LEN;
         let (included, remainder) = self.bitfields.split_at(last_i);
         // Bitfield is fully included in the memory range
         for bitfield in included {
-            bitfield.fill(false);
+            bitfield.fill_range_unfenced(0..Bitfield::<BF>::LEN, false);
         }
         // Bitfield might be only partially included in the memory range
         for bitfield in remainder {
-            bitfield.fill(true);
+            bitfield.fill_range_unfenced(0..Bitfield::<BF>::LEN, true);
         }
+        stream_fence();
     }
 
     /// Allocate frames up to order 8
-    fn get_small(&self, start: usize, order: usize) -> Result<(usize, bool)> {
-        debug_assert!(order < Bitfield::ORDER);
+    fn get_small(&self, core: usize, start: usize, order: usize) -> Result<(usize, bool)> {
+        debug_assert!(order < Bitfield::<BF>::ORDER);
 
-        let first_bf_i = align_down(start / Bitfield::LEN, TREE_HUGE);
-        let start_bf_e = (start / Bitfield::ENTRY_BITS) % Bitfield::ENTRIES;
-        let table = &self.children[start / TREE_FRAMES];
-        let offset = (start / Bitfield::LEN) % TREE_HUGE;
+        let first_bf_i = align_down(start / Bitfield::<BF>::LEN, HP);
+        let start_bf_e = (start / Bitfield::<BF>::ENTRY_BITS) % Bitfield::<BF>::ENTRIES;
+        let start_bit = start % Bitfield::<BF>::ENTRY_BITS;
+        let table = &self.children[start / Self::N];
+        let offset = (start / Bitfield::<BF>::LEN) % HP;
 
-        for j in 0..TREE_HUGE {
-            let i = (j + offset) % TREE_HUGE;
+        for j in 0..HP {
+            let i = (j + offset) % HP;
 
-            if let Ok(child) = table[i].fetch_update(|v| v.dec(1 << order)) {
+            if let Ok(child) =
+                table[i].fetch_update(counted(|v| v.dec(Bitfield::<BF>::LEN, 1 << order)))
+            {
                 let bf_i = first_bf_i + i;
-                // start with the previous bitfield entry
-                let bf_e = if j == 0 { start_bf_e } else { 0 };
-
-                if let Ok(offset) = self.bitfields[bf_i].set_first_zeros(bf_e, order) {
-                    return Ok((bf_i * Bitfield::LEN + offset, child.free() == Bitfield::LEN));
+                // start at the previous allocation
+                let hint = if j == 0 {
+                    (start_bf_e, start_bit)
+                } else {
+                    (0, 0)
+                };
+
+                if let Ok(offset) = self.bitfields[bf_i].set_first_zeros(hint, order) {
+                    return Ok((
+                        bf_i * Bitfield::<BF>::LEN + offset,
+                        child.free() == Bitfield::<BF>::LEN,
+                    ));
                 }
 
-                // Revert conter
-                table[i]
-                    .fetch_update(|v| v.inc(Bitfield::LEN, 1 << order))
-                    .expect("undo failed");
+                // Revert conter (and split marker)
+                let revert = table[i].fetch_update(|v| {
+                    let v = v.inc(Bitfield::<BF>::LEN, 1 << order)?;
+                    if v.free() == Bitfield::<BF>::LEN {
+                        Some(v.with_split(child.split()))
+                    } else {
+                        Some(v)
+                    }
+                });
+                if let Err(entry) = revert {
+                    error!("Failed undo get i{i} o={order} {entry:?}");
+                    return Err(Error::Corruption);
+                }
+                self.count(core, Event::Revert, order);
             }
         }
 
-        info!("Nothing found o={order}");
+        log_throttled!(info, "Nothing found o={order}");
         Err(Error::Memory)
     }
 
     /// Allocate huge frame
     fn get_huge(&self, start: usize) -> Result<usize> {
-        let table = &self.children[start / TREE_FRAMES];
-        let offset = (start / Bitfield::LEN) % TREE_HUGE;
+        let table = &self.children[start / Self::N];
+        let offset = (start / Bitfield::<BF>::LEN) % HP;
 
-        for i in 0..TREE_HUGE {
-            let i = (offset + i) % TREE_HUGE;
-            if let Ok(_) = table[i].fetch_update(|v| v.mark_huge(Bitfield::LEN)) {
-                return Ok(align_down(start, TREE_FRAMES) + i * Bitfield::LEN);
+        for i in 0..HP {
+            let i = (offset + i) % HP;
+            if let Ok(_) = table[i].fetch_update(|v| v.mark_huge(Bitfield::<BF>::LEN)) {
+                return Ok(align_down(start, Self::N) + i * Bitfield::<BF>::LEN);
             }
         }
 
-        info!("Nothing found o=9");
+        log_throttled!(info, "Nothing found o=9");
         Err(Error::Memory)
     }
 
     /// Allocate multiple huge frames
     fn get_max(&self, start: usize) -> Result<usize> {
         let table_pair = self.table_pair(start);
-        let offset = ((start / Bitfield::LEN) % TREE_HUGE) / 2;
+        let offset = ((start / Bitfield::<BF>::LEN) % HP) / 2;
 
-        for i in 0..TREE_HUGE / 2 {
-            let i = (offset + i) % (TREE_HUGE / 2);
-            if let Ok(_) = table_pair[i].fetch_update(|v| v.map(|v| v.mark_huge(Bitfield::LEN))) {
-                return Ok(align_down(start, TREE_FRAMES) + 2 * i * Bitfield::LEN);
+        for i in 0..HP / 2 {
+            let i = (offset + i) % (HP / 2);
+            if let Ok(_) =
+                table_pair[i].fetch_update(|v| v.map(|v| v.mark_huge(Bitfield::<BF>::LEN)))
+            {
+                return Ok(align_down(start, Self::N) + 2 * i * Bitfield::<BF>::LEN);
             }
         }
 
-        info!("Nothing found o=10");
+        log_throttled!(info, "Nothing found o=10");
         Err(Error::Memory)
     }
 
-    fn put_small(&self, frame: usize, order: usize) -> Result<bool> {
-        debug_assert!(order < HUGE_ORDER);
+    fn put_small(&self, frame: usize, order: usize) -> Result<usize> {
+        debug_assert!(order < Self::HUGE_ORDER);
 
-        let bitfield = &self.bitfields[frame / Bitfield::LEN];
-        let i = frame % Bitfield::LEN;
-        if bitfield.toggle(i, order, true).is_err() {
+        let bitfield = &self.bitfields[frame / Bitfield::<BF>::LEN];
+        let i = frame % Bitfield::<BF>::LEN;
+        if let Err(e) = bitfield.toggle(i, order, true) {
             error!("L1 put failed i{i} p={frame}");
-            return Err(Error::Address);
+            return Err(e);
         }
 
-        let table = &self.children[frame / TREE_FRAMES];
-        let i = (frame / Bitfield::LEN) % TREE_HUGE;
-        match table[i].fetch_update(|v| v.inc(Bitfield::LEN, 1 << order)) {
+        let table = &self.children[frame / Self::N];
+        let i = (frame / Bitfield::<BF>::LEN) % HP;
+        match table[i].fetch_update(counted(|v| v.inc(Bitfield::<BF>::LEN, 1 << order))) {
             Err(entry) => panic!("Inc failed i{i} p={frame} {entry:?}"),
-            Ok(entry) => Ok(entry.free() + (1 << order) == Bitfield::LEN),
+            Ok(entry) if entry.free() + (1 << order) == Bitfield::<BF>::LEN => {
+                Ok(self.huge_free_order(frame))
+            }
+            // Only the counter decides about huge frames, the bitfield might be ahead
+            Ok(_) => Ok(bitfield
+                .zero_order(frame % Bitfield::<BF>::LEN, order)
+                .min(Self::HUGE_ORDER - 1)),
+        }
+    }
+
+    /// Returns the free order of the completely free huge frame at `frame`,
+    /// which is [Self::MAX_ORDER] if its partner is also free.
+    fn huge_free_order(&self, frame: usize) -> usize {
+        let i = (frame / Bitfield::<BF>::LEN) % HP;
+        let table = &self.children[frame / Self::N];
+        if table[i ^ 1].load().free() == Bitfield::<BF>::LEN {
+            Self::MAX_ORDER
+        } else {
+            Self::HUGE_ORDER
         }
     }
 
     pub fn put_max(&self, frame: usize) -> Result<()> {
         let table_pair = self.table_pair(frame);
-        let i = ((frame / Bitfield::LEN) % TREE_HUGE) / 2;
+        let i = ((frame / Bitfield::<BF>::LEN) % HP) / 2;
 
         if let Err(old) = table_pair[i].compare_exchange(
             HugePair(HugeEntry::new_huge(), HugeEntry::new_huge()),
             HugePair(
-                HugeEntry::new_free(Bitfield::LEN),
-                HugeEntry::new_free(Bitfield::LEN),
+                HugeEntry::new_free(Bitfield::<BF>::LEN),
+                HugeEntry::new_free(Bitfield::<BF>::LEN),
             ),
         ) {
-            error!("Addr {frame} o={} {old:?} i={i}", MAX_ORDER);
+            error!("Addr {frame} o={} {old:?} i={i}", Self::MAX_ORDER);
             Err(Error::Address)
         } else {
             Ok(())
         }
     }
 
-    fn partial_put_huge(&self, old: HugeEntry, frame: usize, order: usize) -> Result<bool> {
+    fn partial_put_huge(&self, old: HugeEntry, frame: usize, order: usize) -> Result<usize> {
         info!("partial free of huge frame {frame:x} o={order}");
-        let i = (frame / Bitfield::LEN) % TREE_HUGE;
-        let table = &self.children[frame / TREE_FRAMES];
-        let bitfield = &self.bitfields[frame / Bitfield::LEN];
+        let i = (frame / Bitfield::<BF>::LEN) % HP;
+        let table = &self.children[frame / Self::N];
+        let bitfield = &self.bitfields[frame / Bitfield::<BF>::LEN];
 
         // Try filling the whole bitfield
-        if bitfield.toggle(0, Bitfield::ORDER, false).is_ok() {
+        let filled = match bitfield.toggle(0, Bitfield::<BF>::ORDER, false) {
+            Err(Error::Corruption) => return Err(Error::Corruption),
+            filled => filled.is_ok(),
+        };
+        if filled {
+            // The huge frame is split into small frames
             table[i]
-                .compare_exchange(old, HugeEntry::new())
+                .compare_exchange(old, HugeEntry::new().with_split(true))
                 .expect("Failed partial clear");
         }
         // Wait for parallel partial_put_huge to finish
@@ -466,23 +1164,127 @@ impl<'a> Lower<'a> {
         self.put_small(frame, order)
     }
 
+    /// Append the tables and bitfields to `out`.
+    #[cfg(feature = "std")]
+    pub fn snapshot(&self, out: &mut std::vec::Vec<u8>) {
+        for table in self.children {
+            for entry in table.iter() {
+                out.extend_from_slice(&u16::from(entry.load()).to_le_bytes());
+            }
+        }
+        for bitfield in self.bitfields {
+            for i in 0..Bitfield::<BF>::ENTRIES {
+                out.extend_from_slice(&bitfield.get_entry(i).to_le_bytes());
+            }
+        }
+    }
+
+    /// Overwrite the tables and bitfields with the `data` of a [Lower::snapshot].
+    #[cfg(feature = "std")]
+    pub fn restore(&self, data: &mut &[u8]) -> Result<()> {
+        use crate::util::take_bytes;
+
+        for table in self.children {
+            for entry in table.iter() {
+                let count = take_bytes(data).ok_or(Error::Initialization)?;
+                entry.store(HugeEntry::from(u16::from_le_bytes(count)));
+            }
+        }
+        for bitfield in self.bitfields {
+            for i in 0..Bitfield::<BF>::ENTRIES {
+                let e = take_bytes(data).ok_or(Error::Initialization)?;
+                bitfield.set_entry(i, u64::from_le_bytes(e));
+            }
+        }
+        Ok(())
+    }
+
+    /// Number of tables, one per tree
+    #[cfg(feature = "introspect")]
+    pub fn tables(&self) -> usize {
+        self.children.len()
+    }
+    /// Raw table entry `j` of the table `i`
+    #[cfg(feature = "introspect")]
+    pub fn table_entry(&self, i: usize, j: usize) -> u16 {
+        self.children[i][j].load().into()
+    }
+    /// Overwrite the table entry `j` of the table `i` with `raw`
+    #[cfg(feature = "introspect")]
+    pub fn set_table_entry(&self, i: usize, j: usize, raw: u16) {
+        self.children[i][j].store(raw.into())
+    }
+    /// Number of bitfields, one per huge frame
+    #[cfg(feature = "introspect")]
+    pub fn bitfields(&self) -> usize {
+        self.bitfields.len()
+    }
+    /// Raw word `w` of the bitfield `i`
+    #[cfg(feature = "introspect")]
+    pub fn bitfield_word(&self, i: usize, w: usize) -> u64 {
+        self.bitfields[i].get_entry(w)
+    }
+    /// Overwrite the word `w` of the bitfield `i` with `raw`
+    #[cfg(feature = "introspect")]
+    pub fn set_bitfield_word(&self, i: usize, w: usize, raw: u64) {
+        self.bitfields[i].set_entry(w, raw)
+    }
+
+    /// Returns the states of all chunks and their huge frames, e.g., for visualizations.
+    ///
+    /// This is not thread-safe, the allocator should not be used concurrently.
+    #[cold]
+    #[cfg(feature = "std")]
+    pub fn memory_map(&self) -> MemoryMap {
+        let mut chunks = std::vec::Vec::with_capacity(self.children.len());
+        for (ti, table) in self.children.iter().enumerate() {
+            let start = ti * Self::N;
+            let mut chunk = ChunkState {
+                start,
+                len: (self.frames() - start).min(Self::N),
+                free: 0,
+                huge_entries: std::vec::Vec::with_capacity(HP),
+            };
+            for (i, entry) in table.iter().enumerate() {
+                let frame = start + i * Bitfield::<BF>::LEN;
+                if frame >= self.frames() {
+                    break;
+                }
+                // The last huge frame might be only partially within the memory
+                let covered = (self.frames() - frame).min(Bitfield::<BF>::LEN);
+                let entry = entry.load();
+                let state = if entry.huge() {
+                    HugeStateKind::Huge
+                } else if entry.free() == covered {
+                    HugeStateKind::Free
+                } else {
+                    HugeStateKind::Partial(entry.free())
+                };
+                chunk.free += entry.free();
+                chunk.huge_entries.push(HugeState { frame, state });
+            }
+            chunks.push(chunk);
+        }
+        MemoryMap { chunks }
+    }
+
     #[cfg(feature = "std")]
     #[allow(dead_code)]
     pub fn dump(&self, start: usize) {
         use std::fmt::Write;
 
         let mut out = std::string::String::new();
-        writeln!(out, "Dumping pt {}", start / TREE_FRAMES).unwrap();
-        let table = &self.children[start / TREE_FRAMES];
+        writeln!(out, "Dumping pt {}", start / Self::N).unwrap();
+        let table = &self.children[start / Self::N];
         for (i, entry) in table.iter().enumerate() {
-            let start = align_down(start, TREE_FRAMES) + i * Bitfield::LEN;
+            let start = align_down(start, Self::N) + i * Bitfield::<BF>::LEN;
             if start >= self.frames() {
                 break;
             }
 
             let entry = entry.load();
             let indent = 4;
-            let bitfield = &self.bitfields[start / Bitfield::LEN];
+            let bitfield = &self.bitfields[start / Bitfield::<BF>::LEN];
             writeln!(out, "{:indent$}l2 i={i}: {entry:?}\t{bitfield:?}", "").unwrap();
             if !entry.huge() {
                 assert_eq!(bitfield.count_zeros(), entry.free());
@@ -492,20 +1294,62 @@ impl<'a> Lower<'a> {
     }
 }
 
+/// Counts the retries of a `fetch_update` with `f` in [CONFLICTS].
+#[cfg(feature = "search_stats")]
+fn counted(
+    mut f: impl FnMut(HugeEntry) -> Option<HugeEntry>,
+) -> impl FnMut(HugeEntry) -> Option<HugeEntry> {
+    let mut first = true;
+    move |v| {
+        if !first {
+            CONFLICTS.fetch_add(1, Relaxed);
+        }
+        first = false;
+        f(v)
+    }
+}
+#[cfg(not(feature = "search_stats"))]
+fn counted(
+    f: impl FnMut(HugeEntry) -> Option<HugeEntry>,
+) -> impl FnMut(HugeEntry) -> Option<HugeEntry> {
+    f
+}
+
+/// Offset of the free counter in the raw table entries
+#[cfg(feature = "introspect")]
+pub const ENTRY_COUNT_OFFSET: usize = HugeEntry::COUNT_OFFSET;
+/// Size of the free counter in the raw table entries
+#[cfg(feature = "introspect")]
+pub const ENTRY_COUNT_BITS: usize = HugeEntry::COUNT_BITS;
+/// Counter value of the raw table entries marking an allocated huge frame
+#[cfg(feature = "introspect")]
+pub const ENTRY_HUGE: u16 = HugeEntry::HUGE;
+/// Offset of the split marker in the raw table entries
+#[cfg(feature = "introspect")]
+pub const ENTRY_SPLIT_OFFSET: usize = HugeEntry::SPLIT_OFFSET;
+
 /// Manages huge frame, that can be allocated as base frames.
 #[bitfield(u16)]
 #[derive(PartialEq, Eq)]
 struct HugeEntry {
-    /// Number of free 4K frames or u16::MAX for a huge frame.
+    /// Number of free 4K frames or [HugeEntry::HUGE] for a huge frame.
+    #[bits(15)]
     count: u16,
+    /// If the huge frame was split for small allocations since the last
+    /// [Lower::clear_split_markers].
+    split: bool,
 }
+
 impl Atomic for HugeEntry {
     type I = AtomicU16;
 }
 impl HugeEntry {
+    /// Counter value of an allocated huge frame
+    const HUGE: u16 = (1 << Self::COUNT_BITS) - 1;
+
     /// Creates an entry marked as allocated huge frame.
     fn new_huge() -> Self {
-        Self::new().with_count(u16::MAX)
+        Self::new().with_count(Self::HUGE)
     }
     /// Creates a new entry with the given free counter.
     fn new_free(free: usize) -> Self {
@@ -513,7 +1357,7 @@ impl HugeEntry {
     }
     /// Returns wether this entry is allocated as huge frame.
     fn huge(self) -> bool {
-        self.count() == u16::MAX
+        self.count() == Self::HUGE
     }
     /// Returns the free frames counter
     fn free(self) -> usize {
@@ -531,10 +1375,13 @@ impl HugeEntry {
             None
         }
     }
-    /// Decrement the free frames counter.
-    fn dec(self, num_frames: usize) -> Option<Self> {
+    /// Decrement the free frames counter, marking the entry as split if it was entirely free.
+    fn dec(self, span: usize, num_frames: usize) -> Option<Self> {
         if !self.huge() && self.free() >= num_frames {
-            Some(Self::new_free(self.free() - num_frames))
+            Some(
+                self.with_count((self.free() - num_frames) as _)
+                    .with_split(self.split() || self.free() == span),
+            )
         } else {
             None
         }
@@ -542,13 +1389,28 @@ impl HugeEntry {
     /// Increments the free frames counter.
     fn inc(self, span: usize, num_frames: usize) -> Option<Self> {
         if !self.huge() && self.free() <= span - num_frames {
-            Some(Self::new_free(self.free() + num_frames))
+            Some(self.with_count((self.free() + num_frames) as _))
         } else {
             None
         }
     }
 }
 
+/// Shows the raw bits of the fields with their offsets, e.g., for debugging CAS failures.
+impl fmt::Binary for HugeEntry {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "0b[{}:split={:b}][{}:count={:0w$b}]",
+            Self::SPLIT_OFFSET,
+            self.split() as u8,
+            Self::COUNT_OFFSET,
+            self.count(),
+            w = Self::COUNT_BITS
+        )
+    }
+}
+
 /// Pair of huge entries that can be changed at once.
 #[derive(Debug, Clone, Copy, PartialEq, Eq)]
 #[repr(C, align(4))]
@@ -585,38 +1447,55 @@ impl From<HugePair> for u32 {
         u32::from_ne_bytes([a, b, c, d])
     }
 }
+impl fmt::Binary for HugePair {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "({:b}, {:b})", self.0, self.1)
+    }
+}
 
 #[cfg(all(test, feature = "std"))]
 mod test {
     use core::mem::ManuallyDrop;
-    use core::ops::Deref;
+    use core::ops::{Deref, Range};
     use std::sync::Barrier;
     use std::vec::Vec;
 
     use log::warn;
 
-    use super::Bitfield;
+    use super::{FixedCounter, HugeEntry, RecoverMode};
     use crate::lower::Lower;
     use crate::util::{aligned_buf, logging, WyRand};
     use crate::{
-        thread, Error, Flags, Init, Result, HUGE_FRAMES, MAX_ORDER, TREE_FRAMES, TREE_HUGE,
+        thread, Error, Flags, Init, Result, FRAME_SIZE, HUGE_FRAMES, HUGE_ORDER, MAX_ORDER,
+        TREE_FRAMES, TREE_HUGE,
     };
 
-    struct LowerTest<'a>(ManuallyDrop<Lower<'a>>);
+    type Bitfield = super::Bitfield<8>;
+
+    struct LowerTest<'a, const HP: usize = TREE_HUGE, const BF: usize = 8>(
+        ManuallyDrop<Lower<'a, HP, BF>>,
+    );
 
     impl<'a> LowerTest<'a> {
         fn create(frames: usize, init: Init) -> Result<Self> {
-            let primary = aligned_buf(Lower::metadata_size(frames)).leak();
-            Ok(Self(ManuallyDrop::new(Lower::new(frames, init, primary)?)))
+            Self::with_hp(frames, init)
+        }
+    }
+    impl<'a, const HP: usize, const BF: usize> LowerTest<'a, HP, BF> {
+        fn with_hp(frames: usize, init: Init) -> Result<Self> {
+            let primary = aligned_buf(Lower::<HP, BF>::metadata_size(frames)).leak();
+            Ok(Self(ManuallyDrop::new(Lower::new(
+                1, frames, init, primary,
+            )?)))
         }
     }
-    impl<'a> Deref for LowerTest<'a> {
-        type Target = Lower<'a>;
+    impl<'a, const HP: usize, const BF: usize> Deref for LowerTest<'a, HP, BF> {
+        type Target = Lower<'a, HP, BF>;
         fn deref(&self) -> &Self::Target {
             &self.0
         }
     }
-    impl<'a> Drop for LowerTest<'a> {
+    impl<'a, const HP: usize, const BF: usize> Drop for LowerTest<'a, HP, BF> {
         fn drop(&mut self) {
             let meta = self.0.metadata();
             unsafe {
@@ -626,17 +1505,304 @@ mod test {
         }
     }
 
+    #[test]
+    fn memory_map() {
+        use std::format;
+
+        use super::{ChunkState, HugeState, HugeStateKind};
+
+        const FRAMES: usize = TREE_FRAMES + HUGE_FRAMES + 5;
+        let lower = LowerTest::create(FRAMES, Init::FreeAll).unwrap();
+        lower.reserve_frames(0..HUGE_FRAMES).unwrap();
+        lower.reserve_frames(HUGE_FRAMES..HUGE_FRAMES + 3).unwrap();
+        lower
+            .reserve_frames(TREE_FRAMES + HUGE_FRAMES..FRAMES)
+            .unwrap();
+
+        let map = lower.memory_map();
+        assert_eq!(map.chunks.len(), 2);
+        let chunk = &map.chunks[0];
+        assert_eq!(chunk.start, 0);
+        assert_eq!(chunk.len, TREE_FRAMES);
+        assert_eq!(chunk.free, TREE_FRAMES - HUGE_FRAMES - 3);
+        assert_eq!(chunk.huge_entries.len(), TREE_HUGE);
+        assert_eq!(chunk.huge_entries[0].state, HugeStateKind::Huge);
+        assert_eq!(
+            chunk.huge_entries[1],
+            HugeState {
+                frame: HUGE_FRAMES,
+                state: HugeStateKind::Partial(HUGE_FRAMES - 3)
+            }
+        );
+        for huge in &chunk.huge_entries[2..] {
+            assert_eq!(huge.state, HugeStateKind::Free);
+        }
+        // The last chunk ends with a partial huge frame
+        assert_eq!(
+            map.chunks[1],
+            ChunkState {
+                start: TREE_FRAMES,
+                len: HUGE_FRAMES + 5,
+                free: HUGE_FRAMES,
+                huge_entries: vec![
+                    HugeState {
+                        frame: TREE_FRAMES,
+                        state: HugeStateKind::Free
+                    },
+                    HugeState {
+                        frame: TREE_FRAMES + HUGE_FRAMES,
+                        state: HugeStateKind::Partial(0)
+                    },
+                ],
+            }
+        );
+
+        let expected = format!(
+            "{:>10} [H+......] {}/{TREE_FRAMES}\n{TREE_FRAMES:>10} [.#] {HUGE_FRAMES}/{}\n",
+            0,
+            TREE_FRAMES - HUGE_FRAMES - 3,
+            HUGE_FRAMES + 5
+        );
+        assert_eq!(format!("{map}"), expected);
+    }
+
+    #[test]
+    fn huge_entry_split() {
+        let entry = HugeEntry::new_free(Bitfield::LEN);
+        assert!(!entry.split());
+        let entry = entry.dec(Bitfield::LEN, 1).unwrap();
+        assert!(entry.split());
+        assert_eq!(entry.free(), Bitfield::LEN - 1);
+        // Preserved by counter updates
+        let entry = entry.dec(Bitfield::LEN, 4).unwrap();
+        assert!(entry.split() && entry.free() == Bitfield::LEN - 5);
+        let entry = entry.inc(Bitfield::LEN, 5).unwrap();
+        assert!(entry.split() && entry.free() == Bitfield::LEN);
+        // Does not affect huge frames
+        let huge = entry.mark_huge(Bitfield::LEN).unwrap();
+        assert!(huge.huge() && !huge.split());
+        assert_eq!(huge.free(), 0);
+        assert!(huge.dec(Bitfield::LEN, 1).is_none());
+        // Only set on the first split
+        let entry = HugeEntry::new_free(3).dec(Bitfield::LEN, 1).unwrap();
+        assert!(!entry.split());
+    }
+
+    #[test]
+    fn huge_entry_fmt() {
+        use std::format;
+
+        use super::HugePair;
+
+        let entry = HugeEntry::new_free(Bitfield::LEN);
+        assert_eq!(
+            format!("{entry:?}"),
+            "HugeEntry { count: 512, split: false }"
+        );
+        assert_eq!(
+            format!("{entry:b}"),
+            "0b[15:split=0][0:count=000001000000000]"
+        );
+        let entry = entry.dec(Bitfield::LEN, 1).unwrap();
+        assert_eq!(
+            format!("{entry:b}"),
+            "0b[15:split=1][0:count=000000111111111]"
+        );
+        let pair = HugePair(entry, HugeEntry::new_huge());
+        assert_eq!(
+            format!("{pair:b}"),
+            "(0b[15:split=1][0:count=000000111111111], 0b[15:split=0][0:count=111111111111111])"
+        );
+    }
+
+    /// The counters compile to nothing without the feature
+    #[cfg(not(feature = "counters"))]
+    #[test]
+    fn no_counters() {
+        use core::mem::size_of;
+        assert_eq!(
+            size_of::<Lower>(),
+            2 * size_of::<usize>() + 2 * size_of::<&[u8]>()
+        );
+    }
+
+    #[cfg(feature = "counters")]
+    #[test]
+    fn counters() {
+        use super::{Counters, EventCounts};
+        use crate::util::Align;
+
+        logging();
+
+        let mut lower = LowerTest::create(TREE_FRAMES, Init::FreeAll).unwrap();
+        let counters = Vec::from_iter((0..2).map(|_| Align(Counters::default()))).leak();
+        lower.0.set_counters(counters);
+
+        // All but the last huge frame
+        for i in 0..TREE_HUGE - 1 {
+            assert_eq!(
+                lower.get(1, 0, Flags::o(HUGE_ORDER)).unwrap().0,
+                i * HUGE_FRAMES
+            );
+        }
+        let last = (TREE_HUGE - 1) * HUGE_FRAMES;
+        for i in 0..HUGE_FRAMES {
+            assert_eq!(lower.get(1, 0, Flags::o(0)).unwrap().0, last + i);
+        }
+        assert_eq!(lower.get(1, 0, Flags::o(0)), Err(Error::Memory));
+
+        // Free every other frame, leaving no order 1 block
+        for i in (0..HUGE_FRAMES).step_by(2) {
+            lower.put(1, last + i, Flags::o(0)).unwrap();
+        }
+        assert_eq!(lower.get(1, 0, Flags::o(1)), Err(Error::Memory));
+
+        // The partner of the first huge frame is still allocated
+        lower.put(1, 0, Flags::o(HUGE_ORDER)).unwrap();
+        assert_eq!(lower.get(1, 0, Flags::o(MAX_ORDER)), Err(Error::Memory));
+        lower.put(1, HUGE_FRAMES, Flags::o(HUGE_ORDER)).unwrap();
+        assert_eq!(lower.get(1, 0, Flags::o(MAX_ORDER)).unwrap().0, 0);
+
+        let mut expected = EventCounts::default();
+        expected.allocs[0] = HUGE_FRAMES;
+        expected.allocs[HUGE_ORDER] = TREE_HUGE - 1;
+        expected.allocs[MAX_ORDER] = 1;
+        expected.frees[0] = HUGE_FRAMES / 2;
+        expected.frees[HUGE_ORDER] = 2;
+        expected.failed[0] = 1;
+        expected.failed[1] = 1;
+        expected.failed[MAX_ORDER] = 1;
+        expected.reverts[1] = 1;
+        assert_eq!(lower.counters(1), expected);
+        // Wrapped around to the same counters
+        assert_eq!(lower.counters(3), expected);
+        assert_eq!(lower.counters(0), EventCounts::default());
+    }
+
+    #[test]
+    fn persistent_metadata_size() {
+        for len in [
+            4,
+            TREE_FRAMES,
+            TREE_FRAMES + 1,
+            (1 << 30) / FRAME_SIZE,
+            (16 << 30) / FRAME_SIZE,
+            (1 << 40) / FRAME_SIZE,
+        ] {
+            let meta = <Lower>::persistent_metadata_size(len);
+            assert_eq!(meta % FRAME_SIZE, 0);
+            let frames = len - meta / FRAME_SIZE;
+            assert!(<Lower>::metadata_size(frames) <= meta);
+
+            if frames <= 16 * TREE_FRAMES {
+                let lower = LowerTest::create(frames, Init::FreeAll).unwrap();
+                assert_eq!(lower.metadata_frames() + lower.frames(), len);
+            } else {
+                let m = <Lower>::metadata_size(frames).div_ceil(FRAME_SIZE);
+                assert_eq!(m + frames, len);
+            }
+        }
+    }
+
+    #[test]
+    fn free_huge_frames() {
+        logging();
+
+        let lower = LowerTest::create(TREE_FRAMES, Init::FreeAll).unwrap();
+        let free = |range: Range<usize>| {
+            let mut free = Vec::new();
+            lower.for_each_free_huge_frame(range, |f| free.push(f));
+            free
+        };
+        let all = (0..TREE_HUGE).map(|i| i * HUGE_FRAMES).collect::<Vec<_>>();
+        assert_eq!(free(0..TREE_FRAMES), all);
+
+        // Split the first and fourth and allocate the third huge frame
+        let small = lower.get(0, 0, Flags::o(0)).unwrap().0;
+        let huge = lower
+            .get(0, 2 * HUGE_FRAMES, Flags::o(HUGE_ORDER))
+            .unwrap()
+            .0;
+        let partial = lower.get(0, 3 * HUGE_FRAMES, Flags::o(3)).unwrap().0;
+        assert_eq!(small, 0);
+        assert_eq!(huge, 2 * HUGE_FRAMES);
+        assert_eq!(partial, 3 * HUGE_FRAMES);
+
+        let mut expected = all.clone();
+        expected.retain(|&f| ![0, huge, partial].contains(&f));
+        assert_eq!(free(0..TREE_FRAMES), expected);
+        // Only huge frames that start within the range
+        assert_eq!(free(1..4 * HUGE_FRAMES), [HUGE_FRAMES]);
+        assert_eq!(
+            free(HUGE_FRAMES + 1..4 * HUGE_FRAMES + 1),
+            [4 * HUGE_FRAMES]
+        );
+        assert!(free(2 * HUGE_FRAMES..3 * HUGE_FRAMES).is_empty());
+
+        lower.put(0, small, Flags::o(0)).unwrap();
+        lower.put(0, huge, Flags::o(HUGE_ORDER)).unwrap();
+        lower.put(0, partial, Flags::o(3)).unwrap();
+        assert_eq!(free(0..TREE_FRAMES), all);
+    }
+
+    #[test]
+    fn split_markers() {
+        logging();
+
+        let lower = LowerTest::create(TREE_FRAMES, Init::FreeAll).unwrap();
+        let split = || {
+            let mut split = Vec::new();
+            lower.for_each_split_huge(|f| split.push(f));
+            split
+        };
+
+        // Split two huge frames
+        let a = (0..4)
+            .map(|_| lower.get(0, 0, Flags::o(0)).unwrap().0)
+            .collect::<Vec<_>>();
+        let b = (0..4)
+            .map(|_| lower.get(0, HUGE_FRAMES, Flags::o(0)).unwrap().0)
+            .collect::<Vec<_>>();
+        assert!(a.iter().all(|f| *f < HUGE_FRAMES));
+        assert!(b.iter().all(|f| (HUGE_FRAMES..2 * HUGE_FRAMES).contains(f)));
+        assert_eq!(split(), [0, HUGE_FRAMES]);
+
+        // Allocating a huge frame is not a split
+        let huge = lower.get(0, 0, Flags::o(HUGE_ORDER)).unwrap().0;
+        assert_eq!(split(), [0, HUGE_FRAMES]);
+
+        // Free one entirely
+        for frame in a {
+            lower.put(0, frame, Flags::o(0)).unwrap();
+        }
+        assert_eq!(split(), [0, HUGE_FRAMES]);
+        lower.clear_split_markers(0);
+        assert_eq!(split(), [HUGE_FRAMES]);
+
+        // Recovery ignores the markers
+        lower.recover(1, RecoverMode::Fix);
+        assert_eq!(split(), [HUGE_FRAMES]);
+
+        lower.put(0, huge, Flags::o(HUGE_ORDER)).unwrap();
+        for frame in b {
+            lower.put(0, frame, Flags::o(0)).unwrap();
+        }
+        lower.clear_split_markers(0);
+        assert!(split().is_empty());
+        assert_eq!(lower.free_frames(), lower.frames());
+    }
+
     #[test]
     fn alloc_normal() {
         logging();
 
         let lower = LowerTest::create(TREE_FRAMES, Init::FreeAll).unwrap();
-        lower.get(0, Flags::o(0)).unwrap();
+        lower.get(0, 0, Flags::o(0)).unwrap();
 
         thread::parallel(0..2, |t| {
-            thread::pin(t);
+            thread::try_pin(t);
 
-            let frame = lower.get(0, Flags::o(0)).unwrap().0;
+            let frame = lower.get(0, 0, Flags::o(0)).unwrap().0;
             assert!(frame < lower.frames());
         });
 
@@ -651,9 +1817,9 @@ mod test {
         let lower = LowerTest::create(TREE_FRAMES, Init::FreeAll).unwrap();
 
         thread::parallel(0..2, |t| {
-            thread::pin(t);
+            thread::try_pin(t);
 
-            lower.get(0, Flags::o(0)).unwrap();
+            lower.get(0, 0, Flags::o(0)).unwrap();
         });
 
         let entry2 = lower.children[0][0].load();
@@ -668,13 +1834,13 @@ mod test {
         let lower = LowerTest::create(TREE_FRAMES, Init::FreeAll).unwrap();
 
         for _ in 0..Bitfield::LEN - 1 {
-            lower.get(0, Flags::o(0)).unwrap();
+            lower.get(0, 0, Flags::o(0)).unwrap();
         }
 
         thread::parallel(0..2, |t| {
-            thread::pin(t);
+            thread::try_pin(t);
 
-            lower.get(0, Flags::o(0)).unwrap();
+            lower.get(0, 0, Flags::o(0)).unwrap();
         });
 
         let table = &lower.children[0];
@@ -691,13 +1857,13 @@ mod test {
 
         let lower = LowerTest::create(TREE_FRAMES, Init::FreeAll).unwrap();
 
-        frames[0] = lower.get(0, Flags::o(0)).unwrap().0;
-        frames[1] = lower.get(0, Flags::o(0)).unwrap().0;
+        frames[0] = lower.get(0, 0, Flags::o(0)).unwrap().0;
+        frames[1] = lower.get(0, 0, Flags::o(0)).unwrap().0;
 
         thread::parallel(0..2, |t| {
-            thread::pin(t);
+            thread::try_pin(t);
 
-            lower.put(frames[t as usize], Flags::o(0)).unwrap();
+            lower.put(0, frames[t as usize], Flags::o(0)).unwrap();
         });
 
         assert_eq!(lower.children[0][0].load().free(), Bitfield::LEN);
@@ -712,13 +1878,13 @@ mod test {
         let lower = LowerTest::create(TREE_FRAMES, Init::FreeAll).unwrap();
 
         for frame in &mut frames {
-            *frame = lower.get(0, Flags::o(0)).unwrap().0;
+            *frame = lower.get(0, 0, Flags::o(0)).unwrap().0;
         }
 
         thread::parallel(0..2, |t| {
-            thread::pin(t);
+            thread::try_pin(t);
 
-            lower.put(frames[t as usize], Flags::o(0)).unwrap();
+            lower.put(0, frames[t as usize], Flags::o(0)).unwrap();
         });
 
         let table = &lower.children[0];
@@ -735,18 +1901,18 @@ mod test {
         let lower = LowerTest::create(TREE_FRAMES, Init::FreeAll).unwrap();
 
         for frame in &mut frames[..Bitfield::LEN - 1] {
-            *frame = lower.get(0, Flags::o(0)).unwrap().0;
+            *frame = lower.get(0, 0, Flags::o(0)).unwrap().0;
         }
 
         std::thread::scope(|s| {
             s.spawn(|| {
-                thread::pin(0);
+                thread::try_pin(0);
 
-                lower.get(0, Flags::o(0)).unwrap();
+                lower.get(0, 0, Flags::o(0)).unwrap();
             });
-            thread::pin(1);
+            thread::try_pin(1);
 
-            lower.put(frames[0], Flags::o(0)).unwrap();
+            lower.put(0, frames[0], Flags::o(0)).unwrap();
         });
 
         let table = &lower.children[0];
@@ -766,13 +1932,13 @@ mod test {
         logging();
 
         let lower = LowerTest::create(TREE_FRAMES, Init::FreeAll).unwrap();
-        lower.get(0, Flags::o(0)).unwrap();
+        lower.get(0, 0, Flags::o(0)).unwrap();
 
         thread::parallel(0..2, |t| {
-            thread::pin(t);
+            thread::try_pin(t);
 
             let order = t + 1; // order 1 and 2
-            let frame = lower.get(0, Flags::o(order)).unwrap().0;
+            let frame = lower.get(0, 0, Flags::o(order)).unwrap().0;
             assert!(frame < lower.frames());
         });
 
@@ -792,15 +1958,15 @@ mod test {
 
         let lower = LowerTest::create(TREE_FRAMES, Init::FreeAll).unwrap();
 
-        frames[0] = lower.get(0, Flags::o(1)).unwrap().0;
-        frames[1] = lower.get(0, Flags::o(2)).unwrap().0;
+        frames[0] = lower.get(0, 0, Flags::o(1)).unwrap().0;
+        frames[1] = lower.get(0, 0, Flags::o(2)).unwrap().0;
 
         assert_eq!(lower.children[0][0].load().free(), Bitfield::LEN - 2 - 4);
 
         thread::parallel(0..2, |t| {
-            thread::pin(t);
+            thread::try_pin(t);
 
-            lower.put(frames[t as usize], Flags::o(t + 1)).unwrap();
+            lower.put(0, frames[t as usize], Flags::o(t + 1)).unwrap();
         });
 
         assert_eq!(lower.children[0][0].load().free(), Bitfield::LEN);
@@ -839,7 +2005,7 @@ mod test {
             for i in 0..lower.frames().div_ceil(TREE_FRAMES) {
                 // fall back to other chunks
                 let i = (i + tree_idx) % lower.frames().div_ceil(TREE_FRAMES);
-                match lower.get(i * TREE_FRAMES, Flags::o(*order)) {
+                match lower.get(0, i * TREE_FRAMES, Flags::o(*order)) {
                     Ok((free, _huge)) => {
                         *frame = free;
                         tree_idx = free / TREE_FRAMES;
@@ -855,7 +2021,7 @@ mod test {
         assert_eq!(lower.frames() - lower.free_frames(), num_frames);
 
         for (order, frame) in &frames {
-            lower.put(*frame, Flags::o(*order)).unwrap();
+            lower.put(0, *frame, Flags::o(*order)).unwrap();
         }
 
         assert_eq!(lower.free_frames(), lower.frames());
@@ -873,7 +2039,7 @@ mod test {
 
         for i in 0..FRAMES {
             lower
-                .put(i * (1 << MAX_ORDER), Flags::o(MAX_ORDER))
+                .put(0, i * (1 << MAX_ORDER), Flags::o(MAX_ORDER))
                 .unwrap();
         }
 
@@ -888,11 +2054,342 @@ mod test {
 
         assert_eq!(lower.free_frames(), 0);
 
-        lower.put(0, Flags::o(0)).unwrap();
+        lower.put(0, 0, Flags::o(0)).unwrap();
 
         assert_eq!(lower.free_frames(), 1);
     }
 
+    #[test]
+    fn huge_per_tree() {
+        logging();
+
+        fn get_put_recover<const HP: usize>() {
+            warn!("HP={HP}");
+            let frames = 4 * Lower::<HP>::N;
+            let lower = LowerTest::<HP>::with_hp(frames, Init::FreeAll).unwrap();
+            assert_eq!(lower.free_frames(), frames);
+
+            // Every allocation in its own tree
+            let mut allocated = Vec::new();
+            for (i, order) in [0, 3, HUGE_ORDER, MAX_ORDER].into_iter().enumerate() {
+                let (frame, _) = lower.get(0, i * Lower::<HP>::N, Flags::o(order)).unwrap();
+                assert_eq!(frame / Lower::<HP>::N, i);
+                allocated.push((frame, order));
+            }
+            let used = allocated.iter().map(|(_, o)| 1 << o).sum::<usize>();
+            assert_eq!(lower.frames() - lower.free_frames(), used);
+
+            // Corrupt the counters and rebuild them from the bitfields
+            for table in lower.children {
+                for entry in table.iter() {
+                    if !entry.load().huge() {
+                        entry.store(HugeEntry::new_free(0));
+                    }
+                }
+            }
+            lower.recover(1, RecoverMode::Fix);
+            assert_eq!(lower.frames() - lower.free_frames(), used);
+
+            for (frame, order) in allocated {
+                lower.put(0, frame, Flags::o(order)).unwrap();
+            }
+            assert_eq!(lower.free_frames(), frames);
+            assert_eq!(lower.free_huge(), frames / HUGE_FRAMES);
+        }
+
+        get_put_recover::<2>();
+        get_put_recover::<8>();
+        get_put_recover::<32>();
+    }
+
+    #[test]
+    fn bitfield_size() {
+        logging();
+
+        fn battery<const HP: usize, const BF: usize>() {
+            warn!("HP={HP} BF={BF}");
+            let huge_frames = Lower::<HP, BF>::HUGE_FRAMES;
+            let max_order = Lower::<HP, BF>::MAX_ORDER;
+            let frames = 4 * Lower::<HP, BF>::N;
+            let lower = LowerTest::<HP, BF>::with_hp(frames, Init::FreeAll).unwrap();
+            assert_eq!(lower.free_huge(), frames / huge_frames);
+
+            // All orders in the first tree
+            let mut allocated = Vec::new();
+            for order in 0..=max_order {
+                let (frame, _) = lower.get(0, 0, Flags::o(order)).unwrap();
+                assert!(frame % (1 << order) == 0 && frame < Lower::<HP, BF>::N);
+                assert!(!lower.is_free(frame, order));
+                allocated.push((frame, order));
+            }
+            let used = allocated.iter().map(|(_, o)| 1 << o).sum::<usize>();
+            assert_eq!(lower.frames() - lower.free_frames(), used);
+
+            // Rebuild the counters from the bitfields
+            for table in lower.children {
+                for entry in table.iter() {
+                    if !entry.load().huge() {
+                        entry.store(HugeEntry::new_free(0));
+                    }
+                }
+            }
+            lower.recover(1, RecoverMode::Fix);
+            assert_eq!(lower.frames() - lower.free_frames(), used);
+
+            for (frame, order) in allocated {
+                lower.put(0, frame, Flags::o(order)).unwrap();
+            }
+            assert_eq!(lower.free_frames(), frames);
+            assert_eq!(lower.free_huge(), frames / huge_frames);
+
+            // Concurrent small allocations in the same tree
+            const THREADS: usize = 4;
+            let barrier = Barrier::new(THREADS);
+            let mut allocated = thread::parallel(0..THREADS, |t| {
+                barrier.wait();
+                let start = t * Lower::<HP, BF>::N / THREADS;
+                let frames = (0..Lower::<HP, BF>::N / THREADS)
+                    .map(|_| lower.get(0, start, Flags::o(0)).unwrap().0)
+                    .collect::<Vec<_>>();
+                barrier.wait();
+                for &frame in &frames {
+                    lower.put(0, frame, Flags::o(0)).unwrap();
+                }
+                frames
+            })
+            .concat();
+            allocated.sort_unstable();
+            assert!(allocated.iter().enumerate().all(|(i, f)| i == *f));
+            assert_eq!(lower.free_frames(), frames);
+            assert_eq!(lower.free_huge(), frames / huge_frames);
+        }
+
+        battery::<TREE_HUGE, 8>();
+        battery::<TREE_HUGE, 2>();
+        battery::<{ 4 * TREE_HUGE }, 2>();
+        battery::<4, 4>();
+    }
+
+    /// Contention on the table entries if all cores allocate in the same tree
+    #[test]
+    #[ignore]
+    fn bitfield_size_contention() {
+        logging();
+
+        fn bench<const HP: usize, const BF: usize>() {
+            const THREADS: usize = 4;
+            const ROUNDS: usize = 256;
+            let lower = LowerTest::<HP, BF>::with_hp(TREE_FRAMES, Init::FreeAll).unwrap();
+            let barrier = Barrier::new(THREADS);
+
+            #[cfg(feature = "search_stats")]
+            let conflicts = super::CONFLICTS.load(core::sync::atomic::Ordering::Relaxed);
+            let timer = std::time::Instant::now();
+            thread::parallel(0..THREADS, |t| {
+                thread::try_pin(t);
+                let mut frames = Vec::with_capacity(TREE_FRAMES / THREADS / 2);
+                barrier.wait();
+                for _ in 0..ROUNDS {
+                    for _ in 0..frames.capacity() {
+                        frames.push(lower.get(0, 0, Flags::o(0)).unwrap().0);
+                    }
+                    for frame in frames.drain(..) {
+                        lower.put(0, frame, Flags::o(0)).unwrap();
+                    }
+                }
+            });
+            warn!("BF={BF}: {:?}", timer.elapsed());
+            #[cfg(feature = "search_stats")]
+            warn!(
+                "BF={BF}: {} conflicts",
+                super::CONFLICTS.load(core::sync::atomic::Ordering::Relaxed) - conflicts
+            );
+            assert_eq!(lower.free_frames(), TREE_FRAMES);
+        }
+
+        // Both configurations have the same tree size
+        bench::<TREE_HUGE, 8>();
+        bench::<{ 4 * TREE_HUGE }, 2>();
+    }
+
+    #[test]
+    fn recover_parallel() {
+        logging();
+
+        const TREES: usize = 67;
+        const FRAMES: usize = TREES * TREE_FRAMES;
+        let lower = LowerTest::create(FRAMES, Init::FreeAll).unwrap();
+
+        // Different allocation patterns in every tree
+        let mut rng = WyRand::new(42);
+        for tree in 0..TREES {
+            for _ in 0..rng.range(0..TREE_FRAMES as u64 / 2) {
+                let order = [0, 0, 3, HUGE_ORDER][rng.range(0..4) as usize];
+                let _ = lower.get(0, tree * TREE_FRAMES, Flags::o(order));
+            }
+        }
+        let free = lower.free_frames();
+
+        // Crash with corrupted counters
+        for table in lower.children {
+            for entry in table.iter() {
+                if !entry.load().huge() {
+                    entry.store(HugeEntry::new_free(rng.range(0..64) as _));
+                }
+            }
+        }
+        let mut crashed = Vec::new();
+        lower.snapshot(&mut crashed);
+
+        // The serial recovery is the reference
+        let report = lower.recover(1, RecoverMode::Fix);
+        assert_eq!(lower.recovery_progress(), (TREES, TREES));
+        assert_eq!(lower.free_frames(), free);
+        assert_eq!(report.free_frames, free);
+        let mut expected = Vec::new();
+        lower.snapshot(&mut expected);
+
+        for threads in [2, 3, 4, 16, 32] {
+            lower.restore(&mut crashed.as_slice()).unwrap();
+            assert_eq!(lower.recover(threads, RecoverMode::Fix), report);
+            assert_eq!(lower.recovery_progress(), (TREES, TREES));
+
+            let mut recovered = Vec::new();
+            lower.snapshot(&mut recovered);
+            assert!(recovered == expected, "threads={threads}");
+        }
+    }
+
+    /// A dry run reports the damage without repairing it
+    #[test]
+    fn recover_report() {
+        logging();
+
+        const FRAMES: usize = 8 * TREE_FRAMES;
+        let lower = LowerTest::create(FRAMES, Init::FreeAll).unwrap();
+        for _ in 0..10 {
+            lower.get(0, 0, Flags::o(0)).unwrap();
+        }
+        lower.get(0, TREE_FRAMES, Flags::o(HUGE_ORDER)).unwrap();
+        let free = lower.free_frames();
+
+        // Crash with diverging counters and a huge frame over a used bitfield
+        lower.children[0][0].store(HugeEntry::new_free(3));
+        lower.children[2][1].store(HugeEntry::new_free(100));
+        lower.bitfields[TREE_HUGE].set(0..1, true);
+        let mut crashed = Vec::new();
+        lower.snapshot(&mut crashed);
+
+        let report = lower.recover(1, RecoverMode::DryRun);
+        let mut inspected = Vec::new();
+        lower.snapshot(&mut inspected);
+        assert!(inspected == crashed);
+        assert!(!report.is_clean());
+        assert_eq!(report.free_frames, free);
+        assert_eq!(report.huge_frames, 1);
+        assert_eq!(report.cleared_bitfields, 1);
+        assert_eq!(report.fixed_counters, 2);
+        let expected = [
+            FixedCounter {
+                table: 0,
+                entry: 0,
+                expected: HUGE_FRAMES - 10,
+                found: 3,
+            },
+            FixedCounter {
+                table: 2,
+                entry: 1,
+                expected: HUGE_FRAMES,
+                found: 100,
+            },
+        ];
+        assert_eq!(report.fixed(), expected);
+
+        assert_eq!(lower.recover(1, RecoverMode::Fix), report);
+        assert_eq!(lower.free_frames(), free);
+        lower.check().unwrap();
+        assert!(lower.recover(1, RecoverMode::DryRun).is_clean());
+
+        // Only the first counters are recorded
+        for table in lower.children {
+            for entry in table.iter() {
+                entry.store(HugeEntry::new_free(1));
+            }
+        }
+        let report = lower.recover(1, RecoverMode::DryRun);
+        assert_eq!(report.fixed_counters, FRAMES / HUGE_FRAMES);
+        assert_eq!(report.fixed().len(), super::RecoveryReport::MAX_FIXED);
+    }
+
+    /// Recovery of torn metadata beyond the end of memory
+    #[test]
+    fn recover_deep_verify() {
+        logging();
+
+        // Partial last bitfield and table
+        const FRAMES: usize = TREE_FRAMES + HUGE_FRAMES + 100;
+        const LAST: usize = FRAMES / Bitfield::LEN;
+        let lower = LowerTest::create(FRAMES, Init::FreeAll).unwrap();
+        for _ in 0..200 {
+            lower.get(0, 0, Flags::o(0)).unwrap();
+        }
+        lower.get(0, TREE_FRAMES, Flags::o(HUGE_ORDER)).unwrap();
+        let free = lower.free_frames();
+        assert_eq!(free, FRAMES - 200 - HUGE_FRAMES);
+
+        // Entries without bitfield are skipped
+        lower.recover(1, RecoverMode::Fix);
+        assert_eq!(lower.free_frames(), free);
+
+        // Torn writes beyond the end of memory
+        lower.bitfields[LAST].set(100..Bitfield::LEN, false);
+        lower.children[1][LAST % TREE_HUGE].store(HugeEntry::new_huge());
+        lower.children[1][5].store(HugeEntry::new_free(17));
+        // And corrupted counters
+        lower.children[0][0].store(HugeEntry::new_free(3));
+
+        let report = lower.recover_deep_verify(1).unwrap();
+        assert_eq!(report.torn_entries, 2);
+        // The corrupted counter and the reset partial huge frame
+        assert_eq!(report.fixed_counters, 2);
+        assert_eq!(lower.free_frames(), free);
+        assert_eq!(lower.bitfields[LAST].count_zeros(), 100);
+        lower.check().unwrap();
+    }
+
+    #[test]
+    fn put_free_order() {
+        logging();
+
+        let lower = LowerTest::create(TREE_FRAMES, Init::AllocAll).unwrap();
+
+        // Intermediate frees report smaller orders
+        assert_eq!(lower.put(0, 0, Flags::o(0)), Ok(0));
+        assert_eq!(lower.put(0, 1, Flags::o(0)), Ok(1));
+        assert_eq!(lower.put(0, 3, Flags::o(0)), Ok(0));
+        assert_eq!(lower.put(0, 2, Flags::o(0)), Ok(2));
+        assert_eq!(lower.put(0, 4, Flags::o(2)), Ok(3));
+        for frame in (8..HUGE_FRAMES - 8).step_by(8) {
+            assert!(lower.put(0, frame, Flags::o(3)).unwrap() < HUGE_ORDER);
+        }
+        // The last frame of the huge frame
+        assert_eq!(lower.put(0, HUGE_FRAMES - 8, Flags::o(3)), Ok(HUGE_ORDER));
+
+        // The partner is free too
+        assert_eq!(
+            lower.put(0, HUGE_FRAMES, Flags::o(HUGE_ORDER)),
+            Ok(MAX_ORDER)
+        );
+        assert_eq!(
+            lower.put(0, 2 * HUGE_FRAMES, Flags::o(HUGE_ORDER)),
+            Ok(HUGE_ORDER)
+        );
+        assert_eq!(
+            lower.put(0, 4 * HUGE_FRAMES, Flags::o(MAX_ORDER)),
+            Ok(MAX_ORDER)
+        );
+    }
+
     #[test]
     #[ignore]
     fn rand_realloc_first() {
@@ -907,16 +2404,16 @@ mod test {
 
             let barrier = Barrier::new(THREADS);
             thread::parallel(0..THREADS, |t| {
-                thread::pin(t);
+                thread::try_pin(t);
                 barrier.wait();
 
                 let mut frames = [0; 4];
                 for p in &mut frames {
-                    *p = lower.get(0, Flags::o(0)).unwrap().0;
+                    *p = lower.get(0, 0, Flags::o(0)).unwrap().0;
                 }
                 frames.reverse();
                 for p in frames {
-                    lower.put(p, Flags::o(0)).unwrap();
+                    lower.put(0, p, Flags::o(0)).unwrap();
                 }
             });
 
@@ -938,18 +2435,18 @@ mod test {
             assert_eq!(lower.free_frames(), FRAMES);
 
             for frame in &mut frames[..HUGE_FRAMES - 3] {
-                *frame = lower.get(0, Flags::o(0)).unwrap().0;
+                *frame = lower.get(0, 0, Flags::o(0)).unwrap().0;
             }
 
             let barrier = Barrier::new(THREADS);
             thread::parallel(0..THREADS, |t| {
-                thread::pin(t);
+                thread::try_pin(t);
                 barrier.wait();
 
                 if t < THREADS / 2 {
-                    lower.put(frames[t], Flags::o(0)).unwrap();
+                    lower.put(0, frames[t], Flags::o(0)).unwrap();
                 } else {
-                    lower.get(0, Flags::o(0)).unwrap();
+                    lower.get(0, 0, Flags::o(0)).unwrap();
                 }
             });
 
@@ -966,7 +2463,7 @@ mod test {
         let barrier = Barrier::new(THREADS);
 
         let huge = thread::parallel(0..THREADS, |t| {
-            thread::pin(t);
+            thread::try_pin(t);
             let mut frames = Vec::with_capacity(TREE_FRAMES);
 
             barrier.wait();
@@ -976,7 +2473,7 @@ mod test {
             let mut get = 0;
             let mut put = 0;
             loop {
-                match lower.get(0, Flags::o(0)) {
+                match lower.get(0, 0, Flags::o(0)) {
                     Ok((frame, huge)) => {
                         get += huge as usize;
                         frames.push(frame);
@@ -987,7 +2484,7 @@ mod test {
             }
             rng.shuffle(&mut frames);
             while let Some(frame) = frames.pop() {
-                put += lower.put(frame, Flags::o(0)).unwrap() as usize;
+                put += (lower.put(0, frame, Flags::o(0)).unwrap() >= HUGE_ORDER) as usize;
             }
 
             (get, put)
@@ -1014,7 +2511,7 @@ mod test {
         let barrier = Barrier::new(THREADS);
 
         let huge = thread::parallel(0..THREADS, |t| {
-            thread::pin(t);
+            thread::try_pin(t);
             let mut frames = Vec::with_capacity(TREE_FRAMES);
 
             barrier.wait();
@@ -1028,9 +2525,10 @@ mod test {
 
                 while frames.len() != target {
                     if target < frames.len() {
-                        put += lower.put(frames.pop().unwrap(), Flags::o(0)).unwrap() as usize;
+                        put += (lower.put(0, frames.pop().unwrap(), Flags::o(0)).unwrap()
+                            >= HUGE_ORDER) as usize;
                     } else {
-                        match lower.get(0, Flags::o(0)) {
+                        match lower.get(0, 0, Flags::o(0)) {
                             Ok((frame, huge)) => {
                                 get += huge as usize;
                                 frames.push(frame);
@@ -1043,7 +2541,7 @@ mod test {
                 rng.shuffle(&mut frames);
             }
             for frame in frames {
-                put += lower.put(frame, Flags::o(0)).unwrap() as usize;
+                put += (lower.put(0, frame, Flags::o(0)).unwrap() >= HUGE_ORDER) as usize;
             }
 
             (get, put)
@@ -1056,4 +2554,33 @@ mod test {
         assert_eq!(lower.free_frames(), TREE_FRAMES);
         assert_eq!(lower.free_huge(), TREE_HUGE);
     }
+
+    /// Failed CAS operations on the bitfield and the counter revert of `get_small`
+    #[test]
+    fn inject_get_small() {
+        use crate::atomic::inject;
+
+        logging();
+
+        let lower = LowerTest::create(TREE_FRAMES, Init::FreeAll).unwrap();
+        let bitfield = &*lower.bitfields[0] as *const Bitfield as usize;
+        let in_bitfield = move |a| (bitfield..bitfield + size_of::<Bitfield>()).contains(&a);
+        let entry = &lower.children[0][0];
+
+        // The first bitfield fails, the counter is reverted and the next one is used
+        for _ in 0..Bitfield::ENTRIES {
+            inject::fail_where(0, in_bitfield);
+        }
+        assert_eq!(lower.get(0, 0, Flags::o(0)), Ok((Bitfield::LEN, true)));
+        assert_eq!(entry.load().free(), Bitfield::LEN);
+        assert!(!entry.load().split());
+
+        // Additionally, the revert fails
+        for _ in 0..Bitfield::ENTRIES {
+            inject::fail_where(0, in_bitfield);
+        }
+        inject::fail_nth(entry, 1);
+        assert_eq!(lower.get(0, 0, Flags::o(0)), Err(Error::Corruption));
+        assert_eq!(inject::clear(), 0);
+    }
 }
@@ -0,0 +1,256 @@
+//! Metrics of an allocator in the Prometheus text exposition format.
+//!
+//! The values are racy snapshots, unless the allocator is quiesced.
+//! The counters of the `search_stats` feature are global for all allocators.
+
+use core::fmt;
+
+use crate::Alloc;
+
+/// Prometheus metrics for any [Alloc]
+pub trait Metrics<'a>: Alloc<'a> {
+    /// Writes the metrics with an `allocator` label, without allocating memory.
+    fn metrics(&self, w: &mut dyn fmt::Write) -> fmt::Result {
+        let name = Self::name();
+        let gauges = [
+            ("frames", "Total number of frames", self.frames()),
+            ("free_frames", "Number of free frames", self.free_frames()),
+            (
+                "allocated_frames",
+                "Number of allocated frames",
+                self.allocated_frames(),
+            ),
+            (
+                "free_huge_frames",
+                "Number of entirely free huge frames",
+                self.free_huge(),
+            ),
+            ("cores", "Number of cores", self.cores()),
+        ];
+        for (metric, help, value) in gauges {
+            write_metric(w, metric, "gauge", help, name, value)?;
+        }
+
+        #[cfg(feature = "search_stats")]
+        {
+            use core::sync::atomic::Ordering::Relaxed;
+            let counters = [
+                (
+                    "cas_retries_total",
+                    "Number of retried updates of table entries",
+                    crate::lower::CONFLICTS.load(Relaxed),
+                ),
+                (
+                    "stolen_trees_total",
+                    "Number of trees stolen from other cores",
+                    crate::llfree::STOLEN.load(Relaxed),
+                ),
+                (
+                    "searched_entries_total",
+                    "Number of bitfield entries searched for allocations",
+                    crate::bitfield::SEARCHED.load(Relaxed),
+                ),
+            ];
+            for (metric, help, value) in counters {
+                write_metric(w, metric, "counter", help, name, value)?;
+            }
+        }
+        #[cfg(feature = "latency")]
+        if let Some(histograms) = self.histograms() {
+            write_histograms(w, name, &histograms)?;
+        }
+        Ok(())
+    }
+}
+
+impl<'a, A: Alloc<'a>> Metrics<'a> for A {}
+
+/// Writes a single metric with its metadata
+fn write_metric(
+    w: &mut dyn fmt::Write,
+    metric: &str,
+    kind: &str,
+    help: &str,
+    allocator: &str,
+    value: usize,
+) -> fmt::Result {
+    writeln!(w, "# HELP llfree_{metric} {help}")?;
+    writeln!(w, "# TYPE llfree_{metric} {kind}")?;
+    write!(w, "llfree_{metric}{{")?;
+    write_allocator(w, allocator)?;
+    writeln!(w, "}} {value}")
+}
+
+/// Writes the allocator label
+fn write_allocator(w: &mut dyn fmt::Write, allocator: &str) -> fmt::Result {
+    w.write_str("allocator=\"")?;
+    // Escape the label value
+    for c in allocator.chars() {
+        match c {
+            '\\' => w.write_str("\\\\")?,
+            '"' => w.write_str("\\\"")?,
+            '\n' => w.write_str("\\n")?,
+            c => w.write_char(c)?,
+        }
+    }
+    w.write_char('"')
+}
+
+/// Writes the latency and retry histograms with an additional `orders` label
+#[cfg(feature = "latency")]
+fn write_histograms(
+    w: &mut dyn fmt::Write,
+    allocator: &str,
+    h: &crate::latency::Histograms,
+) -> fmt::Result {
+    use crate::latency::CLASS_ORDERS;
+
+    let latency = h.latency.each_ref().map(|c| c.as_slice());
+    let retries = h.retries.each_ref().map(|c| c.as_slice());
+    // Inclusive upper bound of the buckets
+    let latency_le: fn(usize) -> u64 = |i| (1 << i) - 1;
+    let retries_le: fn(usize) -> u64 = |i| i as _;
+    let families = [
+        (
+            "get_latency_ns",
+            "Latency of successful allocations in ns",
+            latency,
+            h.latency_sum,
+            latency_le,
+        ),
+        (
+            "get_retries",
+            "Number of retries of successful allocations",
+            retries,
+            h.retries_sum,
+            retries_le,
+        ),
+    ];
+    for (metric, help, classes, sums, le) in families {
+        writeln!(w, "# HELP llfree_{metric} {help}")?;
+        writeln!(w, "# TYPE llfree_{metric} histogram")?;
+        for (class, buckets) in classes.iter().enumerate() {
+            let orders = &CLASS_ORDERS[class];
+            let labels = |w: &mut dyn fmt::Write| {
+                write_allocator(w, allocator)?;
+                if orders.start() == orders.end() {
+                    write!(w, ",orders=\"{}\"", orders.start())
+                } else {
+                    write!(w, ",orders=\"{}-{}\"", orders.start(), orders.end())
+                }
+            };
+            // The buckets are cumulative
+            let mut count = 0;
+            for (i, bucket) in buckets.iter().enumerate() {
+                count += bucket;
+                write!(w, "llfree_{metric}_bucket{{")?;
+                labels(w)?;
+                if i + 1 < buckets.len() {
+                    writeln!(w, ",le=\"{}\"}} {count}", le(i))?;
+                } else {
+                    writeln!(w, ",le=\"+Inf\"}} {count}")?;
+                }
+            }
+            write!(w, "llfree_{metric}_sum{{")?;
+            labels(w)?;
+            writeln!(w, "}} {}", sums[class])?;
+            write!(w, "llfree_{metric}_count{{")?;
+            labels(w)?;
+            writeln!(w, "}} {count}")?;
+        }
+    }
+    Ok(())
+}
+
+#[cfg(all(test, feature = "std"))]
+mod test {
+    use std::collections::HashMap;
+    use std::string::String;
+
+    use super::Metrics;
+    use crate::test::TestAlloc;
+    use crate::{Alloc, Flags, Init, LLFree, HUGE_ORDER, TREE_FRAMES};
+
+    /// Parses the samples, checking the format of every line.
+    ///
+    /// Samples with additional labels are keyed by the metric and all labels.
+    fn parse(text: &str) -> HashMap<&str, (&str, usize)> {
+        let mut samples = HashMap::new();
+        for line in text.lines() {
+            if let Some(comment) = line.strip_prefix("# ") {
+                let (kind, rest) = comment.split_once(' ').unwrap();
+                assert!(kind == "HELP" || kind == "TYPE", "{line}");
+                let (metric, _) = rest.split_once(' ').unwrap();
+                assert!(metric.starts_with("llfree_"), "{line}");
+                continue;
+            }
+            let (metric, rest) = line.split_once('{').unwrap();
+            assert!(metric
+                .chars()
+                .all(|c| c.is_ascii_alphanumeric() || c == '_'));
+            let (labels, value) = rest.split_once("} ").unwrap();
+            let (label, extra) = match labels.split_once(',') {
+                Some((label, extra)) => (label, Some(extra)),
+                None => (labels, None),
+            };
+            let (key, label) = label.split_once('=').unwrap();
+            assert_eq!(key, "allocator");
+            let label = label.strip_prefix('"').unwrap().strip_suffix('"').unwrap();
+            let value = value.parse().unwrap();
+            let metric = match extra {
+                Some(extra) => {
+                    assert!(extra.split(',').all(|l| l.contains("=\"")), "{line}");
+                    &line[..metric.len() + labels.len() + 2]
+                }
+                None => metric,
+            };
+            assert!(samples.insert(metric, (label, value)).is_none(), "{line}");
+        }
+        samples
+    }
+
+    #[test]
+    fn metrics() {
+        const FRAMES: usize = 4 * TREE_FRAMES;
+        let alloc = TestAlloc::<LLFree>::create(2, FRAMES, Init::FreeAll).unwrap();
+        alloc.get(0, Flags::o(0)).unwrap();
+        alloc.get(1, Flags::o(HUGE_ORDER)).unwrap();
+
+        let mut text = String::new();
+        alloc.metrics(&mut text).unwrap();
+        let samples = parse(&text);
+
+        assert!(samples.values().all(|(label, _)| *label == LLFree::name()));
+        assert_eq!(samples["llfree_frames"].1, FRAMES);
+        assert_eq!(samples["llfree_cores"].1, 2);
+        let allocated = samples["llfree_allocated_frames"].1;
+        assert_eq!(allocated, alloc.allocated_frames());
+        assert_eq!(allocated, 1 + (1 << HUGE_ORDER));
+        assert_eq!(samples["llfree_free_frames"].1, FRAMES - allocated);
+        assert_eq!(samples["llfree_free_huge_frames"].1, alloc.free_huge());
+
+        #[cfg(feature = "latency")]
+        {
+            let sample = |metric: &str, orders: &str, le: Option<&str>| {
+                let le = le
+                    .map(|le| std::format!(",le=\"{le}\""))
+                    .unwrap_or_default();
+                let name = LLFree::name();
+                let key = std::format!("{metric}{{allocator=\"{name}\",orders=\"{orders}\"{le}}}");
+                samples[key.as_str()].1
+            };
+            assert_eq!(sample("llfree_get_latency_ns_count", "0", None), 1);
+            assert_eq!(sample("llfree_get_latency_ns_count", "7-9", None), 1);
+            assert_eq!(
+                sample("llfree_get_latency_ns_bucket", "7-9", Some("+Inf")),
+                1
+            );
+            assert_eq!(
+                sample("llfree_get_latency_ns_bucket", "1-6", Some("+Inf")),
+                0
+            );
+            assert_eq!(sample("llfree_get_retries_bucket", "0", Some("0")), 1);
+            assert_eq!(sample("llfree_get_retries_sum", "10", None), 0);
+        }
+    }
+}
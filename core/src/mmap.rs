@@ -6,7 +6,10 @@ use std::boxed::Box;
 use std::fs::File;
 use std::os::unix::prelude::AsRawFd;
 
+use spin::mutex::SpinMutex;
+
 use crate::frame::Frame;
+use crate::util::checked_align_up;
 
 /// Create an private anonymous mapping
 pub fn anon<T>(begin: usize, len: usize, shared: bool, populate: bool) -> Box<[T], MMap> {
@@ -31,6 +34,8 @@ pub struct MMap {
     #[allow(unused)]
     populate: bool,
     file: Option<(File, bool)>,
+    /// Address and length of the mapping, only one mapping is supported
+    mapped: SpinMutex<Option<(usize, usize)>>,
 }
 
 impl MMap {
@@ -40,6 +45,7 @@ impl MMap {
             shared,
             populate,
             file: None,
+            mapped: Default::default(),
         }
     }
 
@@ -50,6 +56,23 @@ impl MMap {
             shared: true,
             populate: false,
             file: Some((file, dax)),
+            mapped: Default::default(),
+        }
+    }
+
+    /// Writes the cache lines of `ptr..ptr+len` back to the (persistent) memory.
+    ///
+    /// # Safety
+    /// `ptr..ptr+len` has to be mapped, e.g., be part of a live allocation.
+    pub unsafe fn sync(&self, ptr: *const u8, len: usize) {
+        unsafe { crate::util::persist(ptr, len) };
+    }
+    /// Writes the whole mapping back to the (persistent) memory.
+    pub fn sync_all(&self) {
+        // Holding the lock prevents a concurrent unmap
+        let mapped = self.mapped.lock();
+        if let Some((addr, len)) = *mapped {
+            unsafe { self.sync(addr as _, len) };
         }
     }
 }
@@ -59,7 +82,7 @@ unsafe impl Allocator for MMap {
     fn allocate(&self, layout: Layout) -> Result<NonNull<[u8]>, AllocError> {
         // Enforce alignment
         let begin = if layout.align() != 0 {
-            self.begin.next_multiple_of(layout.align())
+            checked_align_up(self.begin, layout.align()).ok_or(AllocError)?
         } else {
             self.begin
         };
@@ -67,6 +90,10 @@ unsafe impl Allocator for MMap {
         if layout.size() == 0 {
             return Ok(unsafe { std::slice::from_raw_parts(begin as _, 0) }.into());
         }
+        let mut mapped = self.mapped.lock();
+        if mapped.is_some() {
+            return Err(AllocError);
+        }
         // Now ask the os for the memory
         let addr = if let Some((file, _dax)) = &self.file {
             let fd = file.as_raw_fd();
@@ -116,6 +143,7 @@ unsafe impl Allocator for MMap {
         };
 
         if addr != libc::MAP_FAILED {
+            *mapped = Some((addr as _, layout.size()));
             // This non-null slice is somewhat cursed
             Ok(unsafe { std::slice::from_raw_parts(addr.cast(), layout.size()) }.into())
         } else {
@@ -131,6 +159,10 @@ unsafe impl Allocator for MMap {
                 unsafe { libc::perror(b"munmap failed\0".as_ptr().cast()) };
                 panic!("unmap {layout:?}");
             }
+            let mut mapped = self.mapped.lock();
+            if *mapped == Some((ptr.as_ptr() as _, layout.size())) {
+                *mapped = None;
+            }
         }
     }
 }
@@ -225,10 +257,13 @@ pub fn test_mapping(begin: usize, length: usize) -> Box<[Frame], MMap> {
 
 #[cfg(all(test, feature = "std"))]
 mod test {
+    use core::alloc::{Allocator, Layout};
+    use std::boxed::Box;
     use std::thread;
 
     use log::info;
 
+    use super::MMap;
     use crate::frame::Frame;
     use crate::util::logging;
 
@@ -275,6 +310,43 @@ mod test {
         assert_eq!(mapping[0], 42);
     }
 
+    #[test]
+    fn sync() {
+        logging();
+
+        let mut mapping = super::anon(0x1000_0000_0000, 3 * Frame::SIZE, true, false);
+        mapping[0] = 42u8;
+        mapping[Frame::SIZE + 1] = 43;
+        unsafe { Box::allocator(&mapping).sync(&mapping[Frame::SIZE], 64) };
+        Box::allocator(&mapping).sync_all();
+        assert_eq!(mapping[0], 42);
+        assert_eq!(mapping[Frame::SIZE + 1], 43);
+    }
+
+    #[test]
+    fn single_mapping() {
+        logging();
+
+        let mmap = MMap::anon(0x1000_0000_0000, false, false);
+        let layout = Layout::from_size_align(Frame::SIZE, Frame::SIZE).unwrap();
+        let ptr = mmap.allocate(layout).unwrap();
+        assert_eq!(
+            *mmap.mapped.lock(),
+            Some((ptr.as_ptr().cast::<u8>() as _, Frame::SIZE))
+        );
+        // Only one mapping at a time
+        assert!(mmap.allocate(layout).is_err());
+
+        unsafe { mmap.deallocate(ptr.cast(), layout) };
+        assert_eq!(*mmap.mapped.lock(), None);
+        // Nothing to flush
+        mmap.sync_all();
+
+        let ptr = mmap.allocate(layout).unwrap();
+        mmap.sync_all();
+        unsafe { mmap.deallocate(ptr.cast(), layout) };
+    }
+
     #[test]
     fn anonymous() {
         logging();
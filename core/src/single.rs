@@ -0,0 +1,251 @@
+//! Minimal upper allocator for a single core, e.g., for small embedded systems.
+//!
+//! It has no per-core state and no tree array.
+//! Allocations directly search the [Lower] allocator, starting at the last allocation.
+
+use core::fmt;
+use core::sync::atomic::AtomicUsize;
+use core::sync::atomic::Ordering::Relaxed;
+
+use log::{error, warn};
+
+use crate::lower::Lower;
+use crate::util::FmtFn;
+use crate::{
+    spans_of_blocks, Alloc, Error, Flags, Init, MetaData, MetaSize, PutResult, Result, HUGE_ORDER,
+    MAX_ORDER, TREE_FRAMES, TREE_HUGE,
+};
+
+/// Upper allocator that only supports a single core.
+///
+/// It is still thread-safe, but all threads contend on the same frames.
+pub struct Single<'a> {
+    lower: Lower<'a>,
+    /// Frame of the last allocation, where the next search starts
+    cursor: AtomicUsize,
+}
+
+impl<'a> Alloc<'a> for Single<'a> {
+    #[cold]
+    fn name() -> &'static str {
+        "Single"
+    }
+
+    #[cold]
+    fn new(cores: usize, frames: usize, init: Init, meta: MetaData<'a>) -> Result<Self> {
+        if cores != 1 {
+            error!("{} only supports a single core, not {cores}", Self::name());
+            return Err(Error::Initialization);
+        }
+        if !meta.valid(Self::metadata_size(cores, frames)) {
+            error!("invalid metadata");
+            return Err(Error::Initialization);
+        }
+        let lower = Lower::new(cores, frames, init, meta.lower)?;
+        Ok(Self {
+            lower,
+            cursor: AtomicUsize::new(0),
+        })
+    }
+
+    #[cold]
+    fn metadata_size(_cores: usize, frames: usize) -> MetaSize {
+        MetaSize {
+            local: 0,
+            trees: 0,
+            lower: Lower::<TREE_HUGE>::metadata_size(frames),
+        }
+    }
+
+    #[cold]
+    fn metadata(&mut self) -> MetaData<'a> {
+        MetaData {
+            local: &mut [],
+            trees: &mut [],
+            lower: self.lower.metadata(),
+        }
+    }
+
+    fn get(&self, core: usize, flags: Flags) -> Result<usize> {
+        if flags.order() > MAX_ORDER {
+            error!("invalid order");
+            return Err(Error::InvalidOrder {
+                requested: flags.order(),
+                max: MAX_ORDER,
+            });
+        }
+        let trees = self.frames().div_ceil(TREE_FRAMES);
+        let cursor = self
+            .cursor
+            .load(Relaxed)
+            .min(self.frames().saturating_sub(1));
+        // Search the tree of the cursor first, then the following ones
+        for i in 0..trees {
+            let start = if i == 0 {
+                cursor
+            } else {
+                (cursor / TREE_FRAMES + i) % trees * TREE_FRAMES
+            };
+            match self.lower.get(core, start, flags) {
+                Ok((frame, _)) => {
+                    self.cursor.store(frame, Relaxed);
+                    return Ok(frame);
+                }
+                Err(Error::Memory) => {}
+                Err(e) => return Err(e),
+            }
+        }
+        if flags.order() > 0 && self.free_frames() >= 1 << flags.order() {
+            Err(Error::Fragmented)
+        } else {
+            Err(Error::Memory)
+        }
+    }
+
+    fn put(&self, core: usize, frame: usize, flags: Flags) -> Result<()> {
+        self.put_report(core, frame, flags).map(|_| ())
+    }
+
+    fn put_report(&self, core: usize, frame: usize, flags: Flags) -> Result<PutResult> {
+        if frame >= self.frames() {
+            error!("invalid frame number");
+            return Err(Error::Memory);
+        }
+        if flags.order() > MAX_ORDER {
+            error!("invalid order");
+            return Err(Error::InvalidOrder {
+                requested: flags.order(),
+                max: MAX_ORDER,
+            });
+        }
+        let free_order = self.lower.put(core, frame, flags)?;
+        Ok(PutResult {
+            freed_order: flags.order(),
+            now_free_up_to: free_order,
+        })
+    }
+
+    fn try_resize(
+        &self,
+        core: usize,
+        frame: usize,
+        order: usize,
+        new_order: usize,
+    ) -> Result<usize> {
+        if new_order <= order {
+            return crate::shrink(self, core, frame, order, new_order);
+        }
+        crate::check_resize(self, frame, order, new_order)?;
+        if new_order >= HUGE_ORDER
+            || frame % (1 << new_order) != 0
+            || frame + (1 << new_order) > self.frames()
+        {
+            return Err(Error::Memory);
+        }
+        self.lower.grow(frame, order, new_order)?;
+        Ok(frame)
+    }
+
+    fn is_initialized(&self) -> bool {
+        self.lower.is_initialized()
+    }
+
+    fn frames(&self) -> usize {
+        self.lower.frames()
+    }
+
+    fn cores(&self) -> usize {
+        1
+    }
+
+    fn free_frames(&self) -> usize {
+        self.lower.free_frames()
+    }
+
+    fn free_huge(&self) -> usize {
+        self.lower.free_huge()
+    }
+
+    fn is_free(&self, frame: usize, order: usize) -> bool {
+        frame < self.frames() && self.lower.is_free(frame, order)
+    }
+
+    fn free_at(&self, frame: usize, order: usize) -> usize {
+        if order == TREE_FRAMES {
+            self.lower.free_in_tree(frame).0
+        } else {
+            self.lower.free_at(frame, order)
+        }
+    }
+
+    #[cold]
+    fn count_allocatable_spans(&self, buf: &mut [usize]) {
+        let mut blocks = [0; MAX_ORDER + 1];
+        self.lower.free_orders(&mut blocks);
+        spans_of_blocks(&blocks, buf);
+    }
+
+    fn validate(&self) {
+        warn!("validate");
+        assert!(self.lower.check().is_ok());
+        assert!(self.free_frames() <= self.frames());
+    }
+}
+
+impl fmt::Debug for Single<'_> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let huge = self.frames() / (1 << HUGE_ORDER);
+        let free = self.free_frames();
+        let free_huge = self.free_huge();
+
+        f.debug_struct(Self::name())
+            .field(
+                "managed",
+                &FmtFn(|f| write!(f, "{} frames ({huge} huge)", self.frames())),
+            )
+            .field(
+                "free",
+                &FmtFn(|f| write!(f, "{free} frames ({free_huge} huge)")),
+            )
+            .field("cursor", &self.cursor.load(Relaxed))
+            .finish()
+    }
+}
+
+#[cfg(all(test, feature = "std"))]
+mod test {
+    use core::mem::size_of;
+    use std::vec::Vec;
+
+    use super::Single;
+    use crate::test::TestAlloc;
+    use crate::{Alloc, AllocSnapshot, Error, Init, MetaData, TREE_FRAMES};
+
+    type Allocator = TestAlloc<Single<'static>>;
+
+    #[test]
+    fn single_core_only() {
+        assert_eq!(
+            Allocator::create(2, TREE_FRAMES, Init::FreeAll).err(),
+            Some(Error::Initialization)
+        );
+        // Only the lower allocator has metadata
+        let m = Single::metadata_size(1, TREE_FRAMES);
+        assert_eq!((m.local, m.trees), (0, 0));
+        // No per-core state, only the references to the metadata
+        assert!(size_of::<Single>() <= 128, "{}", size_of::<Single>());
+    }
+
+    #[test]
+    fn snapshot_unsupported() {
+        let alloc = Allocator::create(1, TREE_FRAMES, Init::FreeAll).unwrap();
+        assert!(alloc.snapshot().is_none());
+
+        let meta = MetaData::alloc(Single::metadata_size(1, TREE_FRAMES));
+        let snapshot = AllocSnapshot(Vec::new());
+        assert_eq!(
+            Single::restore(snapshot, TREE_FRAMES, meta).err(),
+            Some(Error::Initialization)
+        );
+    }
+}
@@ -0,0 +1,481 @@
+//! Slab allocator for objects smaller than a frame
+//!
+//! Every slab is a base frame of the underlying allocator, which is carved
+//! into equally sized objects.
+//! The header at the start of the slab tracks the allocated objects in a bitmap.
+//! Partially used slabs are kept in per-core lists and entirely free slabs
+//! are returned to the underlying allocator.
+//!
+//! Like [AllocHandle::as_ptr](crate::frame::AllocHandle::as_ptr),
+//! this assumes that frame numbers are identity mapped.
+
+use core::marker::PhantomData;
+use core::mem::size_of;
+use core::ptr::{self, null_mut};
+use core::slice;
+use core::sync::atomic::AtomicUsize;
+use core::sync::atomic::Ordering::Relaxed;
+
+use log::{error, warn};
+use spin::mutex::SpinMutex;
+
+use crate::frame::Frame;
+use crate::util::{align_down, Align};
+use crate::{Alloc, Error, Flags, Result, MAX_ORDER};
+
+/// Header at the start of every slab
+#[repr(C)]
+struct Slab {
+    /// Previous slab in the partial list of the owner
+    prev: *mut Slab,
+    /// Next slab in the partial list of the owner
+    next: *mut Slab,
+    /// Core whose partial list contains this slab, never changes
+    owner: usize,
+    /// Number of allocated objects
+    used: usize,
+    /// Allocated objects, including the slots covered by the header
+    bitmap: [u64; Slab::WORDS],
+}
+
+impl Slab {
+    /// Size of the bitmap, which limits the number of objects per slab
+    const WORDS: usize = 4;
+
+    /// Allocate the first free object, returning its index
+    fn take(&mut self) -> Option<usize> {
+        for (i, word) in self.bitmap.iter_mut().enumerate() {
+            if *word != u64::MAX {
+                let bit = word.trailing_ones() as usize;
+                *word |= 1 << bit;
+                self.used += 1;
+                return Some(i * u64::BITS as usize + bit);
+            }
+        }
+        None
+    }
+    /// Free the object `i`, returning false if it was not allocated
+    fn release(&mut self, i: usize) -> bool {
+        let (word, bit) = (i / u64::BITS as usize, i % u64::BITS as usize);
+        if self.bitmap[word] & (1 << bit) == 0 {
+            return false;
+        }
+        self.bitmap[word] &= !(1 << bit);
+        self.used -= 1;
+        true
+    }
+}
+
+/// Per-core list of partially used slabs
+///
+/// The links of the last slab and of slabs that are not in the list are null.
+/// Only the head distinguishes the sole slab of a list from a detached one.
+struct Partial {
+    head: *mut Slab,
+}
+
+impl Partial {
+    /// Returns if the `slab` is in this list, walking it (for debug assertions)
+    fn contains(&self, slab: &Slab) -> bool {
+        let mut next = self.head;
+        while !next.is_null() {
+            if ptr::eq(next, slab) {
+                return true;
+            }
+            next = unsafe { (*next).next };
+        }
+        false
+    }
+    fn push(&mut self, slab: &mut Slab) {
+        debug_assert!(!self.contains(slab), "slab is already in the list");
+        slab.prev = null_mut();
+        slab.next = self.head;
+        if let Some(head) = unsafe { self.head.as_mut() } {
+            head.prev = slab;
+        }
+        self.head = slab;
+    }
+    fn remove(&mut self, slab: &mut Slab) {
+        // Otherwise, the null links would drop the whole list
+        debug_assert!(self.contains(slab), "slab is not in the list");
+        match unsafe { slab.prev.as_mut() } {
+            Some(prev) => prev.next = slab.next,
+            None => self.head = slab.next,
+        }
+        if let Some(next) = unsafe { slab.next.as_mut() } {
+            next.prev = slab.prev;
+        }
+        slab.prev = null_mut();
+        slab.next = null_mut();
+    }
+}
+
+/// Statistics of a [SlabCache]
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct SlabStats {
+    /// Size of the objects in bytes
+    pub obj_size: usize,
+    /// Number of objects per slab
+    pub slab_objs: usize,
+    /// Number of frames used as slabs
+    pub slabs: usize,
+    /// Number of allocated objects
+    pub objects: usize,
+}
+
+/// Allocates objects of a fixed size from slabs of the underlying allocator.
+pub struct SlabCache<'b, 'a, A: Alloc<'a>> {
+    alloc: &'b A,
+    /// Size of the objects, a power of two
+    obj_size: usize,
+    /// Per-core partial lists, stored in frames of the allocator
+    local: &'b [Align<SpinMutex<Partial>>],
+    /// Order of the frames that store the partial lists
+    local_order: usize,
+    /// Number of slabs
+    slabs: AtomicUsize,
+    /// Number of allocated objects
+    objects: AtomicUsize,
+    _p: PhantomData<&'a ()>,
+}
+
+unsafe impl<'a, A: Alloc<'a> + Sync> Send for SlabCache<'_, 'a, A> {}
+unsafe impl<'a, A: Alloc<'a> + Sync> Sync for SlabCache<'_, 'a, A> {}
+
+impl<'b, 'a, A: Alloc<'a>> SlabCache<'b, 'a, A> {
+    /// Smallest object size
+    pub const MIN_SIZE: usize = Frame::SIZE / (Slab::WORDS * u64::BITS as usize);
+    /// Largest object size, so that every slab has at least three objects
+    pub const MAX_SIZE: usize = Frame::SIZE / 4;
+
+    /// Number of slots covered by the slab header
+    fn header_slots(&self) -> usize {
+        size_of::<Slab>().div_ceil(self.obj_size)
+    }
+    /// Number of objects per slab
+    fn slab_objs(&self) -> usize {
+        Frame::SIZE / self.obj_size - self.header_slots()
+    }
+
+    pub fn stats(&self) -> SlabStats {
+        SlabStats {
+            obj_size: self.obj_size,
+            slab_objs: self.slab_objs(),
+            slabs: self.slabs.load(Relaxed),
+            objects: self.objects.load(Relaxed),
+        }
+    }
+
+    /// Create a cache for objects of `obj_size` bytes for the given number of `cores`.
+    ///
+    /// The size is rounded up to a power of two, which is also the alignment of the objects.
+    pub fn new(alloc: &'b A, cores: usize, obj_size: usize) -> Result<Self> {
+        let obj_size = obj_size.max(Self::MIN_SIZE).next_power_of_two();
+        if obj_size > Self::MAX_SIZE {
+            error!("object size {obj_size} > {}", Self::MAX_SIZE);
+            return Err(Error::Initialization);
+        }
+        // Allocate the partial lists from the allocator itself
+        let cores = cores.max(1);
+        let frames = (cores * size_of::<Align<SpinMutex<Partial>>>()).div_ceil(Frame::SIZE);
+        let local_order = frames.next_power_of_two().ilog2() as usize;
+        if local_order > MAX_ORDER {
+            error!("too many cores {cores}");
+            return Err(Error::Initialization);
+        }
+        let frame = alloc.get(0, Flags::o(local_order))?;
+        let local = unsafe {
+            let local: *mut Align<SpinMutex<Partial>> =
+                Frame::from_addr(frame * Frame::SIZE).cast();
+            for i in 0..cores {
+                local
+                    .add(i)
+                    .write(Align(SpinMutex::new(Partial { head: null_mut() })));
+            }
+            slice::from_raw_parts(local, cores)
+        };
+        Ok(Self {
+            alloc,
+            obj_size,
+            local,
+            local_order,
+            slabs: AtomicUsize::new(0),
+            objects: AtomicUsize::new(0),
+            _p: PhantomData,
+        })
+    }
+
+    /// Allocate an object, preferring the partial slabs of the `core`.
+    pub fn alloc_obj(&self, core: usize) -> Result<*mut u8> {
+        let owner = core % self.local.len();
+        let mut partial = self.local[owner].lock();
+
+        let slab = match unsafe { partial.head.as_mut() } {
+            Some(slab) => slab,
+            None => {
+                let slab = self.new_slab(core, owner)?;
+                partial.push(slab);
+                slab
+            }
+        };
+        let Some(i) = slab.take() else {
+            unreachable!("full slab in partial list")
+        };
+        if slab.used == self.slab_objs() {
+            partial.remove(slab);
+        }
+        self.objects.fetch_add(1, Relaxed);
+        Ok((slab as *mut Slab as usize + i * self.obj_size) as *mut u8)
+    }
+
+    /// Free an object, returning its slab to the allocator if it is entirely free.
+    ///
+    /// Misaligned pointers and double frees are detected.
+    ///
+    /// # Safety
+    /// `ptr` has to point into an object allocated by [SlabCache::alloc_obj]
+    /// of this cache, as the header of its slab is read and modified.
+    pub unsafe fn free_obj(&self, core: usize, ptr: *mut u8) -> Result<()> {
+        let addr = ptr as usize;
+        let slab = align_down(addr, Frame::SIZE) as *mut Slab;
+        let i = (addr % Frame::SIZE) / self.obj_size;
+        if addr & (self.obj_size - 1) != 0 || i < self.header_slots() {
+            error!("invalid object {addr:x}");
+            return Err(Error::Address);
+        }
+        // The owner is immutable as long as the slab has allocated objects
+        let owner = unsafe { (*slab).owner };
+        if owner >= self.local.len() {
+            error!("invalid slab {:x}", slab as usize);
+            return Err(Error::Address);
+        }
+        let mut partial = self.local[owner].lock();
+        let slab = unsafe { &mut *slab };
+        if !slab.release(i) {
+            error!("double free {addr:x}");
+            return Err(Error::Address);
+        }
+        self.objects.fetch_sub(1, Relaxed);
+
+        let was_full = slab.used + 1 == self.slab_objs();
+        if slab.used == 0 {
+            if !was_full {
+                partial.remove(slab);
+            }
+            drop(partial);
+            self.slabs.fetch_sub(1, Relaxed);
+            let frame = slab as *mut Slab as usize / Frame::SIZE;
+            self.alloc.put(core, frame, Flags::o(0))
+        } else {
+            if was_full {
+                partial.push(slab);
+            }
+            Ok(())
+        }
+    }
+
+    /// Allocate and initialize a new slab
+    fn new_slab(&self, core: usize, owner: usize) -> Result<&'b mut Slab> {
+        let frame = self.alloc.get(core, Flags::o(0))?;
+        let slab: *mut Slab = Frame::from_addr(frame * Frame::SIZE).cast();
+        // Mark the header and the slots beyond the slab as allocated
+        let mut bitmap = [u64::MAX; Slab::WORDS];
+        let slots = self.header_slots()..Frame::SIZE / self.obj_size;
+        for i in slots {
+            bitmap[i / u64::BITS as usize] &= !(1 << (i % u64::BITS as usize));
+        }
+        unsafe {
+            slab.write(Slab {
+                prev: null_mut(),
+                next: null_mut(),
+                owner,
+                used: 0,
+                bitmap,
+            })
+        };
+        self.slabs.fetch_add(1, Relaxed);
+        Ok(unsafe { &mut *slab })
+    }
+}
+
+impl<'a, A: Alloc<'a>> Drop for SlabCache<'_, 'a, A> {
+    fn drop(&mut self) {
+        let objects = self.objects.load(Relaxed);
+        if objects > 0 {
+            warn!(
+                "leaking {objects} objects in {} slabs",
+                self.slabs.load(Relaxed)
+            );
+        }
+        let frame = self.local.as_ptr() as usize / Frame::SIZE;
+        if let Err(e) = self.alloc.put(0, frame, Flags::o(self.local_order)) {
+            error!("free of slab cache failed: {e:?}");
+        }
+    }
+}
+
+#[cfg(all(test, feature = "std"))]
+mod test {
+    use core::mem::take;
+    use core::ptr::null_mut;
+    use std::boxed::Box;
+    use std::sync::{Barrier, Mutex};
+    use std::vec::Vec;
+
+    use super::{Partial, Slab, SlabCache};
+    use crate::frame::Frame;
+    use crate::util::logging;
+    use crate::wrapper::ZoneAlloc;
+    use crate::{mmap, thread, Alloc, Error, Init, LLFree, MetaData, TREE_FRAMES};
+
+    type Zone = ZoneAlloc<'static, LLFree<'static>>;
+
+    /// Zone allocator with identity mapped frame numbers
+    fn zone(begin: usize, cores: usize) -> Zone {
+        let zone = Box::leak(mmap::anon::<Frame>(begin, 4 * TREE_FRAMES, false, false));
+        let offset = zone.as_ptr() as usize / Frame::SIZE;
+        let meta = MetaData::alloc(Zone::metadata_size(cores, zone.len()));
+        Zone::create(cores, offset, zone.len(), Init::FreeAll, meta).unwrap()
+    }
+
+    #[test]
+    #[cfg(debug_assertions)]
+    #[should_panic(expected = "slab is not in the list")]
+    fn remove_detached() {
+        let slab = || Slab {
+            prev: null_mut(),
+            next: null_mut(),
+            owner: 0,
+            used: 1,
+            bitmap: [0; Slab::WORDS],
+        };
+        let (mut listed, mut detached) = (slab(), slab());
+        let mut partial = Partial { head: null_mut() };
+        partial.push(&mut listed);
+        // Same links as the listed slab, but not the head
+        partial.remove(&mut detached);
+    }
+
+    #[test]
+    #[cfg(debug_assertions)]
+    #[should_panic(expected = "slab is not in the list")]
+    fn remove_foreign() {
+        let slab = || Slab {
+            prev: null_mut(),
+            next: null_mut(),
+            owner: 0,
+            used: 1,
+            bitmap: [0; Slab::WORDS],
+        };
+        let (mut a, mut b, mut c) = (slab(), slab(), slab());
+        let mut partial = Partial { head: null_mut() };
+        partial.push(&mut a);
+        let mut other = Partial { head: null_mut() };
+        other.push(&mut b);
+        other.push(&mut c);
+        // Linked, but in the other list
+        partial.remove(&mut b);
+    }
+
+    #[test]
+    fn alignment() {
+        logging();
+        let alloc = zone(0x1500_0000_0000, 1);
+
+        for size in [1, 16, 64, 100, 512, 1024] {
+            let slab = SlabCache::new(&alloc, 1, size).unwrap();
+            let obj_size = slab.stats().obj_size;
+            assert!(obj_size >= size && obj_size.is_power_of_two());
+
+            let mut objs = Vec::new();
+            for i in 0..3 * slab.stats().slab_objs {
+                let obj = slab.alloc_obj(0).unwrap();
+                assert!(obj as usize % obj_size == 0, "{obj:?} {obj_size}");
+                unsafe { obj.write_bytes(i as u8, obj_size) };
+                objs.push(obj);
+            }
+            // No overlapping objects
+            for (i, obj) in objs.iter().enumerate() {
+                let data = unsafe { core::slice::from_raw_parts(*obj, obj_size) };
+                assert!(data.iter().all(|b| *b == i as u8));
+            }
+            assert_eq!(slab.stats().slabs, 3);
+
+            for obj in objs {
+                unsafe { slab.free_obj(0, obj) }.unwrap();
+            }
+        }
+        assert!(SlabCache::new(&alloc, 1, 2048).is_err());
+    }
+
+    #[test]
+    fn reclaim() {
+        logging();
+        let alloc = zone(0x1600_0000_0000, 1);
+        let slab = SlabCache::new(&alloc, 1, 64).unwrap();
+        let base = alloc.allocated_frames();
+
+        let n = 10 * slab.stats().slab_objs + 1;
+        let mut objs: Vec<_> = (0..n).map(|_| slab.alloc_obj(0).unwrap()).collect();
+        assert_eq!(slab.stats().slabs, 11);
+        assert_eq!(slab.stats().objects, n);
+        assert_eq!(alloc.allocated_frames(), base + 11);
+
+        // Invalid and double frees
+        assert_eq!(
+            unsafe { slab.free_obj(0, objs[0].wrapping_add(1)) },
+            Err(Error::Address)
+        );
+        let obj = objs.pop().unwrap();
+        unsafe { slab.free_obj(0, obj) }.unwrap();
+        assert_eq!(unsafe { slab.free_obj(0, obj) }, Err(Error::Address));
+        // The last slab is empty again
+        assert_eq!(alloc.allocated_frames(), base + 10);
+
+        for obj in objs {
+            unsafe { slab.free_obj(0, obj) }.unwrap();
+        }
+        assert_eq!(slab.stats().slabs, 0);
+        assert_eq!(slab.stats().objects, 0);
+        assert_eq!(alloc.allocated_frames(), base);
+
+        drop(slab);
+        assert_eq!(alloc.allocated_frames(), 0);
+    }
+
+    #[test]
+    fn cross_core() {
+        logging();
+        const THREADS: usize = 4;
+        const ALLOCS: usize = 1000;
+
+        let alloc = zone(0x1700_0000_0000, THREADS);
+        let slab = SlabCache::new(&alloc, THREADS, 128).unwrap();
+
+        let objs = Mutex::new(vec![Vec::new(); THREADS]);
+        let barrier = Barrier::new(THREADS);
+        thread::parallel(0..THREADS, |t| {
+            thread::try_pin(t);
+            let mine: Vec<_> = (0..ALLOCS)
+                .map(|_| slab.alloc_obj(t).unwrap() as usize)
+                .collect();
+            objs.lock().unwrap()[t] = mine;
+            barrier.wait();
+
+            // Free the objects of another core
+            let other = take(&mut objs.lock().unwrap()[(t + 1) % THREADS]);
+            for obj in other {
+                unsafe { slab.free_obj(t, obj as *mut u8) }.unwrap();
+            }
+            for _ in 0..ALLOCS {
+                let obj = slab.alloc_obj(t).unwrap();
+                unsafe { slab.free_obj(t, obj) }.unwrap();
+            }
+        });
+        assert_eq!(slab.stats().objects, 0);
+        assert_eq!(slab.stats().slabs, 0);
+        drop(slab);
+        assert_eq!(alloc.allocated_frames(), 0);
+        alloc.validate();
+    }
+}
@@ -21,40 +21,97 @@ pub fn cores() -> usize {
     cores
 }
 
+/// Returns the number of virtual cores.
+#[cfg(not(any(target_os = "macos", target_os = "linux")))]
+pub fn cores() -> usize {
+    std::thread::available_parallelism().map_or(1, |n| n.get())
+}
+
+/// Returns the number of physical cores, counting SMT siblings only once.
+pub fn core_count() -> usize {
+    (0..cores())
+        .filter(|&core| smt_siblings(core).first() == Some(&core))
+        .count()
+}
+
+/// Returns the virtual cores that share a physical core with `core`, including `core` itself.
+///
+/// This is only supported on Linux, other platforms assume no SMT.
+pub fn smt_siblings(core: usize) -> std::vec::Vec<usize> {
+    #[cfg(target_os = "linux")]
+    {
+        let path = format!("/sys/devices/system/cpu/cpu{core}/topology/thread_siblings_list");
+        if let Ok(list) = std::fs::read_to_string(path)
+            && let Some(siblings) = parse_cpu_list(&list)
+            && siblings.contains(&core)
+        {
+            return siblings;
+        }
+    }
+    vec![core]
+}
+
+/// Parses a sorted cpu list like `0-3,8,10-11`.
+#[cfg(target_os = "linux")]
+fn parse_cpu_list(list: &str) -> Option<std::vec::Vec<usize>> {
+    let mut cores = std::vec::Vec::new();
+    for range in list.trim().split(',').filter(|r| !r.is_empty()) {
+        let (start, end) = range.split_once('-').unwrap_or((range, range));
+        cores.extend(start.parse::<usize>().ok()?..=end.parse().ok()?);
+    }
+    Some(cores)
+}
+
 /// Returns the core on which we are pinned or [usize::MAX]
 pub fn pinned() -> Option<usize> {
     let p = PINNED.with(|p| p.load(Ordering::Acquire));
     (p != usize::MAX).then_some(p)
 }
 
-/// Pins the current thread to the given virtual core
-#[cfg(target_os = "linux")]
+/// Pins the current thread to the given virtual core.
+///
+/// Panics if the core is not available, see [try_pin].
 pub fn pin(core: usize) {
-    use core::mem::{size_of, zeroed};
+    if try_pin(core).is_none() {
+        panic!("pinning to {core} failed, cores: {}", cores());
+    }
+}
 
+/// Tries to pin the current thread to the given virtual core.
+///
+/// Returns `None` without changing the affinity if `core` is not below [cores]
+/// or the affinity could not be set.
+pub fn try_pin(core: usize) -> Option<()> {
     let max = cores();
-    assert!(core < max, "not enough cores {core} < {max}");
+    if core >= max {
+        return None;
+    }
 
     let core = core * STRIDE.load(Ordering::Relaxed);
-    let core = (core / max) + (core % max); // wrap around
-
-    let mut set = unsafe { zeroed::<libc::cpu_set_t>() };
-    unsafe { libc::CPU_SET(core, &mut set) };
-    let ret = unsafe { libc::sched_setaffinity(0, size_of::<libc::cpu_set_t>(), &set) };
-    if ret != 0 {
-        unsafe { libc::perror(b"sched_setaffinity\0" as *const _ as _) };
-        panic!("sched_setaffinity failed");
-    }
+    let core = ((core / max) + (core % max)) % max; // wrap around
+    set_affinity(core)?;
 
     PINNED.with(|p| {
         p.store(core, Ordering::Release);
     });
+    Some(())
 }
 
-/// Pins the current thread to the given virtual core
+/// Restricts the current thread to the given virtual core
+#[cfg(target_os = "linux")]
+fn set_affinity(core: usize) -> Option<()> {
+    use core::mem::{size_of, zeroed};
+
+    let mut set = unsafe { zeroed::<libc::cpu_set_t>() };
+    unsafe { libc::CPU_SET(core, &mut set) };
+    let ret = unsafe { libc::sched_setaffinity(0, size_of::<libc::cpu_set_t>(), &set) };
+    (ret == 0).then_some(())
+}
+
+/// Restricts the current thread to the given virtual core
 #[cfg(target_os = "macos")]
 #[allow(non_camel_case_types)]
-pub fn pin(core: usize) {
+fn set_affinity(core: usize) -> Option<()> {
     use std::mem::size_of;
     use std::os::raw::{c_int, c_uint};
 
@@ -82,12 +139,6 @@ pub fn pin(core: usize) {
         ) -> kern_return_t;
     }
 
-    let max = cores();
-    assert!(core < max as usize, "not enough cores");
-
-    let core = core * STRIDE.load(Ordering::Relaxed);
-    let core = (core / max as usize) + (core % max as usize); // wrap around
-
     let thread_affinity_policy_count: mach_msg_type_number_t =
         size_of::<thread_affinity_policy_data_t>() as mach_msg_type_number_t
             / size_of::<c_int>() as mach_msg_type_number_t;
@@ -96,18 +147,41 @@ pub fn pin(core: usize) {
         affinity_tag: core as c_int,
     };
 
-    unsafe {
+    let ret = unsafe {
         thread_policy_set(
             libc::pthread_self() as thread_t,
             THREAD_AFFINITY_POLICY,
             &mut info as thread_policy_t,
             thread_affinity_policy_count,
-        );
+        )
+    };
+    (ret == 0).then_some(())
+}
+
+/// Restricts the current thread to the given virtual core
+#[cfg(target_os = "windows")]
+fn set_affinity(core: usize) -> Option<()> {
+    use core::ffi::c_void;
+
+    #[link(name = "kernel32")]
+    extern "system" {
+        fn GetCurrentThread() -> *mut c_void;
+        fn SetThreadAffinityMask(thread: *mut c_void, mask: usize) -> usize;
     }
 
-    PINNED.with(|p| {
-        p.store(core, Ordering::SeqCst);
-    });
+    // Processor groups are not supported
+    if core >= usize::BITS as usize {
+        return None;
+    }
+    let ret = unsafe { SetThreadAffinityMask(GetCurrentThread(), 1 << core) };
+    (ret != 0).then_some(())
+}
+
+/// Pinning is not supported, only remembers the core
+#[cfg(not(any(target_os = "linux", target_os = "macos", target_os = "windows")))]
+fn set_affinity(_core: usize) -> Option<()> {
+    log::warn!("pinning is not supported on this platform");
+    Some(())
 }
 
 /// Executed `f` in parallel for each element in `iter`.
@@ -149,6 +223,19 @@ mod test {
         println!("Pinned to {}", super::pinned().unwrap());
     }
 
+    #[test]
+    fn try_pin() {
+        let cores = super::cores();
+        assert_eq!(super::try_pin(cores - 1), Some(()));
+        let pinned = super::pinned();
+        assert!(pinned.is_some());
+
+        // Unavailable cores do not change the affinity
+        assert_eq!(super::try_pin(cores), None);
+        assert_eq!(super::try_pin(usize::MAX / 2), None);
+        assert_eq!(super::pinned(), pinned);
+    }
+
     #[test]
     fn stride() {
         let old = STRIDE.swap(2, Ordering::Relaxed);
@@ -165,6 +252,43 @@ mod test {
         STRIDE.store(old, Ordering::Relaxed);
     }
 
+    #[cfg(target_os = "linux")]
+    #[test]
+    fn affinity() {
+        use core::mem::{size_of, zeroed};
+
+        for core in 0..super::cores() {
+            super::pin(core);
+            let pinned = super::pinned().unwrap();
+
+            let mut set = unsafe { zeroed::<libc::cpu_set_t>() };
+            let ret = unsafe { libc::sched_getaffinity(0, size_of::<libc::cpu_set_t>(), &mut set) };
+            assert_eq!(ret, 0);
+            assert_eq!(unsafe { libc::CPU_COUNT(&set) }, 1);
+            assert!(unsafe { libc::CPU_ISSET(pinned, &set) });
+        }
+    }
+
+    #[test]
+    fn topology() {
+        let cores = super::cores();
+        let physical = super::core_count();
+        println!("cores {cores} physical {physical}");
+        assert!(0 < physical && physical <= cores);
+
+        for core in 0..cores {
+            let siblings = super::smt_siblings(core);
+            println!("siblings {core}: {siblings:?}");
+            assert!(siblings.contains(&core));
+        }
+
+        #[cfg(target_os = "linux")]
+        {
+            assert_eq!(super::parse_cpu_list("0-2,8\n"), Some(vec![0, 1, 2, 8]));
+            assert_eq!(super::parse_cpu_list("x"), None);
+        }
+    }
+
     #[cfg(any(target_os = "linux", target_os = "macos"))]
     #[test]
     fn cores() {
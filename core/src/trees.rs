@@ -1,19 +1,43 @@
 use core::mem::{align_of, size_of};
-use core::ops::{RangeBounds, RangeInclusive};
-use core::sync::atomic::AtomicU32;
-use core::{fmt, slice};
+use core::ops::{Range, RangeBounds, RangeInclusive};
+use core::sync::atomic::Ordering::Relaxed;
+use core::sync::atomic::{AtomicBool, AtomicU32, AtomicUsize};
+use core::{fmt, iter, slice};
 
 use bitfield_struct::bitfield;
+use log::error;
 
 use crate::atomic::{Atom, Atomic};
 use crate::local::LocalTree;
-use crate::util::{align_down, size_of_slice, Align};
+use crate::util::{align_down, align_up, size_of_slice, Align};
 use crate::{Error, Flags, Result, HUGE_FRAMES, HUGE_ORDER, TREE_FRAMES, TREE_HUGE};
 
+/// Locality bias for [Trees::reserve_near], e.g., towards the memory of the local NUMA node
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct NumaHint {
+    /// Index of the tree the search is biased towards
+    pub preferred_start: usize,
+    /// Cost per tree of distance to the preferred start, 0 on non-NUMA systems
+    pub penalty_per_step: u32,
+}
+
 #[derive(Default)]
 pub struct Trees<'a> {
     /// Array of level 3 entries, which are the roots of the trees
     pub entries: &'a [Atom<Tree>],
+    /// Number of entries searched around the start, adapted by [Trees::reserve]
+    near: AtomicUsize,
+    /// Set if a counter saturated, meaning that the metadata is corrupted
+    corrupted: AtomicBool,
+    /// Number of frames, the last tree might cover less than [TREE_FRAMES]
+    frames: usize,
+    /// Sum of the free counters of all entries, see [Trees::peak_allocated]
+    global_free: AtomicUsize,
+    /// Maximum number of frames that were not free in the global counters
+    peak: AtomicUsize,
+    /// Number of entry reads by [Trees::get] and [Trees::update]
+    #[cfg(feature = "search_stats")]
+    reads: AtomicUsize,
 }
 
 impl<'a> fmt::Debug for Trees<'a> {
@@ -36,11 +60,15 @@ impl<'a> fmt::Debug for Trees<'a> {
 
 impl<'a> Trees<'a> {
     pub const MIN_FREE: usize = TREE_FRAMES / 16;
+    /// Number of entries in a cacheline
+    const CACHELINE: usize = align_of::<Align>() / size_of::<Tree>();
 
-    pub fn metadata_size(frames: usize) -> usize {
+    pub const fn metadata_size(frames: usize) -> usize {
         // Event thought the elements are not cache aligned, the whole array should be
-        size_of_slice::<Atom<Tree>>(frames.div_ceil(TREE_FRAMES))
-            .next_multiple_of(align_of::<Align>())
+        align_up(
+            size_of_slice::<Atom<Tree>>(frames.div_ceil(TREE_FRAMES)),
+            align_of::<Align>(),
+        )
     }
 
     pub fn metadata(&mut self) -> &'a mut [u8] {
@@ -65,7 +93,27 @@ impl<'a> Trees<'a> {
             *e = Atom::new(Tree::with(frames, huge, false, Kind::Fixed));
         }
 
-        Self { entries }
+        let trees = Self {
+            entries,
+            near: AtomicUsize::new(0),
+            corrupted: AtomicBool::new(false),
+            frames,
+            global_free: AtomicUsize::new(0),
+            peak: AtomicUsize::new(0),
+            #[cfg(feature = "search_stats")]
+            reads: AtomicUsize::new(0),
+        };
+        trees.recount();
+        trees
+    }
+
+    /// Recompute the sum of the free counters after the entries have been overwritten.
+    ///
+    /// This also resets the peak, and must not race with other updates.
+    #[cold]
+    pub fn recount(&self) {
+        self.global_free.store(self.free_frames(), Relaxed);
+        self.reset_peak();
     }
 
     pub fn len(&self) -> usize {
@@ -73,8 +121,20 @@ impl<'a> Trees<'a> {
     }
 
     pub fn get(&self, i: usize) -> Tree {
+        #[cfg(feature = "search_stats")]
+        self.reads.fetch_add(1, Relaxed);
         self.entries[i].load()
     }
+    /// Returns the number of entry reads, e.g., by searches for a tree to reserve
+    #[cfg(feature = "search_stats")]
+    pub fn reads(&self) -> usize {
+        self.reads.load(Relaxed)
+    }
+    /// Overwrite the entry `i`, e.g., when restoring or manipulating the metadata
+    pub fn store(&self, i: usize, tree: Tree) {
+        let old = self.entries[i].swap(tree);
+        self.account(old.free(), tree.free());
+    }
 
     /// Return the number of entirely free trees
     pub fn free(&self) -> usize {
@@ -87,14 +147,101 @@ impl<'a> Trees<'a> {
     pub fn free_frames(&self) -> usize {
         self.entries.iter().map(|e| e.load().free()).sum()
     }
+    /// Return the running sum of the tree counters, without loading the entries.
+    ///
+    /// Concurrent updates might not be accounted for yet.
+    pub fn free_frames_cached(&self) -> usize {
+        let free = self.global_free.load(Relaxed);
+        // Temporarily underflowed, see [Trees::account]
+        if free > self.frames {
+            0
+        } else {
+            free
+        }
+    }
     /// Return the total sum of the huge counters
     pub fn free_huge(&self) -> usize {
         self.entries.iter().map(|e| e.load().huge()).sum()
     }
-    /// Sync with the global tree, stealing its counters
-    pub fn sync(&self, i: usize, min: usize, min_huge: usize) -> Option<Tree> {
-        self.entries[i]
-            .fetch_update(|e| e.sync_steal(min, min_huge))
+    /// Returns the maximum free and huge counters of the entry `i`.
+    ///
+    /// These are smaller for a last tree that covers less than [TREE_FRAMES] frames.
+    pub fn max(&self, i: usize) -> (usize, usize) {
+        let free = self.frames.saturating_sub(i * TREE_FRAMES).min(TREE_FRAMES);
+        (free, free / HUGE_FRAMES)
+    }
+    /// Returns the highest number of frames that were not free in the global counters.
+    ///
+    /// This includes the frames in the local copies of reserved trees,
+    /// so it is only an upper bound of the actually allocated frames.
+    /// It is updated whenever the global counters decrease, which only happens
+    /// on the slow paths, when trees are reserved or synchronized.
+    pub fn peak_allocated(&self) -> usize {
+        self.peak.load(Relaxed)
+    }
+    /// Reset the peak to the current number of frames not free in the global counters
+    pub fn reset_peak(&self) {
+        let free = self.global_free.load(Relaxed);
+        self.peak.store(self.frames.saturating_sub(free), Relaxed);
+    }
+    /// Account for a change of the free counter of an entry from `old` to `new`
+    fn account(&self, old: usize, new: usize) {
+        // The sum might temporarily underflow if a concurrent increment is not yet added,
+        // thus wrap around and saturate the allocated frames
+        if new > old {
+            self.global_free.fetch_add(new - old, Relaxed);
+        } else if new < old {
+            let sub = old - new;
+            let free = self.global_free.fetch_sub(sub, Relaxed).wrapping_sub(sub);
+            let allocated = self.frames.saturating_sub(free);
+            self.peak.fetch_max(allocated, Relaxed);
+        }
+    }
+    /// Returns whether a counter saturated, meaning that the metadata is corrupted
+    pub fn corrupted(&self) -> bool {
+        self.corrupted.load(Relaxed)
+    }
+    /// Record a saturated counter of the entry `i`
+    #[cold]
+    fn saturated(&self, i: usize) {
+        error!("saturated counters of tree {i}");
+        self.corrupted.store(true, Relaxed);
+    }
+
+    /// Atomically update the entry `i`, checking its invariants in debug builds
+    #[cfg_attr(feature = "log_debug", track_caller)]
+    fn update(
+        &self,
+        i: usize,
+        mut f: impl FnMut(Tree) -> Option<Tree>,
+    ) -> core::result::Result<Tree, Tree> {
+        #[cfg(feature = "search_stats")]
+        self.reads.fetch_add(1, Relaxed);
+        let max = self.max(i);
+        let mut last = None;
+        let old = self.entries[i].fetch_update(|v| {
+            debug_assert!(v.is_valid(), "invalid {v:?}");
+            last = f(v);
+            if let Some(new) = last {
+                debug_assert!(new.is_valid(), "invalid {new:?}");
+                debug_assert!(
+                    new.inflated() || (new.free() <= max.0 && new.huge() <= max.1),
+                    "exceeds {max:?}: {new:?}"
+                );
+            }
+            last
+        })?;
+        if let Some(new) = last {
+            self.account(old.free(), new.free());
+        }
+        Ok(old)
+    }
+
+    /// Sync with the global tree, stealing its counters.
+    ///
+    /// Fails if the tree has been reserved again since the reservation of `generation`.
+    pub fn sync(&self, i: usize, generation: usize, min: usize, min_huge: usize) -> Option<Tree> {
+        self.update(i, |e| e.sync_steal(generation, min, min_huge))
             .ok()
     }
 
@@ -107,19 +254,30 @@ impl<'a> Trees<'a> {
         may_reserve: bool,
     ) -> Option<Tree> {
         let mut reserved = false;
-        let tree = self.entries[i]
-            .fetch_update(|v| {
-                let v = v.inc(free, huge);
+        let mut saturated = false;
+        let max = self.max(i);
+        let tree = self
+            .update(i, |v| {
+                let (v, s) = v.inc(free, huge, max);
+                saturated = s;
                 if may_reserve && !v.reserved() && v.free() > Self::MIN_FREE {
                     // Reserve the tree that was targeted by the last N frees
                     reserved = true;
-                    Some(v.with_free(0).with_huge(0).with_reserved(true))
+                    Some(
+                        v.with_free(0)
+                            .with_huge(0)
+                            .with_reserved(true)
+                            .with_generation(v.next_generation()),
+                    )
                 } else {
                     reserved = false; // <- This one is very important if CAS fails!
                     Some(v)
                 }
             })
             .unwrap();
+        if saturated {
+            self.saturated(i);
+        }
 
         if reserved {
             Some(tree)
@@ -128,11 +286,65 @@ impl<'a> Trees<'a> {
         }
     }
 
+    /// Decrement the free and huge counters of the entry `i`, if it has enough frames
+    pub fn dec(&self, i: usize, free: usize, huge: usize) -> bool {
+        self.update(i, |v| {
+            let free = v.free().checked_sub(free)?;
+            let huge = v.huge().checked_sub(huge)?;
+            Some(v.with_free(free).with_huge(huge))
+        })
+        .is_ok()
+    }
+
     /// Unreserve an entry, adding the local entry counter to the global one
-    pub fn unreserve(&self, i: usize, free: usize, huge: usize, kind: Kind) {
-        self.entries[i]
-            .fetch_update(|v| v.unreserve_add(free, huge, kind))
-            .expect("Unreserve failed");
+    pub fn unreserve(&self, i: usize, free: usize, huge: usize, generation: usize, kind: Kind) {
+        let mut saturated = false;
+        let max = self.max(i);
+        self.update(i, |v| {
+            let (v, s) = v.unreserve_add(free, huge, kind, generation, max)?;
+            saturated = s;
+            Some(v)
+        })
+        .expect("Unreserve failed");
+        if saturated {
+            self.saturated(i);
+        }
+    }
+
+    /// Iterate over the entry indices around `start`, alternating between before and after it.
+    ///
+    /// `range` selects the positions in this order, wrapping around the array.
+    fn vicinity(&self, start: usize, range: Range<usize>) -> impl Iterator<Item = usize> {
+        let len = self.entries.len();
+        let start = (start % len + len) as isize;
+        range.map(move |i| {
+            let i = i as isize;
+            let off = if i % 2 == 0 { i / 2 } else { -i.div_ceil(2) };
+            (start + off) as usize % len
+        })
+    }
+
+    /// Inflate an entirely free tree, removing it from circulation
+    pub fn inflate(&self, i: usize) -> bool {
+        self.update(i, |v| {
+            (!v.reserved() && v.free() == TREE_FRAMES).then(|| {
+                Tree::with(0, 0, true, Kind::Fixed)
+                    .with_inflated(true)
+                    .with_generation(v.next_generation())
+            })
+        })
+        .is_ok()
+    }
+
+    /// Return an inflated tree to the free pool
+    pub fn deflate(&self, i: usize) -> bool {
+        self.update(i, |v| {
+            v.inflated().then(|| {
+                Tree::with(TREE_FRAMES, TREE_HUGE, false, Kind::Fixed)
+                    .with_generation(v.generation())
+            })
+        })
+        .is_ok()
     }
 
     /// Find and reserve a free tree
@@ -140,8 +352,18 @@ impl<'a> Trees<'a> {
         &self,
         start: usize,
         flags: Flags,
-        offset: usize,
-        len: usize,
+        range: Range<usize>,
+        free: RangeInclusive<usize>,
+        get_lower: impl FnMut(LocalTree, Flags) -> Result<LocalTree>,
+    ) -> Result<LocalTree> {
+        self.reserve_first(self.vicinity(start, range), flags, free, get_lower)
+    }
+
+    /// Reserve the first free tree of the given `indices`
+    fn reserve_first(
+        &self,
+        indices: impl Iterator<Item = usize>,
+        flags: Flags,
         free: RangeInclusive<usize>,
         mut get_lower: impl FnMut(LocalTree, Flags) -> Result<LocalTree>,
     ) -> Result<LocalTree> {
@@ -149,19 +371,17 @@ impl<'a> Trees<'a> {
         let free = (1 << flags.order()).max(*free.start())..=*free.end();
         let min_huge = (1 << flags.order()) / HUGE_FRAMES;
 
-        let start = (start + self.entries.len()) as isize;
-        for i in offset as isize..len as isize {
-            // Alternating between before and after this entry
-            let off = if i % 2 == 0 { i / 2 } else { -i.div_ceil(2) };
-            let i = (start + off) as usize % self.entries.len();
-            if let Ok(entry) =
-                self.entries[i].fetch_update(|v| v.reserve(free.clone(), min_huge, flags.into()))
-            {
-                let tree = LocalTree::with(i * TREE_FRAMES, entry.free(), entry.huge());
+        for i in indices {
+            let max = self.max(i).0;
+            let reserve = |v: Tree| v.reserve(free.clone(), min_huge, flags.into(), max);
+            if let Ok(entry) = self.update(i, reserve) {
+                let generation = entry.next_generation();
+                let tree = LocalTree::with(i * TREE_FRAMES, entry.free(), entry.huge())
+                    .with_generation(generation);
                 match get_lower(tree, flags) {
                     Ok(tree) => return Ok(tree),
                     Err(Error::Memory) => {
-                        self.unreserve(i, entry.free(), entry.huge(), flags.into())
+                        self.unreserve(i, entry.free(), entry.huge(), generation, flags.into())
                     }
                     Err(e) => return Err(e),
                 }
@@ -170,6 +390,24 @@ impl<'a> Trees<'a> {
         Err(Error::Memory)
     }
 
+    /// Returns the current number of near entries, between a cacheline and the per-core share.
+    fn near(&self, cores: usize) -> (usize, Range<usize>) {
+        let bounds = Self::CACHELINE..(self.len() / cores).max(Self::CACHELINE);
+        let near = match self.near.load(Relaxed) {
+            0 => 2 * Self::CACHELINE,
+            near => near,
+        };
+        (near.clamp(bounds.start, bounds.end), bounds)
+    }
+
+    /// Grows the near search if it was successful and shrinks it otherwise,
+    /// as failed searches only add to the scan length.
+    fn adapt_near(&self, near: usize, bounds: Range<usize>, success: bool) {
+        let near = if success { near + near / 4 } else { near / 2 };
+        self.near
+            .store(near.clamp(bounds.start, bounds.end), Relaxed);
+    }
+
     /// Reserves a new tree, prioritizing partially filled trees.
     pub fn reserve(
         &self,
@@ -178,31 +416,166 @@ impl<'a> Trees<'a> {
         flags: Flags,
         get_lower: impl FnMut(LocalTree, Flags) -> Result<LocalTree> + Copy,
     ) -> Result<LocalTree> {
-        const CACHELINE: usize = align_of::<Align>() / size_of::<Tree>();
-        let start = align_down(start, CACHELINE);
+        let start = align_down(start, Self::CACHELINE);
 
         // Search near trees
-        let near = (self.len() / cores / 4).clamp(CACHELINE / 4, CACHELINE * 2);
+        let (near, bounds) = self.near(cores);
+        let far = (2 * near).min(self.len());
 
         // Over half filled trees
         let half = TREE_FRAMES / 16..=TREE_FRAMES / 2;
-        match self.reserve_matching(start, flags, 1, near, half, get_lower) {
+        match self.reserve_matching(start, flags, 0..near, half, get_lower) {
             Err(Error::Memory) => {}
-            r => return r,
+            r => {
+                self.adapt_near(near, bounds, true);
+                return r;
+            }
         }
         // Partially filled trees
         let partial = TREE_FRAMES / 64..=TREE_FRAMES - TREE_FRAMES / 16;
-        match self.reserve_matching(start, flags, 1, 2 * near, partial, get_lower) {
+        match self.reserve_matching(start, flags, 0..far, partial, get_lower) {
             Err(Error::Memory) => {}
-            r => return r,
+            r => {
+                self.adapt_near(near, bounds, true);
+                return r;
+            }
         }
-        // Not free trees
-        match self.reserve_matching(start, flags, 1, self.len(), 0..=TREE_FRAMES - 1, get_lower) {
+        self.adapt_near(near, bounds, false);
+
+        // Not free trees, continuing after the near trees
+        match self.reserve_matching(
+            start,
+            flags,
+            far..self.len(),
+            0..=TREE_FRAMES - 1,
+            get_lower,
+        ) {
             Err(Error::Memory) => {}
             r => return r,
         }
         // Any tree
-        self.reserve_matching(start, flags, 0, self.len(), 0..=TREE_FRAMES, get_lower)
+        self.reserve_matching(start, flags, 0..self.len(), 0..=TREE_FRAMES, get_lower)
+    }
+
+    /// Reserves the tree with the lowest cost, which is its distance to the preferred start
+    /// times the penalty, plus [Trees::CLASS_COST] for each worse class of [Trees::reserve].
+    ///
+    /// Thus, a tree of a better class is chosen, if it is at most
+    /// `CLASS_COST / penalty_per_step` trees further away than one of a worse class.
+    /// Only the trees within `3 * CLASS_COST / penalty_per_step` steps (at least a cacheline)
+    /// of the start are scanned, as the ones further away cost more than any worse class.
+    /// The linear scan stops early at a tree that costs less than [Trees::CLASS_COST].
+    /// If the cheapest tree cannot be reserved, e.g., because of a concurrent reservation,
+    /// or there is none in reach, this falls back to an unbiased search.
+    pub fn reserve_near(
+        &self,
+        hint: NumaHint,
+        flags: Flags,
+        get_lower: impl FnMut(LocalTree, Flags) -> Result<LocalTree> + Copy,
+    ) -> Result<LocalTree> {
+        let start = hint.preferred_start % self.len();
+        let free = (1 << flags.order())..=TREE_FRAMES;
+        let min_huge = (1 << flags.order()) / HUGE_FRAMES;
+
+        let reach = match hint.penalty_per_step as usize {
+            0 => self.len(),
+            penalty => (3 * Self::CLASS_COST)
+                .div_ceil(penalty)
+                .max(Self::CACHELINE),
+        };
+        let window = start.saturating_sub(reach)..(start + reach + 1).min(self.len());
+
+        let mut best: Option<(usize, usize)> = None;
+        for i in window {
+            let tree = self.get(i);
+            let max = self.max(i).0;
+            let Some(_) = tree.reserve(free.clone(), min_huge, flags.into(), max) else {
+                continue;
+            };
+            let cost = Self::class(tree.free()) * Self::CLASS_COST
+                + i.abs_diff(start) * hint.penalty_per_step as usize;
+            if best.is_none_or(|(_, c)| cost < c) {
+                best = Some((i, cost));
+                if cost < Self::CLASS_COST {
+                    break;
+                }
+            }
+        }
+
+        if let Some((i, _)) = best {
+            match self.reserve_first(iter::once(i), flags, free, get_lower) {
+                Err(Error::Memory) => {}
+                r => return r,
+            }
+        }
+        self.reserve_permuted(start, 1, flags, get_lower)
+    }
+
+    /// Cost of a worse class for [Trees::reserve_near]
+    pub const CLASS_COST: usize = 64;
+
+    /// Returns the class of a tree with `free` frames, in the order of [Trees::reserve]
+    fn class(free: usize) -> usize {
+        if (TREE_FRAMES / 16..=TREE_FRAMES / 2).contains(&free) {
+            0
+        } else if (TREE_FRAMES / 64..=TREE_FRAMES - TREE_FRAMES / 16).contains(&free) {
+            1
+        } else if free < TREE_FRAMES {
+            2
+        } else {
+            3
+        }
+    }
+
+    /// Reserves the tree with the lowest index, prioritizing partially filled trees
+    /// like [Trees::reserve].
+    ///
+    /// The result only depends on the tree counters, not on a start or previous searches.
+    pub fn reserve_lowest(
+        &self,
+        flags: Flags,
+        get_lower: impl FnMut(LocalTree, Flags) -> Result<LocalTree> + Copy,
+    ) -> Result<LocalTree> {
+        self.reserve_permuted(0, 1, flags, get_lower)
+    }
+
+    /// Reserves a tree, visiting them in the order `start, start + step, ...`
+    /// and prioritizing partially filled trees like [Trees::reserve].
+    ///
+    /// With a `step` that is coprime to the number of trees, see [Trees::coprime_step],
+    /// every tree is visited once per class, like for [Trees::reserve_lowest].
+    pub fn reserve_permuted(
+        &self,
+        start: usize,
+        step: usize,
+        flags: Flags,
+        get_lower: impl FnMut(LocalTree, Flags) -> Result<LocalTree> + Copy,
+    ) -> Result<LocalTree> {
+        let len = self.len();
+        let classes = [
+            TREE_FRAMES / 16..=TREE_FRAMES / 2,
+            TREE_FRAMES / 64..=TREE_FRAMES - TREE_FRAMES / 16,
+            0..=TREE_FRAMES - 1,
+            0..=TREE_FRAMES,
+        ];
+        for free in classes {
+            let indices = iter::successors(Some(start % len), |i| Some((i + step) % len)).take(len);
+            match self.reserve_first(indices, flags, free, get_lower) {
+                Err(Error::Memory) => {}
+                r => return r,
+            }
+        }
+        Err(Error::Memory)
+    }
+
+    /// Returns a step derived from `rand` that is coprime to the number of trees
+    pub fn coprime_step(&self, rand: u64) -> usize {
+        let len = self.len();
+        let mut step = rand as usize % len;
+        while gcd(step, len) != 1 {
+            step += 1;
+        }
+        step
     }
 
     #[allow(unused)]
@@ -238,10 +611,46 @@ pub struct Tree {
     /// Are the frames movable?
     #[bits(2)]
     pub kind: Kind,
-    #[bits(12)]
+    /// If this subtree is removed from circulation (e.g., by a balloon driver).
+    pub inflated: bool,
+    /// Incremented on every reservation, to detect stale local copies (ABA).
+    #[bits(4)]
+    pub generation: usize,
+    #[bits(7)]
     __: (),
 }
 
+/// Offset of the free frames counter in the tree entries
+#[cfg(feature = "introspect")]
+pub const TREE_FREE_OFFSET: usize = Tree::FREE_OFFSET;
+/// Size of the free frames counter in the tree entries
+#[cfg(feature = "introspect")]
+pub const TREE_FREE_BITS: usize = Tree::FREE_BITS;
+/// Offset of the free huge frames counter in the tree entries
+#[cfg(feature = "introspect")]
+pub const TREE_HUGE_OFFSET: usize = Tree::HUGE_OFFSET;
+/// Size of the free huge frames counter in the tree entries
+#[cfg(feature = "introspect")]
+pub const TREE_HUGE_BITS: usize = Tree::HUGE_BITS;
+/// Offset of the reserved flag in the tree entries
+#[cfg(feature = "introspect")]
+pub const TREE_RESERVED_OFFSET: usize = Tree::RESERVED_OFFSET;
+/// Offset of the kind (huge, movable, fixed) in the tree entries
+#[cfg(feature = "introspect")]
+pub const TREE_KIND_OFFSET: usize = Tree::KIND_OFFSET;
+/// Size of the kind in the tree entries
+#[cfg(feature = "introspect")]
+pub const TREE_KIND_BITS: usize = Tree::KIND_BITS;
+/// Offset of the inflated flag in the tree entries
+#[cfg(feature = "introspect")]
+pub const TREE_INFLATED_OFFSET: usize = Tree::INFLATED_OFFSET;
+/// Offset of the reservation generation in the tree entries
+#[cfg(feature = "introspect")]
+pub const TREE_GENERATION_OFFSET: usize = Tree::GENERATION_OFFSET;
+/// Size of the reservation generation in the tree entries
+#[cfg(feature = "introspect")]
+pub const TREE_GENERATION_BITS: usize = Tree::GENERATION_BITS;
+
 #[derive(Debug, Clone, Copy, PartialEq, Eq)]
 pub enum Kind {
     Huge,
@@ -282,6 +691,8 @@ impl From<Flags> for Kind {
 
 const _: () = assert!(1 << Tree::FREE_BITS >= TREE_FRAMES);
 const _: () = assert!(1 << Tree::HUGE_BITS >= TREE_HUGE);
+const _: () = assert!(1 << Tree::KIND_BITS >= Kind::LEN);
+const _: () = assert!(Trees::MIN_FREE < TREE_FRAMES);
 
 impl Atomic for Tree {
     type I = AtomicU32;
@@ -296,48 +707,460 @@ impl Tree {
             .with_reserved(reserved)
             .with_kind(kind)
     }
-    /// Increments the free frames counter.
-    pub fn inc(self, free: usize, huge: usize) -> Self {
-        let free = self.free() + free;
-        let huge = self.huge() + huge;
-        assert!(free <= TREE_FRAMES && huge <= TREE_HUGE);
-        self.with_free(free).with_huge(huge)
+    /// Number of reservation generations before they wrap around
+    pub const GENERATIONS: usize = 1 << Self::GENERATION_BITS;
+    /// Returns the generation of the next reservation, wrapping around.
+    pub fn next_generation(self) -> usize {
+        (self.generation() + 1) % Self::GENERATIONS
+    }
+    /// Checks the invariants of this entry.
+    pub fn is_valid(self) -> bool {
+        let kind = (u32::from(self) >> Self::KIND_OFFSET) as usize & ((1 << Self::KIND_BITS) - 1);
+        self.free() <= TREE_FRAMES
+            && self.huge() <= TREE_HUGE
+            && kind < Kind::LEN
+            && (!self.inflated() || (self.reserved() && self.free() == 0 && self.huge() == 0))
+    }
+    /// Increments the free frames counter, saturating at the `max` free and huge
+    /// counters of this tree (see [Trees::max]).
+    ///
+    /// Also returns whether the counters saturated, which indicates corrupted metadata.
+    pub fn inc(self, free: usize, huge: usize, max: (usize, usize)) -> (Self, bool) {
+        debug_assert!(free == 0 || self.free() < max.0, "saturated {self:?}");
+        let (free, huge, saturated) = saturating_add(self, free, huge, max);
+        (self.with_free(free).with_huge(huge), saturated)
     }
     /// Reserves this entry if its frame count is in `range`.
+    ///
+    /// Entirely free trees with `max` frames can be reserved for any kind.
     pub fn reserve(
         self,
         free: impl RangeBounds<usize>,
         min_huge: usize,
         kind: Kind,
+        max: usize,
     ) -> Option<Self> {
         if !self.reserved()
             && free.contains(&self.free())
             && self.huge() >= min_huge
-            && (kind == self.kind() || self.free() == TREE_FRAMES)
+            && (kind == self.kind() || self.free() == max)
         {
-            Some(Self::with(0, 0, true, kind))
+            Some(Self::with(0, 0, true, kind).with_generation(self.next_generation()))
         } else {
             None
         }
     }
     /// Add the frames from the `other` entry to the reserved `self` entry and unreserve it.
     /// `self` is the entry in the global array / table.
-    pub fn unreserve_add(self, free: usize, huge: usize, kind: Kind) -> Option<Self> {
-        if self.reserved() {
-            let free = self.free() + free;
-            let huge = self.huge() + huge;
-            assert!(free <= TREE_FRAMES && huge <= TREE_HUGE);
-            Some(Self::with(free, huge, false, kind))
+    ///
+    /// If the reservation of `generation` has already ended, the local copy is stale
+    /// and the frames are only added, leaving the current reservation intact.
+    /// Like [Tree::inc], the counters saturate at `max`.
+    pub fn unreserve_add(
+        self,
+        free: usize,
+        huge: usize,
+        kind: Kind,
+        generation: usize,
+        max: (usize, usize),
+    ) -> Option<(Self, bool)> {
+        let (new_free, new_huge, saturated) = saturating_add(self, free, huge, max);
+        if self.generation() != generation {
+            Some((self.with_free(new_free).with_huge(new_huge), saturated))
+        } else if self.reserved() {
+            let tree = Self::with(new_free, new_huge, false, kind).with_generation(generation);
+            Some((tree, saturated))
         } else {
             None
         }
     }
-    /// Set the free counter to zero if it is large enough for synchronization
-    pub fn sync_steal(self, min: usize, min_huge: usize) -> Option<Self> {
-        if self.reserved() && self.free() > min && self.huge() >= min_huge {
+    /// Set the free counter to zero if it is large enough for synchronization.
+    ///
+    /// Fails if the reservation of `generation` has already ended.
+    pub fn sync_steal(self, generation: usize, min: usize, min_huge: usize) -> Option<Self> {
+        if self.reserved()
+            && self.generation() == generation
+            && self.free() > min
+            && self.huge() >= min_huge
+        {
             Some(self.with_free(0).with_huge(0))
         } else {
             None
         }
     }
 }
+
+/// Greatest common divisor
+fn gcd(mut a: usize, mut b: usize) -> usize {
+    while b != 0 {
+        (a, b) = (b, a % b);
+    }
+    a
+}
+
+/// Adds to the counters of `tree`, saturating at the `max` counters
+fn saturating_add(
+    tree: Tree,
+    free: usize,
+    huge: usize,
+    (max, max_huge): (usize, usize),
+) -> (usize, usize, bool) {
+    let (free, huge) = (tree.free() + free, tree.huge() + huge);
+    let saturated = free > max || huge > max_huge;
+    (free.min(max), huge.min(max_huge), saturated)
+}
+
+#[cfg(all(test, feature = "std"))]
+mod test {
+    use core::ops::{Range, RangeInclusive};
+    use std::vec::Vec;
+
+    use super::{Kind, NumaHint, Tree, Trees};
+    use crate::local::LocalTree;
+    use crate::util::aligned_buf;
+    use crate::{Flags, TREE_FRAMES, TREE_HUGE};
+
+    fn trees(len: usize, free: impl Fn(usize) -> usize) -> Trees<'static> {
+        let frames = len * TREE_FRAMES;
+        let buffer = aligned_buf(Trees::metadata_size(frames)).leak();
+        Trees::new(frames, buffer, |start| (free(start / TREE_FRAMES), 0))
+    }
+
+    #[test]
+    fn valid_entries() {
+        assert!(Tree::with(TREE_FRAMES, TREE_HUGE, false, Kind::Fixed).is_valid());
+        assert!(Tree::with(0, 0, true, Kind::Huge).is_valid());
+        assert!(Tree::with(0, 0, true, Kind::Fixed)
+            .with_inflated(true)
+            .is_valid());
+
+        // Counters beyond a tree
+        assert!(!Tree::new().with_free(TREE_FRAMES + 1).is_valid());
+        assert!(!Tree::new().with_huge(TREE_HUGE + 1).is_valid());
+        // Invalid kind
+        assert!(!Tree::from(3 << Tree::KIND_OFFSET).is_valid());
+        // Inflated trees are reserved and have no free frames
+        let inflated = Tree::with(0, 0, false, Kind::Fixed).with_inflated(true);
+        assert!(!inflated.is_valid());
+        assert!(!inflated.with_reserved(true).with_free(1).is_valid());
+    }
+
+    #[test]
+    fn saturating_counters() {
+        const MAX: (usize, usize) = (TREE_FRAMES, TREE_HUGE);
+        let tree = Tree::with(TREE_FRAMES - 1, TREE_HUGE - 1, false, Kind::Fixed);
+        assert_eq!(
+            tree.inc(1, 1, MAX),
+            (
+                Tree::with(TREE_FRAMES, TREE_HUGE, false, Kind::Fixed),
+                false
+            )
+        );
+
+        // Corrupted counters would exceed the tree
+        let (tree, saturated) = tree.inc(2, 0, MAX);
+        assert!(saturated && tree.is_valid());
+        assert_eq!((tree.free(), tree.huge()), (TREE_FRAMES, TREE_HUGE - 1));
+
+        let reserved = Tree::with(TREE_FRAMES - 1, 0, true, Kind::Fixed);
+        let (tree, saturated) = reserved
+            .unreserve_add(8, TREE_HUGE + 1, Kind::Movable, 0, MAX)
+            .unwrap();
+        assert!(saturated && tree.is_valid() && !tree.reserved());
+        assert_eq!((tree.free(), tree.huge()), (TREE_FRAMES, TREE_HUGE));
+
+        // The corruption is recorded by the tree array
+        let trees = trees(2, |i| TREE_FRAMES - 1 - i);
+        trees.inc_or_reserve(1, 1, 0, false);
+        assert!(!trees.corrupted());
+        trees.inc_or_reserve(0, 2, 0, false);
+        assert!(trees.corrupted());
+        assert_eq!(trees.get(0).free(), TREE_FRAMES);
+
+        // The last tree might be smaller
+        let frames = TREE_FRAMES + 7;
+        let buffer = aligned_buf(Trees::metadata_size(frames)).leak();
+        let trees = Trees::new(frames, buffer, |start| {
+            ((frames - start).min(TREE_FRAMES) - 1, 0)
+        });
+        assert_eq!(trees.max(0), (TREE_FRAMES, TREE_HUGE));
+        assert_eq!(trees.max(1), (7, 0));
+        trees.inc_or_reserve(1, 1, 0, false);
+        assert!(!trees.corrupted());
+        let (tree, saturated) = Tree::with(6, 0, true, Kind::Fixed)
+            .unreserve_add(2, 0, Kind::Fixed, 0, trees.max(1))
+            .unwrap();
+        assert!(saturated);
+        assert_eq!(tree.free(), 7);
+    }
+
+    /// A core syncs with a stale copy of its tree, after another core unreserved
+    /// and a third one reserved it again, which results in the same entry (ABA).
+    #[test]
+    fn stale_sync() {
+        const HALF: usize = TREE_FRAMES / 2;
+        let trees = trees(1, |_| HALF);
+        let get_lower = |t: LocalTree, _| Ok(t);
+        let kind = Flags::o(0).into();
+
+        // Core A reserves the tree, core B takes over its counters
+        let b = trees.reserve(1, 0, Flags::o(0), get_lower).unwrap();
+        let stale = b.with_free(0).with_huge(0);
+        assert_eq!(b.generation(), 1);
+        trees.unreserve(0, b.free(), b.huge(), b.generation(), kind);
+        // Core C reserves it again and other cores free frames
+        let c = trees.reserve(1, 0, Flags::o(0), get_lower).unwrap();
+        assert_eq!(c.generation(), 2);
+        trees.inc_or_reserve(0, 8, 0, false);
+        let global = trees.get(0);
+        assert!(global.reserved() && global.free() == 8);
+
+        // A is rejected and has to reserve a new tree
+        assert_eq!(trees.sync(0, stale.generation(), 0, 0), None);
+        assert_eq!(trees.get(0), global);
+        // Unreserving the stale copy keeps the reservation of C
+        trees.unreserve(0, stale.free(), stale.huge(), stale.generation(), kind);
+        assert_eq!(trees.get(0), global);
+
+        // C steals the freed frames and unreserves the tree
+        let synced = trees.sync(0, c.generation(), 0, 0).unwrap();
+        assert_eq!(synced.free(), 8);
+        trees.unreserve(0, c.free() + synced.free(), c.huge(), c.generation(), kind);
+        let global = trees.get(0);
+        assert!(!global.reserved() && global.free() == HALF + 8);
+
+        // The generation wraps around
+        let tree = Tree::with(0, 0, true, Kind::Fixed).with_generation(15);
+        assert_eq!(tree.next_generation(), 0);
+    }
+
+    #[test]
+    fn vicinity() {
+        let trees = trees(8, |_| 0);
+        let order = trees.vicinity(0, 0..8).collect::<Vec<_>>();
+        assert_eq!(order, [0, 7, 1, 6, 2, 5, 3, 4]);
+        let order = trees.vicinity(6, 0..8).collect::<Vec<_>>();
+        assert_eq!(order, [6, 5, 7, 4, 0, 3, 1, 2]);
+        // Continuing where the near search ended
+        let near = trees.vicinity(7, 0..3).collect::<Vec<_>>();
+        let far = trees.vicinity(7, 3..8).collect::<Vec<_>>();
+        assert_eq!(near, [7, 6, 0]);
+        assert_eq!(far, [5, 1, 4, 2, 3]);
+        // Start is wrapped around
+        assert!(trees.vicinity(15, 0..8).eq(trees.vicinity(7, 0..8)));
+    }
+
+    #[test]
+    fn permuted() {
+        let get_lower = |t: LocalTree, _| Ok(t);
+        for len in [1, 2, 6, 12, 97] {
+            let trees = trees(len, |_| TREE_FRAMES);
+            for rand in 0..2 * len as u64 {
+                let step = trees.coprime_step(rand);
+                assert!(step < len.max(2));
+                // Every tree is reserved exactly once
+                let mut seen = Vec::new();
+                for _ in 0..len {
+                    let tree = trees
+                        .reserve_permuted(rand as usize, step, Flags::o(0), get_lower)
+                        .unwrap();
+                    seen.push(tree.frame() / TREE_FRAMES);
+                }
+                assert!(trees
+                    .reserve_permuted(0, step, Flags::o(0), get_lower)
+                    .is_err());
+                seen.sort_unstable();
+                assert!(seen.iter().copied().eq(0..len));
+                for i in 0..len {
+                    let generation = trees.get(i).generation();
+                    trees.unreserve(i, TREE_FRAMES, TREE_HUGE, generation, Flags::o(0).into());
+                }
+            }
+        }
+        // Prefers partially filled trees
+        let trees = trees(16, |i| {
+            if i == 11 {
+                TREE_FRAMES / 4
+            } else {
+                TREE_FRAMES
+            }
+        });
+        let step = trees.coprime_step(5);
+        let tree = trees
+            .reserve_permuted(3, step, Flags::o(0), get_lower)
+            .unwrap();
+        assert_eq!(tree.frame(), 11 * TREE_FRAMES);
+    }
+
+    #[test]
+    fn reserve_near() {
+        let get_lower = |t: LocalTree, _| Ok(t);
+        let hint = |preferred_start, penalty_per_step| NumaHint {
+            preferred_start,
+            penalty_per_step,
+        };
+        let reserve = |trees: &Trees, hint| {
+            let tree = trees.reserve_near(hint, Flags::o(0), get_lower).unwrap();
+            let i = tree.frame() / TREE_FRAMES;
+            trees.unreserve(
+                i,
+                tree.free(),
+                tree.huge(),
+                tree.generation(),
+                Flags::o(0).into(),
+            );
+            i
+        };
+
+        let fallback = trees(4, |i| if i == 3 { TREE_FRAMES / 4 } else { 0 });
+        // Half filled trees at 10 and 50
+        let trees = trees(64, |i| match i {
+            10 | 50 => TREE_FRAMES / 4,
+            _ => TREE_FRAMES,
+        });
+        // Without a penalty, the first matching tree is chosen
+        assert_eq!(reserve(&trees, hint(48, 0)), 10);
+        // Otherwise the closest one
+        assert_eq!(reserve(&trees, hint(48, 2)), 50);
+        assert_eq!(reserve(&trees, hint(20, 2)), 10);
+        // Unless a good enough tree is found first
+        assert_eq!(reserve(&trees, hint(48, 1)), 10);
+        // With a large penalty, a close free tree is preferred
+        assert_eq!(reserve(&trees, hint(40, 64)), 40);
+        assert_eq!(reserve(&trees, hint(48, 64)), 50);
+
+        // Fails if there is no other tree
+        let tree = fallback
+            .reserve_near(hint(0, 1), Flags::o(0), get_lower)
+            .unwrap();
+        assert_eq!(tree.frame(), 3 * TREE_FRAMES);
+        assert!(fallback
+            .reserve_near(hint(0, 1), Flags::o(0), get_lower)
+            .is_err());
+    }
+
+    /// Only the trees around the preferred start are scanned
+    #[cfg(feature = "search_stats")]
+    #[test]
+    fn reserve_near_window() {
+        let trees = trees(1024, |_| TREE_FRAMES);
+        let hint = NumaHint {
+            preferred_start: 512,
+            penalty_per_step: 64,
+        };
+        let reads = trees.reads();
+        let tree = trees
+            .reserve_near(hint, Flags::o(0), |t: LocalTree, _| Ok(t))
+            .unwrap();
+        assert_eq!(tree.frame(), 512 * TREE_FRAMES);
+        assert!(trees.reads() - reads < 4 * Trees::CACHELINE);
+    }
+
+    #[test]
+    fn adaptive_near() {
+        const LEN: usize = 1024;
+        // Mostly full trees with a few partially filled ones
+        let trees = trees(LEN, |i| if i % 128 == 64 { TREE_FRAMES / 2 } else { 0 });
+        let get_lower = |t: LocalTree, _| Ok(t);
+        let (initial, bounds) = trees.near(1);
+
+        // The near search fails and is shrunk
+        let tree = trees.reserve(1, 0, Flags::o(0), get_lower).unwrap();
+        assert_eq!(tree.frame() / TREE_FRAMES % 128, 64);
+        assert!(trees.near(1).0 < initial);
+        for _ in 0..8 {
+            let tree = trees.reserve(1, 0, Flags::o(0), get_lower).unwrap();
+            let i = tree.frame() / TREE_FRAMES;
+            trees.unreserve(
+                i,
+                tree.free(),
+                tree.huge(),
+                tree.generation(),
+                Flags::o(0).into(),
+            );
+        }
+        assert_eq!(trees.near(1).0, bounds.start);
+
+        // The near search succeeds and is grown
+        for _ in 0..8 {
+            let tree = trees.reserve(1, 192, Flags::o(0), get_lower).unwrap();
+            assert_eq!(tree.frame(), 192 * TREE_FRAMES);
+            trees.unreserve(
+                192,
+                tree.free(),
+                tree.huge(),
+                tree.generation(),
+                Flags::o(0).into(),
+            );
+        }
+        assert!(trees.near(1).0 > bounds.start);
+        assert!(trees.near(1).0 <= bounds.end);
+    }
+
+    /// Number of entries visited by `phases` until a tree can be reserved
+    fn scan_length(
+        trees: &Trees,
+        start: usize,
+        phases: &[(Range<usize>, RangeInclusive<usize>)],
+    ) -> usize {
+        let mut scanned = 0;
+        for (range, free) in phases {
+            for i in trees.vicinity(start, range.clone()) {
+                scanned += 1;
+                let tree: Tree = trees.get(i);
+                if free.contains(&tree.free()) {
+                    return scanned;
+                }
+            }
+        }
+        scanned
+    }
+
+    #[test]
+    fn scan_length_bench() {
+        const LEN: usize = 4096;
+        // Mostly full trees with a few almost full ones
+        let trees = trees(LEN, |i| if i % 512 == 256 { 1 } else { 0 });
+        let get_lower = |t: LocalTree, _| Ok(t);
+        let tree = trees.reserve(1, 0, Flags::o(0), get_lower).unwrap();
+        let i = tree.frame() / TREE_FRAMES;
+        trees.unreserve(
+            i,
+            tree.free(),
+            tree.huge(),
+            tree.generation(),
+            Flags::o(0).into(),
+        );
+
+        // Previously the near phases were fixed and the far phase started over
+        let half = TREE_FRAMES / 16..=TREE_FRAMES / 2;
+        let partial = TREE_FRAMES / 64..=TREE_FRAMES - TREE_FRAMES / 16;
+        let not_free = 1..=TREE_FRAMES - 1;
+        let old = 2 * Trees::CACHELINE;
+        let before = [
+            (1..old, half.clone()),
+            (1..2 * old, partial.clone()),
+            (1..LEN, not_free.clone()),
+        ];
+        let (near, _) = trees.near(1);
+        let after = [
+            (0..near, half),
+            (0..2 * near, partial),
+            (2 * near..LEN, not_free),
+        ];
+
+        let starts = (0..LEN).step_by(Trees::CACHELINE);
+        let n = starts.len();
+        let before = starts
+            .clone()
+            .map(|s| scan_length(&trees, s, &before))
+            .sum::<usize>()
+            / n;
+        let after = starts
+            .map(|s| scan_length(&trees, s, &after))
+            .sum::<usize>()
+            / n;
+        println!("scan length: before {before} after {after}");
+        assert!(after < before);
+    }
+}
@@ -3,11 +3,30 @@
 use core::fmt;
 use core::mem::{align_of, size_of};
 use core::ops::{Add, Deref, DerefMut, Div, Range};
+use core::str::FromStr;
+use core::sync::atomic::AtomicU64;
+use core::sync::atomic::Ordering::Relaxed;
+
+use crate::{Error, Result, FRAME_SIZE_BITS, MAX_ORDER};
 
 /// Align v up to next `align`
+///
+/// Panics if `align` is zero or the result overflows, also in release builds,
+/// so that overflows in const contexts fail the compilation.
+/// See [checked_align_up] for a non-panicking variant.
 #[inline(always)]
 pub const fn align_up(v: usize, align: usize) -> usize {
-    v.next_multiple_of(align)
+    assert!(align != 0, "align_up to zero");
+    match checked_align_up(v, align) {
+        Some(v) => v,
+        None => panic!("align_up overflow"),
+    }
+}
+
+/// Align v up to next `align`, or `None` if the result overflows or `align` is zero
+#[inline(always)]
+pub const fn checked_align_up(v: usize, align: usize) -> Option<usize> {
+    v.checked_next_multiple_of(align)
 }
 
 /// Align v down to previous `align` (power of two!)
@@ -20,7 +39,7 @@ pub const fn align_down(v: usize, align: usize) -> usize {
 ///
 /// Note: This might not be correct for all types, but it is for the ones we use.
 pub const fn size_of_slice<T>(len: usize) -> usize {
-    len * size_of::<T>().next_multiple_of(align_of::<T>())
+    len * align_up(size_of::<T>(), align_of::<T>())
 }
 
 /// Cache alignment for T
@@ -91,6 +110,45 @@ fn panic_handler(info: &std::panic::PanicInfo) {
     log::error!("{info}\n{}", Backtrace::capture());
 }
 
+/// Rate limiter for log messages on hot paths, see [log_throttled].
+///
+/// This counts the messages instead of measuring time, as there is no clock without std.
+pub struct Throttle(AtomicU64);
+
+impl Throttle {
+    /// Only every n-th message is logged
+    pub const PERIOD: u64 = 1 << 10;
+
+    pub const fn new() -> Self {
+        Self(AtomicU64::new(0))
+    }
+    /// Returns the number of suppressed messages since the last one, if this one should be logged
+    pub fn check(&self) -> Option<u64> {
+        let n = self.0.fetch_add(1, Relaxed);
+        (n & (Self::PERIOD - 1) == 0).then_some(n.min(Self::PERIOD - 1))
+    }
+}
+
+impl Default for Throttle {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+/// Logs only every [Throttle::PERIOD]-th message of this call site,
+/// appending the number of suppressed messages.
+macro_rules! log_throttled {
+    ($level:ident, $($arg:tt)+) => {{
+        static THROTTLE: $crate::util::Throttle = $crate::util::Throttle::new();
+        match THROTTLE.check() {
+            Some(0) => log::$level!($($arg)+),
+            Some(n) => log::$level!("{} ({n} suppressed)", format_args!($($arg)+)),
+            None => {}
+        }
+    }};
+}
+pub(crate) use log_throttled;
+
 /// Simple bare bones random number generator based on wyhash.
 ///
 /// - See <https://github.com/wangyi-fudan/wyhash>
@@ -117,25 +175,136 @@ impl WyRand {
         }
     }
     pub fn shuffle<T>(&mut self, target: &mut [T]) {
-        if !target.is_empty() {
-            for i in 0..target.len() - 1 {
-                target.swap(i, self.range(i as u64..target.len() as u64) as usize);
-            }
+        self.shuffle_partial(target, target.len());
+    }
+    /// Only performs the first `k` Fisher-Yates steps, leaving a random sample
+    /// in `target[..k]` and the remaining elements in arbitrary order.
+    pub fn shuffle_partial<T>(&mut self, target: &mut [T], k: usize) {
+        for i in 0..k.min(target.len().saturating_sub(1)) {
+            target.swap(i, self.range(i as u64..target.len() as u64) as usize);
         }
     }
 }
 
 /// Retries the condition n times and returns if it was successfull.
-/// This pauses the CPU between retries if possible.
+/// The strategy between retries is selected by the `spin_*` features, see [Spin::SELECTED].
 #[inline(always)]
-pub fn spin_wait(n: usize, mut cond: impl FnMut() -> bool) -> bool {
-    for _ in 0..n {
-        if cond() {
-            return true;
+pub fn spin_wait(n: usize, cond: impl FnMut() -> bool) -> bool {
+    Spin::SELECTED.wait(n, cond)
+}
+
+/// Waiting strategy of [spin_wait]
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Spin {
+    /// Pause the CPU between retries, without involving the OS (`spin_pure`)
+    Pure,
+    /// Yield to the OS scheduler between retries (`spin_yield`)
+    Yield,
+    /// Pause the CPU with exponential backoff, then yield (`spin_adaptive`)
+    Adaptive,
+}
+
+impl Spin {
+    /// Strategy selected by the features.
+    ///
+    /// If multiple are enabled, `spin_pure` takes precedence over `spin_yield`,
+    /// which takes precedence over the default `spin_adaptive`.
+    pub const SELECTED: Self = if cfg!(feature = "spin_pure") {
+        Self::Pure
+    } else if cfg!(feature = "spin_yield") {
+        Self::Yield
+    } else if cfg!(feature = "spin_adaptive") {
+        Self::Adaptive
+    } else {
+        Self::Pure
+    };
+    /// Number of retries with exponential backoff before [Spin::Adaptive] yields,
+    /// the last one pauses `1 << (BACKOFF - 1)` times
+    const BACKOFF: usize = 7;
+
+    /// Retries the condition at most n times with this strategy and returns if it was successfull.
+    #[inline(always)]
+    pub fn wait(self, n: usize, mut cond: impl FnMut() -> bool) -> bool {
+        for i in 0..n {
+            if cond() {
+                return true;
+            }
+            match self {
+                Self::Pure => core::hint::spin_loop(),
+                Self::Yield => yield_now(),
+                Self::Adaptive if i < Self::BACKOFF => {
+                    for _ in 0..1 << i {
+                        core::hint::spin_loop()
+                    }
+                }
+                Self::Adaptive => yield_now(),
+            }
         }
-        core::hint::spin_loop()
+        false
     }
-    false
+}
+
+/// Yields to the OS scheduler, or pauses the CPU without `std`
+#[inline(always)]
+fn yield_now() {
+    #[cfg(feature = "std")]
+    std::thread::yield_now();
+    #[cfg(not(feature = "std"))]
+    core::hint::spin_loop();
+}
+
+/// Writes the cache lines of `ptr..ptr+len` back to memory, followed by a store fence.
+///
+/// This is required for the durability of writes to persistent memory.
+/// Uses `CLWB` if the target supports it and `CLFLUSH` otherwise.
+/// Other architectures only issue a fence.
+///
+/// # Safety
+/// `ptr..ptr+len` has to be mapped, otherwise the flushes fault.
+#[allow(unexpected_cfgs)] // `clwb` is not known to all rustc versions
+pub unsafe fn persist(ptr: *const u8, len: usize) {
+    #[cfg(target_arch = "x86_64")]
+    unsafe {
+        use core::arch::asm;
+
+        let end = ptr as usize + len;
+        let mut line = align_down(ptr as usize, align_of::<Align>());
+        while line < end {
+            #[cfg(target_feature = "clwb")]
+            asm!("clwb [{}]", in(reg) line, options(nostack, preserves_flags));
+            #[cfg(not(target_feature = "clwb"))]
+            asm!("clflush [{}]", in(reg) line, options(nostack, preserves_flags));
+            line += align_of::<Align>();
+        }
+        asm!("sfence", options(nostack, preserves_flags));
+    }
+    #[cfg(not(target_arch = "x86_64"))]
+    {
+        let _ = (ptr, len);
+        core::sync::atomic::fence(core::sync::atomic::Ordering::SeqCst);
+    }
+}
+
+/// CRC-32 (IEEE) checksum of `data`, computed bitwise without lookup table.
+pub fn crc32(data: &[u8]) -> u32 {
+    let mut crc = !0u32;
+    for &byte in data {
+        crc ^= byte as u32;
+        for _ in 0..8 {
+            crc = (crc >> 1) ^ (0xedb8_8320 & (crc & 1).wrapping_neg());
+        }
+    }
+    !crc
+}
+
+/// Splits the first `N` bytes off `data`.
+pub fn take_bytes<const N: usize>(data: &mut &[u8]) -> Option<[u8; N]> {
+    if data.len() < N {
+        return None;
+    }
+    let (head, tail) = data.split_at(N);
+    *data = tail;
+    head.try_into().ok()
 }
 
 pub fn avg_bounds<T>(iter: impl IntoIterator<Item = T>) -> Option<(T, T, T)>
@@ -198,9 +367,189 @@ where
     }
 }
 
+/// Size in bytes
+///
+/// Parsed from strings like `512K`, `2M`, or `1G` with binary units
+/// and displayed in the largest unit that divides it exactly.
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Hash)]
+pub struct Bytes(pub usize);
+
+/// Number of base frames
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Hash)]
+pub struct Frames(pub usize);
+
+/// Order of an allocation, which consists of `2^order` base frames
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Hash)]
+pub struct Order(pub usize);
+
+impl Bytes {
+    pub const K: Self = Self(1 << 10);
+    pub const M: Self = Self(1 << 20);
+    pub const G: Self = Self(1 << 30);
+    pub const T: Self = Self(1 << 40);
+    const UNITS: [(Self, char); 4] = [
+        (Self::T, 'T'),
+        (Self::G, 'G'),
+        (Self::M, 'M'),
+        (Self::K, 'K'),
+    ];
+
+    /// Number of frames that cover this size, including a partial last frame
+    pub const fn frames_ceil(self) -> Frames {
+        Frames(frames_ceil(self.0, FRAME_SIZE_BITS))
+    }
+    /// Number of entire frames within this size, ignoring a partial last frame
+    pub const fn frames_floor(self) -> Frames {
+        Frames(frames_floor(self.0, FRAME_SIZE_BITS))
+    }
+
+    /// Parses a size like [`str::parse`], but numbers without suffix are multiples of `unit`
+    pub fn parse_in(s: &str, unit: Bytes) -> core::result::Result<Self, ParseBytesError> {
+        let s = s.trim();
+        let (num, unit) = match s.char_indices().last() {
+            Some((i, c)) if c.is_ascii_alphabetic() => {
+                let c = c.to_ascii_uppercase();
+                let Some((unit, _)) = Self::UNITS.iter().find(|(_, u)| *u == c) else {
+                    return Err(ParseBytesError);
+                };
+                (&s[..i], *unit)
+            }
+            _ => (s, unit),
+        };
+        let num: usize = num.trim_end().parse().map_err(|_| ParseBytesError)?;
+        num.checked_mul(unit.0).map(Self).ok_or(ParseBytesError)
+    }
+}
+
+impl FromStr for Bytes {
+    type Err = ParseBytesError;
+
+    fn from_str(s: &str) -> core::result::Result<Self, Self::Err> {
+        Self::parse_in(s, Self(1))
+    }
+}
+
+impl fmt::Display for Bytes {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        for (unit, suffix) in Self::UNITS {
+            if self.0 >= unit.0 && self.0 & (unit.0 - 1) == 0 {
+                return write!(f, "{}{suffix}", self.0 / unit.0);
+            }
+        }
+        write!(f, "{}", self.0)
+    }
+}
+
+/// Invalid or overflowing size string
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct ParseBytesError;
+
+impl fmt::Display for ParseBytesError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str("invalid size, expected a number with an optional K, M, G, or T suffix")
+    }
+}
+
+#[cfg(feature = "std")]
+impl std::error::Error for ParseBytesError {}
+
+impl Frames {
+    /// Size of these frames, or `None` on overflow
+    pub const fn bytes(self) -> Option<Bytes> {
+        match frames_bytes(self.0, FRAME_SIZE_BITS) {
+            Some(bytes) => Some(Bytes(bytes)),
+            None => None,
+        }
+    }
+    /// Smallest order that covers these frames, zero frames need order 0
+    ///
+    /// Fails with [`Error::InvalidOrder`] if this is larger than [`MAX_ORDER`].
+    pub fn order_ceil(self) -> Result<Order> {
+        let order = match self.0.checked_next_power_of_two() {
+            Some(n) => n.trailing_zeros() as usize,
+            None => usize::BITS as usize,
+        };
+        if order > MAX_ORDER {
+            return Err(Error::InvalidOrder {
+                requested: order,
+                max: MAX_ORDER,
+            });
+        }
+        Ok(Order(order))
+    }
+}
+
+/// Frames of `1 << bits` bytes that cover `bytes`
+const fn frames_ceil(bytes: usize, bits: usize) -> usize {
+    bytes.div_ceil(1 << bits)
+}
+/// Entire frames of `1 << bits` bytes within `bytes`
+const fn frames_floor(bytes: usize, bits: usize) -> usize {
+    bytes >> bits
+}
+/// Size of `frames` frames of `1 << bits` bytes, or `None` on overflow
+const fn frames_bytes(frames: usize, bits: usize) -> Option<usize> {
+    frames.checked_mul(1 << bits)
+}
+
+impl fmt::Display for Frames {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self.bytes() {
+            Some(bytes) => write!(f, "{bytes}"),
+            None => write!(f, "{} frames", self.0),
+        }
+    }
+}
+
+impl Order {
+    /// Number of frames of this order, or `None` on overflow
+    pub const fn frames(self) -> Option<Frames> {
+        if self.0 < usize::BITS as usize {
+            Some(Frames(1 << self.0))
+        } else {
+            None
+        }
+    }
+    /// Size of this order, or `None` on overflow
+    pub const fn bytes(self) -> Option<Bytes> {
+        match self.frames() {
+            Some(frames) => frames.bytes(),
+            None => None,
+        }
+    }
+}
+
+impl fmt::Display for Order {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "o{}", self.0)
+    }
+}
+
 #[cfg(all(test, feature = "std"))]
 mod test {
-    use super::{align_down, align_up, WyRand};
+    use std::vec::Vec;
+
+    use super::{
+        align_down, align_up, checked_align_up, crc32, frames_bytes, frames_ceil, frames_floor,
+        Bytes, Frames, Order, Spin, Throttle, WyRand,
+    };
+    use crate::{Error, FRAME_SIZE, FRAME_SIZE_BITS, HUGE_FRAMES, HUGE_ORDER, MAX_ORDER};
+
+    /// The conversions stay consistent with other frame sizes, like 8 KiB frames
+    const _: () = {
+        const fn consistent(bits: usize) -> bool {
+            let size = 1 << bits;
+            frames_floor(size << HUGE_ORDER, bits) == HUGE_FRAMES
+                && frames_floor(size - 1, bits) == 0
+                && frames_ceil(size + 1, bits) == 2
+                && matches!(frames_bytes(3, bits), Some(b) if b == 3 * size)
+                && frames_bytes(usize::MAX >> bits, bits).is_some()
+                && frames_bytes((usize::MAX >> bits) + 1, bits).is_none()
+        }
+        assert!(FRAME_SIZE == 1 << FRAME_SIZE_BITS);
+        assert!(consistent(FRAME_SIZE_BITS));
+        assert!(consistent(13));
+    };
 
     #[test]
     fn wy_rand() {
@@ -225,6 +574,66 @@ mod test {
         println!("avg={avg:.2}, std={std:.2}, min={min}, max={max}");
     }
 
+    #[test]
+    fn shuffle_partial() {
+        let mut rng = WyRand::new(0);
+        let mut v = (0..100).collect::<Vec<usize>>();
+        let orig = v.clone();
+        rng.shuffle_partial(&mut v, 10);
+        // Still a permutation
+        let mut sorted = v.clone();
+        sorted.sort_unstable();
+        assert_eq!(sorted, orig);
+        assert_ne!(v[..10], orig[..10]);
+
+        // Deterministic and consistent with the full shuffle
+        let (mut a, mut b) = (orig.clone(), orig.clone());
+        WyRand::new(1).shuffle(&mut a);
+        WyRand::new(1).shuffle_partial(&mut b, usize::MAX);
+        assert_eq!(a, b);
+        let mut c = orig.clone();
+        WyRand::new(1).shuffle_partial(&mut c, 10);
+        assert_eq!(a[..10], c[..10]);
+
+        // No steps and edge cases
+        rng.shuffle_partial(&mut v[..0], 5);
+        let mut w = orig.clone();
+        rng.shuffle_partial(&mut w, 0);
+        assert_eq!(w, orig);
+    }
+
+    #[test]
+    fn spin() {
+        if !cfg!(any(feature = "spin_pure", feature = "spin_yield")) {
+            assert_eq!(
+                Spin::SELECTED == Spin::Adaptive,
+                cfg!(feature = "spin_adaptive")
+            );
+        }
+        for spin in [Spin::Pure, Spin::Yield, Spin::Adaptive] {
+            let mut tries = 0;
+            assert!(!spin.wait(20, || {
+                tries += 1;
+                false
+            }));
+            assert_eq!(tries, 20);
+
+            let mut tries = 0;
+            assert!(spin.wait(20, || {
+                tries += 1;
+                tries == 10
+            }));
+            assert_eq!(tries, 10);
+            assert!(!spin.wait(0, || true));
+        }
+    }
+
+    #[test]
+    fn crc() {
+        assert_eq!(crc32(b""), 0);
+        assert_eq!(crc32(b"123456789"), 0xcbf4_3926);
+    }
+
     #[test]
     fn align() {
         assert_eq!(align_down(0, 64), 0);
@@ -238,5 +647,104 @@ mod test {
         assert_eq!(align_up(63, 64), 64);
         assert_eq!(align_up(64, 64), 64);
         assert_eq!(align_up(65, 64), 128);
+
+        // Overflows
+        const TOP: usize = usize::MAX - 63;
+        const _: () = assert!(align_up(TOP - 1, 64) == TOP);
+        assert_eq!(align_down(usize::MAX, 64), TOP);
+        assert_eq!(checked_align_up(TOP, 64), Some(TOP));
+        assert_eq!(checked_align_up(TOP + 1, 64), None);
+        assert_eq!(checked_align_up(usize::MAX, 64), None);
+        assert_eq!(checked_align_up(1, 0), None);
+    }
+
+    #[test]
+    #[should_panic(expected = "align_up overflow")]
+    fn align_up_overflow() {
+        align_up(core::hint::black_box(usize::MAX), 64);
+    }
+
+    #[test]
+    #[should_panic(expected = "align_up to zero")]
+    fn align_up_zero() {
+        align_up(1, core::hint::black_box(0));
+    }
+
+    #[test]
+    fn throttle() {
+        let throttle = Throttle::new();
+        let logged: std::vec::Vec<_> = (0..10_000).filter_map(|_| throttle.check()).collect();
+        assert_eq!(logged.len(), 10_000usize.div_ceil(Throttle::PERIOD as _));
+        assert_eq!(logged[0], 0);
+        assert!(logged[1..].iter().all(|n| *n == Throttle::PERIOD - 1));
+    }
+
+    #[test]
+    fn units() {
+        assert_eq!(Bytes(0).frames_ceil(), Frames(0));
+        assert_eq!(Bytes(1).frames_ceil(), Frames(1));
+        assert_eq!(Bytes(FRAME_SIZE).frames_ceil(), Frames(1));
+        assert_eq!(Bytes(FRAME_SIZE + 1).frames_ceil(), Frames(2));
+        assert_eq!(Bytes(FRAME_SIZE + 1).frames_floor(), Frames(1));
+        assert_eq!(
+            Bytes(usize::MAX).frames_ceil(),
+            Frames(usize::MAX / FRAME_SIZE + 1)
+        );
+
+        assert_eq!(Frames(3).bytes(), Some(Bytes(3 * FRAME_SIZE)));
+        assert_eq!(Frames(usize::MAX).bytes(), None);
+
+        assert_eq!(Frames(0).order_ceil(), Ok(Order(0)));
+        assert_eq!(Frames(1).order_ceil(), Ok(Order(0)));
+        assert_eq!(Frames(2).order_ceil(), Ok(Order(1)));
+        assert_eq!(Frames(3).order_ceil(), Ok(Order(2)));
+        assert_eq!(Frames(1 << MAX_ORDER).order_ceil(), Ok(Order(MAX_ORDER)));
+        let invalid = Error::InvalidOrder {
+            requested: MAX_ORDER + 1,
+            max: MAX_ORDER,
+        };
+        assert_eq!(Frames((1 << MAX_ORDER) + 1).order_ceil(), Err(invalid));
+        let invalid = Error::InvalidOrder {
+            requested: usize::BITS as usize,
+            max: MAX_ORDER,
+        };
+        assert_eq!(Frames(usize::MAX).order_ceil(), Err(invalid));
+
+        assert_eq!(Order(9).frames(), Some(Frames(512)));
+        assert_eq!(Order(9).bytes(), Some(Bytes(FRAME_SIZE << 9)));
+        assert_eq!(Order(usize::BITS as usize).frames(), None);
+        assert_eq!(Order(60).bytes(), None);
+    }
+
+    #[test]
+    fn parse_bytes() {
+        assert_eq!("512K".parse(), Ok(Bytes(512 << 10)));
+        assert_eq!("2M".parse(), Ok(Bytes(2 << 20)));
+        assert_eq!("2m".parse(), Ok(Bytes(2 << 20)));
+        assert_eq!(" 1 G ".parse(), Ok(Bytes(1 << 30)));
+        assert_eq!("3T".parse(), Ok(Bytes(3 << 40)));
+        assert_eq!("123".parse(), Ok(Bytes(123)));
+        assert_eq!(Bytes::parse_in("16", Bytes::G), Ok(Bytes(16 << 30)));
+        assert_eq!(Bytes::parse_in("16M", Bytes::G), Ok(Bytes(16 << 20)));
+
+        assert!("".parse::<Bytes>().is_err());
+        assert!("G".parse::<Bytes>().is_err());
+        assert!("2X".parse::<Bytes>().is_err());
+        assert!("-1K".parse::<Bytes>().is_err());
+        assert!("1.5G".parse::<Bytes>().is_err());
+        assert!(format!("{}T", usize::MAX).parse::<Bytes>().is_err());
+        assert!("16777216T".parse::<Bytes>().is_err());
+
+        for size in [0, 1, 4095, 512 << 10, 2 << 20, (1 << 30) + 4096, 3 << 40] {
+            let s = format!("{}", Bytes(size));
+            assert_eq!(s.parse(), Ok(Bytes(size)), "{s}");
+        }
+        assert_eq!(format!("{}", Bytes(2 << 20)), "2M");
+        assert_eq!(format!("{}", Bytes(1536 << 10)), "1536K");
+        assert_eq!(format!("{}", Frames(512)), "2M");
+        assert_eq!(
+            format!("{}", Frames(usize::MAX)),
+            format!("{} frames", usize::MAX)
+        );
     }
 }
@@ -1,13 +1,16 @@
 use core::marker::PhantomData;
-use core::mem::size_of_val;
+use core::mem::{size_of, size_of_val};
+use core::ops::Range;
 use core::sync::atomic::Ordering::*;
-use core::sync::atomic::{AtomicBool, AtomicUsize};
+use core::sync::atomic::{AtomicBool, AtomicIsize, AtomicU32, AtomicUsize};
 use core::{fmt, slice};
 
-use log::error;
+use log::{error, info, warn};
+use spin::mutex::SpinMutex;
 
 use crate::frame::Frame;
-use crate::{Alloc, Error, Flags, Init, MetaData, MetaSize, Result, MAX_ORDER};
+use crate::util::{align_up, crc32};
+use crate::{Alloc, Error, Flags, Init, MetaData, MetaSize, PutResult, Result, MAX_ORDER};
 
 /// Zone allocator, managing a range of memory at a given page frame offset.
 pub struct ZoneAlloc<'a, A: Alloc<'a>> {
@@ -33,12 +36,25 @@ impl<'a, A: Alloc<'a>> Alloc<'a> for ZoneAlloc<'a, A> {
         })
     }
 
+    #[cfg(feature = "std")]
+    fn restore(snapshot: crate::AllocSnapshot, frames: usize, meta: MetaData<'a>) -> Result<Self> {
+        Ok(Self {
+            alloc: A::restore(snapshot, frames, meta)?,
+            offset: 0,
+            _p: PhantomData,
+        })
+    }
+
     fn metadata_size(cores: usize, frames: usize) -> MetaSize {
         A::metadata_size(cores, frames)
     }
     fn metadata(&mut self) -> MetaData<'a> {
         self.alloc.metadata()
     }
+    #[cfg(feature = "std")]
+    fn snapshot(&self) -> Option<crate::AllocSnapshot> {
+        self.alloc.snapshot()
+    }
     fn get(&self, core: usize, flags: Flags) -> Result<usize> {
         Ok(self.alloc.get(core, flags)? + self.offset)
     }
@@ -46,6 +62,26 @@ impl<'a, A: Alloc<'a>> Alloc<'a> for ZoneAlloc<'a, A> {
         let frame = frame.checked_sub(self.offset).ok_or(Error::Address)?;
         self.alloc.put(core, frame, flags)
     }
+    fn put_report(&self, core: usize, frame: usize, flags: Flags) -> Result<PutResult> {
+        let frame = frame.checked_sub(self.offset).ok_or(Error::Address)?;
+        self.alloc.put_report(core, frame, flags)
+    }
+    fn try_resize(
+        &self,
+        core: usize,
+        frame: usize,
+        order: usize,
+        new_order: usize,
+    ) -> Result<usize> {
+        let frame = frame.checked_sub(self.offset).ok_or(Error::Address)?;
+        Ok(self.alloc.try_resize(core, frame, order, new_order)? + self.offset)
+    }
+    fn max_order(&self) -> usize {
+        self.alloc.max_order()
+    }
+    fn is_initialized(&self) -> bool {
+        self.alloc.is_initialized()
+    }
     fn frames(&self) -> usize {
         self.alloc.frames()
     }
@@ -64,6 +100,19 @@ impl<'a, A: Alloc<'a>> Alloc<'a> for ZoneAlloc<'a, A> {
         };
         self.alloc.is_free(frame, order)
     }
+    fn free_frames_below(&self, boundary: usize) -> usize {
+        let boundary = boundary.saturating_sub(self.offset);
+        self.alloc.free_frames_below(boundary)
+    }
+    fn allocated_frames_in(&self, frames: Range<usize>) -> usize {
+        let start = frames.start.saturating_sub(self.offset);
+        let end = frames.end.saturating_sub(self.offset);
+        self.alloc.allocated_frames_in(start..end)
+    }
+    fn count_allocatable_spans(&self, buf: &mut [usize]) {
+        // The offset is aligned to the max order
+        self.alloc.count_allocatable_spans(buf)
+    }
     fn free_at(&self, frame: usize, order: usize) -> usize {
         let Some(frame) = frame.checked_sub(self.offset) else {
             return 0;
@@ -73,6 +122,10 @@ impl<'a, A: Alloc<'a>> Alloc<'a> for ZoneAlloc<'a, A> {
     fn drain(&self, core: usize) -> Result<()> {
         self.alloc.drain(core)
     }
+    #[cfg(feature = "latency")]
+    fn histograms(&self) -> Option<crate::latency::Histograms> {
+        self.alloc.histograms()
+    }
 }
 
 impl<'a, A: Alloc<'a>> ZoneAlloc<'a, A> {
@@ -100,6 +153,33 @@ impl<'a, A: Alloc<'a>> fmt::Debug for ZoneAlloc<'a, A> {
     }
 }
 
+/// Allocators that keep their state in persistent memory and are recovered
+/// after a reboot, like [NvmAlloc] and [NvmLogAlloc].
+///
+/// This is a sealed marker for `where` bounds, volatile allocators do not implement it.
+/// Whether an [Alloc] like [crate::LLFree] is persistent is only decided
+/// at runtime by its metadata, so it can not provide these guarantees.
+///
+/// ```compile_fail
+/// use llfree::wrapper::Persistent;
+/// use llfree::LLFree;
+///
+/// fn volatile(alloc: &LLFree) {
+///     alloc.recover().unwrap();
+/// }
+/// ```
+pub trait Persistent<'a>: Alloc<'a> + sealed::Sealed {
+    /// Checks that the persistent state describes this instance,
+    /// so that it would be recovered if the system crashed now.
+    ///
+    /// Returns [Error::Corruption] if the recovery metadata is invalid.
+    fn recover(&self) -> Result<()>;
+}
+
+mod sealed {
+    pub trait Sealed {}
+}
+
 /// Non-Volatile metadata that is used to recover the allocator at reboot
 #[repr(align(0x1000))]
 struct Meta {
@@ -107,56 +187,149 @@ struct Meta {
     magic: AtomicUsize,
     /// Number of frames managed by the persistent allocator
     frames: AtomicUsize,
+    /// Number of head frames reserved for the caller (only [NvmAlloc])
+    reserved: AtomicUsize,
     /// Flag that stores if the system has crashed or was shutdown correctly
     crashed: AtomicBool,
+    /// Sequence number of the last log record (only [NvmLogAlloc])
+    seq: AtomicUsize,
+    /// Sequence number of the last checkpoint shifted by one and the index of its area (only [NvmLogAlloc])
+    checkpoint: AtomicUsize,
+    /// Generation of this copy, incremented on every update (only [NvmAlloc])
+    generation: AtomicUsize,
+    /// Checksum over magic, frames, reserved, crashed and generation (only [NvmAlloc])
+    crc: AtomicU32,
 }
 impl Meta {
     /// Magic marking the meta frame.
     const MAGIC: usize = 0x_dead_beef;
+    /// Magic marking the meta frame of a [NvmLogAlloc].
+    const MAGIC_LOG: usize = 0x_dead_10c5;
+
+    /// Computes the checksum over the fields of a [NvmAlloc]
+    fn checksum(&self) -> u32 {
+        let fields = [
+            self.magic.load(Acquire),
+            self.frames.load(Acquire),
+            self.reserved.load(Acquire),
+            self.crashed.load(Acquire) as usize,
+            self.generation.load(Acquire),
+        ];
+        let mut bytes = [0; 5 * size_of::<u64>()];
+        for (bytes, field) in bytes.chunks_mut(size_of::<u64>()).zip(fields) {
+            bytes.copy_from_slice(&(field as u64).to_le_bytes());
+        }
+        crc32(&bytes)
+    }
+    /// Returns if this copy was completely written by a [NvmAlloc]
+    fn valid(&self) -> bool {
+        self.magic.load(Acquire) == Self::MAGIC && self.crc.load(Acquire) == self.checksum()
+    }
+    /// Returns why this copy is not valid for an allocator of `frames`
+    fn diagnose(&self, frames: usize) -> &'static str {
+        if self.magic.load(Acquire) != Self::MAGIC {
+            "invalid magic"
+        } else if self.frames.load(Acquire) != frames {
+            "invalid frame count"
+        } else {
+            "invalid checksum"
+        }
+    }
 }
 const _: () = assert!(core::mem::size_of::<Meta>() <= Frame::SIZE);
+const _: () = assert!(core::mem::align_of::<Meta>() == Frame::SIZE);
 
 /// Persistent memory allocator, that is able to recover its state from the memory it manages.
+///
+/// The metadata is stored twice, so that a torn write does not make the allocator unrecoverable.
+/// Updates overwrite the older copy first, recovery uses the valid copy with the highest generation.
+///
+/// ## Memory Layout
+/// ```text
+/// NVRAM: [ Reserved | Frames | Meta | Lower | Meta ]
+/// ```
+///
+/// The reserved head frames are left to the caller, e.g., for a superblock.
+/// They are rounded up to the largest order, so that the managed frames stay aligned,
+/// and are never allocated (freeing them fails with [Error::Address]).
 pub struct NvmAlloc<'a, A: Alloc<'a>> {
     pub alloc: ZoneAlloc<'a, A>,
-    meta: &'a Meta,
+    meta: [&'a Meta; 2],
+    /// Number of reserved head frames, as requested by the caller
+    reserved: usize,
+    /// How the allocator was initialized
+    init: Init,
 }
 
 impl<'a, A: Alloc<'a>> NvmAlloc<'a, A> {
+    /// Creates or recovers the allocator in `zone`, leaving its first
+    /// `reserved_head_frames` frames to the caller.
+    ///
+    /// Recovery fails if the previous instance reserved a different number of frames.
+    /// If neither copy of the metadata is valid, e.g., because they are corrupted,
+    /// the allocator is initialized from scratch, as reported by [NvmAlloc::init].
     pub fn create(
         cores: usize,
         zone: &'a mut [Frame],
+        reserved_head_frames: usize,
         recover: bool,
         local: &'a mut [u8],
         trees: &'a mut [u8],
     ) -> Result<Self> {
         let m = A::metadata_size(cores, zone.len());
-        if size_of_val(zone) < m.lower + Frame::SIZE
+        let head = align_up(reserved_head_frames, 1 << MAX_ORDER);
+        if size_of_val(zone) < m.lower + 2 * Frame::SIZE
+            || zone.len() - (m.lower.div_ceil(Frame::SIZE) + 2) < head
             || zone.as_ptr() as usize % (Frame::SIZE << MAX_ORDER) != 0
         {
             error!("invalid memory region");
             return Err(Error::Initialization);
         }
 
-        let (meta, zone) = zone.split_last_mut().ok_or(Error::Memory)?;
-        let meta = meta.cast::<Meta>();
+        let (_, zone) = zone.split_at_mut(head);
+        let (last, zone) = zone.split_last_mut().ok_or(Error::Memory)?;
+        let (zone, p) = zone.split_at_mut(zone.len() - m.lower.div_ceil(Frame::SIZE));
+        let (first, zone) = zone.split_last_mut().ok_or(Error::Memory)?;
+        let meta = [first.cast::<Meta>(), last.cast::<Meta>()];
+        let frames = zone.len();
+
+        let current = if recover { Self::current(meta) } else { None };
+        if recover && current.is_none() {
+            for (i, copy) in meta.iter().enumerate() {
+                warn!("metadata copy {i}: {}", copy.diagnose(frames));
+            }
+            warn!("no valid metadata found, initializing from scratch");
+        }
+
+        let init = if let Some(current) = current {
+            let (cur, other) = (meta[current], meta[current ^ 1]);
+            if !other.valid()
+                || other.frames.load(Acquire) != cur.frames.load(Acquire)
+                || other.reserved.load(Acquire) != cur.reserved.load(Acquire)
+                || other.crashed.load(Acquire) != cur.crashed.load(Acquire)
+            {
+                warn!("metadata copies disagree");
+            }
+            info!("recover from metadata copy {current}");
 
-        let init = if recover {
-            let frames = meta.frames.load(Acquire);
-            let crashed = meta.crashed.swap(true, AcqRel);
-            if meta.magic.load(Acquire) != Meta::MAGIC || frames != zone.len() {
+            if cur.frames.load(Acquire) != frames {
                 error!("no instance found");
                 return Err(Error::Initialization);
             }
+            if cur.reserved.load(Acquire) != reserved_head_frames {
+                error!("reserved head frames differ");
+                return Err(Error::Initialization);
+            }
+            let crashed = cur.crashed.load(Acquire);
+            Self::update(meta, frames, reserved_head_frames, true);
             Init::Recover(crashed)
         } else {
-            meta.magic.store(Meta::MAGIC, Release);
-            meta.frames.store(zone.len(), Release);
-            meta.crashed.store(true, Release);
+            // Copies left by a clean shutdown would skip the deep recovery
+            // of the lower allocator that is rewritten now
+            Self::invalidate(meta);
             Init::FreeAll
         };
 
-        let (zone, p) = zone.split_at_mut(zone.len() - m.lower.div_ceil(Frame::SIZE));
         let lower = unsafe { slice::from_raw_parts_mut(p.as_mut_ptr().cast(), m.lower) };
         let metadata = MetaData {
             local, trees, lower
@@ -169,7 +342,78 @@ impl<'a, A: Alloc<'a>> NvmAlloc<'a, A> {
             init,
             metadata,
         )?;
-        Ok(Self { alloc, meta })
+        if init == Init::FreeAll {
+            // Only mark the memory as initialized if this succeeded,
+            // a crash or failure before must not leave metadata that seems recoverable
+            Self::update(meta, frames, reserved_head_frames, true);
+        }
+        Ok(Self {
+            alloc,
+            meta,
+            reserved: reserved_head_frames,
+            init,
+        })
+    }
+
+    /// Returns the number of head frames that are not managed by the allocator,
+    /// including the alignment to the largest order
+    pub fn reserved_frames(&self) -> usize {
+        align_up(self.reserved, 1 << MAX_ORDER)
+    }
+
+    /// Returns how the allocator was initialized, which is [Init::Recover]
+    /// with `true` if the previous instance was not shut down cleanly.
+    pub fn init(&self) -> Init {
+        self.init
+    }
+
+    /// Returns the index of the valid metadata copy with the highest generation
+    fn current(meta: [&Meta; 2]) -> Option<usize> {
+        match (meta[0].valid(), meta[1].valid()) {
+            (true, true) => {
+                let newer = meta[1].generation.load(Acquire) > meta[0].generation.load(Acquire);
+                Some(newer as usize)
+            }
+            (true, false) => Some(0),
+            (false, true) => Some(1),
+            (false, false) => None,
+        }
+    }
+
+    /// Invalidates both metadata copies, so that they are not recovered.
+    fn invalidate(meta: [&Meta; 2]) {
+        for copy in meta {
+            copy.magic.store(0, Release);
+            copy.crc.store(0, Release);
+            #[cfg(feature = "pmem_flush")]
+            unsafe {
+                crate::util::persist((copy as *const Meta).cast(), 1);
+            }
+        }
+    }
+
+    /// Updates both metadata copies, the older one first.
+    ///
+    /// If this is interrupted, one copy is still valid.
+    fn update(meta: [&Meta; 2], frames: usize, reserved: usize, crashed: bool) {
+        let (mut generation, older) = match Self::current(meta) {
+            Some(i) => (meta[i].generation.load(Acquire), i ^ 1),
+            None => (0, 0),
+        };
+        for copy in [meta[older], meta[older ^ 1]] {
+            generation += 1;
+            copy.magic.store(Meta::MAGIC, Release);
+            copy.frames.store(frames, Release);
+            copy.reserved.store(reserved, Release);
+            copy.crashed.store(crashed, Release);
+            copy.generation.store(generation, Release);
+            copy.crc.store(copy.checksum(), Release);
+            // The fields fit into the first cache line
+            #[cfg(feature = "pmem_flush")]
+            unsafe {
+                crate::util::persist((copy as *const Meta).cast(), 1);
+            }
+        }
     }
 }
 
@@ -191,12 +435,34 @@ impl<'a, A: Alloc<'a>> Alloc<'a> for NvmAlloc<'a, A> {
     fn metadata(&mut self) -> MetaData<'a> {
         self.alloc.metadata()
     }
+    #[cfg(feature = "std")]
+    fn snapshot(&self) -> Option<crate::AllocSnapshot> {
+        self.alloc.snapshot()
+    }
     fn get(&self, core: usize, flags: Flags) -> Result<usize> {
         self.alloc.get(core, flags)
     }
     fn put(&self, core: usize, frame: usize, flags: Flags) -> Result<()> {
         self.alloc.put(core, frame, flags)
     }
+    fn put_report(&self, core: usize, frame: usize, flags: Flags) -> Result<PutResult> {
+        self.alloc.put_report(core, frame, flags)
+    }
+    fn try_resize(
+        &self,
+        core: usize,
+        frame: usize,
+        order: usize,
+        new_order: usize,
+    ) -> Result<usize> {
+        self.alloc.try_resize(core, frame, order, new_order)
+    }
+    fn max_order(&self) -> usize {
+        self.alloc.max_order()
+    }
+    fn is_initialized(&self) -> bool {
+        self.alloc.is_initialized()
+    }
     fn frames(&self) -> usize {
         self.alloc.frames()
     }
@@ -212,12 +478,49 @@ impl<'a, A: Alloc<'a>> Alloc<'a> for NvmAlloc<'a, A> {
     fn is_free(&self, frame: usize, order: usize) -> bool {
         self.alloc.is_free(frame, order)
     }
+    fn free_frames_below(&self, boundary: usize) -> usize {
+        self.alloc.free_frames_below(boundary)
+    }
+    fn allocated_frames_in(&self, frames: Range<usize>) -> usize {
+        self.alloc.allocated_frames_in(frames)
+    }
+    fn count_allocatable_spans(&self, buf: &mut [usize]) {
+        self.alloc.count_allocatable_spans(buf)
+    }
     fn free_at(&self, frame: usize, order: usize) -> usize {
         self.alloc.free_at(frame, order)
     }
     fn drain(&self, core: usize) -> Result<()> {
         self.alloc.drain(core)
     }
+    #[cfg(feature = "latency")]
+    fn histograms(&self) -> Option<crate::latency::Histograms> {
+        self.alloc.histograms()
+    }
+}
+
+impl<'a, A: Alloc<'a>> sealed::Sealed for NvmAlloc<'a, A> {}
+impl<'a, A: Alloc<'a>> Persistent<'a> for NvmAlloc<'a, A> {
+    fn recover(&self) -> Result<()> {
+        match Self::current(self.meta) {
+            Some(i)
+                if self.meta[i].frames.load(Acquire) == self.alloc.frames()
+                    && self.meta[i].reserved.load(Acquire) == self.reserved =>
+            {
+                Ok(())
+            }
+            Some(i) => {
+                error!("invalid recovery metadata, different instance in copy {i}");
+                Err(Error::Corruption)
+            }
+            None => {
+                for (i, copy) in self.meta.iter().enumerate() {
+                    error!("metadata copy {i}: {}", copy.diagnose(self.alloc.frames()));
+                }
+                Err(Error::Corruption)
+            }
+        }
+    }
 }
 
 impl<'a, A: Alloc<'a>> fmt::Debug for NvmAlloc<'a, A> {
@@ -228,6 +531,1144 @@ impl<'a, A: Alloc<'a>> fmt::Debug for NvmAlloc<'a, A> {
 
 impl<'a, A: Alloc<'a>> Drop for NvmAlloc<'a, A> {
     fn drop(&mut self) {
+        Self::update(self.meta, self.alloc.frames(), self.reserved, false);
+    }
+}
+
+/// Persistent memory allocator, that keeps its metadata in volatile memory.
+///
+/// Only the frees are recorded in a persistent log, which is replayed over the
+/// last [NvmLogAlloc::checkpoint] on recovery.
+/// Allocations are not logged, frames allocated after the last checkpoint
+/// are free again after a crash.
+/// If more frames are freed between two checkpoints than the log can hold,
+/// the frees fail with [Error::Memory] until the next checkpoint.
+///
+/// ## Memory Layout
+/// ```text
+/// NVRAM: [ Frames | Checkpoints | Log | Meta ]
+/// ```
+pub struct NvmLogAlloc<'a, A: Alloc<'a>> {
+    pub alloc: ZoneAlloc<'a, A>,
+    meta: &'a Meta,
+    log: &'a [LogRecord],
+    /// Two areas, so that a crash during a checkpoint does not corrupt the previous one
+    checkpoints: [&'a mut [u8]; 2],
+    /// Serializes the writers of the log
+    lock: SpinMutex<()>,
+}
+
+/// Persistent record of a free operation
+#[repr(C)]
+struct LogRecord {
+    /// Sequence number, zero is invalid
+    seq: AtomicUsize,
+    /// Frame and order of the free
+    entry: AtomicUsize,
+}
+
+impl<'a, A: Alloc<'a>> NvmLogAlloc<'a, A> {
+    /// Number of frames reserved for the log
+    pub const LOG_FRAMES: usize = 16;
+    const LOG_LEN: usize = Self::LOG_FRAMES * Frame::SIZE / size_of::<LogRecord>();
+
+    /// Create a new allocator or recover it from the `zone`.
+    ///
+    /// Opposed to [NvmAlloc], all metadata is allocated in volatile memory.
+    pub fn create(
+        cores: usize,
+        zone: &'a mut [Frame],
+        recover: bool,
+        meta: MetaData<'a>,
+    ) -> Result<Self> {
+        let m = A::metadata_size(cores, zone.len());
+        let checkpoint_frames = m.lower.div_ceil(Frame::SIZE);
+        if zone.len() <= 2 * checkpoint_frames + Self::LOG_FRAMES + 1
+            || zone.as_ptr() as usize % (Frame::SIZE << MAX_ORDER) != 0
+        {
+            error!("invalid memory region");
+            return Err(Error::Initialization);
+        }
+
+        let (p_meta, zone) = zone.split_last_mut().ok_or(Error::Memory)?;
+        let p_meta = p_meta.cast::<Meta>();
+
+        let (zone, log) = zone.split_at_mut(zone.len() - Self::LOG_FRAMES);
+        let log = unsafe { slice::from_raw_parts(log.as_ptr().cast(), Self::LOG_LEN) };
+
+        let (zone, p) = zone.split_at_mut(zone.len() - 2 * checkpoint_frames);
+        let (a, b) = p.split_at_mut(checkpoint_frames);
+        let checkpoints = [a, b]
+            .map(|c| unsafe { slice::from_raw_parts_mut(c.as_mut_ptr().cast::<u8>(), m.lower) });
+
+        let offset = zone.as_ptr() as usize / Frame::SIZE;
+        let frames = zone.len();
+        if recover {
+            if p_meta.magic.load(Acquire) != Meta::MAGIC_LOG
+                || p_meta.frames.load(Acquire) != frames
+            {
+                error!("no instance found");
+                return Err(Error::Initialization);
+            }
+            let crashed = p_meta.crashed.swap(true, AcqRel);
+            Self::persist(&p_meta.crashed);
+            let checkpoint = p_meta.checkpoint.load(Acquire);
+            meta.lower[..m.lower].copy_from_slice(checkpoints[checkpoint & 1]);
+
+            let alloc = ZoneAlloc::create(cores, offset, frames, Init::Recover(crashed), meta)?;
+            let mut alloc = Self {
+                alloc,
+                meta: p_meta,
+                log,
+                checkpoints,
+                lock: SpinMutex::new(()),
+            };
+            alloc.replay(checkpoint >> 1);
+            alloc.checkpoint();
+            Ok(alloc)
+        } else {
+            p_meta.magic.store(Meta::MAGIC_LOG, Release);
+            p_meta.frames.store(frames, Release);
+            p_meta.crashed.store(true, Release);
+            p_meta.seq.store(0, Release);
+            for record in log {
+                record.seq.store(0, Release);
+            }
+            Self::persist(log);
+            Self::persist(p_meta);
+
+            let alloc = ZoneAlloc::create(cores, offset, frames, Init::FreeAll, meta)?;
+            let mut alloc = Self {
+                alloc,
+                meta: p_meta,
+                log,
+                checkpoints,
+                lock: SpinMutex::new(()),
+            };
+            alloc.checkpoint();
+            Ok(alloc)
+        }
+    }
+
+    /// Persist the volatile metadata and truncate the log.
+    pub fn checkpoint(&mut self) {
+        let seq = self.meta.seq.load(Acquire);
+        // Write the currently unused area
+        let area = (self.meta.checkpoint.load(Acquire) & 1) ^ 1;
+        let lower = self.alloc.metadata().lower;
+        let len = self.checkpoints[area].len();
+        self.checkpoints[area].copy_from_slice(&lower[..len]);
+        // The area has to be durable before it is referenced
+        Self::persist(&*self.checkpoints[area]);
+        self.meta.checkpoint.store(seq << 1 | area, Release);
+        Self::persist(&self.meta.checkpoint);
+    }
+
+    /// Checks that `records` can be appended to the log.
+    ///
+    /// Fails with [Error::Memory] if this would overwrite records
+    /// that are newer than the last checkpoint.
+    /// The caller has to hold the lock until they are appended.
+    fn log_reserve(&self, records: usize) -> Result<()> {
+        let seq = self.meta.seq.load(Acquire) + records;
+        if seq - (self.meta.checkpoint.load(Acquire) >> 1) > self.log.len() {
+            warn!("log full, a checkpoint is required");
+            return Err(Error::Memory);
+        }
+        Ok(())
+    }
+
+    /// Append a free to the log, the caller has to hold the lock.
+    fn log(&self, frame: usize, order: usize) {
+        let seq = self.meta.seq.load(Acquire) + 1;
+        let record = &self.log[seq % self.log.len()];
+        // Invalidate the record first, so that partial updates are ignored
+        record.seq.store(0, Release);
+        let entry = (frame - self.alloc.offset) << 8 | order;
+        record.entry.store(entry, Release);
+        record.seq.store(seq, Release);
+        // The record has to be durable before it is counted
+        Self::persist(record);
+        self.meta.seq.store(seq, Release);
+        Self::persist(&self.meta.seq);
+    }
+
+    /// Replay the frees that happened after the `checkpoint`, in the order of their sequence numbers.
+    ///
+    /// The records are written one after another, so the log ends at the first missing sequence number.
+    fn replay(&self, checkpoint: usize) {
+        let mut seq = checkpoint + 1;
+        loop {
+            let record = &self.log[seq % self.log.len()];
+            if record.seq.load(Acquire) != seq {
+                break;
+            }
+            let entry = record.entry.load(Acquire);
+            let (frame, order) = (entry >> 8, entry & 0xff);
+            if let Err(e) = self.alloc.alloc.put(0, frame, Flags::o(order)) {
+                warn!("replay failed s={seq} f={frame} o={order}: {e:?}");
+            }
+            seq += 1;
+        }
+        // Continue after the replayed records, even if their count was not persisted
+        self.meta.seq.store(seq - 1, Release);
+    }
+
+    /// Flushes `value` from the caches to persistent memory
+    #[allow(unused_variables)]
+    fn persist<T: ?Sized>(value: &T) {
+        #[cfg(feature = "pmem_flush")]
+        unsafe {
+            crate::util::persist((value as *const T).cast(), size_of_val(value));
+        }
+    }
+}
+
+impl<'a, A: Alloc<'a>> Alloc<'a> for NvmLogAlloc<'a, A> {
+    fn name() -> &'static str {
+        A::name()
+    }
+    fn new(_cores: usize, _frames: usize, _init: Init, _meta: MetaData) -> Result<Self> {
+        // The log and checkpoints are placed in the persistent zone, see `create`
+        error!("{} has to be created from a persistent zone", Self::name());
+        Err(Error::Initialization)
+    }
+    fn metadata_size(cores: usize, frames: usize) -> MetaSize {
+        A::metadata_size(cores, frames)
+    }
+    fn metadata(&mut self) -> MetaData<'a> {
+        self.alloc.metadata()
+    }
+    fn get(&self, core: usize, flags: Flags) -> Result<usize> {
+        self.alloc.get(core, flags)
+    }
+    fn put(&self, core: usize, frame: usize, flags: Flags) -> Result<()> {
+        let _guard = self.lock.lock();
+        self.log_reserve(1)?;
+        self.alloc.put(core, frame, flags)?;
+        self.log(frame, flags.order());
+        Ok(())
+    }
+    fn try_resize(
+        &self,
+        core: usize,
+        frame: usize,
+        order: usize,
+        new_order: usize,
+    ) -> Result<usize> {
+        if new_order >= order {
+            // Allocations are not logged
+            return self.alloc.try_resize(core, frame, order, new_order);
+        }
+        let _guard = self.lock.lock();
+        self.log_reserve(order - new_order)?;
+        let frame = self.alloc.try_resize(core, frame, order, new_order)?;
+        // The freed tail consists of one frame of every order in between
+        for o in new_order..order {
+            self.log(frame + (1 << o), o);
+        }
+        Ok(frame)
+    }
+    fn max_order(&self) -> usize {
+        self.alloc.max_order()
+    }
+    fn is_initialized(&self) -> bool {
+        self.alloc.is_initialized()
+    }
+    fn frames(&self) -> usize {
+        self.alloc.frames()
+    }
+    fn cores(&self) -> usize {
+        self.alloc.cores()
+    }
+    fn free_frames(&self) -> usize {
+        self.alloc.free_frames()
+    }
+    fn free_huge(&self) -> usize {
+        self.alloc.free_huge()
+    }
+    fn is_free(&self, frame: usize, order: usize) -> bool {
+        self.alloc.is_free(frame, order)
+    }
+    fn free_frames_below(&self, boundary: usize) -> usize {
+        self.alloc.free_frames_below(boundary)
+    }
+    fn allocated_frames_in(&self, frames: Range<usize>) -> usize {
+        self.alloc.allocated_frames_in(frames)
+    }
+    fn count_allocatable_spans(&self, buf: &mut [usize]) {
+        self.alloc.count_allocatable_spans(buf)
+    }
+    fn free_at(&self, frame: usize, order: usize) -> usize {
+        self.alloc.free_at(frame, order)
+    }
+    fn drain(&self, core: usize) -> Result<()> {
+        self.alloc.drain(core)
+    }
+    #[cfg(feature = "latency")]
+    fn histograms(&self) -> Option<crate::latency::Histograms> {
+        self.alloc.histograms()
+    }
+}
+
+impl<'a, A: Alloc<'a>> sealed::Sealed for NvmLogAlloc<'a, A> {}
+impl<'a, A: Alloc<'a>> Persistent<'a> for NvmLogAlloc<'a, A> {
+    fn recover(&self) -> Result<()> {
+        if self.meta.magic.load(Acquire) != Meta::MAGIC_LOG
+            || self.meta.frames.load(Acquire) != self.alloc.frames()
+        {
+            error!("invalid recovery metadata");
+            return Err(Error::Corruption);
+        }
+        Ok(())
+    }
+}
+
+impl<'a, A: Alloc<'a>> fmt::Debug for NvmLogAlloc<'a, A> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        self.alloc.fmt(f)
+    }
+}
+
+impl<'a, A: Alloc<'a>> Drop for NvmLogAlloc<'a, A> {
+    fn drop(&mut self) {
+        self.checkpoint();
         self.meta.crashed.store(false, Release);
+        Self::persist(&self.meta.crashed);
+    }
+}
+
+/// Tiered allocator, that combines a fast (DRAM) and slow (PMEM) allocator.
+///
+/// All frames below `boundary` belong to the fast allocator, the others to the slow one.
+/// Allocations prefer the fast allocator and fall back to the slow one.
+///
+/// The slow allocator, usually a [ZoneAlloc], starts at the `boundary`.
+/// The frames between the end of the fast allocator and the `boundary` are not managed.
+pub struct TieredAlloc<'a, Fast: Alloc<'a>, Slow: Alloc<'a>> {
+    pub fast: Fast,
+    pub slow: Slow,
+    pub boundary: usize,
+    /// Frames promoted by [TieredAlloc::balance] minus the demoted ones
+    hot_count: AtomicIsize,
+    _p: PhantomData<&'a ()>,
+}
+
+impl<'a, Fast: Alloc<'a>, Slow: Alloc<'a>> TieredAlloc<'a, Fast, Slow> {
+    /// Combine the tiers, failing if they overlap at the `boundary`.
+    pub fn create(fast: Fast, slow: Slow, boundary: usize) -> Result<Self> {
+        if fast.frames() > boundary {
+            error!("fast tier exceeds the boundary {boundary}");
+            return Err(Error::Initialization);
+        }
+        if slow.free_frames_below(boundary) > 0 || slow.allocated_frames_in(0..boundary) > 0 {
+            error!("slow tier starts below the boundary {boundary}");
+            return Err(Error::Initialization);
+        }
+        Ok(Self {
+            fast,
+            slow,
+            boundary,
+            hot_count: AtomicIsize::new(0),
+            _p: PhantomData,
+        })
+    }
+
+    /// Return the net number of frames that [TieredAlloc::balance] moved into the fast tier.
+    ///
+    /// Promoted frames increase this counter, demoted ones decrease it.
+    /// Other allocations and frees do not change it, as they stay in their tier.
+    pub fn hot_count(&self) -> isize {
+        self.hot_count.load(Relaxed)
+    }
+
+    /// Return the number of free frames in the fast tier.
+    #[cold]
+    pub fn hot_free_frames(&self) -> usize {
+        self.free_frames_below(self.boundary)
+    }
+    /// Return the number of free frames in the slow tier.
+    #[cold]
+    pub fn cold_free_frames(&self) -> usize {
+        self.free_frames() - self.hot_free_frames()
+    }
+
+    /// Migrates base frames between the tiers until `hot_fraction` of `frames` are in the fast tier.
+    ///
+    /// As the allocator does not know the users of the frames, `migrate(old, new)` has to
+    /// copy the content and update all references, or return false to keep the old frame.
+    /// Migrated frames are replaced in `frames`. Returns the number of migrated frames.
+    /// If an old frame cannot be freed, it is still replaced and the error is returned.
+    ///
+    /// This can be called periodically from a background thread.
+    pub fn balance(
+        &self,
+        core: usize,
+        hot_fraction: f64,
+        frames: &mut [usize],
+        mut migrate: impl FnMut(usize, usize) -> bool,
+    ) -> Result<usize> {
+        let target = (frames.len() as f64 * hot_fraction.clamp(0.0, 1.0)) as usize;
+        let mut fast = frames.iter().filter(|f| **f < self.boundary).count();
+        let mut moved = 0;
+
+        for frame in frames {
+            if fast == target {
+                break;
+            }
+            let promote = fast < target;
+            if (*frame < self.boundary) == promote {
+                continue;
+            }
+
+            let new = if promote {
+                self.fast.get(core, Flags::o(0))
+            } else {
+                self.slow.get(core, Flags::o(0))
+            };
+            let new = match new {
+                Ok(new) => new,
+                Err(Error::Memory | Error::Fragmented) => break,
+                Err(e) => return Err(e),
+            };
+
+            if migrate(*frame, new) {
+                // The new frame is in use now, even if the old one cannot be freed
+                let old = core::mem::replace(frame, new);
+                moved += 1;
+                if promote {
+                    fast += 1;
+                    self.hot_count.fetch_add(1, Relaxed);
+                } else {
+                    fast -= 1;
+                    self.hot_count.fetch_sub(1, Relaxed);
+                }
+                self.put(core, old, Flags::o(0))?;
+            } else {
+                self.put(core, new, Flags::o(0))?;
+            }
+        }
+        Ok(moved)
+    }
+}
+
+impl<'a, Fast: Alloc<'a>, Slow: Alloc<'a>> Alloc<'a> for TieredAlloc<'a, Fast, Slow> {
+    fn name() -> &'static str {
+        Fast::name()
+    }
+    fn new(_cores: usize, _frames: usize, _init: Init, _meta: MetaData) -> Result<Self> {
+        // The tiers are initialized separately, see `create`
+        error!("{} has to be created from its tiers", Self::name());
+        Err(Error::Initialization)
+    }
+    /// Upper bound for the metadata of both tiers, which together manage `frames`.
+    fn metadata_size(cores: usize, frames: usize) -> MetaSize {
+        let fast = Fast::metadata_size(cores, frames);
+        let slow = Slow::metadata_size(cores, frames);
+        MetaSize {
+            local: fast.local + slow.local,
+            trees: fast.trees + slow.trees,
+            lower: fast.lower + slow.lower,
+        }
+    }
+    /// Not supported, as the tiers own their metadata, this returns empty buffers.
+    ///
+    /// Use `fast.metadata()` and `slow.metadata()` instead, e.g., for introspection.
+    fn metadata(&mut self) -> MetaData<'a> {
+        MetaData {
+            local: &mut [],
+            trees: &mut [],
+            lower: &mut [],
+        }
+    }
+    fn get(&self, core: usize, flags: Flags) -> Result<usize> {
+        match self.fast.get(core, flags) {
+            Err(Error::Memory | Error::Fragmented) => self.slow.get(core, flags),
+            r => r,
+        }
+    }
+    fn put(&self, core: usize, frame: usize, flags: Flags) -> Result<()> {
+        if frame < self.boundary {
+            self.fast.put(core, frame, flags)
+        } else {
+            self.slow.put(core, frame, flags)
+        }
+    }
+    fn try_resize(
+        &self,
+        core: usize,
+        frame: usize,
+        order: usize,
+        new_order: usize,
+    ) -> Result<usize> {
+        if frame < self.boundary {
+            self.fast.try_resize(core, frame, order, new_order)
+        } else {
+            self.slow.try_resize(core, frame, order, new_order)
+        }
+    }
+    fn max_order(&self) -> usize {
+        self.fast.max_order().max(self.slow.max_order())
+    }
+    fn is_initialized(&self) -> bool {
+        self.fast.is_initialized() && self.slow.is_initialized()
+    }
+    /// Number of managed frames, without the unmanaged gap below the `boundary`.
+    ///
+    /// The managed frames end at `boundary + slow.frames()`.
+    fn frames(&self) -> usize {
+        self.fast.frames() + self.slow.frames()
+    }
+    fn cores(&self) -> usize {
+        self.fast.cores().max(self.slow.cores())
+    }
+    fn free_frames(&self) -> usize {
+        self.fast.free_frames() + self.slow.free_frames()
+    }
+    fn free_huge(&self) -> usize {
+        self.fast.free_huge() + self.slow.free_huge()
+    }
+    fn is_free(&self, frame: usize, order: usize) -> bool {
+        if frame < self.boundary {
+            self.fast.is_free(frame, order)
+        } else {
+            self.slow.is_free(frame, order)
+        }
+    }
+    fn free_frames_below(&self, boundary: usize) -> usize {
+        // The slow tier starts at the boundary
+        self.fast.free_frames_below(boundary.min(self.boundary))
+            + self.slow.free_frames_below(boundary)
+    }
+    fn allocated_frames_in(&self, frames: Range<usize>) -> usize {
+        let (start, end) = (frames.start, frames.end);
+        let fast = start.min(self.boundary)..end.min(self.boundary);
+        let slow = start.max(self.boundary)..end.max(self.boundary);
+        self.fast.allocated_frames_in(fast) + self.slow.allocated_frames_in(slow)
+    }
+    fn count_allocatable_spans(&self, buf: &mut [usize]) {
+        self.fast.count_allocatable_spans(buf);
+        let mut slow = [0; MAX_ORDER + 1];
+        let slow = &mut slow[..buf.len().min(MAX_ORDER + 1)];
+        self.slow.count_allocatable_spans(slow);
+        for (count, slow) in buf.iter_mut().zip(slow.iter()) {
+            *count += slow;
+        }
+    }
+    fn free_at(&self, frame: usize, order: usize) -> usize {
+        if frame < self.boundary {
+            self.fast.free_at(frame, order)
+        } else {
+            self.slow.free_at(frame, order)
+        }
+    }
+    fn drain(&self, core: usize) -> Result<()> {
+        self.fast.drain(core)?;
+        self.slow.drain(core)
+    }
+    fn validate(&self) {
+        self.fast.validate();
+        self.slow.validate();
+    }
+}
+
+impl<'a, Fast: Alloc<'a>, Slow: Alloc<'a>> fmt::Debug for TieredAlloc<'a, Fast, Slow> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("TieredAlloc")
+            .field("fast", &self.fast)
+            .field("slow", &self.slow)
+            .field("boundary", &self.boundary)
+            .field("hot_count", &self.hot_count())
+            .finish()
+    }
+}
+
+/// Compound frame allocator, that remembers the order of its allocations.
+///
+/// Frames of order > 0 (compound frames) can only be freed as a whole with their original order.
+/// This is a safety layer against freeing single tail frames or using a wrong order.
+#[cfg(feature = "std")]
+pub struct CompoundAlloc<'a, A: Alloc<'a>> {
+    pub alloc: A,
+    /// Head frame to order of the compound frames
+    compound: SpinMutex<std::collections::BTreeMap<usize, usize>>,
+    _p: PhantomData<&'a ()>,
+}
+
+#[cfg(feature = "std")]
+impl<'a, A: Alloc<'a>> CompoundAlloc<'a, A> {
+    pub fn create(alloc: A) -> Self {
+        Self {
+            alloc,
+            compound: SpinMutex::new(std::collections::BTreeMap::new()),
+            _p: PhantomData,
+        }
+    }
+
+    /// Allocate a compound frame of `order`, returning its head frame.
+    pub fn get_compound(&self, core: usize, order: usize) -> Result<usize> {
+        self.get(core, Flags::o(order))
+    }
+    /// Free the compound frame at `head`, which has to match the allocation `order`.
+    pub fn put_compound(&self, core: usize, head: usize, order: usize) -> Result<()> {
+        self.put(core, head, Flags::o(order))
+    }
+
+    /// Returns the head and order of the compound frame containing `frame`, if any.
+    pub fn compound_order(&self, frame: usize) -> Option<(usize, usize)> {
+        let compound = self.compound.lock();
+        let (&head, &order) = compound.range(..=frame).next_back()?;
+        (frame < head + (1 << order)).then_some((head, order))
+    }
+
+    /// Check that the frame is freed with its original order and stop tracking it
+    fn untrack(&self, frame: usize, order: usize) -> Result<()> {
+        let mut compound = self.compound.lock();
+        match compound.range(..=frame).next_back() {
+            Some((&head, &o)) if head == frame && o == order => {
+                compound.remove(&head);
+                Ok(())
+            }
+            Some((&head, &o)) if frame < head + (1 << o) => {
+                error!("invalid free {frame} o={order} of compound {head} o={o}");
+                Err(Error::Address)
+            }
+            _ if order > 0 => {
+                error!("invalid free {frame} o={order} of no compound");
+                Err(Error::Address)
+            }
+            _ => Ok(()),
+        }
+    }
+}
+
+#[cfg(feature = "std")]
+impl<'a, A: Alloc<'a>> Alloc<'a> for CompoundAlloc<'a, A> {
+    fn name() -> &'static str {
+        A::name()
+    }
+    fn new(cores: usize, frames: usize, init: Init, meta: MetaData<'a>) -> Result<Self> {
+        Ok(Self::create(A::new(cores, frames, init, meta)?))
+    }
+    fn metadata_size(cores: usize, frames: usize) -> MetaSize {
+        A::metadata_size(cores, frames)
+    }
+    fn metadata(&mut self) -> MetaData<'a> {
+        self.alloc.metadata()
+    }
+    fn get(&self, core: usize, flags: Flags) -> Result<usize> {
+        let frame = self.alloc.get(core, flags)?;
+        if flags.order() > 0 {
+            self.compound.lock().insert(frame, flags.order());
+        }
+        Ok(frame)
+    }
+    fn put(&self, core: usize, frame: usize, flags: Flags) -> Result<()> {
+        self.put_report(core, frame, flags).map(|_| ())
+    }
+    fn put_report(&self, core: usize, frame: usize, flags: Flags) -> Result<PutResult> {
+        self.untrack(frame, flags.order())?;
+        let result = self.alloc.put_report(core, frame, flags);
+        if result.is_err() && flags.order() > 0 {
+            self.compound.lock().insert(frame, flags.order());
+        }
+        result
+    }
+    fn try_resize(
+        &self,
+        core: usize,
+        frame: usize,
+        order: usize,
+        new_order: usize,
+    ) -> Result<usize> {
+        // Only whole compound frames can be resized
+        self.untrack(frame, order)?;
+        let result = self.alloc.try_resize(core, frame, order, new_order);
+        let (head, order) = match result {
+            Ok(head) => (head, new_order),
+            Err(_) => (frame, order),
+        };
+        if order > 0 {
+            self.compound.lock().insert(head, order);
+        }
+        result
+    }
+    fn max_order(&self) -> usize {
+        self.alloc.max_order()
+    }
+    fn is_initialized(&self) -> bool {
+        self.alloc.is_initialized()
+    }
+    fn frames(&self) -> usize {
+        self.alloc.frames()
+    }
+    fn cores(&self) -> usize {
+        self.alloc.cores()
+    }
+    fn free_frames(&self) -> usize {
+        self.alloc.free_frames()
+    }
+    fn free_huge(&self) -> usize {
+        self.alloc.free_huge()
+    }
+    fn is_free(&self, frame: usize, order: usize) -> bool {
+        self.alloc.is_free(frame, order)
+    }
+    fn free_frames_below(&self, boundary: usize) -> usize {
+        self.alloc.free_frames_below(boundary)
+    }
+    fn allocated_frames_in(&self, frames: Range<usize>) -> usize {
+        self.alloc.allocated_frames_in(frames)
+    }
+    fn count_allocatable_spans(&self, buf: &mut [usize]) {
+        self.alloc.count_allocatable_spans(buf)
+    }
+    fn free_at(&self, frame: usize, order: usize) -> usize {
+        self.alloc.free_at(frame, order)
+    }
+    fn drain(&self, core: usize) -> Result<()> {
+        self.alloc.drain(core)
+    }
+    #[cfg(feature = "latency")]
+    fn histograms(&self) -> Option<crate::latency::Histograms> {
+        self.alloc.histograms()
+    }
+    fn validate(&self) {
+        self.alloc.validate()
+    }
+}
+
+#[cfg(feature = "std")]
+impl<'a, A: Alloc<'a>> fmt::Debug for CompoundAlloc<'a, A> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        self.alloc.fmt(f)
+    }
+}
+
+/// Allocator wrapper, that lets allocations wait for frees instead of failing with [Error::Memory]
+/// or [Error::Fragmented].
+///
+/// Waiting allocations are queued (best-effort FIFO) and woken by frees of at least their order.
+/// Wakeups might be spurious, the allocation is retried after every wakeup.
+#[cfg(feature = "std")]
+pub struct BlockingAlloc<'a, A: Alloc<'a>> {
+    pub alloc: A,
+    /// Orders and signals of the waiting allocations
+    waiters: SpinMutex<std::collections::VecDeque<(usize, std::sync::Arc<Signal>)>>,
+    _p: PhantomData<&'a ()>,
+}
+
+#[cfg(feature = "std")]
+impl<'a, A: Alloc<'a>> BlockingAlloc<'a, A> {
+    pub fn create(alloc: A) -> Self {
+        Self {
+            alloc,
+            waiters: SpinMutex::new(std::collections::VecDeque::new()),
+            _p: PhantomData,
+        }
+    }
+
+    /// Allocate a frame of `order`, blocking until enough memory is freed.
+    ///
+    /// Returns [Error::Memory] if nothing was freed within the `timeout`.
+    pub fn get_blocking(
+        &self,
+        core: usize,
+        order: usize,
+        timeout: std::time::Duration,
+    ) -> Result<usize> {
+        let deadline = std::time::Instant::now() + timeout;
+        loop {
+            let waiting = match self.attempt(core, Flags::o(order))? {
+                Ok(frame) => return Ok(frame),
+                Err(waiting) => waiting,
+            };
+            let remaining = deadline.saturating_duration_since(std::time::Instant::now());
+            if !waiting.signal.wait(remaining) {
+                return Err(Error::Memory);
+            }
+        }
+    }
+
+    /// Allocate a frame of `order`, waiting until enough memory is freed.
+    ///
+    /// The returned future is woken by the frees and does not depend on any runtime.
+    pub async fn get_async(&self, core: usize, order: usize) -> Result<usize> {
+        loop {
+            let waiting = match self.attempt(core, Flags::o(order))? {
+                Ok(frame) => return Ok(frame),
+                Err(waiting) => waiting,
+            };
+            core::future::poll_fn(|cx| waiting.signal.poll(cx)).await;
+        }
+    }
+
+    /// Try to allocate a frame, registering a waiter if out of memory.
+    fn attempt(
+        &self,
+        core: usize,
+        flags: Flags,
+    ) -> Result<core::result::Result<usize, Waiting<'_, 'a, A>>> {
+        match self.alloc.get(core, flags) {
+            Err(Error::Memory | Error::Fragmented) => {}
+            result => return result.map(Ok),
+        }
+        let signal = std::sync::Arc::new(Signal::default());
+        self.waiters
+            .lock()
+            .push_back((flags.order(), signal.clone()));
+        let waiting = Waiting {
+            alloc: self,
+            signal,
+        };
+        // Retry, as the frame might have been freed before the registration
+        match self.alloc.get(core, flags) {
+            Err(Error::Memory | Error::Fragmented) => Ok(Err(waiting)),
+            result => {
+                // Pass the wakeup on, if we have already been woken
+                if !waiting.unregister() {
+                    self.wake(flags.order());
+                }
+                result.map(Ok)
+            }
+        }
+    }
+
+    /// Wake the first waiter that is satisfied by a free of `order`
+    fn wake(&self, order: usize) {
+        let waiter = {
+            let mut waiters = self.waiters.lock();
+            let i = waiters.iter().position(|(o, _)| *o <= order);
+            i.and_then(|i| waiters.remove(i))
+        };
+        if let Some((_, signal)) = waiter {
+            signal.wake();
+        }
+    }
+}
+
+/// Notification for a waiting allocation of a [BlockingAlloc]
+#[cfg(feature = "std")]
+#[derive(Default)]
+struct Signal {
+    woken: std::sync::Mutex<bool>,
+    cond: std::sync::Condvar,
+    waker: std::sync::Mutex<Option<core::task::Waker>>,
+}
+
+#[cfg(feature = "std")]
+impl Signal {
+    fn wake(&self) {
+        *self.woken.lock().unwrap() = true;
+        self.cond.notify_all();
+        if let Some(waker) = self.waker.lock().unwrap().take() {
+            waker.wake();
+        }
+    }
+    /// Block until woken, returns false on timeout
+    fn wait(&self, timeout: std::time::Duration) -> bool {
+        let woken = self.woken.lock().unwrap();
+        let (woken, _) = (self.cond)
+            .wait_timeout_while(woken, timeout, |woken| !*woken)
+            .unwrap();
+        *woken
+    }
+    fn poll(&self, cx: &mut core::task::Context<'_>) -> core::task::Poll<()> {
+        // Register the waker before checking, so that no wakeup is lost
+        *self.waker.lock().unwrap() = Some(cx.waker().clone());
+        if *self.woken.lock().unwrap() {
+            core::task::Poll::Ready(())
+        } else {
+            core::task::Poll::Pending
+        }
+    }
+}
+
+/// Registered waiter, that is removed from the queue if dropped before being woken
+#[cfg(feature = "std")]
+struct Waiting<'b, 'a, A: Alloc<'a>> {
+    alloc: &'b BlockingAlloc<'a, A>,
+    signal: std::sync::Arc<Signal>,
+}
+
+#[cfg(feature = "std")]
+impl<'a, A: Alloc<'a>> Waiting<'_, 'a, A> {
+    /// Remove the waiter from the queue, returns false if it has already been woken
+    fn unregister(&self) -> bool {
+        let mut waiters = self.alloc.waiters.lock();
+        if let Some(i) = waiters
+            .iter()
+            .position(|(_, s)| std::sync::Arc::ptr_eq(s, &self.signal))
+        {
+            waiters.remove(i);
+            true
+        } else {
+            false
+        }
+    }
+}
+
+#[cfg(feature = "std")]
+impl<'a, A: Alloc<'a>> Drop for Waiting<'_, 'a, A> {
+    fn drop(&mut self) {
+        self.unregister();
+    }
+}
+
+#[cfg(feature = "std")]
+impl<'a, A: Alloc<'a>> Alloc<'a> for BlockingAlloc<'a, A> {
+    fn name() -> &'static str {
+        A::name()
+    }
+    fn new(cores: usize, frames: usize, init: Init, meta: MetaData<'a>) -> Result<Self> {
+        Ok(Self::create(A::new(cores, frames, init, meta)?))
+    }
+    fn metadata_size(cores: usize, frames: usize) -> MetaSize {
+        A::metadata_size(cores, frames)
+    }
+    fn metadata(&mut self) -> MetaData<'a> {
+        self.alloc.metadata()
+    }
+    fn get(&self, core: usize, flags: Flags) -> Result<usize> {
+        self.alloc.get(core, flags)
+    }
+    fn put(&self, core: usize, frame: usize, flags: Flags) -> Result<()> {
+        self.put_report(core, frame, flags).map(|_| ())
+    }
+    fn put_report(&self, core: usize, frame: usize, flags: Flags) -> Result<PutResult> {
+        let result = self.alloc.put_report(core, frame, flags)?;
+        // The freed frame might have been merged into a larger free block
+        self.wake(result.now_free_up_to.max(flags.order()));
+        Ok(result)
+    }
+    fn try_resize(
+        &self,
+        core: usize,
+        frame: usize,
+        order: usize,
+        new_order: usize,
+    ) -> Result<usize> {
+        let frame = self.alloc.try_resize(core, frame, order, new_order)?;
+        // The freed tail consists of one frame of every order in between
+        for o in new_order..order {
+            self.wake(o);
+        }
+        Ok(frame)
+    }
+    fn max_order(&self) -> usize {
+        self.alloc.max_order()
+    }
+    fn is_initialized(&self) -> bool {
+        self.alloc.is_initialized()
+    }
+    fn frames(&self) -> usize {
+        self.alloc.frames()
+    }
+    fn cores(&self) -> usize {
+        self.alloc.cores()
+    }
+    fn free_frames(&self) -> usize {
+        self.alloc.free_frames()
+    }
+    fn free_huge(&self) -> usize {
+        self.alloc.free_huge()
+    }
+    fn is_free(&self, frame: usize, order: usize) -> bool {
+        self.alloc.is_free(frame, order)
+    }
+    fn free_frames_below(&self, boundary: usize) -> usize {
+        self.alloc.free_frames_below(boundary)
+    }
+    fn allocated_frames_in(&self, frames: Range<usize>) -> usize {
+        self.alloc.allocated_frames_in(frames)
+    }
+    fn count_allocatable_spans(&self, buf: &mut [usize]) {
+        self.alloc.count_allocatable_spans(buf)
+    }
+    fn free_at(&self, frame: usize, order: usize) -> usize {
+        self.alloc.free_at(frame, order)
+    }
+    fn drain(&self, core: usize) -> Result<()> {
+        self.alloc.drain(core)
+    }
+    #[cfg(feature = "latency")]
+    fn histograms(&self) -> Option<crate::latency::Histograms> {
+        self.alloc.histograms()
+    }
+    fn validate(&self) {
+        self.alloc.validate()
+    }
+}
+
+#[cfg(feature = "std")]
+impl<'a, A: Alloc<'a>> fmt::Debug for BlockingAlloc<'a, A> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        self.alloc.fmt(f)
+    }
+}
+
+/// Low-memory callback of a [WatermarkAlloc]
+#[cfg(feature = "std")]
+type OomCallback = std::boxed::Box<dyn Fn() + Send + Sync>;
+
+/// Allocator wrapper, that calls a callback once the free frames drop below a watermark,
+/// e.g., to start reclaiming memory before allocations fail.
+///
+/// The allocations only check a cached counter of free frames, which is updated by
+/// the allocations and frees of this wrapper. It is refreshed from the inner
+/// allocator before the callback is called.
+#[cfg(feature = "std")]
+pub struct WatermarkAlloc<'a, A: Alloc<'a>> {
+    pub alloc: A,
+    /// Cached number of free frames
+    free: AtomicUsize,
+    /// Watermark of the registered callback, 0 if there is none
+    watermark: AtomicUsize,
+    oom: std::sync::Mutex<Option<OomCallback>>,
+    _p: PhantomData<&'a ()>,
+}
+
+#[cfg(feature = "std")]
+impl<'a, A: Alloc<'a>> WatermarkAlloc<'a, A> {
+    pub fn create(alloc: A) -> Self {
+        Self {
+            free: AtomicUsize::new(alloc.free_frames()),
+            alloc,
+            watermark: AtomicUsize::new(0),
+            oom: std::sync::Mutex::new(None),
+            _p: PhantomData,
+        }
+    }
+
+    /// Registers `cb` to be called once, as soon as fewer than `frames` are free.
+    ///
+    /// The callback is called from the thread that allocated below the watermark.
+    /// Registering a new callback overwrites the previous one.
+    pub fn set_watermark_oom<F: Fn() + Send + Sync + 'static>(&self, frames: usize, cb: F) {
+        *self.oom.lock().unwrap() = Some(std::boxed::Box::new(cb));
+        self.free.store(self.alloc.free_frames(), Release);
+        self.watermark.store(frames, Release);
+    }
+
+    /// Updates the cached counter after `frames` were allocated
+    fn claimed(&self, frames: usize) {
+        let free = self.free.fetch_sub(frames, AcqRel).wrapping_sub(frames);
+        let watermark = self.watermark.load(Acquire);
+        if free < watermark {
+            self.low_memory(watermark);
+        }
+    }
+
+    /// Refreshes the cached counter and calls the callback if still below the watermark
+    #[cold]
+    fn low_memory(&self, watermark: usize) {
+        let free = self.alloc.free_frames();
+        self.free.store(free, Release);
+        // Only the first thread below the watermark calls the callback
+        if free < watermark
+            && self
+                .watermark
+                .compare_exchange(watermark, 0, AcqRel, Acquire)
+                .is_ok()
+        {
+            let oom = self.oom.lock().unwrap().take();
+            if let Some(oom) = oom {
+                oom();
+            }
+        }
+    }
+}
+
+#[cfg(feature = "std")]
+impl<'a, A: Alloc<'a>> Alloc<'a> for WatermarkAlloc<'a, A> {
+    fn name() -> &'static str {
+        A::name()
+    }
+    fn new(cores: usize, frames: usize, init: Init, meta: MetaData<'a>) -> Result<Self> {
+        Ok(Self::create(A::new(cores, frames, init, meta)?))
+    }
+    fn metadata_size(cores: usize, frames: usize) -> MetaSize {
+        A::metadata_size(cores, frames)
+    }
+    fn metadata(&mut self) -> MetaData<'a> {
+        self.alloc.metadata()
+    }
+    fn get(&self, core: usize, flags: Flags) -> Result<usize> {
+        let frame = self.alloc.get(core, flags)?;
+        self.claimed(1 << flags.order());
+        Ok(frame)
+    }
+    fn put(&self, core: usize, frame: usize, flags: Flags) -> Result<()> {
+        self.put_report(core, frame, flags).map(|_| ())
+    }
+    fn put_report(&self, core: usize, frame: usize, flags: Flags) -> Result<PutResult> {
+        let result = self.alloc.put_report(core, frame, flags)?;
+        self.free.fetch_add(1 << flags.order(), AcqRel);
+        Ok(result)
+    }
+    fn try_resize(
+        &self,
+        core: usize,
+        frame: usize,
+        order: usize,
+        new_order: usize,
+    ) -> Result<usize> {
+        let frame = self.alloc.try_resize(core, frame, order, new_order)?;
+        if new_order > order {
+            self.claimed((1 << new_order) - (1 << order));
+        } else {
+            self.free.fetch_add((1 << order) - (1 << new_order), AcqRel);
+        }
+        Ok(frame)
+    }
+    fn max_order(&self) -> usize {
+        self.alloc.max_order()
+    }
+    fn is_initialized(&self) -> bool {
+        self.alloc.is_initialized()
+    }
+    fn frames(&self) -> usize {
+        self.alloc.frames()
+    }
+    fn cores(&self) -> usize {
+        self.alloc.cores()
+    }
+    fn free_frames(&self) -> usize {
+        self.alloc.free_frames()
+    }
+    fn free_huge(&self) -> usize {
+        self.alloc.free_huge()
+    }
+    fn is_free(&self, frame: usize, order: usize) -> bool {
+        self.alloc.is_free(frame, order)
+    }
+    fn free_frames_below(&self, boundary: usize) -> usize {
+        self.alloc.free_frames_below(boundary)
+    }
+    fn allocated_frames_in(&self, frames: Range<usize>) -> usize {
+        self.alloc.allocated_frames_in(frames)
+    }
+    fn count_allocatable_spans(&self, buf: &mut [usize]) {
+        self.alloc.count_allocatable_spans(buf)
+    }
+    fn free_at(&self, frame: usize, order: usize) -> usize {
+        self.alloc.free_at(frame, order)
+    }
+    fn drain(&self, core: usize) -> Result<()> {
+        self.alloc.drain(core)
+    }
+    #[cfg(feature = "latency")]
+    fn histograms(&self) -> Option<crate::latency::Histograms> {
+        self.alloc.histograms()
+    }
+    fn validate(&self) {
+        self.alloc.validate()
+    }
+}
+
+#[cfg(feature = "std")]
+impl<'a, A: Alloc<'a>> fmt::Debug for WatermarkAlloc<'a, A> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        self.alloc.fmt(f)
     }
 }
@@ -0,0 +1,359 @@
+//! Routing of frees to independent memory zones by their frame number.
+//!
+//! A kernel might manage several zones, each with its own allocator,
+//! but drivers that free a frame only know its (physical) frame number.
+//! [Zones] finds the owning zone by binary search over the sorted zone ranges.
+//!
+//! The routing is lock-free. Registering a zone, e.g., for memory hotplug,
+//! publishes a new sorted table and waits for the readers of the old table
+//! before freeing it, similar to sleepable RCU.
+
+use alloc::boxed::Box;
+use alloc::sync::Arc;
+use alloc::vec::Vec;
+use core::fmt;
+use core::ops::Range;
+use core::ptr::null_mut;
+use core::sync::atomic::Ordering::SeqCst;
+use core::sync::atomic::{AtomicPtr, AtomicUsize};
+
+use log::error;
+use spin::mutex::SpinMutex;
+
+use crate::{Alloc, Error, Flags, Result, MAX_ORDER};
+
+/// Object-safe subset of [Alloc] that is needed for the routing.
+///
+/// It is implemented for all allocators.
+pub trait Zone: fmt::Debug + Send + Sync {
+    /// See [Alloc::put]
+    fn put(&self, core: usize, frame: usize, flags: Flags) -> Result<()>;
+    /// See [Alloc::is_free]
+    fn is_free(&self, frame: usize, order: usize) -> bool;
+    /// See [Alloc::frames]
+    fn frames(&self) -> usize;
+    /// See [Alloc::free_frames]
+    fn free_frames(&self) -> usize;
+    /// See [Alloc::free_huge]
+    fn free_huge(&self) -> usize;
+}
+
+impl<'a, A: Alloc<'a>> Zone for A {
+    fn put(&self, core: usize, frame: usize, flags: Flags) -> Result<()> {
+        Alloc::put(self, core, frame, flags)
+    }
+    fn is_free(&self, frame: usize, order: usize) -> bool {
+        Alloc::is_free(self, frame, order)
+    }
+    fn frames(&self) -> usize {
+        Alloc::frames(self)
+    }
+    fn free_frames(&self) -> usize {
+        Alloc::free_frames(self)
+    }
+    fn free_huge(&self) -> usize {
+        Alloc::free_huge(self)
+    }
+}
+
+/// Registered zone with the frame range it manages
+struct Entry<'a> {
+    frames: Range<usize>,
+    alloc: Arc<dyn Zone + 'a>,
+}
+
+/// Router for the frees of multiple zones, see the [module docs](self).
+pub struct Zones<'a> {
+    /// Sorted and non-overlapping zones, replaced on registration
+    table: AtomicPtr<Box<[Entry<'a>]>>,
+    /// Selects the reader counter of new readers
+    epoch: AtomicUsize,
+    /// Readers of the table, for the two alternating epochs
+    readers: [AtomicUsize; 2],
+    /// Serializes registrations
+    lock: SpinMutex<()>,
+}
+
+impl Default for Zones<'_> {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl<'a> Zones<'a> {
+    pub fn new() -> Self {
+        Self {
+            table: AtomicPtr::new(Box::into_raw(Box::new(Box::from([])))),
+            epoch: AtomicUsize::new(0),
+            readers: [AtomicUsize::new(0), AtomicUsize::new(0)],
+            lock: SpinMutex::new(()),
+        }
+    }
+
+    /// Register the `alloc` for the `frames`, that have to be aligned to the max order.
+    ///
+    /// The frames of the allocator are mapped to `frames.start + frame`.
+    /// Fails with [Error::Address] if the range is invalid or overlaps another zone.
+    /// This can be called concurrently to the routing, e.g., for memory hotplug.
+    #[cold]
+    pub fn register(&self, alloc: Arc<dyn Zone + 'a>, frames: Range<usize>) -> Result<()> {
+        if frames.is_empty()
+            || frames.start % (1 << MAX_ORDER) != 0
+            || frames.len() != alloc.frames()
+        {
+            error!("invalid zone {frames:?} for {} frames", alloc.frames());
+            return Err(Error::Address);
+        }
+
+        let _guard = self.lock.lock();
+        // Only modified by us, while holding the lock
+        let old = unsafe { &*self.table.load(SeqCst) };
+        let i = old.partition_point(|e| e.frames.start < frames.start);
+        if old.get(i).is_some_and(|e| e.frames.start < frames.end)
+            || (i > 0 && old[i - 1].frames.end > frames.start)
+        {
+            error!("zone {frames:?} overlaps");
+            return Err(Error::Address);
+        }
+
+        let mut table = Vec::with_capacity(old.len() + 1);
+        table.extend(old.iter().map(|e| Entry {
+            frames: e.frames.clone(),
+            alloc: e.alloc.clone(),
+        }));
+        table.insert(i, Entry { frames, alloc });
+        let new = Box::into_raw(Box::new(table.into_boxed_slice()));
+        let old = self.table.swap(new, SeqCst);
+        self.synchronize();
+        drop(unsafe { Box::from_raw(old) });
+        Ok(())
+    }
+
+    /// Waits until all readers that might still use a replaced table are done.
+    ///
+    /// They have incremented one of the counters before the table was replaced.
+    /// New readers use the other counter, so that they cannot starve us.
+    fn synchronize(&self) {
+        for _ in 0..2 {
+            let epoch = self.epoch.fetch_add(1, SeqCst) % 2;
+            while self.readers[epoch].load(SeqCst) != 0 {
+                core::hint::spin_loop();
+            }
+        }
+    }
+
+    /// Calls `f` with the current table, which is not freed in the meantime
+    fn read<R>(&self, f: impl FnOnce(&[Entry<'a>]) -> R) -> R {
+        let epoch = self.epoch.load(SeqCst) % 2;
+        self.readers[epoch].fetch_add(1, SeqCst);
+        // The table is only freed after the readers of its epoch are done
+        let table = unsafe { &*self.table.load(SeqCst) };
+        let ret = f(table);
+        self.readers[epoch].fetch_sub(1, SeqCst);
+        ret
+    }
+
+    /// Calls `f` with the zone of the `frame` and the frame within the zone
+    fn route<R>(&self, frame: usize, f: impl FnOnce(&dyn Zone, usize) -> R) -> Option<R> {
+        self.read(|table| {
+            let i = table
+                .partition_point(|e| e.frames.start <= frame)
+                .checked_sub(1)?;
+            let entry = &table[i];
+            entry
+                .frames
+                .contains(&frame)
+                .then(|| f(&*entry.alloc, frame - entry.frames.start))
+        })
+    }
+
+    /// Free the `frame` of `order` in the zone that manages it.
+    ///
+    /// Fails with [Error::Address] if the frame is not part of any zone.
+    pub fn route_put(&self, core: usize, frame: usize, order: usize) -> Result<()> {
+        match self.route(frame, |zone, frame| zone.put(core, frame, Flags::o(order))) {
+            Some(result) => result,
+            None => {
+                error!("frame {frame:x} is not part of a zone");
+                Err(Error::Address)
+            }
+        }
+    }
+
+    /// Returns if `frame` is free in the zone that manages it. This might be racy!
+    pub fn is_free(&self, frame: usize, order: usize) -> bool {
+        self.route(frame, |zone, frame| zone.is_free(frame, order)) == Some(true)
+    }
+
+    /// Returns the number of registered zones
+    pub fn len(&self) -> usize {
+        self.read(|table| table.len())
+    }
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    /// Returns the total number of frames of all zones
+    pub fn frames(&self) -> usize {
+        self.read(|table| table.iter().map(|e| e.alloc.frames()).sum())
+    }
+    /// Returns the number of free frames of all zones
+    pub fn free_frames(&self) -> usize {
+        self.read(|table| table.iter().map(|e| e.alloc.free_frames()).sum())
+    }
+    /// Returns the number of free huge frames of all zones
+    pub fn free_huge(&self) -> usize {
+        self.read(|table| table.iter().map(|e| e.alloc.free_huge()).sum())
+    }
+    /// Returns the number of allocated frames of all zones
+    pub fn allocated_frames(&self) -> usize {
+        self.read(|table| {
+            (table.iter())
+                .map(|e| e.alloc.frames() - e.alloc.free_frames())
+                .sum()
+        })
+    }
+}
+
+impl Drop for Zones<'_> {
+    fn drop(&mut self) {
+        let table = self.table.swap(null_mut(), SeqCst);
+        drop(unsafe { Box::from_raw(table) });
+    }
+}
+
+impl fmt::Debug for Zones<'_> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        self.read(|table| {
+            f.debug_map()
+                .entries(table.iter().map(|e| (&e.frames, &e.alloc)))
+                .finish()
+        })
+    }
+}
+
+#[cfg(all(test, feature = "std"))]
+mod test {
+    use std::sync::atomic::AtomicBool;
+    use std::sync::atomic::Ordering::SeqCst;
+    use std::sync::Arc;
+    use std::vec::Vec;
+
+    use super::Zones;
+    use crate::util::logging;
+    use crate::{
+        thread, Alloc, Error, Flags, Init, LLFree, MetaData, HUGE_FRAMES, HUGE_ORDER, TREE_FRAMES,
+    };
+
+    fn zone(frames: usize) -> Arc<LLFree<'static>> {
+        let meta = MetaData::alloc(LLFree::metadata_size(4, frames));
+        Arc::new(LLFree::new(4, frames, Init::FreeAll, meta).unwrap())
+    }
+
+    #[test]
+    fn routing() {
+        logging();
+
+        let zones = Zones::new();
+        let (a, b) = (zone(TREE_FRAMES), zone(2 * TREE_FRAMES));
+        let b_start = 4 * TREE_FRAMES;
+        zones
+            .register(b.clone(), b_start..b_start + b.frames())
+            .unwrap();
+        zones.register(a.clone(), 0..a.frames()).unwrap();
+        assert_eq!(zones.len(), 2);
+        assert_eq!(zones.frames(), 3 * TREE_FRAMES);
+
+        // Invalid and overlapping ranges
+        let c = zone(TREE_FRAMES);
+        assert_eq!(zones.register(c.clone(), 0..1), Err(Error::Address));
+        assert_eq!(
+            zones.register(c.clone(), 1..TREE_FRAMES + 1),
+            Err(Error::Address)
+        );
+        for start in [0, b_start - TREE_FRAMES / 2, b_start + TREE_FRAMES] {
+            let frames = start..start + TREE_FRAMES;
+            assert_eq!(zones.register(c.clone(), frames), Err(Error::Address));
+        }
+        assert_eq!(zones.len(), 2);
+
+        // Frees are routed to the owning zone
+        let fa = a.get(0, Flags::o(0)).unwrap();
+        let fb = b.get(0, Flags::o(HUGE_ORDER)).unwrap();
+        assert_eq!(zones.allocated_frames(), 1 + HUGE_FRAMES);
+        assert!(!zones.is_free(fa, 0) && !zones.is_free(b_start + fb, 0));
+        zones.route_put(0, b_start + fb, HUGE_ORDER).unwrap();
+        assert_eq!((a.allocated_frames(), b.allocated_frames()), (1, 0));
+        zones.route_put(0, fa, 0).unwrap();
+        assert_eq!(zones.allocated_frames(), 0);
+        assert!(zones.is_free(fa, 0) && zones.is_free(b_start + fb, 0));
+
+        // Between and after the zones
+        for frame in [TREE_FRAMES, b_start - 1, b_start + b.frames()] {
+            assert_eq!(zones.route_put(0, frame, 0), Err(Error::Address));
+            assert!(!zones.is_free(frame, 0));
+        }
+    }
+
+    #[test]
+    fn hotplug() {
+        logging();
+
+        const THREADS: usize = 4;
+        let zones = Zones::new();
+        let a = zone(4 * TREE_FRAMES);
+        zones.register(a.clone(), 0..a.frames()).unwrap();
+        let b_start = 8 * TREE_FRAMES;
+        let b = zone(4 * TREE_FRAMES);
+        zones
+            .register(b.clone(), b_start..b_start + b.frames())
+            .unwrap();
+
+        let done = AtomicBool::new(false);
+        let c = zone(2 * TREE_FRAMES);
+        let c_start = 4 * TREE_FRAMES;
+        thread::parallel(0..=THREADS, |t| {
+            if t == THREADS {
+                // Register a third zone while the others are routing
+                zones
+                    .register(c.clone(), c_start..c_start + c.frames())
+                    .unwrap();
+                while !done.load(SeqCst) {
+                    std::hint::spin_loop();
+                }
+                return;
+            }
+            let (alloc, offset) = if t % 2 == 0 { (&a, 0) } else { (&b, b_start) };
+            let mut frames = Vec::new();
+            // Each thread keeps a quarter of its zone
+            for i in 0..1 << 13 {
+                frames.push(alloc.get(t, Flags::o(0)).unwrap());
+                if i % 2 == 1 {
+                    let frame = frames.swap_remove(i % frames.len());
+                    zones.route_put(t, offset + frame, 0).unwrap();
+                }
+            }
+            for frame in frames {
+                zones.route_put(t, offset + frame, 0).unwrap();
+            }
+            if t == 0 {
+                // The new zone is routed as soon as it was registered
+                while zones.len() < 3 {
+                    std::hint::spin_loop();
+                }
+                let frame = c.get(t, Flags::o(0)).unwrap();
+                zones.route_put(t, c_start + frame, 0).unwrap();
+                done.store(true, SeqCst);
+            }
+        });
+
+        assert_eq!(zones.len(), 3);
+        assert_eq!(zones.allocated_frames(), 0);
+        assert_eq!(zones.free_frames(), 10 * TREE_FRAMES);
+        assert!(zones.is_free(c_start, 0));
+        assert_eq!(
+            zones.route_put(0, c_start + c.frames(), 0),
+            Err(Error::Address)
+        );
+    }
+}